@@ -0,0 +1,33 @@
+// Records what the library was built with, for age_version_info() (see src/version.rs)
+use std::process::Command;
+
+/// The version of `name` in Cargo.lock
+fn locked_version(lock: &str, name: &str) -> Option<String> {
+    let mut lines = lock.lines();
+    let entry = format!("name = \"{}\"", name);
+    while let Some(line) = lines.next() {
+        if line == entry {
+            return lines.next()?
+                .strip_prefix("version = \"")?
+                .strip_suffix('"')
+                .map(String::from);
+        }
+    }
+    None
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+    let age = locked_version(&lock, "age").unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=LOCKBOX_AGE_VERSION={}", age);
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = Command::new(rustc).arg("--version").output().ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|version| version.trim().to_string())
+        .filter(|version| !version.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=LOCKBOX_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=LOCKBOX_TARGET={}", std::env::var("TARGET").unwrap_or_default());
+}
@@ -0,0 +1,153 @@
+// Permissions and ownership of output files
+//
+// A pipeline on a shared server often decrypts into a directory that another account
+// reads, such as a web server's or a group of analysts'. Fixing the owner and group in
+// R once lockbox returns leaves a moment when the plaintext exists with the wrong
+// ones, so `create_output_file()` sets them on the open file, before anything is
+// written to it, along with its mode.
+//
+// Changing the owner usually needs root, and changing the group needs membership of
+// it. When the system refuses, the output is removed and the call fails, rather than
+// leaving a file that the intended reader cannot open. On other systems than Unix,
+// none of this applies and a file gets the platform's default.
+use extendr_api::prelude::*;
+use std::fs::File;
+
+use crate::error::LockboxError;
+
+/// How a new output file is to be accessed; the default leaves all to the system
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Access {
+    /// Permissions such as 0o600, or None for the umask's default
+    mode: Option<u32>,
+    /// User ID to give the file to
+    owner: Option<u32>,
+    /// Group ID to give the file to
+    group: Option<u32>,
+}
+
+impl Access {
+    /// Readable and writable by the file's owner only, for keys and plaintext
+    pub(crate) const OWNER_ONLY: Access = Access { mode: Some(0o600), owner: None, group: None };
+
+    pub(crate) fn mode(mode: Option<u32>) -> Self {
+        Access { mode, ..Access::default() }
+    }
+
+    /// Read the `mode`, `owner` and `group` arguments of an R function
+    ///
+    /// `mode` is an integer such as 384 (octal 600). `owner` and `group` are names,
+    /// such as "www-data", or numeric IDs.
+    pub(crate) fn from_r(mode: Nullable<i32>, owner: Nullable<String>, group: Nullable<String>) -> Result<Self> {
+        let mode = match mode {
+            Nullable::NotNull(mode) if (0..=0o7777).contains(&mode) => Some(mode as u32),
+            Nullable::NotNull(mode) => {
+                return Err(LockboxError::InvalidArgument(format!(
+                    "The file mode must be between 0000 and 7777 in octal, got {:o}",
+                    mode
+                )).into());
+            }
+            Nullable::Null => None,
+        };
+        #[cfg(unix)]
+        let (owner, group) = (
+            match owner {
+                Nullable::NotNull(name) => Some(user_id(&name)?),
+                Nullable::Null => None,
+            },
+            match group {
+                Nullable::NotNull(name) => Some(group_id(&name)?),
+                Nullable::Null => None,
+            },
+        );
+        #[cfg(not(unix))]
+        let (owner, group) = {
+            let _ = (owner, group);
+            (None, None)
+        };
+        Ok(Access { mode, owner, group })
+    }
+
+    /// The permissions to create the file with, which the umask may still reduce
+    pub(crate) fn creation_mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    /// Give the newly opened `file` its owner, group and mode
+    ///
+    /// The owner and group come first, as a change of owner clears the setuid and
+    /// setgid bits.
+    pub(crate) fn apply(&self, file: &File, path: &str) -> Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            use std::os::unix::io::AsRawFd;
+
+            if self.owner.is_some() || self.group.is_some() {
+                // -1 leaves an ID as it is
+                let owner = self.owner.map_or(libc::uid_t::MAX, |id| id as libc::uid_t);
+                let group = self.group.map_or(libc::gid_t::MAX, |id| id as libc::gid_t);
+                if unsafe { libc::fchown(file.as_raw_fd(), owner, group) } != 0 {
+                    let e = std::io::Error::last_os_error();
+                    return Err(LockboxError::io(format!("Failed to change the owner or group of '{}'", path), &e).into());
+                }
+            }
+            // The umask clears bits of the mode given at creation, and an overwritten
+            // file keeps its own
+            if let Some(mode) = self.mode {
+                file.set_permissions(std::fs::Permissions::from_mode(mode))
+                    .map_err(|e| LockboxError::io(format!("Failed to set the permissions of '{}'", path), &e))?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (file, path);
+        Ok(())
+    }
+}
+
+/// Call a `get*nam_r` function, growing its buffer until the entry fits
+#[cfg(unix)]
+fn lookup<T>(name: &str, kind: &str, get: impl Fn(*const libc::c_char, &mut T, &mut [libc::c_char], &mut *mut T) -> libc::c_int) -> Result<Option<T>> {
+    let c_name = std::ffi::CString::new(name)
+        .map_err(|_| LockboxError::InvalidArgument(format!("Invalid {} name '{}'", kind, name)))?;
+    let mut buffer: Vec<libc::c_char> = vec![0; 4096];
+    loop {
+        let mut entry: T = unsafe { std::mem::zeroed() };
+        let mut found: *mut T = std::ptr::null_mut();
+        match get(c_name.as_ptr(), &mut entry, &mut buffer, &mut found) {
+            0 if found.is_null() => return Ok(None),
+            0 => return Ok(Some(entry)),
+            libc::ERANGE if buffer.len() < 1 << 20 => buffer.resize(buffer.len() * 2, 0),
+            errno => {
+                let e = std::io::Error::from_raw_os_error(errno);
+                return Err(LockboxError::io(format!("Failed to look up the {} '{}'", kind, name), &e).into());
+            }
+        }
+    }
+}
+
+/// The ID of a user given by name or number
+#[cfg(unix)]
+fn user_id(name: &str) -> Result<u32> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(id);
+    }
+    let entry = lookup(name, "user", |name, entry: &mut libc::passwd, buffer, found| unsafe {
+        libc::getpwnam_r(name, entry, buffer.as_mut_ptr(), buffer.len(), found)
+    })?;
+    entry.map(|entry| entry.pw_uid)
+        .ok_or_else(|| LockboxError::InvalidArgument(format!("Unknown user '{}'", name)).into())
+}
+
+/// The ID of a group given by name or number
+#[cfg(unix)]
+fn group_id(name: &str) -> Result<u32> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(id);
+    }
+    let entry = lookup(name, "group", |name, entry: &mut libc::group, buffer, found| unsafe {
+        libc::getgrnam_r(name, entry, buffer.as_mut_ptr(), buffer.len(), found)
+    })?;
+    entry.map(|entry| entry.gr_gid)
+        .ok_or_else(|| LockboxError::InvalidArgument(format!("Unknown group '{}'", name)).into())
+}
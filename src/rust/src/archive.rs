@@ -0,0 +1,425 @@
+// Encrypted directory archives
+//
+// A directory is packed into a tar archive that is streamed straight into age, so
+// neither the archive nor its plaintext ever exists on disk as a whole. Reading goes
+// the same way in reverse: the archive is decrypted and walked entry by entry, and
+// only the members asked for are written out. Pulling one file out of a large
+// archive still decrypts everything before it (age has no index), but writes nothing
+// else and stops as soon as the last requested member is out.
+//
+// Symbolic links are archived as links, never followed. Extraction refuses entries
+// that would land outside the destination, and never overwrites existing files.
+//
+// Each regular file is preceded by a PAX extended header recording its SHA-256
+// (`LOCKBOX.sha256`) and media type (`LOCKBOX.content_type`), so that a deliverable
+// sent as one archive describes its own contents. Other tar readers skip these
+// records. Extraction checks each file against its recorded hash.
+use extendr_api::prelude::*;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::access::Access;
+use crate::audit;
+use crate::error::LockboxError;
+use crate::filter::{walk_filtered, PathFilter};
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stream::{age_input, check_age_file, check_interrupt, PartialOutput};
+use crate::{create_output_file, parse_identities_from_key_file, parse_recipients, read_key_file};
+
+/// PAX keys under which each regular file's SHA-256 and content type are recorded
+const SHA256_KEY: &str = "LOCKBOX.sha256";
+const CONTENT_TYPE_KEY: &str = "LOCKBOX.content_type";
+
+/// The media type of a file, guessed from the extension of its name
+fn content_type(name: &Path) -> &'static str {
+    let extension = name.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+    match extension.as_str() {
+        "txt" | "log" => "text/plain",
+        "md" => "text/markdown",
+        "csv" => "text/csv",
+        "tsv" => "text/tab-separated-values",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "yaml" | "yml" => "application/yaml",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "parquet" => "application/vnd.apache.parquet",
+        "arrow" | "feather" => "application/vnd.apache.arrow.file",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "svg" => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Append `path` to an archive as `name`, recording the SHA-256 and content type of
+/// regular files in a PAX header before it
+fn append_member<W: Write>(builder: &mut tar::Builder<W>, path: &Path, name: &Path, content_type_override: Option<&str>) -> Result<()> {
+    let write_failed = |e: std::io::Error| LockboxError::io(format!("Failed to archive '{}'", path.display()), &e);
+    let metadata = std::fs::symlink_metadata(path).map_err(write_failed)?;
+    if metadata.is_file() {
+        let sha256 = audit::sha256_file(&path.to_string_lossy())
+            .ok_or_else(|| write_failed(std::io::Error::new(std::io::ErrorKind::Other, "cannot be read")))?;
+        let content_type = content_type_override.unwrap_or_else(|| content_type(name));
+        builder.append_pax_extensions([(SHA256_KEY, sha256.as_bytes()), (CONTENT_TYPE_KEY, content_type.as_bytes())])
+            .map_err(write_failed)?;
+    }
+    builder.append_path_with_name(path, name).map_err(write_failed)?;
+    Ok(())
+}
+
+/// Stream the members added by `append` into a new encrypted archive
+///
+/// The output is removed if anything fails.
+fn create_archive<F>(output_file_path: &str, recipients: Vec<String>, armor: bool, overwrite: bool, append: F) -> Result<()>
+where
+    F: FnOnce(&mut tar::Builder<age::stream::StreamWriter<age::armor::ArmoredWriter<BufWriter<File>>>>) -> Result<()>,
+{
+    use age::armor::{ArmoredWriter, Format};
+
+    policy::check_armor(armor)?;
+    let parsed_recipients = parse_recipients(recipients)?;
+    let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+
+    let output = create_output_file(output_file_path, overwrite, Access::default())?;
+    let partial = PartialOutput::new(output_file_path);
+    let format = if armor { Format::AsciiArmor } else { Format::Binary };
+    let armored_writer = ArmoredWriter::wrap_output(BufWriter::new(output), format)
+        .map_err(|e| LockboxError::Armor(format!("Failed to create armored writer: {}", e)))?;
+    let encrypted_writer = encryptor.wrap_output(armored_writer)
+        .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+
+    let mut builder = tar::Builder::new(encrypted_writer);
+    builder.follow_symlinks(false);
+    append(&mut builder)?;
+
+    let encrypted_writer = builder.into_inner()
+        .map_err(|e| LockboxError::io("Failed to finalize the archive", &e))?;
+    let armored_writer = encrypted_writer.finish()
+        .map_err(|e| LockboxError::io("Failed to finalize encryption", &e))?;
+    armored_writer.finish()
+        .and_then(|mut writer| writer.flush())
+        .map_err(|e| LockboxError::Armor(format!("Failed to finalize armored writer: {}", e)))?;
+
+    partial.complete();
+    Ok(())
+}
+
+/// Archive a directory and encrypt the archive to public keys
+///
+/// Members are stored under the directory's own name, as `tar` does. `include` and
+/// `exclude` are glob patterns that select the members, and `ignore_files` also
+/// leaves out what `.gitignore` files do (see filter.rs).
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_archive_create(
+    dir: &str,
+    output_file_path: &str,
+    recipients: Vec<String>,
+    include: Nullable<Vec<String>>,
+    exclude: Nullable<Vec<String>>,
+    ignore_files: bool,
+    armor: bool,
+    overwrite: bool,
+) -> Result<i32> {
+    let keys = recipients.clone();
+    let result = (|| {
+        let root = fs_path(dir);
+        let base = root.file_name().map(PathBuf::from)
+            .ok_or_else(|| LockboxError::InvalidArgument(format!("'{}' has no directory name to archive under", dir)))?;
+        let filter = PathFilter::new(include, exclude, ignore_files)?;
+        let mut paths = Vec::new();
+        walk_filtered(&root, &filter, &mut paths)
+            .map_err(|e| LockboxError::io(format!("Failed to read '{}'", dir), &e))?;
+
+        create_archive(output_file_path, recipients, armor, overwrite, |builder| {
+            append_member(builder, &root, &base, None)?;
+            for path in &paths {
+                check_interrupt()?;
+                let name = base.join(path.strip_prefix(&root).unwrap_or(path));
+                append_member(builder, path, &name, None)?;
+            }
+            Ok(())
+        })?;
+        Ok(paths.len() as i32 + 1)
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(Some(dir), output_file_path, done.is_some(), audit::recipient_keys(keys))
+    })
+}
+
+/// Archive a set of files, each under the name given, and encrypt the archive
+///
+/// `names` are relative member paths; `content_types` are recorded instead of the
+/// types guessed from the names, where not NA.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_archive_create_files(
+    paths: Vec<String>,
+    names: Vec<String>,
+    content_types: Strings,
+    output_file_path: &str,
+    recipients: Vec<String>,
+    armor: bool,
+    overwrite: bool,
+) -> Result<()> {
+    let keys = recipients.clone();
+    let result = (|| {
+        for name in &names {
+            let relative = Path::new(name);
+            if name.is_empty() || !relative.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+                return Err(LockboxError::InvalidArgument(format!(
+                    "'{}' is not a relative member name without '..'",
+                    name
+                )).into());
+            }
+        }
+        create_archive(output_file_path, recipients, armor, overwrite, |builder| {
+            for ((path, name), content_type) in paths.iter().zip(&names).zip(content_types.iter()) {
+                check_interrupt()?;
+                let content_type = if content_type.is_na() { None } else { Some(content_type.as_str()) };
+                append_member(builder, &fs_path(path), Path::new(name), content_type)?;
+            }
+            Ok(())
+        })
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(None, output_file_path, done.is_some(), audit::recipient_keys(keys))
+    })
+}
+
+/// Open an encrypted archive for reading, decrypting as it is read
+fn open_archive(archive_path: &str, private_key_path: &str, strict_permissions: bool) -> Result<tar::Archive<Box<dyn Read>>> {
+    let key_content = read_key_file(private_key_path, strict_permissions)?;
+    let identities = parse_identities_from_key_file(&key_content)?;
+
+    policy::check_decrypt_from(&fs_path(archive_path))?;
+    check_age_file(&fs_path(archive_path))?;
+    let input = File::open(fs_path(archive_path))
+        .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", archive_path), &e))?;
+    let decryptor = age::Decryptor::new(age_input(BufReader::new(input))?)
+        .map_err(|e| LockboxError::from_decrypt(e, false))?;
+    let scrypt = decryptor.is_scrypt();
+    let reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))
+        .map_err(|e| LockboxError::from_decrypt(e, scrypt))?;
+    Ok(tar::Archive::new(Box::new(reader) as Box<dyn Read>))
+}
+
+/// A member's path as listed, with forward slashes and no trailing slash
+fn member_path(entry: &tar::Entry<Box<dyn Read>>) -> std::result::Result<String, LockboxError> {
+    let path = entry.path()
+        .map_err(|e| LockboxError::CorruptPayload(format!("The archive holds an unreadable member name: {}", e)))?;
+    Ok(path.to_string_lossy().replace('\\', "/").trim_end_matches('/').to_string())
+}
+
+/// The SHA-256 and content type recorded for a member, if any
+///
+/// Archives written by other tools, or before these were recorded, have neither.
+struct RecordedMetadata {
+    sha256: Option<String>,
+    content_type: Option<String>,
+}
+
+impl RecordedMetadata {
+    fn read(entry: &mut tar::Entry<Box<dyn Read>>) -> std::result::Result<Self, LockboxError> {
+        let mut recorded = RecordedMetadata { sha256: None, content_type: None };
+        let extensions = entry.pax_extensions().map_err(LockboxError::from_payload)?;
+        for extension in extensions.into_iter().flatten() {
+            let extension = extension.map_err(LockboxError::from_payload)?;
+            let value = || extension.value().ok().map(String::from);
+            match extension.key() {
+                Ok(SHA256_KEY) => recorded.sha256 = value(),
+                Ok(CONTENT_TYPE_KEY) => recorded.content_type = value(),
+                _ => {}
+            }
+        }
+        Ok(recorded)
+    }
+}
+
+fn member_type(entry_type: tar::EntryType) -> &'static str {
+    if entry_type.is_dir() {
+        "directory"
+    } else if entry_type.is_symlink() {
+        "symlink"
+    } else if entry_type.is_file() {
+        "file"
+    } else {
+        "other"
+    }
+}
+
+/// List the members of an encrypted archive
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_archive_list(archive_path: &str, private_key_path: &str, strict_permissions: bool) -> Result<List> {
+    let result = (|| {
+        let mut archive = open_archive(archive_path, private_key_path, strict_permissions)?;
+        let (mut paths, mut sizes, mut types) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut hashes, mut content_types) = (Vec::new(), Vec::new());
+        for entry in archive.entries().map_err(LockboxError::from_payload)? {
+            check_interrupt()?;
+            let mut entry = entry.map_err(LockboxError::from_payload)?;
+            paths.push(member_path(&entry)?);
+            sizes.push(entry.size() as f64);
+            types.push(member_type(entry.header().entry_type()).to_string());
+            let recorded = RecordedMetadata::read(&mut entry)?;
+            hashes.push(recorded.sha256.map_or_else(Rstr::na, Rstr::from));
+            content_types.push(recorded.content_type.map_or_else(Rstr::na, Rstr::from));
+        }
+        Ok(list!(
+            path = paths,
+            size = sizes,
+            kind = types,
+            sha256 = Strings::from_values(hashes),
+            content_type = Strings::from_values(content_types)
+        ))
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::file(archive_path, None, audit::key_file_public_keys(private_key_path))
+    })
+}
+
+/// The umask recorded when the package was loaded, for systems without /proc
+#[cfg(unix)]
+static LOADED_UMASK: AtomicU32 = AtomicU32::new(0o022);
+
+/// The umask as Linux reports it in /proc/self/status
+#[cfg(unix)]
+fn proc_umask() -> Option<u32> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let value = status.lines().find_map(|line| line.strip_prefix("Umask:"))?;
+    u32::from_str_radix(value.trim(), 8).ok()
+}
+
+/// The process's umask, read without changing it
+///
+/// Elsewhere than on Linux it can only be read by setting it, which would give files
+/// created meanwhile by job and worker threads the wrong mode, so the value recorded
+/// by `age_archive_init()` is used.
+#[cfg(unix)]
+fn umask() -> u32 {
+    proc_umask().unwrap_or_else(|| LOADED_UMASK.load(Ordering::Relaxed))
+}
+
+#[cfg(not(unix))]
+fn umask() -> u32 {
+    0
+}
+
+/// Record the umask where it cannot be read otherwise, before any thread creates files
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_archive_init() {
+    #[cfg(unix)]
+    if proc_umask().is_none() {
+        let mask = unsafe { libc::umask(0o077) };
+        unsafe { libc::umask(mask) };
+        LOADED_UMASK.store(mask as u32, Ordering::Relaxed);
+    }
+}
+
+/// Extract members of an encrypted archive into `dest`
+///
+/// `members` are member paths as listed; a directory selects everything below it.
+/// With NULL, everything is extracted. Returns the paths written, relative to `dest`.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_archive_extract(archive_path: &str, private_key_path: &str, strict_permissions: bool, members: Nullable<Vec<String>>, dest: &str) -> Result<Vec<String>> {
+    let result = (|| {
+        let wanted: Option<Vec<String>> = match members {
+            Nullable::NotNull(members) => Some(members.iter().map(|m| m.trim_end_matches('/').to_string()).collect()),
+            Nullable::Null => None,
+        };
+        let selects = |path: &str, member: &str| path == member || path.starts_with(&format!("{}/", member));
+        let mut found = vec![false; wanted.as_ref().map_or(0, Vec::len)];
+        // A file member is complete once seen; a directory may have contents anywhere after it
+        let mut complete = found.clone();
+
+        let dest_path = fs_path(dest);
+        policy::check_plaintext_to(&dest_path)?;
+        let mut archive = open_archive(archive_path, private_key_path, strict_permissions)?;
+        archive.set_overwrite(false);
+        // As tar run by a user other than root: the permission bits only, reduced
+        // by the umask, so no member comes out setuid, setgid or sticky
+        archive.set_preserve_permissions(false);
+        archive.set_mask(umask());
+        let mut extracted = Vec::new();
+        for entry in archive.entries().map_err(LockboxError::from_payload)? {
+            check_interrupt()?;
+            let mut entry = entry.map_err(LockboxError::from_payload)?;
+            let path = member_path(&entry)?;
+            if let Some(wanted) = &wanted {
+                let mut selected = false;
+                for (i, member) in wanted.iter().enumerate() {
+                    if selects(&path, member) {
+                        selected = true;
+                        found[i] = true;
+                        complete[i] = path == *member && !entry.header().entry_type().is_dir();
+                    }
+                }
+                if !selected {
+                    continue;
+                }
+            }
+            let is_dir = entry.header().entry_type().is_dir();
+            if !is_dir && std::fs::symlink_metadata(dest_path.join(&path)).is_ok() {
+                return Err(LockboxError::FileExists(format!("'{}' already exists in '{}'", path, dest)).into());
+            }
+            let recorded = RecordedMetadata::read(&mut entry)?;
+            let unpacked = entry.unpack_in(&dest_path)
+                .map_err(|e| LockboxError::io(format!("Failed to extract '{}'", path), &e))?;
+            if !unpacked {
+                return Err(LockboxError::InvalidArgument(format!("The archive member '{}' points outside the destination", path)).into());
+            }
+            if let Some(expected) = recorded.sha256.filter(|_| entry.header().entry_type().is_file()) {
+                let written = dest_path.join(&path);
+                if audit::sha256_file(&written.to_string_lossy()).as_deref() != Some(expected.as_str()) {
+                    let _ = std::fs::remove_file(&written);
+                    return Err(LockboxError::CorruptPayload(format!(
+                        "'{}' does not match the SHA-256 recorded in the archive",
+                        path
+                    )).into());
+                }
+            }
+            extracted.push(path);
+            if !complete.is_empty() && complete.iter().all(|c| *c) {
+                break;
+            }
+        }
+
+        if let Some(wanted) = &wanted {
+            let missing: Vec<&str> = wanted.iter().zip(&found).filter(|(_, f)| !**f).map(|(m, _)| m.as_str()).collect();
+            if !missing.is_empty() {
+                return Err(LockboxError::NotFound(format!("Not in the archive: {}", missing.join(", "))).into());
+            }
+        }
+        Ok(extracted)
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::file(archive_path, Some(dest), audit::key_file_public_keys(private_key_path))
+    })
+}
+
+extendr_module! {
+    mod archive;
+    fn age_archive_create;
+    fn age_archive_create_files;
+    fn age_archive_list;
+    fn age_archive_extract;
+    fn age_archive_init;
+}
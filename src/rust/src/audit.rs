@@ -0,0 +1,411 @@
+// Audit log of encryption and decryption
+//
+// Some settings must be able to show who decrypted what, and when. Once a session
+// names a log file, every file and string encrypted or decrypted appends one JSON
+// object on its own line, whether the operation succeeded or not:
+//
+//     {"time":"2026-01-05T14:03:11.204Z","user":"alice","host":"ws-12","pid":4242,
+//      "operation":"decrypt","method":"key","input":"/data/survey.csv.age",
+//      "output":"/data/survey.csv","sha256":"9f2c...","keys":["age1..."],"ok":true,
+//      "code":null,"message":null,"prev":"5e0b..."}
+//
+// `sha256` is the hash of the encrypted content, never of the plaintext, which would
+// let anyone holding the log confirm a guess at a secret. `keys` are the public keys
+// encrypted to, or those of the private keys offered for decryption; private keys
+// and passphrases are never written. The file is only ever appended to and is
+// created readable by its owner only.
+//
+// Entries are chained: `prev` is the SHA-256 of the line before, as written, and of
+// nothing for the first (64 zeros). Editing, inserting or deleting a line therefore
+// breaks the chain from there on, which `age_audit_verify()` reports. Removing lines
+// from the end leaves a valid chain, so the hash of the last line is returned for
+// keeping elsewhere.
+//
+// Parallel R workers (callr, future, parallel) often share one log. Reading the last
+// line and appending the next must then happen as one step, or two workers chain to
+// the same line. Appends hold a lock file, `<log>.lock`, created exclusively, which
+// works across processes and platforms alike. A lock left behind by a crashed worker
+// is taken over once it is STALE_LOCK old.
+use extendr_api::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+use crate::error::{error_parts, LockboxError};
+use crate::logging::{self, Level, Value};
+use crate::stats;
+use crate::paths::fs_path;
+
+static AUDIT_LOG: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+fn audit_log() -> MutexGuard<'static, Option<PathBuf>> {
+    AUDIT_LOG.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// What an audited operation worked on, gathered only when a log is set
+#[derive(Default)]
+pub(crate) struct Details {
+    /// Input and output files; None for strings
+    pub(crate) input: Option<String>,
+    pub(crate) output: Option<String>,
+    /// SHA-256 of the encrypted content, in hex
+    pub(crate) sha256: Option<String>,
+    /// Public keys encrypted to or decrypted with; empty for a passphrase
+    pub(crate) keys: Vec<String>,
+}
+
+impl Details {
+    /// Decrypting the file `input`, to `output` or into memory
+    pub(crate) fn file(input: &str, output: Option<&str>, keys: Vec<String>) -> Self {
+        Details {
+            input: Some(input.to_string()),
+            output: output.map(String::from),
+            sha256: sha256_file(input),
+            keys,
+        }
+    }
+
+    /// Encrypting the file `input`, or data in memory, to `output`, which is only hashed once `done`
+    pub(crate) fn encrypted_file(input: Option<&str>, output: &str, done: bool, keys: Vec<String>) -> Self {
+        Details {
+            input: input.map(String::from),
+            output: Some(output.to_string()),
+            sha256: if done { sha256_file(output) } else { None },
+            keys,
+        }
+    }
+
+    /// Encrypting or decrypting a string, hashed as the encrypted text
+    pub(crate) fn string(encrypted: Option<&str>, keys: Vec<String>) -> Self {
+        Details::raw(encrypted.map(str::as_bytes), None, keys)
+    }
+
+    /// Encrypting or decrypting the files of directory `input` into `output`, not hashed
+    pub(crate) fn directory(input: &str, output: &str, keys: Vec<String>) -> Self {
+        Details { input: Some(input.to_string()), output: Some(output.to_string()), keys, ..Details::default() }
+    }
+
+    /// Encrypting or decrypting from `input` to `output`, either of which may be a pipe
+    ///
+    /// A pipe cannot be read a second time, so nothing is hashed.
+    pub(crate) fn stream(input: &str, output: &str, keys: Vec<String>) -> Self {
+        Details { input: Some(input.to_string()), output: Some(output.to_string()), keys, ..Details::default() }
+    }
+
+    /// Downloading and decrypting `url`, to `output` or into memory
+    ///
+    /// The ciphertext is streamed, so it is not hashed.
+    pub(crate) fn download(url: &str, output: Option<&str>, keys: Vec<String>) -> Self {
+        Details { input: Some(without_query(url)), output: output.map(String::from), keys, ..Details::default() }
+    }
+
+    /// Encrypting the file `input` and uploading it to `url`
+    pub(crate) fn upload(input: &str, url: &str, keys: Vec<String>) -> Self {
+        Details { input: Some(input.to_string()), output: Some(without_query(url)), keys, ..Details::default() }
+    }
+
+    /// Encrypting or decrypting a raw vector, possibly decrypted to `output`
+    pub(crate) fn raw(encrypted: Option<&[u8]>, output: Option<&str>, keys: Vec<String>) -> Self {
+        Details { output: output.map(String::from), sha256: encrypted.map(sha256_hex), keys, ..Details::default() }
+    }
+}
+
+/// A URL without its query, where a presigned URL carries its credentials
+fn without_query(url: &str) -> String {
+    url.split('?').next().unwrap_or(url).to_string()
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    time: String,
+    user: Option<String>,
+    host: Option<String>,
+    pid: u32,
+    operation: &'a str,
+    method: &'a str,
+    input: Option<String>,
+    output: Option<String>,
+    sha256: Option<String>,
+    keys: Vec<String>,
+    ok: bool,
+    code: Option<String>,
+    message: Option<String>,
+    prev: String,
+}
+
+/// Age after which a lock file is taken to be left over from a crashed process
+const STALE_LOCK: Duration = Duration::from_secs(10);
+
+/// How long to wait for another process to finish appending
+const LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The `prev` of the first entry in a log
+const CHAIN_START: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// SHA-256 of bytes, in hex
+pub(crate) fn sha256_hex(bytes: &[u8]) -> String {
+    hex_digest(&Sha256::digest(bytes))
+}
+
+/// SHA-256 of a file, in hex, or None if it cannot be read
+pub(crate) fn sha256_file(path: &str) -> Option<String> {
+    let mut file = std::fs::File::open(fs_path(path)).ok()?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).ok()?;
+    Some(hex_digest(&hasher.finalize()))
+}
+
+fn hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The public keys of the x25519 private keys in a key file
+///
+/// Keys of other kinds, such as plugin identities, have no public key to log.
+pub(crate) fn identity_public_keys(key_content: &str) -> Vec<String> {
+    crate::parse_x25519_identities(key_content)
+        .map(|identities| identities.iter().map(|i| i.to_public().to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// The public keys of a key file, or none if it cannot be read
+pub(crate) fn key_file_public_keys(path: &str) -> Vec<String> {
+    std::fs::read_to_string(fs_path(path))
+        .map(Zeroizing::new)
+        .map_or_else(|_| Vec::new(), |content| identity_public_keys(&content))
+}
+
+/// Recipients as public keys, with team names resolved where possible
+pub(crate) fn recipient_keys(recipients: Vec<String>) -> Vec<String> {
+    crate::team::resolve_recipients(&recipients, None).unwrap_or(recipients)
+}
+
+fn host_name() -> Option<String> {
+    ["HOSTNAME", "COMPUTERNAME"].iter()
+        .find_map(|name| std::env::var(name).ok())
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+/// Open the log for reading and appending, creating it readable by its owner only
+fn open_log(path: &Path) -> std::io::Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true).append(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// The last line of a file, without its newline, read backwards from the end
+fn last_line(file: &mut std::fs::File) -> std::io::Result<Option<Vec<u8>>> {
+    const BLOCK: u64 = 4096;
+    let size = file.seek(SeekFrom::End(0))?;
+    let mut end = size;
+    let mut line = Vec::new();
+    while end > 0 {
+        let start = end.saturating_sub(BLOCK);
+        let mut block = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut block)?;
+        block.extend_from_slice(&line);
+        line = block;
+        end = start;
+        let content = line.strip_suffix(b"\n").unwrap_or(&line);
+        if let Some(newline) = content.iter().rposition(|&b| b == b'\n') {
+            return Ok(Some(content[newline + 1..].to_vec()));
+        }
+    }
+    let content = line.strip_suffix(b"\n").unwrap_or(&line);
+    Ok(if content.is_empty() { None } else { Some(content.to_vec()) })
+}
+
+/// Exclusive right to append to a log, released when dropped
+struct LogLock(PathBuf);
+
+impl LogLock {
+    fn acquire(log: &Path) -> std::io::Result<Self> {
+        let mut path = log.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        let started = Instant::now();
+        loop {
+            match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(LogLock(path)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+            let stale = std::fs::metadata(&path).and_then(|m| m.modified()).ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .map_or(false, |age| age > STALE_LOCK);
+            if stale {
+                let _ = std::fs::remove_file(&path);
+            } else if started.elapsed() > LOCK_TIMEOUT {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::WouldBlock,
+                    format!("'{}' is held by another process", path.display()),
+                ));
+            } else {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        }
+    }
+}
+
+impl Drop for LogLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Append an entry, chained to the last line of the log
+fn append(path: &Path, entry: &mut Entry) -> std::io::Result<()> {
+    let _lock = LogLock::acquire(path)?;
+    let mut file = open_log(path)?;
+    entry.prev = match last_line(&mut file)? {
+        Some(line) => sha256_hex(&line),
+        None => CHAIN_START.to_string(),
+    };
+    let line = serde_json::to_string(entry)?;
+    file.write_all(format!("{}\n", line).as_bytes())
+}
+
+/// Log the outcome of an operation, if the session has an audit log, and pass it on
+///
+/// `operation` is "encrypt" or "decrypt" and `method` "key" or "passphrase";
+/// `details` is only called when there is a log to write. An operation whose entry
+/// cannot be written fails, so nothing escapes the log unnoticed. The outcome also
+/// goes to the debug log (see logging.rs), at the "info" level, and to the session
+/// counters (see stats.rs).
+pub(crate) fn record<T>(operation: &'static str, method: &'static str, result: Result<T>, details: impl FnOnce(Option<&T>) -> Details) -> Result<T> {
+    stats::count(operation, &result);
+    match &result {
+        Ok(_) => logging::event(Level::Info, operation, &[("method", Value::Label(method)), ("ok", Value::Flag(true))]),
+        Err(e) => logging::event(Level::Info, operation, &[
+            ("method", Value::Label(method)),
+            ("ok", Value::Flag(false)),
+            ("code", Value::Failure(e)),
+        ]),
+    }
+    let path = match audit_log().clone() {
+        Some(path) => path,
+        None => return result,
+    };
+    let details = details(result.as_ref().ok());
+    let (code, message) = match &result {
+        Ok(_) => (None, None),
+        Err(e) => {
+            let (code, message) = error_parts(e);
+            (Some(code), Some(message))
+        }
+    };
+    let mut entry = Entry {
+        time: chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        user: std::env::var("USER").or_else(|_| std::env::var("USERNAME")).ok(),
+        host: host_name(),
+        pid: std::process::id(),
+        operation,
+        method,
+        input: details.input,
+        output: details.output,
+        sha256: details.sha256,
+        keys: details.keys,
+        ok: result.is_ok(),
+        code,
+        message,
+        prev: String::new(),
+    };
+    append(&path, &mut entry)
+        .map_err(|e| LockboxError::io(format!("Failed to write the audit log '{}'", path.display()), &e))?;
+    result
+}
+
+/// Set or remove the session's audit log
+///
+/// The file is opened once now, so that an unwritable path is reported at once.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_audit_log(path: Nullable<String>) -> Result<()> {
+    *audit_log() = match path {
+        Nullable::NotNull(path) => {
+            let path = fs_path(&path);
+            open_log(&path).map_err(|e| {
+                LockboxError::io(format!("Failed to open the audit log '{}'", path.display()), &e)
+            })?;
+            Some(path)
+        }
+        Nullable::Null => None,
+    };
+    Ok(())
+}
+
+/// The result of checking an audit log's hash chain
+pub(crate) struct Verification {
+    pub(crate) entries: i32,
+    /// The first line that breaks the chain, and why
+    pub(crate) broken: Option<(i32, String)>,
+    /// SHA-256 of the last line, or None for an empty log
+    pub(crate) last_hash: Option<String>,
+}
+
+pub(crate) fn verify_chain(path: &str) -> Result<Verification> {
+    let read_failed = |e: std::io::Error| LockboxError::io(format!("Failed to read the audit log '{}'", path), &e);
+    let file = std::fs::File::open(fs_path(path)).map_err(read_failed)?;
+    let mut expected = CHAIN_START.to_string();
+    let mut entries = 0;
+    let mut broken = None;
+    for line in std::io::BufReader::new(file).split(b'\n') {
+        let line = line.map_err(read_failed)?;
+        entries += 1;
+        if broken.is_none() {
+            let prev = serde_json::from_slice::<serde_json::Value>(&line).ok()
+                .and_then(|entry| entry.get("prev").and_then(|prev| prev.as_str()).map(String::from));
+            broken = match prev {
+                None => Some((entries, "not an audit log entry with a `prev` hash".to_string())),
+                Some(prev) if prev == expected => None,
+                Some(_) if entries == 1 => Some((entries, "the first entry does not start the chain; lines were removed from the start".to_string())),
+                Some(_) => Some((entries, "`prev` does not match the line before; the log was edited".to_string())),
+            };
+        }
+        expected = sha256_hex(&line);
+    }
+    let last_hash = if entries > 0 { Some(expected) } else { None };
+    Ok(Verification { entries, broken, last_hash })
+}
+
+/// Check the hash chain of an audit log
+///
+/// Returns `valid`, the number of `entries`, the first `line` that breaks the chain
+/// and the `problem` with it (both NA when valid), and `last_hash`, the SHA-256 of the
+/// last line (NA for an empty log).
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_audit_verify(path: &str) -> Result<List> {
+    let verification = verify_chain(path)?;
+    let (line, problem) = match verification.broken {
+        Some((line, problem)) => (Rint::from(line), Some(problem)),
+        None => (Rint::na(), None),
+    };
+    Ok(list!(
+        valid = problem.is_none(),
+        entries = verification.entries,
+        line = Integers::from_values([line]),
+        problem = Strings::from_values([problem.map_or_else(Rstr::na, Rstr::from)]),
+        last_hash = Strings::from_values([verification.last_hash.map_or_else(Rstr::na, Rstr::from)])
+    ))
+}
+
+extendr_module! {
+    mod audit;
+    fn age_audit_log;
+    fn age_audit_verify;
+}
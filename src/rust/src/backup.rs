@@ -0,0 +1,534 @@
+// Incremental encrypted backups
+//
+// Each backup of a directory is a set: a subdirectory of the destination named after
+// the time it was taken (`20240611T093000Z`), holding the files that are new or changed
+// since the previous set, and an encrypted manifest of every file in the directory at
+// that time. Each manifest entry names the set that holds the file's current content,
+// so restoring any set only needs that set and the ones before it.
+//
+//     dest/20240611T093000Z/manifest.json.age
+//     dest/20240611T093000Z/000001.age
+//
+// Blobs are numbered rather than named, so the destination reveals neither file names
+// nor which files changed. Change detection needs the previous file hashes without a
+// private key, so they are kept in a plaintext state file on the source side; it
+// should stay off the backup destination, where the hashes would let anyone confirm
+// a guess at a file's content.
+//
+// A set is written under a `.partial` name and renamed once complete, and the state
+// file is only updated after that, so an interrupted backup leaves no half set behind
+// and the next backup simply redoes the work.
+use extendr_api::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::access::Access;
+use crate::audit;
+use crate::batch::encrypted_size;
+use crate::cache::write_atomic;
+use crate::error::LockboxError;
+use crate::filter::{walk_filtered, PathFilter};
+use crate::metadata::{file_mode, file_mtime};
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stream::{check_interrupt, copy_interruptible, PartialOutput, Progress};
+use crate::{create_output_file, decrypt_content, parse_identities_from_key_file, parse_recipients, read_key_file};
+
+/// Version of the manifest and state file format
+const BACKUP_VERSION: u32 = 1;
+const MANIFEST_FILE: &str = "manifest.json.age";
+const PARTIAL_SUFFIX: &str = ".partial";
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+struct BackupEntry {
+    /// The set holding this content
+    set: String,
+    blob: String,
+    size: u64,
+    /// BLAKE3 hash of the plaintext, in hex
+    hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<f64>,
+}
+
+/// A set's manifest; the state file is the latest manifest plus the destination
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    set: String,
+    created: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dest: Option<String>,
+    files: BTreeMap<String, BackupEntry>,
+}
+
+impl Snapshot {
+    fn parse(content: &[u8], what: &str) -> Result<Snapshot> {
+        let snapshot: Snapshot = serde_json::from_slice(content)
+            .map_err(|e| LockboxError::Encoding(format!("The {} is damaged: {}", what, e)))?;
+        if snapshot.version > BACKUP_VERSION {
+            return Err(LockboxError::Encoding(format!(
+                "The {} uses format version {}, which this version of lockbox cannot read",
+                what, snapshot.version
+            )).into());
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Whether `name` is a relative path of plain components, which stays below the
+/// directory it is joined to; with `single`, it must also be one component only
+fn is_contained(name: &str, single: bool) -> bool {
+    let components: Vec<Component> = Path::new(name).components().collect();
+    !components.is_empty()
+        && components.iter().all(|c| matches!(c, Component::Normal(_)))
+        && (!single || components.len() == 1)
+}
+
+/// A reader that hashes what passes through it
+///
+/// Hashing while encrypting records the content that was actually stored, even if
+/// the file changes between the change check and encryption.
+struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader { inner, hasher: blake3::Hasher::new() }
+    }
+
+    fn hash(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Removes a set directory that was not completed
+struct PartialSet {
+    path: Option<PathBuf>,
+}
+
+impl PartialSet {
+    fn complete(mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for PartialSet {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = std::fs::remove_dir_all(path);
+        }
+    }
+}
+
+/// Files encrypted into a set, as reported back to R
+#[derive(Default)]
+struct Encrypted {
+    path: Vec<String>,
+    status: Vec<&'static str>,
+    blob: Vec<String>,
+    size: Vec<f64>,
+    output_size: Vec<f64>,
+}
+
+impl Encrypted {
+    fn push(&mut self, path: &str, status: &'static str, blob: &str, size: u64, output_size: u64) {
+        self.path.push(path.to_string());
+        self.status.push(status);
+        self.blob.push(blob.to_string());
+        self.size.push(size as f64);
+        self.output_size.push(output_size as f64);
+    }
+
+    fn into_list(self) -> List {
+        list!(path = self.path, status = self.status, blob = self.blob, size = self.size, output_size = self.output_size)
+    }
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let file = File::open(path)
+        .map_err(|e| LockboxError::io(format!("Failed to read '{}'", path.display()), &e))?;
+    let mut reader = HashingReader::new(file);
+    copy_interruptible(&mut reader, &mut std::io::sink(), &mut Progress::none(), |e| {
+        LockboxError::io(format!("Failed to read '{}'", path.display()), &e)
+    })?;
+    Ok(reader.hash())
+}
+
+/// Encrypt `path` into the blob file `blob_path`, returning the size and hash stored
+fn encrypt_blob(path: &Path, blob_path: &str, recipients: &[Box<dyn age::Recipient>]) -> Result<(u64, String)> {
+    let read_failed = |e: std::io::Error| LockboxError::io(format!("Failed to read '{}'", path.display()), &e);
+    let mut input = HashingReader::new(File::open(path).map_err(read_failed)?);
+
+    let encryptor = policy::encryptor(recipients.iter().map(|r| r.as_ref()))?;
+    let output = create_output_file(blob_path, false, Access::default())?;
+    let mut writer = encryptor.wrap_output(BufWriter::new(output))
+        .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+    let size = copy_interruptible(&mut input, &mut writer, &mut Progress::none(), read_failed)?;
+    writer.finish()
+        .and_then(|mut writer| writer.flush())
+        .map_err(|e| LockboxError::io("Failed to finalize encryption", &e))?;
+    Ok((size, input.hash()))
+}
+
+/// Name for a new set, unique within `dest`
+fn new_set_name(dest: &Path) -> String {
+    let stamp = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let taken = |name: &str| dest.join(name).exists() || dest.join(format!("{}{}", name, PARTIAL_SUFFIX)).exists();
+    let mut name = stamp.clone();
+    let mut n = 1;
+    while taken(&name) {
+        name = format!("{}-{}", stamp, n);
+        n += 1;
+    }
+    name
+}
+
+/// Complete sets in `dest`, oldest first
+fn set_names(dest: &Path) -> Result<Vec<String>> {
+    let entries = std::fs::read_dir(dest)
+        .map_err(|e| LockboxError::io(format!("Failed to read '{}'", dest.display()), &e))?;
+    let mut sets = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| LockboxError::io(format!("Failed to read '{}'", dest.display()), &e))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.ends_with(PARTIAL_SUFFIX) && entry.path().join(MANIFEST_FILE).is_file() {
+            sets.push(name);
+        }
+    }
+    sets.sort();
+    Ok(sets)
+}
+
+/// Back up the files below `dir` that changed since the last backup
+///
+/// `state_file` holds the hashes from the last backup; if it does not exist, every
+/// file is backed up. Returns the new set's name (NA if nothing changed), counts of
+/// files added, changed, unchanged and removed, and the `files` encrypted into the
+/// set (`path`, `status`, `blob`, `size`, `output_size`). With `dry_run`, files are
+/// still hashed to find the changes, but nothing is written and `output_size` is an
+/// estimate. `include`, `exclude` and `ignore_files` select the files as in
+/// `age_dir_encrypt`; files left out count as removed from the backup.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_backup(
+    dir: &str,
+    dest: &str,
+    recipients: Vec<String>,
+    state_file: &str,
+    include: Nullable<Vec<String>>,
+    exclude: Nullable<Vec<String>>,
+    ignore_files: bool,
+    dry_run: bool,
+) -> Result<List> {
+    let keys = recipients.clone();
+    let result = (|| {
+        let root = fs_path(dir);
+        let filter = PathFilter::new(include, exclude, ignore_files)?;
+        let dest_path = fs_path(dest);
+        let parsed_recipients = parse_recipients(recipients)?;
+
+        let previous = match std::fs::read(fs_path(state_file)) {
+            Ok(content) => Some(Snapshot::parse(&content, "backup state file")?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(LockboxError::io("Failed to read the backup state file", &e).into()),
+        };
+        let previous_files = match &previous {
+            Some(previous) => {
+                if previous.dest.as_deref() != Some(dest) {
+                    return Err(LockboxError::InvalidArgument(format!(
+                        "The state file '{}' belongs to backups in '{}'",
+                        state_file,
+                        previous.dest.as_deref().unwrap_or("")
+                    )).into());
+                }
+                // Unchanged files point into earlier sets, which must still be there
+                let sets = set_names(&dest_path)?;
+                if let Some(missing) = previous.files.values().find(|entry| !sets.contains(&entry.set)) {
+                    return Err(LockboxError::NotFound(format!(
+                        "The backup set '{}' is missing from '{}'; delete the state file to start a full backup",
+                        missing.set, dest
+                    )).into());
+                }
+                previous.files.clone()
+            }
+            None => BTreeMap::new(),
+        };
+
+        let mut paths = Vec::new();
+        walk_filtered(&root, &filter, &mut paths)
+            .map_err(|e| LockboxError::io(format!("Failed to read '{}'", dir), &e))?;
+        let state_path = fs_path(state_file);
+
+        let set = new_set_name(&dest_path);
+        let set_path = dest_path.join(&set);
+        let partial_path = dest_path.join(format!("{}{}", set, PARTIAL_SUFFIX));
+        let mut partial: Option<PartialSet> = None;
+
+        let mut files = BTreeMap::new();
+        let mut encrypted = Encrypted::default();
+        let (mut added, mut changed, mut unchanged) = (0, 0, 0);
+        for path in &paths {
+            check_interrupt()?;
+            let metadata = std::fs::symlink_metadata(path)
+                .map_err(|e| LockboxError::io(format!("Failed to read '{}'", path.display()), &e))?;
+            // Links are not followed, and the state file is not backed up with the files
+            if !metadata.is_file() || *path == state_path {
+                continue;
+            }
+            let relative = path.strip_prefix(&root).unwrap_or(path).to_string_lossy().replace('\\', "/");
+            let mode = file_mode(&metadata);
+            let mtime = file_mtime(&metadata);
+
+            let status = if let Some(entry) = previous_files.get(&relative) {
+                if entry.size == metadata.len() && entry.hash == hash_file(path)? {
+                    unchanged += 1;
+                    files.insert(relative, BackupEntry { mode, mtime, ..entry.clone() });
+                    continue;
+                }
+                changed += 1;
+                "changed"
+            } else {
+                added += 1;
+                "added"
+            };
+            let blob = format!("{:06}.age", added + changed);
+            if dry_run {
+                let size = encrypted_size(metadata.len(), &parsed_recipients, false)?;
+                encrypted.push(&relative, status, &blob, metadata.len(), size);
+                files.insert(relative, BackupEntry { set: set.clone(), blob, size: metadata.len(), hash: String::new(), mode, mtime });
+                continue;
+            }
+
+            if partial.is_none() {
+                std::fs::create_dir_all(&partial_path)
+                    .map_err(|e| LockboxError::io(format!("Failed to create '{}'", partial_path.display()), &e))?;
+                partial = Some(PartialSet { path: Some(partial_path.clone()) });
+            }
+            let blob_path = partial_path.join(&blob).to_string_lossy().into_owned();
+            let (size, hash) = encrypt_blob(path, &blob_path, &parsed_recipients)?;
+            let output_size = std::fs::metadata(&blob_path).map(|m| m.len()).unwrap_or(0);
+            encrypted.push(&relative, status, &blob, size, output_size);
+            files.insert(relative, BackupEntry { set: set.clone(), blob, size, hash, mode, mtime });
+        }
+        let removed = previous_files.keys().filter(|path| !files.contains_key(*path)).count();
+
+        if added + changed == 0 && removed == 0 {
+            return Ok(list!(
+                set = Strings::from_values([Rstr::na()]),
+                added = 0,
+                changed = 0,
+                unchanged = unchanged,
+                removed = 0,
+                files = encrypted.into_list()
+            ));
+        }
+        if dry_run {
+            return Ok(list!(
+                set = set,
+                added = added,
+                changed = changed,
+                unchanged = unchanged,
+                removed = removed as i32,
+                files = encrypted.into_list()
+            ));
+        }
+
+        let mut snapshot = Snapshot {
+            version: BACKUP_VERSION,
+            set: set.clone(),
+            created: chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            dest: None,
+            files,
+        };
+        let manifest = serde_json::to_vec(&snapshot)
+            .map_err(|e| LockboxError::Encoding(format!("Failed to write the backup manifest: {}", e)))?;
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+        let manifest = crate::encrypt_binary(encryptor, &manifest)?;
+
+        if partial.is_none() {
+            // Only removals: the set holds just its manifest
+            std::fs::create_dir_all(&partial_path)
+                .map_err(|e| LockboxError::io(format!("Failed to create '{}'", partial_path.display()), &e))?;
+            partial = Some(PartialSet { path: Some(partial_path.clone()) });
+        }
+        let manifest_path = partial_path.join(MANIFEST_FILE).to_string_lossy().into_owned();
+        let mut output = create_output_file(&manifest_path, false, Access::default())?;
+        output.write_all(&manifest)
+            .and_then(|_| output.flush())
+            .map_err(|e| LockboxError::io("Failed to write the backup manifest", &e))?;
+        std::fs::rename(&partial_path, &set_path)
+            .map_err(|e| LockboxError::io(format!("Failed to complete the backup set '{}'", set), &e))?;
+        if let Some(partial) = partial {
+            partial.complete();
+        }
+
+        snapshot.dest = Some(dest.to_string());
+        let state = serde_json::to_vec_pretty(&snapshot)
+            .map_err(|e| LockboxError::Encoding(format!("Failed to write the backup state file: {}", e)))?;
+        write_atomic(&state_path.to_string_lossy(), &state)?;
+
+        Ok(list!(
+            set = set,
+            added = added,
+            changed = changed,
+            unchanged = unchanged,
+            removed = removed as i32,
+            files = encrypted.into_list()
+        ))
+    })();
+    if dry_run {
+        return result;
+    }
+    audit::record("encrypt", "key", result, |_| audit::Details::directory(dir, dest, audit::recipient_keys(keys)))
+}
+
+/// Complete backup sets in a destination, oldest first
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_backup_sets(dest: &str) -> Result<Vec<String>> {
+    set_names(&fs_path(dest))
+}
+
+/// Restore a backup set into `output_dir`
+///
+/// With a NULL `set`, the latest set is restored; with `files`, only those paths.
+/// Every file is checked against the hash recorded when it was backed up. Returns the
+/// paths restored, relative to `output_dir`, with their recorded modes and mtimes.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_restore(
+    dest: &str,
+    set: Nullable<String>,
+    private_key_path: &str,
+    strict_permissions: bool,
+    output_dir: &str,
+    files: Nullable<Vec<String>>,
+    overwrite: bool,
+) -> Result<List> {
+    let result = (|| {
+        let dest_path = fs_path(dest);
+        let set = match set {
+            Nullable::NotNull(set) => set,
+            Nullable::Null => set_names(&dest_path)?.pop()
+                .ok_or_else(|| LockboxError::NotFound(format!("'{}' holds no backup sets", dest)))?,
+        };
+        if !is_contained(&set, true) {
+            return Err(LockboxError::InvalidArgument(format!("'{}' is not the name of a backup set", set)).into());
+        }
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+
+        let manifest_path = dest_path.join(&set).join(MANIFEST_FILE);
+        policy::check_decrypt_from(&manifest_path)?;
+        let manifest = std::fs::read(&manifest_path).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                LockboxError::NotFound(format!("'{}' holds no backup set '{}'", dest, set))
+            } else {
+                LockboxError::io("Failed to read the backup manifest", &e)
+            }
+        })?;
+        let manifest = decrypt_content(&manifest, identities.iter().map(|i| i.as_ref()), &mut Progress::none())?;
+        let snapshot = Snapshot::parse(&manifest, "backup manifest")?;
+
+        let selected: Vec<(&String, &BackupEntry)> = match &files {
+            Nullable::NotNull(files) => {
+                let missing: Vec<&str> = files.iter().filter(|f| !snapshot.files.contains_key(*f)).map(|f| f.as_str()).collect();
+                if !missing.is_empty() {
+                    return Err(LockboxError::NotFound(format!("Not in backup set '{}': {}", set, missing.join(", "))).into());
+                }
+                snapshot.files.iter().filter(|(path, _)| files.contains(path)).collect()
+            }
+            Nullable::Null => snapshot.files.iter().collect(),
+        };
+
+        // Anyone with the public key can write a manifest, so nothing it names may
+        // lead outside the output directory or the destination
+        for (path, entry) in &selected {
+            if !is_contained(path, false) || !is_contained(&entry.set, true) || !is_contained(&entry.blob, true) {
+                return Err(LockboxError::CorruptPayload(format!(
+                    "The manifest of backup set '{}' records '{}' outside the backup",
+                    set, path
+                )).into());
+            }
+        }
+
+        let output_root = fs_path(output_dir);
+        let (mut paths, mut modes, mut mtimes) = (Vec::new(), Vec::new(), Vec::new());
+        for (path, entry) in selected {
+            check_interrupt()?;
+            let target = output_root.join(path);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| LockboxError::io(format!("Failed to create '{}'", parent.display()), &e))?;
+            }
+            let target = target.to_string_lossy().into_owned();
+            restore_blob(path, &dest_path.join(&entry.set).join(&entry.blob), &target, entry, &identities, overwrite)?;
+            paths.push(path.clone());
+            modes.push(entry.mode.map(|mode| mode as i32));
+            mtimes.push(entry.mtime);
+        }
+        Ok(list!(set = set, path = paths, mode = Integers::from_values(modes), mtime = Doubles::from_values(mtimes)))
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::directory(dest, output_dir, audit::key_file_public_keys(private_key_path))
+    })
+}
+
+/// Decrypt one blob into `target`, checking it against its manifest entry
+fn restore_blob(path: &str, blob_path: &Path, target: &str, entry: &BackupEntry, identities: &[Box<dyn age::Identity>], overwrite: bool) -> Result<()> {
+    policy::check_decrypt_from(blob_path)?;
+    policy::check_plaintext_to(&fs_path(target))?;
+    let blob = File::open(blob_path).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            LockboxError::NotFound(format!("The backup of '{}' is missing: '{}'", path, blob_path.display()))
+        } else {
+            LockboxError::io(format!("Failed to read '{}'", blob_path.display()), &e)
+        }
+    })?;
+    let decryptor = age::Decryptor::new(BufReader::new(blob))
+        .map_err(|e| LockboxError::from_decrypt(e, false))?;
+    let reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))
+        .map_err(|e| LockboxError::from_decrypt(e, false))?;
+    let mut reader = HashingReader::new(reader);
+
+    let mut output = BufWriter::new(create_output_file(target, overwrite, Access::OWNER_ONLY)?);
+    let partial = PartialOutput::new(target);
+    let size = copy_interruptible(&mut reader, &mut output, &mut Progress::none(), LockboxError::from_payload)?;
+    output.flush()
+        .map_err(|e| LockboxError::io(format!("Failed to write '{}'", target), &e))?;
+    if size != entry.size || reader.hash() != entry.hash {
+        return Err(LockboxError::CorruptPayload(format!(
+            "The backup of '{}' in '{}' does not hold the content recorded for it",
+            path, blob_path.display()
+        )).into());
+    }
+    partial.complete();
+    Ok(())
+}
+
+extendr_module! {
+    mod backup;
+    fn age_backup;
+    fn age_backup_sets;
+    fn age_restore;
+}
@@ -0,0 +1,297 @@
+// Encryption of every file in a directory
+//
+// Each regular file below the directory is encrypted to its own age file, next to it
+// (`data/survey.csv.age`) or at the same relative path in an output directory. Files
+// that already end in `.age` are left alone, so the operation can be repeated as new
+// files appear, and symbolic links are never followed.
+//
+// The plan, which files go to which outputs, is worked out and checked before anything
+// is written, so a dry run reports exactly what a real run would do, including the
+// existing outputs that would be refused. Removing the plaintext makes the operation
+// destructive, which is what the dry run is for.
+//
+// A file that cannot be encrypted does not stop the others: each one's outcome, with
+// the error code it failed with, is returned as a row, so callers can retry failures.
+use extendr_api::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::access::Access;
+use crate::audit;
+use crate::error::{error_parts, LockboxError};
+use crate::filter::{walk_filtered, PathFilter};
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stream::check_interrupt;
+use crate::{encrypt_binary, encrypt_file, parse_recipients};
+
+/// Plaintext size of an age payload chunk
+const PAYLOAD_CHUNK_SIZE: u64 = 64 * 1024;
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----\n";
+const ARMOR_END: &str = "-----END AGE ENCRYPTED FILE-----\n";
+const ARMOR_LINE_LENGTH: u64 = 64;
+
+/// Size of the age file that `plaintext` bytes encrypt to for `recipients`
+///
+/// The header is measured by encrypting nothing, since its size depends on the
+/// recipient types; the payload adds a nonce and a tag per 64 KiB chunk.
+pub(crate) fn encrypted_size(plaintext: u64, recipients: &[Box<dyn age::Recipient>], armor: bool) -> Result<u64> {
+    let encryptor = policy::encryptor(recipients.iter().map(|r| r.as_ref()))?;
+    // Nonce and the tag of the final, empty chunk
+    let empty = encrypt_binary(encryptor, &[])?.len() as u64 - 16;
+    let chunks = ((plaintext + PAYLOAD_CHUNK_SIZE - 1) / PAYLOAD_CHUNK_SIZE).max(1);
+    let binary = empty + plaintext + 16 * (chunks - 1);
+    if !armor {
+        return Ok(binary);
+    }
+    let encoded = (binary + 2) / 3 * 4;
+    let lines = (encoded + ARMOR_LINE_LENGTH - 1) / ARMOR_LINE_LENGTH;
+    Ok(ARMOR_BEGIN.len() as u64 + encoded + lines + ARMOR_END.len() as u64)
+}
+
+pub(crate) struct Planned {
+    pub(crate) input: PathBuf,
+    pub(crate) output: PathBuf,
+    pub(crate) size: u64,
+    /// Why the file cannot be encrypted, found while planning
+    pub(crate) problem: Option<LockboxError>,
+}
+
+/// The files below `root` that `filter` selects and where each one goes
+pub(crate) fn plan(root: &Path, filter: &PathFilter, output_dir: Option<&Path>, overwrite: bool) -> Result<Vec<Planned>> {
+    let mut paths = Vec::new();
+    walk_filtered(root, filter, &mut paths)
+        .map_err(|e| LockboxError::io(format!("Failed to read '{}'", root.display()), &e))?;
+
+    let mut planned = Vec::new();
+    for path in paths {
+        // Outputs written into the directory itself are not inputs
+        if output_dir.map_or(false, |dir| path.starts_with(dir)) {
+            continue;
+        }
+        let metadata = std::fs::symlink_metadata(&path)
+            .map_err(|e| LockboxError::io(format!("Failed to read '{}'", path.display()), &e))?;
+        if !metadata.is_file() || path.extension().map_or(false, |ext| ext == "age") {
+            continue;
+        }
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".age");
+        let output = match output_dir {
+            Some(dir) => dir.join(path.strip_prefix(root).unwrap_or(&path)).with_file_name(name),
+            None => path.with_file_name(name),
+        };
+        let problem = if !overwrite && std::fs::symlink_metadata(&output).is_ok() {
+            Some(LockboxError::FileExists(format!(
+                "File '{}' already exists; use `overwrite = TRUE` to replace it",
+                output.display()
+            )))
+        } else {
+            None
+        };
+        planned.push(Planned { input: path, output, size: metadata.len(), problem });
+    }
+    Ok(planned)
+}
+
+/// Encrypt one planned file, returning the size of the output
+///
+/// `keys` are the recipients as given, for the audit log.
+pub(crate) fn encrypt_planned(file: &Planned, recipients: &[Box<dyn age::Recipient>], keys: &[String], armor: bool, overwrite: bool, remove_plaintext: bool) -> Result<u64> {
+    if let Some(parent) = file.output.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| LockboxError::io(format!("Failed to create '{}'", parent.display()), &e))?;
+    }
+    let (input, output) = (file.input.to_string_lossy(), file.output.to_string_lossy());
+    let result = (|| {
+        let encryptor = policy::encryptor(recipients.iter().map(|r| r.as_ref()))?;
+        encrypt_file(encryptor, &input, &output, armor, overwrite, Access::default(), false, false, Nullable::Null)
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(Some(&input), &output, done.is_some(), audit::recipient_keys(keys.to_vec()))
+    })?;
+    if remove_plaintext {
+        std::fs::remove_file(&file.input)
+            .map_err(|e| LockboxError::io(format!("Failed to remove '{}'", input), &e))?;
+    }
+    Ok(std::fs::metadata(&file.output).map(|m| m.len()).unwrap_or(0))
+}
+
+/// Per-file outcomes of a batch operation, as returned to R
+#[derive(Default)]
+pub(crate) struct BatchResults {
+    input: Vec<String>,
+    output: Vec<String>,
+    ok: Vec<bool>,
+    code: Vec<Option<String>>,
+    message: Vec<Option<String>>,
+    size: Vec<f64>,
+    output_size: Vec<Option<f64>>,
+    seconds: Vec<f64>,
+}
+
+impl BatchResults {
+    /// Record the outcome of one file; interruptions are raised rather than recorded
+    ///
+    /// A successful outcome carries the size of the output, if known.
+    pub(crate) fn record(&mut self, input: &Path, output: &Path, size: u64, outcome: Result<Option<u64>>, started: Instant) -> Result<()> {
+        let (ok, code, message, output_size) = match outcome {
+            Ok(output_size) => (true, None, None, output_size.map(|size| size as f64)),
+            Err(err) => {
+                let (code, message) = error_parts(&err);
+                if code == "interrupted" {
+                    return Err(err);
+                }
+                (false, Some(code), Some(message), None)
+            }
+        };
+        self.input.push(input.to_string_lossy().into_owned());
+        self.output.push(output.to_string_lossy().into_owned());
+        self.ok.push(ok);
+        self.code.push(code);
+        self.message.push(message);
+        self.size.push(size as f64);
+        self.output_size.push(output_size);
+        self.seconds.push(started.elapsed().as_secs_f64());
+        Ok(())
+    }
+
+    pub(crate) fn into_list(self) -> List {
+        list!(
+            input = self.input,
+            output = self.output,
+            ok = self.ok,
+            code = Strings::from_values(self.code.into_iter().map(|c| c.map_or_else(Rstr::na, Rstr::from))),
+            message = Strings::from_values(self.message.into_iter().map(|m| m.map_or_else(Rstr::na, Rstr::from))),
+            size = self.size,
+            output_size = Doubles::from_values(self.output_size),
+            seconds = self.seconds
+        )
+    }
+}
+
+/// What a batch operation does when a file fails
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OnError {
+    /// Raise the error and remove the outputs written so far
+    Stop,
+    /// Record the failure and go on with the other files
+    Continue,
+}
+
+impl OnError {
+    fn parse(name: &str) -> std::result::Result<Self, LockboxError> {
+        match name {
+            "stop" => Ok(OnError::Stop),
+            "continue" => Ok(OnError::Continue),
+            _ => Err(LockboxError::InvalidArgument(format!(
+                "`on_error` must be \"stop\" or \"continue\", got \"{}\"",
+                name
+            ))),
+        }
+    }
+}
+
+/// Outputs created by a batch run, removed again unless the run completes
+struct WrittenOutputs(Vec<PathBuf>);
+
+impl WrittenOutputs {
+    fn complete(mut self) {
+        self.0.clear();
+    }
+}
+
+impl Drop for WrittenOutputs {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Encrypt every file below a directory to public keys
+///
+/// Outputs go next to their inputs, or below `output_dir` at the same relative path.
+/// `include` and `exclude` are glob patterns that select the files, and
+/// `ignore_files` also leaves out what `.gitignore` files do (see filter.rs).
+/// With `on_error = "continue"`, a file that fails is reported and the others are
+/// still encrypted. With `"stop"`, problems found while planning are raised before
+/// anything is written, the first failure is raised, outputs created by the run are
+/// removed, and plaintext is only removed once every file is encrypted. With
+/// `dry_run`, nothing is written and `output_size` is the size the outputs would have.
+/// Returns one row per file: `input`, `output`, `ok`, the error `code` and `message`
+/// (NA if ok), `size`, `output_size` (NA if failed) and `seconds`.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_dir_encrypt(
+    dir: &str,
+    include: Nullable<Vec<String>>,
+    exclude: Nullable<Vec<String>>,
+    ignore_files: bool,
+    output_dir: Nullable<String>,
+    recipients: Vec<String>,
+    armor: bool,
+    overwrite: bool,
+    remove_plaintext: bool,
+    on_error: &str,
+    dry_run: bool,
+) -> Result<List> {
+    let on_error = OnError::parse(on_error)?;
+    let root = fs_path(dir);
+    let output_dir = match output_dir {
+        Nullable::NotNull(output_dir) => Some(fs_path(&output_dir)),
+        Nullable::Null => None,
+    };
+    let filter = PathFilter::new(include, exclude, ignore_files)?;
+    let keys = recipients.clone();
+    let parsed_recipients = parse_recipients(recipients)?;
+    let mut planned = plan(&root, &filter, output_dir.as_deref(), overwrite)?;
+    if on_error == OnError::Stop {
+        if let Some(problem) = planned.iter_mut().find_map(|file| file.problem.take()) {
+            return Err(problem.into());
+        }
+    }
+
+    let mut results = BatchResults::default();
+    let mut written = WrittenOutputs(Vec::new());
+    let mut inputs = Vec::new();
+    for mut file in planned {
+        check_interrupt()?;
+        let started = Instant::now();
+        let outcome = match file.problem.take() {
+            Some(problem) => Err(problem.into()),
+            None if dry_run => encrypted_size(file.size, &parsed_recipients, armor),
+            None => {
+                let existed = std::fs::symlink_metadata(&file.output).is_ok();
+                let remove_now = remove_plaintext && on_error == OnError::Continue;
+                let outcome = encrypt_planned(&file, &parsed_recipients, &keys, armor, overwrite, remove_now);
+                if !existed && outcome.is_ok() {
+                    written.0.push(file.output.clone());
+                }
+                outcome
+            }
+        };
+        let outcome = match (outcome, on_error) {
+            // Returning drops `written`, which removes the outputs created so far
+            (Err(e), OnError::Stop) => return Err(e),
+            (outcome, _) => outcome,
+        };
+        results.record(&file.input, &file.output, file.size, outcome.map(Some), started)?;
+        inputs.push(file.input);
+    }
+    written.complete();
+
+    if remove_plaintext && on_error == OnError::Stop && !dry_run {
+        for input in &inputs {
+            std::fs::remove_file(input)
+                .map_err(|e| LockboxError::io(format!("Failed to remove '{}'", input.display()), &e))?;
+        }
+    }
+    Ok(results.into_list())
+}
+
+extendr_module! {
+    mod batch;
+    fn age_dir_encrypt;
+}
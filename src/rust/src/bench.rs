@@ -0,0 +1,114 @@
+// Throughput measurements on the current machine
+//
+// Encryption and decryption are timed in memory, so the figures are what the CPU can
+// do and disks only slow them down. Each measurement is repeated until it has taken
+// long enough to be stable. scrypt is timed separately, since passphrase encryption
+// and decryption cost one key derivation each, whatever the file size: the work
+// factor that takes about a second here is the one age would pick.
+use extendr_api::prelude::*;
+use rand::RngCore;
+use std::io::Read;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+use crate::error::LockboxError;
+use crate::policy;
+use crate::stream::check_interrupt;
+use crate::{encrypt_binary, parse_x25519_recipients, scrypt_recipient};
+
+/// Measurements are repeated until they have taken at least this long together
+const MIN_DURATION: Duration = Duration::from_millis(200);
+
+/// Mean seconds per run of `f`, over as many runs as `MIN_DURATION` needs
+fn time<F>(mut f: F) -> Result<f64>
+where
+    F: FnMut() -> Result<()>,
+{
+    let start = Instant::now();
+    let mut runs = 0u32;
+    while runs == 0 || start.elapsed() < MIN_DURATION {
+        check_interrupt()?;
+        f()?;
+        runs += 1;
+    }
+    Ok(start.elapsed().as_secs_f64() / runs as f64)
+}
+
+/// Measure encryption and decryption throughput, and scrypt timing
+///
+/// Data is encrypted to `recipients` and a fresh key, which decrypts it. Returns one
+/// row per operation and size or work factor: `operation`, `size` (bytes, NA for
+/// scrypt), `work_factor` (NA unless scrypt), `seconds` per run and `throughput` in
+/// bytes per second (NA for scrypt).
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_benchmark(sizes: Vec<f64>, recipients: Nullable<Vec<String>>, work_factors: Vec<i32>) -> Result<List> {
+    let identity = age::x25519::Identity::generate();
+    let mut keys = match recipients {
+        Nullable::NotNull(recipients) => recipients,
+        Nullable::Null => Vec::new(),
+    };
+    keys.push(identity.to_public().to_string());
+    let recipients = parse_x25519_recipients(&keys)?;
+    let encryptor = || {
+        policy::encryptor(recipients.iter().map(|r| r as &dyn age::Recipient))
+    };
+
+    let (mut operations, mut measured_sizes, mut factors, mut seconds, mut throughputs) =
+        (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let mut record = |operation: &str, size: Option<f64>, work_factor: Option<i32>, elapsed: f64| {
+        operations.push(operation.to_string());
+        measured_sizes.push(size);
+        factors.push(work_factor);
+        seconds.push(elapsed);
+        throughputs.push(size.map(|size| size / elapsed));
+    };
+
+    for size in sizes {
+        if !(size >= 0.0 && size <= usize::MAX as f64) {
+            return Err(LockboxError::InvalidArgument(format!("Benchmark sizes must be non-negative byte counts, not {}", size)).into());
+        }
+        let mut data = Zeroizing::new(vec![0u8; size as usize]);
+        rand::thread_rng().fill_bytes(&mut data);
+
+        let encrypted = encrypt_binary(encryptor()?, &data)?;
+        let elapsed = time(|| encrypt_binary(encryptor()?, &data).map(|_| ()))?;
+        record("encrypt", Some(size), None, elapsed);
+
+        let mut decrypted = Zeroizing::new(Vec::with_capacity(data.len()));
+        let elapsed = time(|| {
+            decrypted.clear();
+            let decryptor = age::Decryptor::new(encrypted.as_slice())
+                .map_err(|e| LockboxError::from_decrypt(e, false))?;
+            decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity))
+                .map_err(|e| LockboxError::from_decrypt(e, false))?
+                .read_to_end(&mut decrypted)
+                .map_err(LockboxError::from_payload)?;
+            Ok(())
+        })?;
+        record("decrypt", Some(size), None, elapsed);
+    }
+
+    for work_factor in work_factors {
+        check_interrupt()?;
+        let recipient = scrypt_recipient("lockbox benchmark", Nullable::NotNull(work_factor))?;
+        let start = Instant::now();
+        let encryptor = policy::encryptor(std::iter::once(&recipient as &dyn age::Recipient))?;
+        encrypt_binary(encryptor, &[])?;
+        record("scrypt", None, Some(work_factor), start.elapsed().as_secs_f64());
+    }
+
+    Ok(list!(
+        operation = operations,
+        size = Doubles::from_values(measured_sizes),
+        work_factor = Integers::from_values(factors),
+        seconds = seconds,
+        throughput = Doubles::from_values(throughputs)
+    ))
+}
+
+extendr_module! {
+    mod bench;
+    fn age_benchmark;
+}
@@ -0,0 +1,120 @@
+// Encryption of many binary blobs at once
+//
+// Applications that keep encrypted attachments in a database through DBI hold a
+// blob column: a list of raw vectors, with NULL for missing values. Encrypting it
+// element by element from R parses the recipients or key file once per element and
+// uses one core. Here they are parsed once and the elements spread over a thread
+// pool, as for character columns (see strings.rs). The raw vectors are read in
+// place by the workers, which never call into R; results become raw vectors on
+// the main thread.
+use extendr_api::prelude::*;
+use std::io::{Read, Write};
+use zeroize::Zeroizing;
+
+use crate::audit;
+use crate::encoding::StringEncoding;
+use crate::error::LockboxError;
+use crate::policy;
+use crate::stats::{self, Direction};
+use crate::strings::map_parallel;
+use crate::{encrypted_capacity, parse_x25519_identities, parse_x25519_recipients, read_key_file};
+
+/// Encrypt one blob to binary age, or its armor
+fn encrypt_blob(recipients: &[age::x25519::Recipient], blob: &[u8], armor: bool) -> std::result::Result<Vec<u8>, LockboxError> {
+    let encryptor = policy::encryptor(recipients.iter().map(|r| r as &dyn age::Recipient))?;
+
+    let mut output = Vec::with_capacity(encrypted_capacity(blob.len()));
+    let mut encrypted_writer = encryptor.wrap_output(&mut output)
+        .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+    encrypted_writer.write_all(blob)
+        .and_then(|_| encrypted_writer.finish())
+        .map_err(|e| LockboxError::io("Failed to write encrypted data", &e))?;
+    stats::add_bytes(Direction::Encrypt, blob.len() as u64);
+
+    if armor {
+        Ok(StringEncoding::Armor.encode(&output)?.into_bytes())
+    } else {
+        Ok(output)
+    }
+}
+
+/// Decrypt one blob, binary or armored
+fn decrypt_blob(identities: &[age::x25519::Identity], blob: &[u8]) -> std::result::Result<Zeroizing<Vec<u8>>, LockboxError> {
+    let reader = age::armor::ArmoredReader::new(blob);
+    let decryptor = age::Decryptor::new(reader)
+        .map_err(|e| LockboxError::from_decrypt(e, false))?;
+    let scrypt = decryptor.is_scrypt();
+    let mut decrypted_reader = decryptor.decrypt(identities.iter().map(|i| i as &dyn age::Identity))
+        .map_err(|e| LockboxError::from_decrypt(e, scrypt))?;
+
+    let mut decrypted = Zeroizing::new(Vec::with_capacity(blob.len()));
+    decrypted_reader.read_to_end(&mut decrypted)
+        .map_err(LockboxError::from_payload)?;
+    stats::add_bytes(Direction::Decrypt, decrypted.len() as u64);
+    Ok(decrypted)
+}
+
+/// Encrypt every blob that is not missing, keeping the order
+pub(crate) fn encrypt_blobs(blobs: &[Option<&[u8]>], recipients: &[String], armor: bool, threads: Nullable<i32>) -> Result<Vec<Option<Vec<u8>>>> {
+    let recipients = parse_x25519_recipients(recipients)?;
+    map_parallel(blobs, threads, |blob| blob.map(|blob| encrypt_blob(&recipients, blob, armor)).transpose())
+}
+
+/// Decrypt every blob that is not missing with the keys in a key file, keeping the order
+pub(crate) fn decrypt_blobs(blobs: &[Option<&[u8]>], private_key_path: &str, strict_permissions: bool, threads: Nullable<i32>) -> Result<Vec<Option<Zeroizing<Vec<u8>>>>> {
+    let key_content = read_key_file(private_key_path, strict_permissions)?;
+    let identities = parse_x25519_identities(&key_content)?;
+    map_parallel(blobs, threads, |blob| blob.map(|blob| decrypt_blob(&identities, blob)).transpose())
+}
+
+/// The elements of a list of raw vectors, None for NULL
+///
+/// R checks the element types; anything else is treated as missing.
+fn list_blobs(values: &[Robj]) -> Vec<Option<&[u8]>> {
+    values.iter().map(Robj::as_raw_slice).collect()
+}
+
+/// A list of raw vectors, with NULL for missing elements
+fn blob_list<B: AsRef<[u8]>>(blobs: Vec<Option<B>>) -> List {
+    List::from_values(blobs.into_iter().map(|blob| match blob {
+        Some(blob) => Robj::from(Raw::from_bytes(blob.as_ref())),
+        None => Robj::from(()),
+    }))
+}
+
+/// Encrypt each raw vector of a list to public keys
+///
+/// NULL elements stay NULL. `armor` returns armored text as raw vectors. The list is
+/// one operation in the audit log, without a hash.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_blobs(values: List, recipients: Vec<String>, armor: bool, threads: Nullable<i32>) -> Result<List> {
+    let values: Vec<Robj> = values.values().collect();
+    let result = encrypt_blobs(&list_blobs(&values), &recipients, armor, threads);
+    let blobs = audit::record("encrypt", "key", result, |_| {
+        audit::Details { keys: audit::recipient_keys(recipients), ..audit::Details::default() }
+    })?;
+    Ok(blob_list(blobs))
+}
+
+/// Decrypt each raw vector of a list with a private key file
+///
+/// NULL elements stay NULL. The list is one operation in the audit log, without a hash.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_blobs(values: List, private_key_path: &str, strict_permissions: bool, threads: Nullable<i32>) -> Result<List> {
+    let values: Vec<Robj> = values.values().collect();
+    let result = decrypt_blobs(&list_blobs(&values), private_key_path, strict_permissions, threads);
+    let blobs = audit::record("decrypt", "key", result, |_| {
+        audit::Details { keys: audit::key_file_public_keys(private_key_path), ..audit::Details::default() }
+    })?;
+    Ok(blob_list(blobs))
+}
+
+extendr_module! {
+    mod blobs;
+    fn age_encrypt_blobs;
+    fn age_decrypt_blobs;
+}
@@ -0,0 +1,140 @@
+// Buffer sizes of the file pipelines
+//
+// Files are encrypted and decrypted through buffered readers and writers. On a local
+// disk 64 KiB per system call is plenty, but on network file systems (NFS, SMB,
+// Lustre and the like) every call can cost a round trip to the server, and small
+// buffers leave most of the bandwidth unused. The sizes can be set for the session;
+// by default ("auto") they are picked per file, large on network file systems and
+// small elsewhere. Network mounts are recognised from /proc/self/mounts on Linux,
+// which is where research clusters run; elsewhere auto always means local.
+//
+// The buffers only change how much is read or written per call. Interrupts are still
+// checked every CHUNK_SIZE bytes (see stream.rs).
+use extendr_api::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::LockboxError;
+
+/// A size of 0 means auto
+const AUTO: usize = 0;
+const LOCAL_BUFFER: usize = 64 * 1024;
+const NETWORK_BUFFER: usize = 4 * 1024 * 1024;
+const MIN_BUFFER: usize = 4 * 1024;
+const MAX_BUFFER: usize = 256 * 1024 * 1024;
+
+/// File system types, as listed in /proc/self/mounts, that are served over a network
+const NETWORK_FILE_SYSTEMS: &[&str] = &[
+    "nfs", "nfs4", "cifs", "smb3", "smbfs", "afs", "lustre", "gpfs", "beegfs", "ceph",
+    "glusterfs", "9p", "davfs", "fuse.sshfs", "fuse.rclone", "fuse.s3fs", "fuse.gcsfuse",
+];
+
+static READ_BUFFER: AtomicUsize = AtomicUsize::new(AUTO);
+static WRITE_BUFFER: AtomicUsize = AtomicUsize::new(AUTO);
+
+/// The buffer size for reading `path`
+pub(crate) fn read_buffer_size(path: &Path) -> usize {
+    buffer_size(&READ_BUFFER, path)
+}
+
+/// The buffer size for writing `path`, which may not exist yet
+pub(crate) fn write_buffer_size(path: &Path) -> usize {
+    buffer_size(&WRITE_BUFFER, path)
+}
+
+fn buffer_size(setting: &AtomicUsize, path: &Path) -> usize {
+    match setting.load(Ordering::Relaxed) {
+        AUTO if on_network_file_system(path) => NETWORK_BUFFER,
+        AUTO => LOCAL_BUFFER,
+        size => size,
+    }
+}
+
+/// The path with links resolved, or for a file not created yet, its directory's
+#[cfg(target_os = "linux")]
+fn resolved(path: &Path) -> Option<PathBuf> {
+    path.canonicalize().ok()
+        .or_else(|| path.parent().and_then(|dir| dir.canonicalize().ok()))
+}
+
+/// Mount points in /proc/self/mounts escape spaces and a few other bytes in octal
+#[cfg(target_os = "linux")]
+fn unescape_mount_point(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Whether `path` is on a network file system, going by the most specific mount above it
+#[cfg(target_os = "linux")]
+fn on_network_file_system(path: &Path) -> bool {
+    let (Some(path), Ok(mounts)) = (resolved(path), std::fs::read_to_string("/proc/self/mounts")) else {
+        return false;
+    };
+    mounts.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let mount_point = PathBuf::from(unescape_mount_point(fields.nth(1)?));
+            let fs_type = fields.next()?;
+            Some((mount_point, fs_type))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.components().count())
+        .map_or(false, |(_, fs_type)| NETWORK_FILE_SYSTEMS.contains(&fs_type))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn on_network_file_system(_path: &Path) -> bool {
+    false
+}
+
+fn setting(value: Nullable<f64>, current: &AtomicUsize) -> Result<()> {
+    if let Nullable::NotNull(size) = value {
+        let size = size as usize;
+        if size != AUTO && !(MIN_BUFFER..=MAX_BUFFER).contains(&size) {
+            return Err(LockboxError::InvalidArgument(format!(
+                "Buffer sizes must be between {} and {} bytes",
+                MIN_BUFFER, MAX_BUFFER
+            )).into());
+        }
+        current.store(size, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Set the read and write buffer sizes, in bytes, 0 for auto
+///
+/// NULL leaves a size unchanged. Returns the sizes in effect.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_io_buffers(read: Nullable<f64>, write: Nullable<f64>) -> Result<List> {
+    setting(read, &READ_BUFFER)?;
+    setting(write, &WRITE_BUFFER)?;
+    Ok(list!(
+        read = READ_BUFFER.load(Ordering::Relaxed) as f64,
+        write = WRITE_BUFFER.load(Ordering::Relaxed) as f64
+    ))
+}
+
+extendr_module! {
+    mod buffers;
+    fn age_io_buffers;
+}
@@ -0,0 +1,172 @@
+// Bundles of strings
+//
+// Every encrypted string carries its own age header, a couple of hundred bytes per
+// recipient, which dwarfs short values such as identifiers or phone numbers. A bundle
+// encrypts a whole character vector as one age payload instead, so the header is paid
+// once; the price is that single values can no longer be decrypted on their own.
+//
+// Inside the payload the vector is framed as:
+//
+// - the magic line `lockbox-bundle/v1\n`
+// - the number of elements, as a little-endian u64
+// - for each element, its length in bytes as a little-endian u64 followed by its
+//   UTF-8 bytes; a length of `u64::MAX` with no bytes marks `NA`
+use extendr_api::prelude::*;
+use std::iter;
+use zeroize::Zeroizing;
+
+use crate::audit;
+use crate::error::LockboxError;
+use crate::policy;
+use crate::stream::Progress;
+use crate::{
+    decrypt_content, encrypt_bytes, encrypted_string_bytes, parse_identities_from_key_file, parse_recipients,
+    passphrase_from, read_key_file, scrypt_identity, scrypt_recipient,
+};
+
+const BUNDLE_MAGIC: &[u8] = b"lockbox-bundle/v1\n";
+const NA_LENGTH: u64 = u64::MAX;
+
+/// Frame a character vector as bundle plaintext
+pub(crate) fn pack(values: &Strings) -> Zeroizing<Vec<u8>> {
+    let size = values.iter().map(|v| v.as_str().len() + 8).sum::<usize>();
+    let mut packed = Zeroizing::new(Vec::with_capacity(BUNDLE_MAGIC.len() + 8 + size));
+    packed.extend_from_slice(BUNDLE_MAGIC);
+    packed.extend_from_slice(&(values.len() as u64).to_le_bytes());
+    for value in values.iter() {
+        if value.is_na() {
+            packed.extend_from_slice(&NA_LENGTH.to_le_bytes());
+        } else {
+            packed.extend_from_slice(&(value.as_str().len() as u64).to_le_bytes());
+            packed.extend_from_slice(value.as_str().as_bytes());
+        }
+    }
+    packed
+}
+
+/// A cursor over bundle plaintext
+struct Frames<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Frames<'a> {
+    fn take(&mut self, n: usize) -> std::result::Result<&'a [u8], LockboxError> {
+        if self.rest.len() < n {
+            return Err(corrupt("it ends early"));
+        }
+        let (head, tail) = self.rest.split_at(n);
+        self.rest = tail;
+        Ok(head)
+    }
+
+    fn length(&mut self) -> std::result::Result<u64, LockboxError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+fn corrupt(detail: &str) -> LockboxError {
+    LockboxError::CorruptPayload(format!("The bundle is damaged: {}", detail))
+}
+
+/// Read the character vector back from bundle plaintext
+pub(crate) fn unpack(packed: &[u8]) -> std::result::Result<Strings, LockboxError> {
+    let rest = packed.strip_prefix(BUNDLE_MAGIC)
+        .ok_or_else(|| LockboxError::InvalidArgument("The encrypted string is not a bundle; use string_decrypt()".to_string()))?;
+    let mut frames = Frames { rest };
+
+    let count = frames.length()?;
+    // Every element takes at least eight bytes, which bounds a forged count
+    if count > (frames.rest.len() / 8) as u64 {
+        return Err(corrupt("the element count is too large"));
+    }
+    let mut values = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let length = frames.length()?;
+        if length == NA_LENGTH {
+            values.push(Rstr::na());
+            continue;
+        }
+        let length = usize::try_from(length).map_err(|_| corrupt("an element is too long"))?;
+        let value = std::str::from_utf8(frames.take(length)?)
+            .map_err(|_| corrupt("an element is not valid UTF-8"))?;
+        values.push(Rstr::from(value));
+    }
+    if !frames.rest.is_empty() {
+        return Err(corrupt("there is data after the last element"));
+    }
+    Ok(Strings::from_values(values))
+}
+
+/// Encrypt a character vector as one bundle to public keys
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_bundle_with_key(values: Strings, recipients: Vec<String>, encoding: &str) -> Result<String> {
+    let keys = recipients.clone();
+    let result = (|| {
+        let parsed_recipients = parse_recipients(recipients)?;
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+        encrypt_bytes(encryptor, &pack(&values), encoding)
+    })();
+    audit::record("encrypt", "key", result, |encrypted| {
+        audit::Details::string(encrypted.map(String::as_str), audit::recipient_keys(keys))
+    })
+}
+
+/// Encrypt a character vector as one bundle with a passphrase
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_bundle_with_passphrase(values: Strings, passphrase: &str, passphrase_source: Nullable<String>, work_factor: Nullable<i32>, encoding: &str) -> Result<String> {
+    let result = (|| {
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+        let recipient = scrypt_recipient(&passphrase, work_factor)?;
+        let encryptor = policy::encryptor(iter::once(&recipient as _))?;
+        encrypt_bytes(encryptor, &pack(&values), encoding)
+    })();
+    audit::record("encrypt", "passphrase", result, |encrypted| {
+        audit::Details::string(encrypted.map(String::as_str), Vec::new())
+    })
+}
+
+/// Decrypt a bundle with a private key file, returning the character vector
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_bundle_with_key(encrypted_string: &str, private_key_path: &str, strict_permissions: bool) -> Result<Strings> {
+    let result = (|| {
+        let encrypted_bytes = encrypted_string_bytes(encrypted_string, false)?;
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+        let packed = decrypt_content(&encrypted_bytes, identities.iter().map(|i| i.as_ref()), &mut Progress::none())?;
+        Ok(unpack(&packed)?)
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::string(Some(encrypted_string), audit::key_file_public_keys(private_key_path))
+    })
+}
+
+/// Decrypt a bundle with a passphrase, returning the character vector
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_bundle_with_passphrase(encrypted_string: &str, passphrase: &str, passphrase_source: Nullable<String>, max_work_factor: Nullable<i32>) -> Result<Strings> {
+    let result = (|| {
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+        let encrypted_bytes = encrypted_string_bytes(encrypted_string, false)?;
+        let identity = scrypt_identity(&passphrase, max_work_factor)?;
+        let packed = decrypt_content(&encrypted_bytes, iter::once(&identity as _), &mut Progress::none())?;
+        Ok(unpack(&packed)?)
+    })();
+    audit::record("decrypt", "passphrase", result, |_| audit::Details::string(Some(encrypted_string), Vec::new()))
+}
+
+extendr_module! {
+    mod bundle;
+    fn age_encrypt_bundle_with_key;
+    fn age_encrypt_bundle_with_passphrase;
+    fn age_decrypt_bundle_with_key;
+    fn age_decrypt_bundle_with_passphrase;
+}
@@ -0,0 +1,352 @@
+// Encrypted on-disk cache
+//
+// A cache is a directory of binary age files, one per entry, and an `index.json`
+// that maps entry names to their files. Blob files are named after a hash of the
+// entry name, so names never need to be valid file names. The index records each
+// blob's size, BLAKE3 hash, and when it was written and last read, which is all that
+// eviction needs: entries can be evicted by age or size without the private key.
+//
+// The index itself is not encrypted, so entry names are visible to anyone who can
+// read the directory. Memoisation backends use hashes of function calls as names.
+//
+// Each blob binds its entry name inside the encryption, and its hash is checked
+// against the index before decrypting, so a blob that was corrupted, replaced, or
+// moved to another name is never returned. Such entries are dropped and reported as
+// missing, which makes the caller recompute them.
+use extendr_api::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::access::Access;
+use crate::audit;
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stats::{self, Direction};
+use crate::stream::{PartialOutput, Progress};
+use crate::{create_output_file, decrypt_content, parse_identities_from_key_file, parse_recipients, read_key_file};
+
+/// Version of the index format
+const CACHE_VERSION: u32 = 1;
+const INDEX_FILE: &str = "index.json";
+const BLOB_EXTENSION: &str = ".age";
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Entry {
+    file: String,
+    size: u64,
+    /// BLAKE3 hash of the blob file, in hex
+    hash: String,
+    /// Milliseconds since the Unix epoch
+    created: i64,
+    accessed: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Index {
+    version: u32,
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Index {
+    fn read(dir: &str) -> Result<Index> {
+        let path = fs_path(dir).join(INDEX_FILE);
+        let content = match std::fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Index { version: CACHE_VERSION, entries: BTreeMap::new() });
+            }
+            Err(e) => return Err(LockboxError::io("Failed to read cache index", &e).into()),
+        };
+        let mut index: Index = serde_json::from_slice(&content)
+            .map_err(|e| LockboxError::Encoding(format!("The cache index is damaged: {}", e)))?;
+        if index.version > CACHE_VERSION {
+            return Err(LockboxError::Encoding(format!(
+                "The cache uses format version {}, which this version of lockbox cannot read",
+                index.version
+            )).into());
+        }
+        // The index is not encrypted, so an entry naming any file but its own blob is
+        // dropped rather than read or deleted
+        index.entries.retain(|name, entry| entry.file == blob_file(name));
+        Ok(index)
+    }
+
+    fn write(&self, dir: &str) -> Result<()> {
+        let content = serde_json::to_vec_pretty(self)
+            .map_err(|e| LockboxError::Encoding(format!("Failed to serialize cache index: {}", e)))?;
+        write_atomic(&path_in(dir, INDEX_FILE), &content)
+    }
+
+    /// Remove an entry and its blob
+    fn remove(&mut self, dir: &str, name: &str) -> bool {
+        match self.entries.remove(name) {
+            Some(entry) => {
+                let _ = std::fs::remove_file(fs_path(&path_in(dir, &entry.file)));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Evict entries written more than `max_age` seconds ago, then the least recently
+    /// read entries until the blobs take at most `max_size` bytes
+    ///
+    /// Returns the number of entries evicted.
+    fn evict(&mut self, dir: &str, max_size: f64, max_age: f64) -> usize {
+        let now = now();
+        let expired: Vec<String> = self.entries.iter()
+            .filter(|(_, entry)| is_expired(entry, max_age, now))
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut evicted = expired.len();
+        for name in expired {
+            self.remove(dir, &name);
+        }
+
+        let mut total: u64 = self.entries.values().map(|entry| entry.size).sum();
+        if (total as f64) <= max_size {
+            return evicted;
+        }
+        let mut by_access: Vec<(String, i64, u64)> = self.entries.iter()
+            .map(|(name, entry)| (name.clone(), entry.accessed, entry.size))
+            .collect();
+        by_access.sort_by_key(|(_, accessed, _)| *accessed);
+        for (name, _, size) in by_access {
+            if (total as f64) <= max_size {
+                break;
+            }
+            self.remove(dir, &name);
+            total -= size;
+            evicted += 1;
+        }
+        evicted
+    }
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn is_expired(entry: &Entry, max_age: f64, now: i64) -> bool {
+    (now - entry.created) as f64 / 1000.0 > max_age
+}
+
+fn path_in(dir: &str, file: &str) -> String {
+    Path::new(dir).join(file).to_string_lossy().into_owned()
+}
+
+/// File name of the blob holding an entry
+fn blob_file(name: &str) -> String {
+    let hash = blake3::hash(name.as_bytes()).to_hex();
+    format!("{}{}", &hash[..32], BLOB_EXTENSION)
+}
+
+/// Write a file through a temporary file, so readers never see it half-written
+pub(crate) fn write_atomic(path: &str, content: &[u8]) -> Result<()> {
+    let temp_path = format!("{}.lockbox-tmp", path);
+    let mut file = create_output_file(&temp_path, true, Access::OWNER_ONLY)?;
+    let partial = PartialOutput::new(&temp_path);
+    file.write_all(content)
+        .and_then(|_| file.flush())
+        .map_err(|e| LockboxError::io(format!("Failed to write '{}'", temp_path), &e))?;
+    std::fs::rename(fs_path(&temp_path), fs_path(path))
+        .map_err(|e| LockboxError::io(format!("Failed to replace '{}'", path), &e))?;
+    partial.complete();
+    Ok(())
+}
+
+/// Plaintext of a blob: the entry name, length-prefixed, then the value
+fn envelope(name: &str, value: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mut plaintext = Zeroizing::new(Vec::with_capacity(4 + name.len() + value.len()));
+    plaintext.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    plaintext.extend_from_slice(name.as_bytes());
+    plaintext.extend_from_slice(value);
+    plaintext
+}
+
+/// Return the value of an envelope if it belongs to `name`
+fn open_envelope<'a>(name: &str, plaintext: &'a [u8]) -> Option<&'a [u8]> {
+    if plaintext.len() < 4 {
+        return None;
+    }
+    let (length, rest) = plaintext.split_at(4);
+    let length = u32::from_be_bytes(length.try_into().ok()?) as usize;
+    if rest.len() < length {
+        return None;
+    }
+    let (stored_name, value) = rest.split_at(length);
+    (stored_name == name.as_bytes()).then_some(value)
+}
+
+/// Encrypt a value into the cache under `name`, then apply the eviction limits
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_cache_put(dir: &str, name: &str, value: Raw, recipients: Vec<String>, max_size: f64, max_age: f64) -> Result<()> {
+    let keys = recipients.clone();
+    let file = blob_file(name);
+    let result = (|| {
+        let parsed_recipients = parse_recipients(recipients)?;
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+
+        let plaintext = envelope(name, value.as_slice());
+        let mut ciphertext = Vec::with_capacity(plaintext.len() + 256);
+        let mut writer = encryptor.wrap_output(&mut ciphertext)
+            .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+        writer.write_all(&plaintext)
+            .and_then(|_| writer.finish())
+            .map_err(|e| LockboxError::EncryptFailed(format!("Failed to encrypt cache entry: {}", e)))?;
+        stats::add_bytes(Direction::Encrypt, value.as_slice().len() as u64);
+
+        write_atomic(&path_in(dir, &file), &ciphertext)?;
+        Ok(ciphertext)
+    })();
+    let ciphertext = audit::record("encrypt", "key", result, |ciphertext| {
+        audit::Details::raw(ciphertext.map(Vec::as_slice), Some(&path_in(dir, &file)), audit::recipient_keys(keys))
+    })?;
+
+    let mut index = Index::read(dir)?;
+    let now = now();
+    index.entries.insert(name.to_string(), Entry {
+        file,
+        size: ciphertext.len() as u64,
+        hash: blake3::hash(&ciphertext).to_hex().to_string(),
+        created: now,
+        accessed: now,
+    });
+    index.evict(dir, max_size, max_age);
+    index.write(dir)
+}
+
+/// Decrypt the value cached under `name`
+///
+/// Returns NULL if there is no such entry, it is older than `max_age` seconds, or
+/// its blob is missing or does not match the index. Such entries are removed.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_cache_get(dir: &str, name: &str, private_key_path: &str, strict_permissions: bool, max_age: f64) -> Result<Robj> {
+    let mut index = Index::read(dir)?;
+    let entry = match index.entries.get(name) {
+        Some(entry) => entry.clone(),
+        None => return Ok(().into()),
+    };
+
+    let ciphertext = (!is_expired(&entry, max_age, now()))
+        .then(|| std::fs::read(fs_path(&path_in(dir, &entry.file))).ok())
+        .flatten()
+        .filter(|ciphertext| blake3::hash(ciphertext).to_hex().as_str() == entry.hash);
+    let ciphertext = match ciphertext {
+        Some(ciphertext) => ciphertext,
+        None => {
+            index.remove(dir, name);
+            index.write(dir)?;
+            return Ok(().into());
+        }
+    };
+
+    let result = (|| {
+        policy::check_decrypt_from(&fs_path(&path_in(dir, &entry.file)))?;
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+        decrypt_content(&ciphertext, identities.iter().map(|i| i.as_ref()), &mut Progress::none())
+    })();
+    let plaintext = audit::record("decrypt", "key", result, |_| {
+        audit::Details::raw(Some(&ciphertext), None, audit::key_file_public_keys(private_key_path))
+    })?;
+    let value = match open_envelope(name, &plaintext) {
+        Some(value) => Raw::from_bytes(value),
+        None => {
+            index.remove(dir, name);
+            index.write(dir)?;
+            return Ok(().into());
+        }
+    };
+
+    if let Some(entry) = index.entries.get_mut(name) {
+        entry.accessed = now();
+    }
+    index.write(dir)?;
+    Ok(value.into())
+}
+
+/// Remove an entry; returns whether it existed
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_cache_remove(dir: &str, name: &str) -> Result<bool> {
+    let mut index = Index::read(dir)?;
+    let removed = index.remove(dir, name);
+    if removed {
+        index.write(dir)?;
+    }
+    Ok(removed)
+}
+
+/// List the entries with their size and times (seconds since the Unix epoch)
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_cache_info(dir: &str) -> Result<List> {
+    let index = Index::read(dir)?;
+    let names: Vec<String> = index.entries.keys().cloned().collect();
+    let sizes: Vec<f64> = index.entries.values().map(|entry| entry.size as f64).collect();
+    let created: Vec<f64> = index.entries.values().map(|entry| entry.created as f64 / 1000.0).collect();
+    let accessed: Vec<f64> = index.entries.values().map(|entry| entry.accessed as f64 / 1000.0).collect();
+    Ok(list!(name = names, size = sizes, created = created, accessed = accessed))
+}
+
+/// Apply the eviction limits and delete blob files that no entry refers to
+///
+/// Returns the number of entries evicted.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_cache_prune(dir: &str, max_size: f64, max_age: f64) -> Result<i32> {
+    let mut index = Index::read(dir)?;
+    let evicted = index.evict(dir, max_size, max_age);
+    index.write(dir)?;
+
+    let listing = std::fs::read_dir(dir)
+        .map_err(|e| LockboxError::io("Failed to read cache directory", &e))?;
+    for item in listing.flatten() {
+        let file = item.file_name().to_string_lossy().into_owned();
+        let is_orphan = file.ends_with(BLOB_EXTENSION) && !index.entries.values().any(|entry| entry.file == file);
+        if is_orphan || file.ends_with(".lockbox-tmp") {
+            let _ = std::fs::remove_file(item.path());
+        }
+    }
+    Ok(evicted as i32)
+}
+
+/// Remove every entry and the index
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_cache_clear(dir: &str) -> Result<()> {
+    let mut index = Index::read(dir)?;
+    let names: Vec<String> = index.entries.keys().cloned().collect();
+    for name in names {
+        index.remove(dir, &name);
+    }
+    match std::fs::remove_file(fs_path(&path_in(dir, INDEX_FILE))) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(LockboxError::io("Failed to remove cache index", &e).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+extendr_module! {
+    mod cache;
+    fn age_cache_put;
+    fn age_cache_get;
+    fn age_cache_remove;
+    fn age_cache_info;
+    fn age_cache_prune;
+    fn age_cache_clear;
+}
@@ -0,0 +1,131 @@
+// Armored secrets through the clipboard
+//
+// A password or API token sent to a colleague usually goes through a chat window or
+// an email, and on its way out of R it lands in a file, the console or the history.
+// Encrypting to the clipboard puts only the armored ciphertext there, ready to paste;
+// decrypting from it reads a pasted ciphertext, such as a copied chat message, without
+// the text ever being written down. The armored block may be surrounded by other text
+// (see `encoding::extract_armor()`).
+//
+// On Linux, text copied by a program is served by that program until another copies
+// something else, so the clipboard handle is kept for the whole R session rather than
+// dropped after each call. The clipboard is used from R's main thread only.
+//
+// Support is only compiled with the `clipboard` feature. Without it, every function
+// here fails with an explanation, so the R wrappers exist in every build.
+use extendr_api::prelude::*;
+
+use crate::error::LockboxError;
+#[cfg(feature = "clipboard")]
+use crate::stream::Progress;
+#[cfg(feature = "clipboard")]
+use crate::{
+    audit, decrypt_content, decrypted_string, encrypt_string, encrypted_string_bytes, parse_identities_from_key_file,
+    parse_recipients, policy, read_key_file,
+};
+#[cfg(feature = "clipboard")]
+use std::cell::RefCell;
+
+#[cfg(feature = "clipboard")]
+thread_local! {
+    static CLIPBOARD: RefCell<Option<arboard::Clipboard>> = const { RefCell::new(None) };
+}
+
+#[cfg(not(feature = "clipboard"))]
+fn unsupported<T>() -> Result<T> {
+    Err(LockboxError::InvalidArgument(
+        "lockbox was built without clipboard support; reinstall it with the environment variable LOCKBOX_CLIPBOARD=true".to_string(),
+    )
+    .into())
+}
+
+/// Run `f` on the session's clipboard, opening it on first use
+#[cfg(feature = "clipboard")]
+fn with_clipboard<T>(f: impl FnOnce(&mut arboard::Clipboard) -> std::result::Result<T, arboard::Error>) -> Result<T> {
+    CLIPBOARD.with(|slot| {
+        let mut clipboard = match slot.borrow_mut().take() {
+            Some(clipboard) => clipboard,
+            None => arboard::Clipboard::new().map_err(clipboard_error)?,
+        };
+        let result = f(&mut clipboard);
+        *slot.borrow_mut() = Some(clipboard);
+        result.map_err(|e| clipboard_error(e).into())
+    })
+}
+
+#[cfg(feature = "clipboard")]
+fn clipboard_error(err: arboard::Error) -> LockboxError {
+    match err {
+        arboard::Error::ContentNotAvailable => LockboxError::InvalidArgument("The clipboard holds no text".to_string()),
+        arboard::Error::ClipboardNotSupported => LockboxError::Io(
+            "No clipboard is available; on Linux, a graphical session (X11 or Wayland) is needed".to_string(),
+        ),
+        e => LockboxError::Io(format!("Failed to use the clipboard: {}", e)),
+    }
+}
+
+/// Whether this build includes clipboard support
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_clipboard_enabled() -> bool {
+    cfg!(feature = "clipboard")
+}
+
+/// Encrypt a string to public keys and copy the armored result to the clipboard
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_to_clipboard(input_string: &str, recipients: Vec<String>) -> Result<()> {
+    #[cfg(feature = "clipboard")]
+    {
+        let keys = recipients.clone();
+        let result = (|| {
+            let parsed_recipients = parse_recipients(recipients)?;
+            let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+            encrypt_string(encryptor, input_string, "armor")
+        })();
+        let encrypted = audit::record("encrypt", "key", result, |encrypted| {
+            audit::Details::string(encrypted.map(String::as_str), audit::recipient_keys(keys))
+        })?;
+        with_clipboard(|clipboard| clipboard.set_text(encrypted))
+    }
+    #[cfg(not(feature = "clipboard"))]
+    {
+        let _ = (input_string, recipients);
+        unsupported()
+    }
+}
+
+/// Decrypt the armored age file held by the clipboard with a private key
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_from_clipboard(private_key_path: &str, strict_permissions: bool) -> Result<Robj> {
+    #[cfg(feature = "clipboard")]
+    {
+        let encrypted_string = with_clipboard(|clipboard| clipboard.get_text())?;
+        let result = (|| {
+            let encrypted_bytes = encrypted_string_bytes(&encrypted_string, true)?;
+            let key_content = read_key_file(private_key_path, strict_permissions)?;
+            let identities = parse_identities_from_key_file(&key_content)?;
+            let decrypted_bytes = decrypt_content(&encrypted_bytes, identities.iter().map(|i| i.as_ref()), &mut Progress::none())?;
+            decrypted_string(&decrypted_bytes)
+        })();
+        audit::record("decrypt", "key", result, |_| {
+            audit::Details::string(Some(&encrypted_string), audit::key_file_public_keys(private_key_path))
+        })
+    }
+    #[cfg(not(feature = "clipboard"))]
+    {
+        let _ = (private_key_path, strict_permissions);
+        unsupported()
+    }
+}
+
+extendr_module! {
+    mod clipboard;
+    fn age_clipboard_enabled;
+    fn age_encrypt_to_clipboard;
+    fn age_decrypt_from_clipboard;
+}
@@ -0,0 +1,258 @@
+// Convergent (deterministic) encryption
+//
+// Normally every age file gets a random file key, so encrypting the same input twice
+// gives unrelated ciphertexts. In convergent mode all of the randomness in the file
+// (the file key, the payload nonce and the ephemeral X25519 keys in the header) is
+// derived from a keyed hash of the plaintext, so the same input, convergence key and
+// recipients always produce the same bytes. The output is an ordinary age v1 file.
+//
+// age draws that randomness from the OS inside its STREAM writer and x25519
+// recipient, so both are reimplemented here following the age v1 specification.
+use extendr_api::prelude::*;
+use age::secrecy::ExposeSecret;
+use age_core::format::{FileKey, Stanza};
+use age_core::primitives::{aead_encrypt, hkdf};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+use bech32::FromBase32;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::access::Access;
+use crate::audit;
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::recipients::write_header;
+use crate::stats::{self, Direction};
+use crate::stream::{check_interrupt, PartialOutput, CHUNK_SIZE};
+use crate::{create_output_file, parse_x25519_identities, parse_x25519_recipients, read_key_file};
+
+/// Size of a plaintext chunk in the age payload
+const PAYLOAD_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Key of the plaintext hash, derived from the user's identity
+fn convergence_key(identity: &age::x25519::Identity) -> Zeroizing<[u8; 32]> {
+    Zeroizing::new(hkdf(&[], b"lockbox/convergent/key", identity.to_string().expose_secret().as_bytes()))
+}
+
+/// Convergence key of the first identity in a private key file
+pub(crate) fn convergence_key_from_file(private_key_path: &str, strict_permissions: bool) -> Result<Zeroizing<[u8; 32]>> {
+    let key_content = read_key_file(private_key_path, strict_permissions)?;
+    let identities = parse_x25519_identities(&key_content)?;
+    Ok(convergence_key(&identities[0]))
+}
+
+/// Keyed hash of the plaintext
+fn plaintext_mac(key: &[u8; 32]) -> Hmac<Sha256> {
+    <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length")
+}
+
+/// HMAC-SHA256 of the plaintext, from which everything else is derived
+pub(crate) fn plaintext_seed<R: Read>(input: &mut R, key: &[u8; 32]) -> Result<Zeroizing<[u8; 32]>> {
+    let mut mac = plaintext_mac(key);
+    let mut buffer = Zeroizing::new(vec![0u8; CHUNK_SIZE]);
+
+    loop {
+        check_interrupt()?;
+        let n = input.read(&mut buffer)
+            .map_err(|e| LockboxError::io("Failed to read input file", &e))?;
+        if n == 0 {
+            break;
+        }
+        mac.update(&buffer[..n]);
+    }
+
+    Ok(Zeroizing::new(mac.finalize().into_bytes().into()))
+}
+
+/// Reader that hashes everything read through it, to check that the plaintext
+/// encrypted is the one the seed was derived from
+struct HashingReader<R> {
+    inner: R,
+    mac: Hmac<Sha256>,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.mac.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Raw bytes of an `age1...` public key
+fn recipient_bytes(recipient: &str) -> Result<[u8; 32]> {
+    let invalid = || LockboxError::InvalidRecipient(format!("Invalid recipient '{}'", recipient));
+    let (_, data, _) = bech32::decode(recipient).map_err(|_| invalid())?;
+    let bytes = Vec::<u8>::from_base32(&data).map_err(|_| invalid())?;
+    bytes.try_into().map_err(|_| invalid().into())
+}
+
+/// Wrap the file key for one recipient, with an ephemeral key derived from `seed`
+fn wrap_file_key(recipient: &[u8; 32], file_key: &FileKey, seed: &[u8; 32]) -> Stanza {
+    let esk = StaticSecret::from(hkdf(recipient, b"lockbox/convergent/x25519", seed));
+    let epk = PublicKey::from(&esk);
+    let shared_secret = esk.diffie_hellman(&PublicKey::from(*recipient));
+
+    let mut salt = [0; 64];
+    salt[..32].copy_from_slice(epk.as_bytes());
+    salt[32..].copy_from_slice(recipient);
+    let enc_key = Zeroizing::new(hkdf(&salt, b"age-encryption.org/v1/X25519", shared_secret.as_bytes()));
+
+    Stanza {
+        tag: "X25519".to_string(),
+        args: vec![STANDARD_NO_PAD.encode(epk.as_bytes())],
+        body: aead_encrypt(&enc_key, file_key.expose_secret()),
+    }
+}
+
+/// Read up to one payload chunk
+fn read_chunk<R: Read>(input: &mut R, chunk: &mut Vec<u8>) -> Result<()> {
+    chunk.clear();
+    input.take(PAYLOAD_CHUNK_SIZE).read_to_end(chunk)
+        .map_err(|e| LockboxError::io("Failed to read input file", &e))?;
+    Ok(())
+}
+
+/// Encrypt `input` with the age STREAM construction
+///
+/// Chunks are sealed with ChaCha20-Poly1305 under a nonce made of an 11-byte big-endian
+/// counter and a final byte that is 1 for the last chunk only. The last chunk may be
+/// full, and is only empty when the whole plaintext is.
+fn write_payload<R: Read, W: Write>(input: &mut R, output: &mut W, payload_key: &[u8; 32]) -> Result<()> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(payload_key));
+    let mut chunk = Zeroizing::new(Vec::with_capacity(PAYLOAD_CHUNK_SIZE as usize));
+    let mut next_chunk = Zeroizing::new(Vec::with_capacity(PAYLOAD_CHUNK_SIZE as usize));
+    read_chunk(input, &mut chunk)?;
+
+    let mut counter: u128 = 0;
+    loop {
+        check_interrupt()?;
+        read_chunk(input, &mut next_chunk)?;
+        let last = next_chunk.is_empty();
+
+        let mut nonce = [0u8; 12];
+        nonce[..11].copy_from_slice(&counter.to_be_bytes()[5..]);
+        nonce[11] = last as u8;
+        let sealed = cipher.encrypt(Nonce::from_slice(&nonce), chunk.as_slice())
+            .map_err(|_| LockboxError::EncryptFailed("Failed to encrypt payload".to_string()))?;
+        output.write_all(&sealed)
+            .map_err(|e| LockboxError::io("Failed to write output", &e))?;
+        stats::add_bytes(Direction::Encrypt, chunk.len() as u64);
+
+        if last {
+            return Ok(());
+        }
+        std::mem::swap(&mut chunk, &mut next_chunk);
+        counter += 1;
+    }
+}
+
+/// Raw bytes of each recipient, validated as X25519 public keys, then of the policy's
+/// escrow recipients
+pub(crate) fn convergent_recipients(recipients: &[String]) -> Result<Vec<[u8; 32]>> {
+    parse_x25519_recipients(recipients)?
+        .iter()
+        .chain(policy::escrow_recipients()?.iter())
+        .map(|r| recipient_bytes(&r.to_string()))
+        .collect()
+}
+
+/// Write `input` as an age file whose randomness is all derived from `seed`
+pub(crate) fn write_convergent<R: Read, W: Write>(
+    input: &mut R,
+    output: W,
+    recipients: &[[u8; 32]],
+    seed: &[u8; 32],
+    armor: bool,
+) -> Result<W> {
+    use age::armor::{ArmoredWriter, Format};
+
+    let file_key = FileKey::init_with_mut(|file_key| {
+        file_key.copy_from_slice(&hkdf(&[], b"lockbox/convergent/file-key", seed)[..16]);
+    });
+    let mut nonce = [0u8; 16];
+    nonce.copy_from_slice(&hkdf(&[], b"lockbox/convergent/nonce", seed)[..16]);
+    let payload_key = Zeroizing::new(hkdf(&nonce, b"payload", file_key.expose_secret()));
+
+    let stanzas: Vec<Stanza> = recipients.iter()
+        .map(|recipient| wrap_file_key(recipient, &file_key, seed))
+        .collect();
+    let header = write_header(&stanzas, &file_key);
+
+    let format = if armor { Format::AsciiArmor } else { Format::Binary };
+    let mut output = ArmoredWriter::wrap_output(output, format)
+        .map_err(|e| LockboxError::Armor(format!("Failed to create armored writer: {}", e)))?;
+    output.write_all(&header)
+        .and_then(|_| output.write_all(&nonce))
+        .map_err(|e| LockboxError::io("Failed to write output", &e))?;
+    write_payload(input, &mut output, &payload_key)?;
+    output.finish()
+        .map_err(|e| LockboxError::io("Failed to finalize output", &e).into())
+}
+
+/// Encrypt a file deterministically to public keys
+///
+/// The plaintext hash is keyed with the first identity in `private_key_path`, so only
+/// holders of that key can produce (or test guesses against) the same ciphertext.
+/// The file is read twice through one handle: once to hash it, once to encrypt it
+/// while hashing it again. If the two hashes differ the file changed in between, and
+/// the output is removed rather than keeping a file key and nonce that belong to
+/// other plaintext.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_encrypt_convergent(
+    input_file_path: &str,
+    output_file_path: &str,
+    recipients: Vec<String>,
+    private_key_path: &str,
+    strict_permissions: bool,
+    armor: bool,
+    overwrite: bool,
+) -> Result<()> {
+    let keys = recipients.clone();
+    let result = (|| {
+        policy::check_armor(armor)?;
+        let recipients = convergent_recipients(&recipients)?;
+        let key = convergence_key_from_file(private_key_path, strict_permissions)?;
+
+        let mut input = File::open(fs_path(input_file_path))
+            .map_err(|e| LockboxError::io("Failed to read input file", &e))?;
+        let seed = plaintext_seed(&mut input, &key)?;
+        input.seek(SeekFrom::Start(0))
+            .map_err(|e| LockboxError::io("Failed to read input file", &e))?;
+
+        let output_file = create_output_file(output_file_path, overwrite, Access::default())?;
+        let partial = PartialOutput::new(output_file_path);
+        let mut input = HashingReader { inner: input, mac: plaintext_mac(&key) };
+        write_convergent(&mut input, BufWriter::new(output_file), &recipients, &seed, armor)?
+            .flush()
+            .map_err(|e| LockboxError::io("Failed to finalize output", &e))?;
+
+        let encrypted: Zeroizing<[u8; 32]> = Zeroizing::new(input.mac.finalize().into_bytes().into());
+        if *encrypted != *seed {
+            return Err(LockboxError::EncryptFailed(format!(
+                "'{}' changed while it was being encrypted", input_file_path
+            )).into());
+        }
+
+        partial.complete();
+        Ok(())
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(Some(input_file_path), output_file_path, done.is_some(), audit::recipient_keys(keys))
+    })
+}
+
+extendr_module! {
+    mod convergent;
+    fn age_encrypt_convergent;
+}
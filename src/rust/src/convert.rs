@@ -0,0 +1,68 @@
+// Converting between binary and ASCII-armored age files
+//
+// Armor is only a PEM-style base64 encoding of the binary file, so converting
+// between the two never touches the encryption: no key is needed, the header and
+// payload are carried over byte for byte, and both files decrypt with the same keys.
+use extendr_api::prelude::*;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use crate::access::Access;
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::stream::{copy_interruptible, PartialOutput, Progress};
+use crate::create_output_file;
+
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1";
+
+/// Re-encode an age file as binary or ASCII armor
+///
+/// `to` is "armor" or "binary". The input may be in either format.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_convert_format(input_file_path: &str, output_file_path: &str, to: &str, overwrite: bool) -> Result<()> {
+    use age::armor::{ArmoredReader, ArmoredWriter, Format};
+
+    let format = match to {
+        "armor" => Format::AsciiArmor,
+        "binary" => Format::Binary,
+        _ => {
+            return Err(LockboxError::InvalidArgument(format!("`to` must be \"armor\" or \"binary\", got \"{}\"", to)).into());
+        }
+    };
+
+    let input = std::fs::File::open(fs_path(input_file_path))
+        .map_err(|e| LockboxError::io(format!("Failed to open '{}'", input_file_path), &e))?;
+    let mut reader = ArmoredReader::new(BufReader::new(input));
+
+    // Check the decoded start before creating the output, so other files are refused
+    let not_age = || LockboxError::NotAgeFile(format!("'{}' is not an age-encrypted file", input_file_path));
+    let mut magic = Vec::with_capacity(AGE_MAGIC.len());
+    match (&mut reader).take(AGE_MAGIC.len() as u64).read_to_end(&mut magic) {
+        Ok(_) if magic == AGE_MAGIC => {}
+        Ok(_) => return Err(not_age().into()),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Err(not_age().into()),
+        Err(e) => return Err(LockboxError::Armor(format!("Failed to read '{}': {}", input_file_path, e)).into()),
+    }
+
+    let output = create_output_file(output_file_path, overwrite, Access::default())?;
+    let partial = PartialOutput::new(output_file_path);
+    let mut writer = ArmoredWriter::wrap_output(BufWriter::new(output), format)
+        .map_err(|e| LockboxError::Armor(format!("Failed to create armored writer: {}", e)))?;
+    writer.write_all(&magic)
+        .map_err(|e| LockboxError::io(format!("Failed to write '{}'", output_file_path), &e))?;
+    copy_interruptible(&mut reader, &mut writer, &mut Progress::none(), |e| {
+        LockboxError::Armor(format!("Failed to read '{}': {}", input_file_path, e))
+    })?;
+    writer.finish()
+        .and_then(|mut writer| writer.flush())
+        .map_err(|e| LockboxError::io(format!("Failed to finalize '{}'", output_file_path), &e))?;
+
+    partial.complete();
+    Ok(())
+}
+
+extendr_module! {
+    mod convert;
+    fn age_convert_format;
+}
@@ -0,0 +1,146 @@
+// Text encodings of encrypted strings
+//
+// Encrypted strings are binary age files written as text. Standard base64 is the
+// default; base64url (without padding) and hex survive URLs, JSON and file names
+// without escaping; ASCII armor is the PEM-style format other age tools read.
+//
+// Decoding needs no hint: armor has its begin line, a binary age file always starts
+// with the same magic bytes (which appear as a fixed prefix in hex), and base64url
+// differs from base64 in the two characters `-` and `_`.
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine as _;
+use std::io::Write;
+
+use crate::error::LockboxError;
+use crate::stream::is_armored;
+
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+const ARMOR_END: &str = "-----END AGE ENCRYPTED FILE-----";
+/// "age-encryption.org/v1" in hex
+const HEX_MAGIC: &str = "6167652d656e6372797074696f6e2e6f72672f7631";
+
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+const BASE64URL: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum StringEncoding {
+    Base64,
+    Base64Url,
+    Hex,
+    Armor,
+}
+
+impl StringEncoding {
+    pub(crate) fn parse(name: &str) -> std::result::Result<Self, LockboxError> {
+        match name {
+            "base64" => Ok(StringEncoding::Base64),
+            "base64url" => Ok(StringEncoding::Base64Url),
+            "hex" => Ok(StringEncoding::Hex),
+            "armor" => Ok(StringEncoding::Armor),
+            _ => Err(LockboxError::InvalidArgument(format!(
+                "`encoding` must be one of \"base64\", \"base64url\", \"hex\" or \"armor\", got \"{}\"",
+                name
+            ))),
+        }
+    }
+
+    /// Write a binary age file as text
+    pub(crate) fn encode(self, ciphertext: &[u8]) -> std::result::Result<String, LockboxError> {
+        match self {
+            StringEncoding::Base64 => Ok(BASE64.encode(ciphertext)),
+            StringEncoding::Base64Url => Ok(BASE64URL.encode(ciphertext)),
+            StringEncoding::Hex => Ok(ciphertext.iter().map(|b| format!("{:02x}", b)).collect()),
+            StringEncoding::Armor => {
+                use age::armor::{ArmoredWriter, Format};
+
+                let mut output = Vec::with_capacity(ciphertext.len() * 4 / 3 + 128);
+                let mut writer = ArmoredWriter::wrap_output(&mut output, Format::AsciiArmor)
+                    .map_err(|e| LockboxError::Armor(format!("Failed to create armored writer: {}", e)))?;
+                writer.write_all(ciphertext)
+                    .and_then(|_| writer.finish())
+                    .map_err(|e| LockboxError::Armor(format!("Failed to write armor: {}", e)))?;
+                String::from_utf8(output)
+                    .map_err(|e| LockboxError::Encoding(format!("Failed to convert armored output to string: {}", e)))
+            }
+        }
+    }
+
+    /// The encoding of an encrypted string
+    pub(crate) fn detect(encrypted: &str) -> Self {
+        if is_armored(encrypted.as_bytes()) {
+            StringEncoding::Armor
+        } else if encrypted.len() >= HEX_MAGIC.len() && encrypted[..HEX_MAGIC.len()].eq_ignore_ascii_case(HEX_MAGIC) {
+            StringEncoding::Hex
+        } else if encrypted.contains(['-', '_']) {
+            StringEncoding::Base64Url
+        } else {
+            StringEncoding::Base64
+        }
+    }
+}
+
+fn decode_hex(encrypted: &str) -> std::result::Result<Vec<u8>, LockboxError> {
+    let invalid = || LockboxError::Encoding("Failed to decode hex: invalid hex digits".to_string());
+    if encrypted.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    encrypted.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair).ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+/// The bytes of an encrypted string, in any of the encodings
+///
+/// Armor is returned as is; age reads it directly.
+pub(crate) fn decode_encrypted(encrypted: &str) -> std::result::Result<Vec<u8>, LockboxError> {
+    match StringEncoding::detect(encrypted) {
+        StringEncoding::Armor => Ok(encrypted.as_bytes().to_vec()),
+        StringEncoding::Hex => decode_hex(encrypted),
+        StringEncoding::Base64Url => BASE64URL.decode(encrypted)
+            .map_err(|e| LockboxError::Encoding(format!("Failed to decode base64url: {}", e))),
+        StringEncoding::Base64 => BASE64.decode(encrypted)
+            .map_err(|e| LockboxError::Encoding(format!("Failed to decode base64: {}", e))),
+    }
+}
+
+/// The armored age file in `text`, such as a pasted email body or Markdown document
+///
+/// Lines are taken from the begin marker to the end marker, without the quote
+/// markers (`>`) and indentation that mail clients and Markdown put in front of them,
+/// or the blank lines they insert. Text around the block, code fences included, is
+/// ignored. Without an end marker, the lines after the begin marker are returned, so
+/// that decryption reports the armor as cut off.
+pub(crate) fn extract_armor(text: &str) -> std::result::Result<String, LockboxError> {
+    let mut lines = text.lines()
+        .map(|line| line.trim_start_matches(|c: char| c == '>' || c == '\u{feff}' || c.is_whitespace()).trim_end())
+        .skip_while(|line| *line != ARMOR_BEGIN);
+    let Some(begin) = lines.next() else {
+        return Err(LockboxError::NotAgeFile(format!(
+            "The text holds no armored age file: no line reads '{}'",
+            ARMOR_BEGIN
+        )));
+    };
+    let mut armor = format!("{}\n", begin);
+    for line in lines.filter(|line| !line.is_empty()) {
+        armor.push_str(line);
+        armor.push('\n');
+        if line == ARMOR_END {
+            break;
+        }
+    }
+    Ok(armor)
+}
@@ -0,0 +1,168 @@
+// Encrypted .env files
+//
+// The file is encrypted as is, but parsed first so that a syntax error is reported
+// when encrypting rather than when the secrets are needed. On load, the decrypted
+// content is parsed here and only the resulting names and values are handed to R;
+// the decrypted file itself is never written to disk.
+use extendr_api::prelude::*;
+use zeroize::Zeroizing;
+
+use crate::access::Access;
+use crate::audit;
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stream::Progress;
+use crate::{decrypt_content, encrypt_file, parse_identities_from_key_file, parse_recipients, read_key_file};
+
+/// A parsed variable; the value is wiped when dropped
+type EnvVar = (String, Zeroizing<String>);
+
+fn env_syntax_error(line_number: usize, detail: &str) -> LockboxError {
+    // Never include the line itself, which may hold a secret
+    LockboxError::InvalidArgument(format!("Invalid .env file, line {}: {}", line_number, detail))
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parse a quoted value, returning it and whatever follows the closing quote
+fn parse_quoted(value: &str, quote: char, line_number: usize) -> Result<(Zeroizing<String>, &str)> {
+    let mut parsed = Zeroizing::new(String::new());
+    let mut chars = value[1..].char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        if c == quote {
+            return Ok((parsed, &value[i + 2..]));
+        }
+        // Escapes are only interpreted in double quotes, as in POSIX shells
+        if c == '\\' && quote == '"' {
+            match chars.next() {
+                Some((_, 'n')) => parsed.push('\n'),
+                Some((_, 't')) => parsed.push('\t'),
+                Some((_, 'r')) => parsed.push('\r'),
+                Some((_, escaped)) => parsed.push(escaped),
+                None => break,
+            }
+        } else {
+            parsed.push(c);
+        }
+    }
+
+    Err(env_syntax_error(line_number, "unterminated quoted value").into())
+}
+
+/// Parse the content of a .env file
+///
+/// Supports `NAME=value` lines, an optional `export ` prefix, blank lines and `#`
+/// comments, single-quoted (literal) and double-quoted (with `\n`, `\t`, `\r` and
+/// `\\` escapes) values, and trailing ` # comments` after unquoted values. When a
+/// name appears more than once, the last value wins.
+fn parse_env(content: &str) -> Result<Vec<EnvVar>> {
+    let mut vars: Vec<EnvVar> = Vec::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").map(str::trim_start).unwrap_or(line);
+
+        let (name, value) = line.split_once('=')
+            .ok_or_else(|| env_syntax_error(line_number, "expected NAME=value"))?;
+        let name = name.trim_end();
+        if !is_valid_name(name) {
+            return Err(env_syntax_error(line_number, "names may only contain letters, digits and underscores, and may not start with a digit").into());
+        }
+
+        let value = value.trim_start();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let (parsed, rest) = parse_quoted(value, quote, line_number)?;
+                let rest = rest.trim_start();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    return Err(env_syntax_error(line_number, "unexpected text after the closing quote").into());
+                }
+                parsed
+            }
+            _ => {
+                let value = match value.find(" #") {
+                    Some(comment) => &value[..comment],
+                    None => value,
+                };
+                Zeroizing::new(value.trim_end().to_string())
+            }
+        };
+
+        match vars.iter_mut().find(|(existing, _)| existing == name) {
+            Some(var) => var.1 = value,
+            None => vars.push((name.to_string(), value)),
+        }
+    }
+
+    Ok(vars)
+}
+
+/// Encrypt a .env file to public keys
+///
+/// The file is parsed before encrypting, so that syntax errors surface now.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_env(env_file_path: &str, output_file_path: &str, recipients: Vec<String>, armor: bool, overwrite: bool) -> Result<()> {
+    let keys = recipients.clone();
+    let result = (|| {
+        let content = Zeroizing::new(std::fs::read_to_string(fs_path(env_file_path))
+            .map_err(|e| LockboxError::io("Failed to read .env file", &e))?);
+        parse_env(&content)?;
+
+        let parsed_recipients = parse_recipients(recipients)?;
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+        encrypt_file(encryptor, env_file_path, output_file_path, armor, overwrite, Access::default(), false, false, Nullable::Null)
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(Some(env_file_path), output_file_path, done.is_some(), audit::recipient_keys(keys))
+    })
+}
+
+/// Decrypt an encrypted .env file and parse it
+///
+/// Returns a character vector of values named by variable.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_load_env(encrypted_file_path: &str, private_key_path: &str, strict_permissions: bool) -> Result<Robj> {
+    let result = (|| {
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+
+        let path = fs_path(encrypted_file_path);
+        policy::check_decrypt_from(&path)?;
+        let file_content = std::fs::read(&path)
+            .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", encrypted_file_path), &e))?;
+        let decrypted_bytes = decrypt_content(&file_content, identities.iter().map(|i| i.as_ref()), &mut Progress::none())?;
+        let decrypted = std::str::from_utf8(&decrypted_bytes)
+            .map_err(|e| LockboxError::Encoding(format!("Failed to convert decrypted content to UTF-8: {}", e)))?;
+
+        let vars = parse_env(decrypted)?;
+        let names: Vec<&str> = vars.iter().map(|(name, _)| name.as_str()).collect();
+        let values: Vec<&str> = vars.iter().map(|(_, value)| value.as_str()).collect();
+
+        let mut result = Robj::from(values);
+        result.set_attrib("names", names)?;
+        Ok(result)
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::file(encrypted_file_path, None, audit::key_file_public_keys(private_key_path))
+    })
+}
+
+extendr_module! {
+    mod env;
+    fn age_encrypt_env;
+    fn age_load_env;
+}
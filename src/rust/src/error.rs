@@ -0,0 +1,265 @@
+// Structured errors for the Rust functions exposed to R
+use extendr_api::prelude::*;
+use std::fmt;
+
+/// Error categories with stable, machine-readable codes
+///
+/// Every variant carries a human-readable message. When converted into an extendr
+/// error, the code is embedded as a `[lockbox:<code>]` prefix that the R layer
+/// strips and turns into a classed condition (see `rust_call()` in R/utils.R).
+#[derive(Debug, Clone)]
+pub(crate) enum LockboxError {
+    /// None of the supplied private keys can decrypt the file
+    WrongKey(String),
+    /// The passphrase does not decrypt the file
+    BadPassphrase(String),
+    /// A passphrase was given for a file encrypted to public keys, or the reverse
+    WrongMethod(String),
+    /// The age header is malformed or fails authentication
+    CorruptHeader(String),
+    /// The encrypted payload fails authentication or is truncated
+    CorruptPayload(String),
+    /// The file header requests more scrypt work than allowed
+    ExcessiveWork(String),
+    /// The encrypted payload could not be decrypted
+    DecryptFailed(String),
+    /// Encryption could not be set up or completed
+    EncryptFailed(String),
+    /// Reading or writing a file or buffer failed
+    Io(String),
+    /// An I/O operation failed with an OS error, whose kind is kept (see `io_kind()`)
+    FileIo(String, &'static str),
+    /// An output file already exists and overwriting was not requested
+    FileExists(String),
+    /// ASCII armor could not be read or written
+    Armor(String),
+    /// The input is not an age-encrypted file
+    NotAgeFile(String),
+    /// A public key (recipient) could not be parsed
+    InvalidRecipient(String),
+    /// A private key (identity) could not be parsed
+    InvalidIdentity(String),
+    /// A private key file is accessible by users other than its owner
+    InsecureKey(String),
+    /// Base64 or UTF-8 decoding failed
+    Encoding(String),
+    /// An argument is outside its accepted range
+    InvalidArgument(String),
+    /// A requested entry (such as a vault secret) does not exist
+    NotFound(String),
+    /// A signature does not match the file or the public key
+    BadSignature(String),
+    /// An HTTP request failed or the server refused it
+    Network(String),
+    /// The user interrupted a long-running operation
+    Interrupted(String),
+    /// A path to write to or shred is a symbolic link and following links was not requested
+    Symlink(String),
+    /// Too many passphrase attempts have failed recently
+    RateLimited(String),
+    /// The session's lockbox policy forbids the operation
+    PolicyDenied(String),
+}
+
+impl LockboxError {
+    /// Machine-readable code, used as the R condition class suffix
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            LockboxError::WrongKey(_) => "wrong_key",
+            LockboxError::BadPassphrase(_) => "bad_passphrase",
+            LockboxError::WrongMethod(_) => "wrong_method",
+            LockboxError::CorruptHeader(_) => "corrupt_header",
+            LockboxError::CorruptPayload(_) => "corrupt_payload",
+            LockboxError::ExcessiveWork(_) => "excessive_work",
+            LockboxError::DecryptFailed(_) => "decrypt_failed",
+            LockboxError::EncryptFailed(_) => "encrypt_failed",
+            LockboxError::Io(_) | LockboxError::FileIo(..) => "io_error",
+            LockboxError::FileExists(_) => "file_exists",
+            LockboxError::Armor(_) => "armor_error",
+            LockboxError::NotAgeFile(_) => "not_age_file",
+            LockboxError::InvalidRecipient(_) => "invalid_recipient",
+            LockboxError::InvalidIdentity(_) => "invalid_identity",
+            LockboxError::InsecureKey(_) => "insecure_key",
+            LockboxError::Encoding(_) => "encoding_error",
+            LockboxError::InvalidArgument(_) => "invalid_argument",
+            LockboxError::NotFound(_) => "not_found",
+            LockboxError::BadSignature(_) => "bad_signature",
+            LockboxError::Network(_) => "network_error",
+            LockboxError::Interrupted(_) => "interrupted",
+            LockboxError::Symlink(_) => "symlink",
+            LockboxError::RateLimited(_) => "rate_limited",
+            LockboxError::PolicyDenied(_) => "policy_denied",
+        }
+    }
+
+    /// Human-readable message
+    pub(crate) fn message(&self) -> &str {
+        match self {
+            LockboxError::WrongKey(msg)
+            | LockboxError::BadPassphrase(msg)
+            | LockboxError::WrongMethod(msg)
+            | LockboxError::CorruptHeader(msg)
+            | LockboxError::CorruptPayload(msg)
+            | LockboxError::ExcessiveWork(msg)
+            | LockboxError::DecryptFailed(msg)
+            | LockboxError::EncryptFailed(msg)
+            | LockboxError::Io(msg)
+            | LockboxError::FileExists(msg)
+            | LockboxError::Armor(msg)
+            | LockboxError::NotAgeFile(msg)
+            | LockboxError::InvalidRecipient(msg)
+            | LockboxError::InvalidIdentity(msg)
+            | LockboxError::InsecureKey(msg)
+            | LockboxError::Encoding(msg)
+            | LockboxError::InvalidArgument(msg)
+            | LockboxError::NotFound(msg)
+            | LockboxError::BadSignature(msg)
+            | LockboxError::Network(msg)
+            | LockboxError::Interrupted(msg)
+            | LockboxError::Symlink(msg)
+            | LockboxError::RateLimited(msg)
+            | LockboxError::PolicyDenied(msg)
+            | LockboxError::FileIo(msg, _) => msg,
+        }
+    }
+
+    /// An I/O error with what was being done, such as `Failed to read 'x.age'`
+    ///
+    /// The OS error is appended to the message and its kind kept for the R condition,
+    /// so that a missing file, a permission problem and a full disk can be told apart.
+    pub(crate) fn io(what: impl fmt::Display, err: &std::io::Error) -> Self {
+        LockboxError::FileIo(format!("{}: {}", what, err), io_kind(err))
+    }
+
+    /// Classify an error returned by age while setting up or running decryption
+    ///
+    /// `scrypt` tells whether the file was passphrase-encrypted, so that a failed
+    /// unwrap can be reported as a bad passphrase rather than a wrong key.
+    pub(crate) fn from_decrypt(err: age::DecryptError, scrypt: bool) -> Self {
+        use age::DecryptError;
+
+        match err {
+            // A passphrase answers any scrypt stanza, so only private keys find no match
+            DecryptError::NoMatchingKeys if scrypt => LockboxError::WrongMethod(
+                "Failed to decrypt: the file is encrypted with a passphrase, not to public keys; \
+                 decrypt it with the passphrase instead of a private key"
+                    .to_string(),
+            ),
+            DecryptError::DecryptionFailed | DecryptError::KeyDecryptionFailed
+                if scrypt =>
+            {
+                // Every wrong passphrase is reported from here, so it is counted here
+                crate::limit::record_passphrase_failure();
+                LockboxError::BadPassphrase("Failed to decrypt: the passphrase is incorrect".to_string())
+            }
+            DecryptError::NoMatchingKeys => LockboxError::WrongKey(
+                "Failed to decrypt: none of the supplied private keys match the file's recipients".to_string(),
+            ),
+            // `target` is what this machine does in about a second, each step doubles it
+            DecryptError::ExcessiveWork { required, target } => {
+                let cost = match required.checked_sub(target) {
+                    Some(extra) if extra > 0 && extra < 63 => format!(
+                        ", about {} seconds of key derivation on this machine",
+                        1u64 << extra
+                    ),
+                    _ => String::new(),
+                };
+                LockboxError::ExcessiveWork(format!(
+                    "Failed to decrypt: the file requires scrypt work factor {}{}, more than allowed. \
+                     Raise `max_work_factor` (or the `lockbox.max_work_factor` option) only if the file is trusted",
+                    required, cost
+                ))
+            }
+            // The file key was unwrapped, so the key is right but the header was modified
+            DecryptError::InvalidMac => LockboxError::CorruptHeader(
+                "Failed to decrypt: the header MAC is invalid, so the file is corrupted or was tampered with"
+                    .to_string(),
+            ),
+            DecryptError::InvalidHeader | DecryptError::UnknownFormat => {
+                LockboxError::CorruptHeader(format!("Failed to decrypt: {}", err))
+            }
+            DecryptError::Io(e) => LockboxError::io("Failed to decrypt", &e),
+            other => LockboxError::DecryptFailed(format!("Failed to decrypt: {}", other)),
+        }
+    }
+
+    /// Classify an I/O error raised while reading decrypted content
+    ///
+    /// By the time the payload is read the key has already matched, so authentication
+    /// failures and early EOFs mean the ciphertext itself is damaged.
+    pub(crate) fn from_payload(err: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+
+        match err.kind() {
+            ErrorKind::InvalidData | ErrorKind::UnexpectedEof => LockboxError::CorruptPayload(format!(
+                "Failed to decrypt: the encrypted payload is corrupted or truncated ({})",
+                err
+            )),
+            _ => LockboxError::io("Failed to read decrypted content", &err),
+        }
+    }
+}
+
+// Running out of space and writing to a read-only file system have no stable
+// `ErrorKind` with the minimum supported Rust, so they are told by their OS codes
+#[cfg(target_os = "linux")]
+const DISK_FULL: &[i32] = &[28, 122]; // ENOSPC, EDQUOT
+#[cfg(all(unix, not(target_os = "linux")))]
+const DISK_FULL: &[i32] = &[28, 69]; // ENOSPC, EDQUOT
+#[cfg(not(unix))]
+const DISK_FULL: &[i32] = &[39, 112]; // ERROR_HANDLE_DISK_FULL, ERROR_DISK_FULL
+#[cfg(unix)]
+const READ_ONLY: &[i32] = &[30]; // EROFS
+#[cfg(not(unix))]
+const READ_ONLY: &[i32] = &[19]; // ERROR_WRITE_PROTECT
+#[cfg(unix)]
+const IS_DIRECTORY: &[i32] = &[21]; // EISDIR
+#[cfg(not(unix))]
+const IS_DIRECTORY: &[i32] = &[];
+
+/// The kind of an I/O error, as reported to R in the condition's `io_kind` field
+pub(crate) fn io_kind(err: &std::io::Error) -> &'static str {
+    use std::io::ErrorKind;
+
+    match (err.kind(), err.raw_os_error()) {
+        (ErrorKind::NotFound, _) => "not_found",
+        (ErrorKind::PermissionDenied, _) => "permission_denied",
+        (ErrorKind::AlreadyExists, _) => "already_exists",
+        (_, Some(code)) if DISK_FULL.contains(&code) => "disk_full",
+        (_, Some(code)) if READ_ONLY.contains(&code) => "read_only",
+        (_, Some(code)) if IS_DIRECTORY.contains(&code) => "is_directory",
+        _ => "other",
+    }
+}
+
+impl fmt::Display for LockboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for LockboxError {}
+
+/// The code and message of an error, for reporting it as data rather than raising it
+///
+/// Errors that did not come from `LockboxError` have the code `error`. The kind of
+/// an I/O error is dropped.
+pub(crate) fn error_parts(err: &Error) -> (String, String) {
+    let text = match err {
+        Error::Other(text) => text.clone(),
+        other => other.to_string(),
+    };
+    text.strip_prefix("[lockbox:")
+        .and_then(|rest| rest.split_once("] "))
+        .map(|(code, message)| (code.split(':').next().unwrap_or(code).to_string(), message.to_string()))
+        .unwrap_or_else(|| ("error".to_string(), text))
+}
+
+impl From<LockboxError> for Error {
+    fn from(err: LockboxError) -> Self {
+        match &err {
+            LockboxError::FileIo(message, kind) => Error::Other(format!("[lockbox:{}:{}] {}", err.code(), kind, message)),
+            _ => Error::Other(format!("[lockbox:{}] {}", err.code(), err.message())),
+        }
+    }
+}
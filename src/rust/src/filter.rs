@@ -0,0 +1,157 @@
+// Include and exclude patterns for the directory functions
+//
+// Patterns are globs matched against paths relative to the directory, written with
+// forward slashes. As in `.gitignore`, a pattern without a slash matches a name at any
+// depth (`*.tmp`), while one with a slash is matched against the whole relative path
+// (`data/**/*.csv`); a leading slash only anchors (`/notes.txt`). `*` and `?` stay
+// within one path component and `**` crosses any number of them. A trailing slash
+// restricts a pattern to directories (`.git/`).
+//
+// Excluding a directory skips everything below it without reading it. Include
+// patterns select files; directories are always walked, so `data/**/*.csv` finds
+// files at any depth under `data`.
+//
+// Optionally, `.gitignore` and `.lockboxignore` files found along the way are obeyed
+// too, with git's rules: a file's patterns apply to its own directory and below, and
+// deeper files override shallower ones, including with `!` negations. Ignore files
+// above the directory, git's global excludes and `.git/info/exclude` are not read, so
+// the result depends only on the directory itself.
+use extendr_api::prelude::*;
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::{Path, PathBuf};
+
+use crate::error::LockboxError;
+
+/// Files whose patterns are obeyed when walking with `ignore_files`
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".lockboxignore"];
+
+struct Pattern {
+    matcher: GlobMatcher,
+    /// Matched against the whole relative path rather than the last component
+    anchored: bool,
+    directory_only: bool,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> std::result::Result<Self, LockboxError> {
+        let invalid = |why: &str| LockboxError::InvalidArgument(format!("Invalid pattern '{}': {}", pattern, why));
+        let (glob, directory_only) = match pattern.strip_suffix('/') {
+            Some(glob) => (glob, true),
+            None => (pattern, false),
+        };
+        let anchored = glob.contains('/');
+        let glob = glob.strip_prefix('/').unwrap_or(glob);
+        if glob.is_empty() {
+            return Err(invalid("the pattern is empty"));
+        }
+        let matcher = GlobBuilder::new(glob)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| invalid(&e.to_string()))?
+            .compile_matcher();
+        Ok(Pattern { matcher, anchored, directory_only })
+    }
+
+    fn matches(&self, relative: &str, is_dir: bool) -> bool {
+        if self.directory_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.matcher.is_match(relative)
+        } else {
+            self.matcher.is_match(relative.rsplit('/').next().unwrap_or(relative))
+        }
+    }
+}
+
+/// Which paths below a directory a directory function works on
+pub(crate) struct PathFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    /// Whether `.gitignore` and `.lockboxignore` files are obeyed
+    ignore_files: bool,
+}
+
+impl PathFilter {
+    /// Parse the `include` and `exclude` arguments; NULL means no patterns
+    pub(crate) fn new(include: Nullable<Vec<String>>, exclude: Nullable<Vec<String>>, ignore_files: bool) -> Result<Self> {
+        let parse = |patterns: Nullable<Vec<String>>| match patterns {
+            Nullable::NotNull(patterns) => patterns.iter().map(|p| Pattern::parse(p)).collect::<std::result::Result<Vec<_>, _>>(),
+            Nullable::Null => Ok(Vec::new()),
+        };
+        Ok(PathFilter { include: parse(include)?, exclude: parse(exclude)?, ignore_files })
+    }
+
+    fn excludes(&self, relative: &str, is_dir: bool) -> bool {
+        self.exclude.iter().any(|p| p.matches(relative, is_dir))
+    }
+
+    /// Whether a file at `relative` is selected by the include patterns
+    pub(crate) fn selects_file(&self, relative: &str) -> bool {
+        self.include.is_empty() || self.include.iter().any(|p| p.matches(relative, false))
+    }
+}
+
+/// Every path below `root` that `filter` lets through, parents before their contents,
+/// in a stable order
+///
+/// Without include patterns, directories are listed along with their contents; with
+/// them, only the selected files are.
+pub(crate) fn walk_filtered(root: &Path, filter: &PathFilter, paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    walk_below(root, root, filter, &mut Vec::new(), paths)
+}
+
+/// The patterns of the ignore files in `dir`, if it has any
+///
+/// Lines that are not valid patterns are skipped, as git does.
+fn ignore_file_patterns(dir: &Path) -> Option<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+    for name in IGNORE_FILES {
+        let path = dir.join(name);
+        if path.is_file() {
+            let _ = builder.add(path);
+        }
+    }
+    builder.build().ok().filter(|patterns| !patterns.is_empty())
+}
+
+/// Whether the ignore files in effect, deepest last, leave out `path`
+fn is_ignored(ignores: &[Gitignore], path: &Path, is_dir: bool) -> bool {
+    ignores.iter().rev()
+        .map(|patterns| patterns.matched(path, is_dir))
+        .find(|matched| !matched.is_none())
+        .map_or(false, |matched| matched.is_ignore())
+}
+
+fn walk_below(root: &Path, dir: &Path, filter: &PathFilter, ignores: &mut Vec<Gitignore>, paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let patterns = if filter.ignore_files { ignore_file_patterns(dir) } else { None };
+    let has_patterns = patterns.is_some();
+    ignores.extend(patterns);
+
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        // Symbolic links are not followed, so a link to a directory is treated as a file
+        let is_dir = std::fs::symlink_metadata(&path)?.is_dir();
+        let relative = path.strip_prefix(root).unwrap_or(&path).to_string_lossy().replace('\\', "/");
+        if filter.excludes(&relative, is_dir) || is_ignored(ignores, &path, is_dir) {
+            continue;
+        }
+        if is_dir {
+            if filter.include.is_empty() {
+                paths.push(path.clone());
+            }
+            walk_below(root, &path, filter, ignores, paths)?;
+        } else if filter.selects_file(&relative) {
+            paths.push(path);
+        }
+    }
+
+    if has_patterns {
+        ignores.pop();
+    }
+    Ok(())
+}
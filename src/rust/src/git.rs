@@ -0,0 +1,139 @@
+// Git clean and smudge filters
+//
+// With a filter attribute, git passes files through `clean` when staging them and
+// through `smudge` when checking them out, so the repository stores ciphertext while
+// the working tree holds plaintext. Git runs `clean` whenever it needs to compare a
+// working file with the index, so the filter must map the same plaintext to the same
+// bytes every time or every encrypted file would always look modified. Clean output
+// is therefore convergent (see convergent.rs) and ASCII-armored, so that diffs and
+// merges of the stored ciphertext stay textual.
+//
+// Both filters pass through content that is already in the target form: clean leaves
+// age files alone and smudge leaves plaintext alone, which covers files committed
+// before the filter was installed.
+use extendr_api::prelude::*;
+use std::io::Write;
+
+use crate::access::Access;
+use crate::audit;
+use crate::convergent::{convergence_key_from_file, convergent_recipients, plaintext_seed, write_convergent};
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stream::{is_armored, PartialOutput, Progress};
+use crate::{create_output_file, decrypt_content, parse_identities_from_key_file, read_key_file};
+
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1\n";
+
+fn is_age(content: &[u8]) -> bool {
+    is_armored(content) || content.starts_with(AGE_MAGIC)
+}
+
+/// Encrypt content for storage in git
+///
+/// The same content, recipients and key always give the same armored output.
+/// Content that is already age-encrypted is returned unchanged.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_git_clean(content: Raw, recipients: Vec<String>, private_key_path: &str, strict_permissions: bool) -> Result<Raw> {
+    let content = content.as_slice();
+    if is_age(content) {
+        return Ok(Raw::from_bytes(content));
+    }
+
+    let keys = recipients.clone();
+    let result = (|| {
+        let recipients = convergent_recipients(&recipients)?;
+        if recipients.is_empty() {
+            return Err(LockboxError::InvalidRecipient("The git filter has no recipients to encrypt to".to_string()).into());
+        }
+        let key = convergence_key_from_file(private_key_path, strict_permissions)?;
+        let seed = plaintext_seed(&mut &content[..], &key)?;
+        write_convergent(&mut &content[..], Vec::new(), &recipients, &seed, true)
+    })();
+    let output = audit::record("encrypt", "key", result, |output| {
+        audit::Details::raw(output.map(Vec::as_slice), None, audit::recipient_keys(keys))
+    })?;
+    Ok(Raw::from_bytes(&output))
+}
+
+/// Decrypt content stored in git
+///
+/// Content that is not age-encrypted is returned unchanged.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_git_smudge(content: Raw, private_key_path: &str, strict_permissions: bool) -> Result<Raw> {
+    let content = content.as_slice();
+    if !is_age(content) {
+        return Ok(Raw::from_bytes(content));
+    }
+
+    let result = (|| {
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+        let decrypted = decrypt_content(content, identities.iter().map(|i| i.as_ref()), &mut Progress::none())?;
+        Ok(Raw::from_bytes(&decrypted))
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::raw(Some(content), None, audit::key_file_public_keys(private_key_path))
+    })
+}
+
+/// Read filter input from a file
+///
+/// A file of `encrypted` content, as smudge reads, must be allowed by the policy's
+/// decryption directories.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_read_filter_input(input: &str, encrypted: bool) -> Result<Raw> {
+    let path = fs_path(input);
+    if encrypted {
+        policy::check_decrypt_from(&path)?;
+    }
+    let content = std::fs::read(&path)
+        .map_err(|e| LockboxError::io(format!("Failed to read '{}'", input), &e))?;
+    Ok(Raw::from_bytes(&content))
+}
+
+/// Write filter output to a file, replacing it, or to standard output if NULL
+///
+/// R can only write text to `stdout()`, but filter output may be binary. A file of
+/// `plaintext`, as smudge writes, must be allowed by the policy's plaintext
+/// directories and is created readable by its owner only.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_write_filter_output(content: Raw, output: Nullable<String>, plaintext: bool) -> Result<()> {
+    let output = match output {
+        Nullable::NotNull(output) => output,
+        Nullable::Null => {
+            let mut stdout = std::io::stdout().lock();
+            return stdout.write_all(content.as_slice())
+                .and_then(|_| stdout.flush())
+                .map_err(|e| LockboxError::io("Failed to write to standard output", &e).into());
+        }
+    };
+    let access = if plaintext {
+        policy::check_plaintext_to(&fs_path(&output))?;
+        Access::OWNER_ONLY
+    } else {
+        Access::default()
+    };
+    let mut file = create_output_file(&output, true, access)?;
+    let partial = PartialOutput::new(&output);
+    file.write_all(content.as_slice())
+        .map_err(|e| LockboxError::io(format!("Failed to write '{}'", output), &e))?;
+    partial.complete();
+    Ok(())
+}
+
+extendr_module! {
+    mod git;
+    fn age_git_clean;
+    fn age_git_smudge;
+    fn age_read_filter_input;
+    fn age_write_filter_output;
+}
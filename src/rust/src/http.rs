@@ -0,0 +1,346 @@
+// Encrypted payloads over HTTP(S)
+//
+// Downloads are decrypted as they arrive and uploads are encrypted as they are sent,
+// so neither the plaintext nor a second copy of the ciphertext is staged on disk.
+//
+// Uploads run the HTTP request on a worker thread, which reads ciphertext from a
+// bounded channel that the main thread fills. Encryption stays on the main thread,
+// where interrupts can be checked; the worker never calls into R.
+use extendr_api::prelude::*;
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+use crate::access::Access;
+use crate::audit;
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stats::{self, Direction};
+use crate::stream::{age_input, copy_interruptible, PartialOutput, Progress, CHUNK_SIZE};
+use crate::{create_output_file, parse_identities_from_key_file, parse_recipients, read_key_file};
+
+/// Number of ciphertext chunks buffered between encryption and upload
+const UPLOAD_QUEUE: usize = 16;
+/// Size of a plaintext chunk in the age payload
+const PAYLOAD_CHUNK_SIZE: u64 = 64 * 1024;
+/// Size of the Poly1305 tag added to every payload chunk
+const TAG_SIZE: u64 = 16;
+
+pub(crate) fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(30))
+        .timeout_read(Duration::from_secs(300))
+        .timeout_write(Duration::from_secs(300))
+        .build()
+}
+
+/// Attach user-supplied headers, given as parallel vectors of names and values
+fn with_headers(mut request: ureq::Request, names: &[String], values: &[String]) -> Result<ureq::Request> {
+    if names.len() != values.len() {
+        return Err(LockboxError::InvalidArgument("Every header needs a name and a value".to_string()).into());
+    }
+    for (name, value) in names.iter().zip(values) {
+        request = request.set(name, value);
+    }
+    Ok(request)
+}
+
+/// Describe a failed request without echoing the URL, which may carry a signed token
+pub(crate) fn http_error(action: &str, err: ureq::Error) -> LockboxError {
+    match err {
+        // Only sent for a conditional PUT (`If-None-Match: *`) onto an existing object
+        ureq::Error::Status(412, _) if action == "upload" => LockboxError::FileExists(
+            "The upload destination already exists; use `overwrite = TRUE` to replace it".to_string(),
+        ),
+        ureq::Error::Status(code, response) => LockboxError::Network(format!(
+            "Failed to {}: the server responded with HTTP {} {}",
+            action,
+            code,
+            response.status_text()
+        )),
+        ureq::Error::Transport(transport) => {
+            let detail = transport.message()
+                .map(str::to_string)
+                .or_else(|| std::error::Error::source(&transport).map(|e| e.to_string()));
+            match detail {
+                Some(detail) => LockboxError::Network(format!("Failed to {}: {} ({})", action, transport.kind(), detail)),
+                None => LockboxError::Network(format!("Failed to {}: {}", action, transport.kind())),
+            }
+        }
+    }
+}
+
+/// The path of an optional output file
+pub(crate) fn output_path(output_file_path: &Nullable<String>) -> Option<String> {
+    match output_file_path {
+        Nullable::NotNull(path) => Some(path.clone()),
+        Nullable::Null => None,
+    }
+}
+
+/// Send a GET request and decrypt the response body as it arrives
+///
+/// With `output_file_path`, the plaintext is written to a file created with
+/// `access`, which the policy must allow, and an empty raw vector is returned;
+/// otherwise the plaintext is returned.
+pub(crate) fn download_decrypt<'a, I>(
+    request: ureq::Request,
+    identities: I,
+    output_file_path: Nullable<String>,
+    overwrite: bool,
+    access: Access,
+    progress: Nullable<Function>,
+) -> Result<Raw>
+where
+    I: Iterator<Item = &'a dyn age::Identity>,
+{
+    if let Nullable::NotNull(path) = &output_file_path {
+        policy::check_plaintext_to(&fs_path(path))?;
+    }
+    let response = request.call().map_err(|e| http_error("download", e))?;
+    let total = response.header("Content-Length").and_then(|n| n.parse().ok()).unwrap_or(0);
+    let mut progress = Progress::new(progress, total);
+    let body = progress.track(response.into_reader());
+
+    let decryptor = age::Decryptor::new(age_input(BufReader::new(body))?)
+        .map_err(|e| LockboxError::from_decrypt(e, false))?;
+    let scrypt = decryptor.is_scrypt();
+    let mut decrypted_reader = decryptor.decrypt(identities)
+        .map_err(|e| LockboxError::from_decrypt(e, scrypt))?;
+
+    match output_file_path {
+        Nullable::NotNull(path) => {
+            let output_file = create_output_file(&path, overwrite, access)?;
+            let partial = PartialOutput::new(&path);
+            let mut output = BufWriter::new(output_file);
+            let bytes = copy_interruptible(&mut decrypted_reader, &mut output, &mut progress, LockboxError::from_payload)?;
+            stats::add_bytes(Direction::Decrypt, bytes);
+            output.flush()
+                .map_err(|e| LockboxError::io("Failed to flush output", &e))?;
+            partial.complete();
+            Ok(Raw::from_bytes(&[]))
+        }
+        Nullable::Null => {
+            let mut decrypted = Zeroizing::new(Vec::new());
+            let bytes = copy_interruptible(&mut decrypted_reader, &mut *decrypted, &mut progress, LockboxError::from_payload)?;
+            stats::add_bytes(Direction::Decrypt, bytes);
+            Ok(Raw::from_bytes(&decrypted))
+        }
+    }
+}
+
+/// Download and decrypt an age file
+///
+/// The response body is decrypted as it arrives. With `output_file_path`, the
+/// plaintext is written there and an empty raw vector is returned; otherwise the
+/// plaintext is returned.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_decrypt_url(
+    url: &str,
+    header_names: Vec<String>,
+    header_values: Vec<String>,
+    private_key_path: &str,
+    strict_permissions: bool,
+    output_file_path: Nullable<String>,
+    overwrite: bool,
+) -> Result<Raw> {
+    let output = output_path(&output_file_path);
+    let result = (|| {
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+        let request = with_headers(agent().get(url), &header_names, &header_values)?;
+        download_decrypt(request, identities.iter().map(|i| i.as_ref()), output_file_path, overwrite, Access::OWNER_ONLY, Nullable::Null)
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::download(url, output.as_deref(), audit::key_file_public_keys(private_key_path))
+    })
+}
+
+/// Writer half of the upload channel; counts the bytes it accepts
+///
+/// Bytes are sent in chunks of `CHUNK_SIZE`, so the many small writes of the age
+/// header fit in the channel before the upload has started reading.
+struct ChannelWriter {
+    sender: SyncSender<Vec<u8>>,
+    pending: Vec<u8>,
+    written: Rc<Cell<u64>>,
+    /// Set when the upload stopped reading, so its error is the one to report
+    closed: Rc<Cell<bool>>,
+}
+
+impl ChannelWriter {
+    fn send(&mut self, chunk: Vec<u8>) -> std::io::Result<()> {
+        self.sender.send(chunk).map_err(|_| {
+            self.closed.set(true);
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the upload stopped")
+        })
+    }
+
+    /// Send what is left and tell the upload that the body is complete
+    fn finish(mut self) -> std::io::Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        if !pending.is_empty() {
+            self.send(pending)?;
+        }
+        self.send(Vec::new())
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        self.written.set(self.written.get() + buf.len() as u64);
+        if self.pending.len() >= CHUNK_SIZE {
+            let chunk = std::mem::replace(&mut self.pending, Vec::with_capacity(CHUNK_SIZE));
+            self.send(chunk)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reader half of the upload channel, used as the request body
+///
+/// An empty chunk marks the end of the body. If the sender goes away without one,
+/// encryption failed, and reading fails so that the request is aborted rather than
+/// sent short.
+struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            let chunk = self.receiver.recv()
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "encryption stopped"))?;
+            self.finished = chunk.is_empty();
+            self.chunk = chunk;
+            self.position = 0;
+        }
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Size of the age payload for `plaintext_size` bytes: every chunk gains a tag, and
+/// even an empty plaintext has one (empty) chunk
+fn payload_size(plaintext_size: u64) -> u64 {
+    let chunks = ((plaintext_size + PAYLOAD_CHUNK_SIZE - 1) / PAYLOAD_CHUNK_SIZE).max(1);
+    plaintext_size + chunks * TAG_SIZE
+}
+
+/// Encrypt a file into the body of a request, returning the HTTP status code
+///
+/// The ciphertext (binary age) is streamed with an exact Content-Length, which
+/// object stores and presigned URLs generally require.
+pub(crate) fn encrypt_upload(
+    encryptor: age::Encryptor,
+    input_file_path: &str,
+    request: ureq::Request,
+    progress: Nullable<Function>,
+) -> Result<i32> {
+    let input_file = File::open(fs_path(input_file_path))
+        .map_err(|e| LockboxError::io("Failed to read input file", &e))?;
+    let input_size = input_file.metadata()
+        .map_err(|e| LockboxError::io("Failed to read input file", &e))?
+        .len();
+    let mut progress = Progress::new(progress, input_size);
+    let mut input = progress.track(input_file);
+
+    let (sender, receiver) = sync_channel(UPLOAD_QUEUE);
+    let written = Rc::new(Cell::new(0));
+    let closed = Rc::new(Cell::new(false));
+    let channel_writer = ChannelWriter {
+        sender,
+        pending: Vec::with_capacity(CHUNK_SIZE),
+        written: Rc::clone(&written),
+        closed: Rc::clone(&closed),
+    };
+
+    // The header and nonce are written as soon as the output is wrapped, so what has
+    // gone through the channel by then plus the payload is the whole request body
+    let mut encrypted_writer = encryptor.wrap_output(channel_writer)
+        .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+    let content_length = written.get() + payload_size(input_size);
+
+    let upload = std::thread::spawn(move || {
+        let body = ChannelReader { receiver, chunk: Vec::new(), position: 0, finished: false };
+        request
+            .set("Content-Length", &content_length.to_string())
+            .set("Content-Type", "application/octet-stream")
+            .send(body)
+            .map(|response| response.status())
+            .map_err(|e| http_error("upload", e))
+    });
+
+    // On failure the writer is dropped without finishing, which aborts the upload
+    let encrypted = copy_interruptible(&mut input, &mut encrypted_writer, &mut progress, |e| {
+        LockboxError::io("Failed to read input file", &e)
+    })
+    .and_then(|bytes| {
+        encrypted_writer.finish()
+            .and_then(ChannelWriter::finish)
+            .map(|_| bytes)
+            .map_err(|e| LockboxError::io("Failed to finalize encryption", &e).into())
+    });
+    let uploaded = upload.join()
+        .map_err(|_| LockboxError::Network("Failed to upload: the upload thread panicked".to_string()))?;
+
+    match (encrypted, uploaded) {
+        (Ok(bytes), Ok(status)) => {
+            stats::add_bytes(Direction::Encrypt, bytes);
+            Ok(status as i32)
+        }
+        (Err(e), _) if !closed.get() => Err(e),
+        (_, Err(e)) => Err(e.into()),
+        (Err(e), Ok(_)) => Err(e),
+    }
+}
+
+/// Encrypt a file and upload it with an HTTP PUT
+///
+/// Returns the HTTP status code.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_upload(
+    input_file_path: &str,
+    url: &str,
+    recipients: Vec<String>,
+    header_names: Vec<String>,
+    header_values: Vec<String>,
+) -> Result<i32> {
+    let keys = recipients.clone();
+    let result = (|| {
+        let parsed_recipients = parse_recipients(recipients)?;
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+        let request = with_headers(agent().put(url), &header_names, &header_values)?;
+        encrypt_upload(encryptor, input_file_path, request, Nullable::Null)
+    })();
+    audit::record("encrypt", "key", result, |_| audit::Details::upload(input_file_path, url, audit::recipient_keys(keys)))
+}
+
+extendr_module! {
+    mod http;
+    fn age_decrypt_url;
+    fn age_encrypt_upload;
+}
@@ -0,0 +1,187 @@
+// Hybrid encryption: recipient keys plus a break-glass passphrase
+//
+// age refuses to mix a scrypt (passphrase) recipient with other recipients in one
+// file. Instead, the payload is encrypted to the recipients plus a freshly generated
+// escrow key, and that escrow key is written to a second, passphrase-encrypted file.
+// Recipients decrypt the main file like any other age file; with the passphrase, the
+// escrow file yields a key that decrypts it as well.
+use extendr_api::prelude::*;
+use age::secrecy::ExposeSecret;
+use std::io::Write;
+use std::iter;
+use zeroize::Zeroizing;
+
+use crate::access::Access;
+use crate::audit;
+use crate::error::LockboxError;
+use crate::http::output_path;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::source::passphrase_from;
+use crate::stream::{check_age_file, decrypt_to_file, temp_path_for, PartialOutput, Progress};
+use crate::{
+    create_output_file, decrypt_content, encrypt_file, parse_identities_from_key_file, parse_recipients,
+    scrypt_identity, scrypt_recipient,
+};
+
+/// An escrow file written next to its destination, until the main output is in place
+struct PendingEscrow {
+    path: String,
+    temp_path: String,
+    temp: PartialOutput,
+    /// The empty file holding the name of a new escrow file
+    placeholder: Option<PartialOutput>,
+}
+
+impl PendingEscrow {
+    /// Move the escrow file into place, replacing any previous one
+    fn commit(self) -> Result<()> {
+        std::fs::rename(fs_path(&self.temp_path), fs_path(&self.path))
+            .map_err(|e| LockboxError::io(format!("Failed to write escrow file '{}'", self.path), &e))?;
+        self.temp.complete();
+        if let Some(placeholder) = self.placeholder {
+            placeholder.complete();
+        }
+        Ok(())
+    }
+}
+
+/// Write an escrow key to an armored, passphrase-encrypted file
+///
+/// The key goes to a temporary file, which `PendingEscrow::commit()` moves into place
+/// once the main output is written; if that fails, an existing escrow file is left as
+/// it was, still opening the file it was made for. Without `overwrite`, the name is
+/// claimed at once, so that a clash fails before anything is encrypted.
+fn write_escrow(escrow_file_path: &str, escrow_key: &str, passphrase: &str, work_factor: Nullable<i32>, overwrite: bool) -> Result<PendingEscrow> {
+    use age::armor::{ArmoredWriter, Format};
+
+    let recipient = scrypt_recipient(passphrase, work_factor)?;
+    let encryptor = policy::encryptor(iter::once(&recipient as _))?;
+
+    let placeholder = if overwrite {
+        None
+    } else {
+        create_output_file(escrow_file_path, false, Access::OWNER_ONLY)?;
+        Some(PartialOutput::new(escrow_file_path))
+    };
+    let temp_path = temp_path_for(escrow_file_path);
+    let escrow_file = create_output_file(&temp_path, false, Access::OWNER_ONLY)?;
+    let temp = PartialOutput::new(&temp_path);
+
+    let armored_writer = ArmoredWriter::wrap_output(escrow_file, Format::AsciiArmor)
+        .map_err(|e| LockboxError::Armor(format!("Failed to create armored writer: {}", e)))?;
+    let mut encrypted_writer = encryptor.wrap_output(armored_writer)
+        .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+
+    encrypted_writer.write_all(escrow_key.as_bytes())
+        .map_err(|e| LockboxError::io("Failed to write escrow file", &e))?;
+    encrypted_writer.finish()
+        .and_then(|armored_writer| armored_writer.finish())
+        .map_err(|e| LockboxError::io("Failed to finalize escrow file", &e))?;
+
+    Ok(PendingEscrow { path: escrow_file_path.to_string(), temp_path, temp, placeholder })
+}
+
+/// Encrypt a file to public keys plus a passphrase-protected escrow key
+///
+/// Writes the encrypted file to `output_file_path`, readable with any of the
+/// recipients' private keys, and the escrow key to `escrow_file_path`, encrypted with
+/// `passphrase`. Both files are needed to decrypt with the passphrase.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_encrypt_hybrid(
+    input_file_path: &str,
+    output_file_path: &str,
+    escrow_file_path: &str,
+    recipients: Vec<String>,
+    passphrase: &str,
+    passphrase_source: Nullable<String>,
+    armor: bool,
+    work_factor: Nullable<i32>,
+    overwrite: bool,
+) -> Result<()> {
+    let keys = recipients.clone();
+    let result = (|| {
+        // The escrow file is encrypted with the passphrase alone
+        policy::check_passphrase_encryption()?;
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+        let mut parsed_recipients = parse_recipients(recipients)?;
+
+        // The escrow key only ever protects this one file
+        let escrow_identity = age::x25519::Identity::generate();
+        parsed_recipients.push(Box::new(escrow_identity.to_public()));
+
+        let escrow_key = Zeroizing::new(format!(
+            "# lockbox escrow key\n# created: {}\n# public key: {}\n{}\n",
+            chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC"),
+            escrow_identity.to_public(),
+            escrow_identity.to_string().expose_secret()
+        ));
+        let escrow = write_escrow(escrow_file_path, &escrow_key, &passphrase, work_factor, overwrite)?;
+
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+        encrypt_file(encryptor, input_file_path, output_file_path, armor, overwrite, Access::default(), false, false, Nullable::Null)?;
+
+        escrow.commit()
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(Some(input_file_path), output_file_path, done.is_some(), audit::recipient_keys(keys))
+    })
+}
+
+/// Decrypt a hybrid-encrypted file with the escrow passphrase
+///
+/// Unlocks the escrow key in `escrow_file_path` with `passphrase`, then uses it to
+/// decrypt `encrypted_file_path`, into `output_file_path`, which is created readable
+/// by its owner only, or if NULL into memory. Returns the decrypted content as raw
+/// bytes, empty when written to a file.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_escrow(
+    encrypted_file_path: &str,
+    escrow_file_path: &str,
+    output_file_path: Nullable<String>,
+    passphrase: &str,
+    passphrase_source: Nullable<String>,
+    max_work_factor: Nullable<i32>,
+) -> Result<Raw> {
+    let output_file_path = output_path(&output_file_path);
+    let result = (|| {
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+        let escrow_path = fs_path(escrow_file_path);
+        policy::check_decrypt_from(&escrow_path)?;
+        let escrow_content = std::fs::read(&escrow_path)
+            .map_err(|e| LockboxError::io("Failed to read escrow file", &e))?;
+
+        let identity = scrypt_identity(&passphrase, max_work_factor)?;
+        let escrow_key = decrypt_content(&escrow_content, iter::once(&identity as _), &mut Progress::none())?;
+        let escrow_key = std::str::from_utf8(&escrow_key)
+            .map_err(|_| LockboxError::InvalidIdentity("The escrow file does not contain an age key".to_string()))?;
+        let identities = parse_identities_from_key_file(escrow_key)?;
+
+        let identities = identities.iter().map(|i| i.as_ref());
+        if let Some(output_file_path) = &output_file_path {
+            decrypt_to_file(&fs_path(encrypted_file_path), identities, output_file_path, false, Access::OWNER_ONLY, &mut Progress::none())?;
+            return Ok(Raw::from_bytes(&[]));
+        }
+
+        policy::check_decrypt_from(&fs_path(encrypted_file_path))?;
+        check_age_file(&fs_path(encrypted_file_path))?;
+        let file_content = std::fs::read(fs_path(encrypted_file_path))
+            .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", encrypted_file_path), &e))?;
+        let decrypted_bytes = decrypt_content(&file_content, identities, &mut Progress::none())?;
+        Ok(Raw::from_bytes(&decrypted_bytes))
+    })();
+    audit::record("decrypt", "passphrase", result, |_| {
+        audit::Details::file(encrypted_file_path, output_file_path.as_deref(), Vec::new())
+    })
+}
+
+extendr_module! {
+    mod hybrid;
+    fn age_encrypt_hybrid;
+    fn age_decrypt_escrow;
+}
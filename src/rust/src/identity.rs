@@ -0,0 +1,341 @@
+// Loaded identities and recipients
+//
+// Decrypting reads and parses the private key file on every call, which dominates
+// the cost of decrypting many small strings. A loaded identity keeps the parsed keys
+// in an external pointer owned by R, so a loop can pay for that once. The keys live
+// until R garbage-collects the handle; x25519 keys are wiped when they are dropped.
+//
+// Loaded recipients do the same for public keys, whose bech32 decoding is repeated
+// for every string encrypted otherwise.
+use extendr_api::prelude::*;
+
+use crate::access::Access;
+use crate::audit;
+use crate::bundle::{pack, unpack};
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::raw::encrypt_raw;
+use crate::stream::{check_age_file, decrypt_reader_to_file, decrypt_to_file, Progress};
+use crate::{
+    decrypt_content, decrypted_string, encrypt_bytes, encrypt_file, encrypt_file_to_raw, encrypt_reader_to_file,
+    encrypt_string, encrypted_string_bytes, parse_identities_from_key_file, parse_x25519_recipients,
+    read_key_file,
+};
+
+pub(crate) struct IdentityHandle {
+    path: String,
+    identities: Vec<Box<dyn age::Identity>>,
+    /// Public keys of the x25519 keys, for the audit log
+    public: Vec<String>,
+}
+
+impl IdentityHandle {
+    fn identities(&self) -> impl Iterator<Item = &dyn age::Identity> {
+        self.identities.iter().map(|i| i.as_ref())
+    }
+}
+
+// Never shows the keys
+impl std::fmt::Debug for IdentityHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "IdentityHandle({}, {} keys)", self.path, self.identities.len())
+    }
+}
+
+/// Read and parse a private key file once, returning a handle to the keys
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_load_identity(private_key_path: &str, strict_permissions: bool) -> Result<ExternalPtr<IdentityHandle>> {
+    let key_content = read_key_file(private_key_path, strict_permissions)?;
+    let identities = parse_identities_from_key_file(&key_content)?;
+    let public = audit::identity_public_keys(&key_content);
+    Ok(ExternalPtr::new(IdentityHandle { path: private_key_path.to_string(), identities, public }))
+}
+
+/// Path of the key file a handle was loaded from
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_identity_path(handle: ExternalPtr<IdentityHandle>) -> String {
+    handle.path.clone()
+}
+
+/// Number of keys in a handle
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_identity_count(handle: ExternalPtr<IdentityHandle>) -> i32 {
+    handle.identities.len() as i32
+}
+
+/// Decrypt an encrypted string with a loaded identity
+///
+/// `lenient` looks for an armored block anywhere in the string.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_string_with_identity(encrypted_string: &str, handle: ExternalPtr<IdentityHandle>, lenient: bool) -> Result<Robj> {
+    let result = (|| {
+        let encrypted_bytes = encrypted_string_bytes(encrypted_string, lenient)?;
+        let decrypted_bytes = decrypt_content(&encrypted_bytes, handle.identities(), &mut Progress::none())?;
+        decrypted_string(&decrypted_bytes)
+    })();
+    audit::record("decrypt", "key", result, |_| audit::Details::string(Some(encrypted_string), handle.public.clone()))
+}
+
+/// Decrypt a bundle with a loaded identity
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_bundle_with_identity(encrypted_string: &str, handle: ExternalPtr<IdentityHandle>) -> Result<Strings> {
+    let result = (|| {
+        let encrypted_bytes = encrypted_string_bytes(encrypted_string, false)?;
+        let packed = decrypt_content(&encrypted_bytes, handle.identities(), &mut Progress::none())?;
+        Ok(unpack(&packed)?)
+    })();
+    audit::record("decrypt", "key", result, |_| audit::Details::string(Some(encrypted_string), handle.public.clone()))
+}
+
+/// Decrypt a raw vector with a loaded identity
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_raw_with_identity(input: Raw, handle: ExternalPtr<IdentityHandle>) -> Result<Raw> {
+    let result = decrypt_content(input.as_slice(), handle.identities(), &mut Progress::none())
+        .map(|decrypted| Raw::from_bytes(&decrypted));
+    audit::record("decrypt", "key", result, |_| audit::Details::raw(Some(input.as_slice()), None, handle.public.clone()))
+}
+
+/// Decrypt a raw vector with a loaded identity straight into an output file
+///
+/// See `age_decrypt_file_with_key()` for `into_directory` and the value returned.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_raw_to_file_with_identity(
+    input: Raw,
+    output_file_path: &str,
+    into_directory: bool,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+    handle: ExternalPtr<IdentityHandle>,
+) -> Result<Raw> {
+    let result = Access::from_r(mode, owner, group)
+        .and_then(|access| decrypt_reader_to_file(input.as_slice(), handle.identities(), output_file_path, into_directory, access, &mut Progress::none()))
+        .map(|metadata| Raw::from_bytes(&metadata));
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::raw(Some(input.as_slice()), Some(output_file_path), handle.public.clone())
+    })
+}
+
+/// Decrypt an age-encrypted file with a loaded identity
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_with_identity(encrypted_file_path: &str, handle: ExternalPtr<IdentityHandle>, progress: Nullable<Function>) -> Result<Raw> {
+    let result = (|| {
+        policy::check_decrypt_from(&fs_path(encrypted_file_path))?;
+        check_age_file(&fs_path(encrypted_file_path))?;
+        let file_content = std::fs::read(fs_path(encrypted_file_path))
+            .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", encrypted_file_path), &e))?;
+        let mut progress = Progress::new(progress, file_content.len() as u64);
+        let decrypted_bytes = decrypt_content(&file_content, handle.identities(), &mut progress)?;
+        Ok(Raw::from_bytes(&decrypted_bytes))
+    })();
+    audit::record("decrypt", "key", result, |_| audit::Details::file(encrypted_file_path, None, handle.public.clone()))
+}
+
+/// Decrypt a file with a loaded identity straight into an output file
+///
+/// See `age_decrypt_file_with_key()` for `into_directory` and the value returned.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_decrypt_file_with_identity(
+    encrypted_file_path: &str,
+    output_file_path: &str,
+    into_directory: bool,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+    handle: ExternalPtr<IdentityHandle>,
+    progress: Nullable<Function>,
+) -> Result<Raw> {
+    let result = (|| {
+        let access = Access::from_r(mode, owner, group)?;
+        let input = fs_path(encrypted_file_path);
+        let size = std::fs::metadata(&input).map(|m| m.len()).unwrap_or(0);
+        let mut progress = Progress::new(progress, size);
+        let metadata = decrypt_to_file(&input, handle.identities(), output_file_path, into_directory, access, &mut progress)?;
+        Ok(Raw::from_bytes(&metadata))
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::file(encrypted_file_path, Some(output_file_path), handle.public.clone())
+    })
+}
+
+pub(crate) struct RecipientsHandle {
+    recipients: Vec<age::x25519::Recipient>,
+}
+
+impl RecipientsHandle {
+    fn encryptor(&self) -> Result<age::Encryptor> {
+        policy::encryptor(self.recipients.iter().map(|r| r as &dyn age::Recipient)).map_err(Error::from)
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.recipients.iter().map(|r| r.to_string()).collect()
+    }
+}
+
+impl std::fmt::Debug for RecipientsHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RecipientsHandle({} keys)", self.recipients.len())
+    }
+}
+
+/// Parse public keys once, returning a handle to the recipients
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_load_recipients(recipients: Vec<String>) -> Result<ExternalPtr<RecipientsHandle>> {
+    let recipients = parse_x25519_recipients(&recipients)?;
+    Ok(ExternalPtr::new(RecipientsHandle { recipients }))
+}
+
+/// Public keys in a recipients handle
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_recipients_keys(handle: ExternalPtr<RecipientsHandle>) -> Vec<String> {
+    handle.keys()
+}
+
+/// Encrypt a string to loaded recipients
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_string_with_recipients(input_string: &str, handle: ExternalPtr<RecipientsHandle>, encoding: &str) -> Result<String> {
+    let result = handle.encryptor().and_then(|encryptor| encrypt_string(encryptor, input_string, encoding));
+    audit::record("encrypt", "key", result, |encrypted| {
+        audit::Details::string(encrypted.map(String::as_str), handle.keys())
+    })
+}
+
+/// Encrypt a character vector as one bundle to loaded recipients
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_bundle_with_recipients(values: Strings, handle: ExternalPtr<RecipientsHandle>, encoding: &str) -> Result<String> {
+    let result = handle.encryptor().and_then(|encryptor| encrypt_bytes(encryptor, &pack(&values), encoding));
+    audit::record("encrypt", "key", result, |encrypted| {
+        audit::Details::string(encrypted.map(String::as_str), handle.keys())
+    })
+}
+
+/// Encrypt a raw vector to loaded recipients
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_raw_with_recipients(input: Raw, handle: ExternalPtr<RecipientsHandle>, armor: bool) -> Result<Raw> {
+    let result = handle.encryptor().and_then(|encryptor| encrypt_raw(encryptor, input.as_slice(), armor));
+    audit::record("encrypt", "key", result, |encrypted| {
+        audit::Details::raw(encrypted.map(Raw::as_slice), None, handle.keys())
+    })
+}
+
+/// Encrypt a file to loaded recipients
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_encrypt_with_recipients(
+    input_file_path: &str,
+    output_file_path: &str,
+    handle: ExternalPtr<RecipientsHandle>,
+    armor: bool,
+    overwrite: bool,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+    mmap: bool,
+    metadata: bool,
+    progress: Nullable<Function>,
+) -> Result<()> {
+    let result = Access::from_r(mode, owner, group).and_then(|access| {
+        let encryptor = handle.encryptor()?;
+        encrypt_file(encryptor, input_file_path, output_file_path, armor, overwrite, access, mmap, metadata, progress)
+    });
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(Some(input_file_path), output_file_path, done.is_some(), handle.keys())
+    })
+}
+
+/// Encrypt a raw vector to loaded recipients straight into a new output file
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_encrypt_raw_to_file_with_recipients(
+    input: Raw,
+    output_file_path: &str,
+    handle: ExternalPtr<RecipientsHandle>,
+    armor: bool,
+    overwrite: bool,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+) -> Result<()> {
+    let result = Access::from_r(mode, owner, group).and_then(|access| {
+        let encryptor = handle.encryptor()?;
+        encrypt_reader_to_file(encryptor, &mut input.as_slice(), output_file_path, armor, overwrite, access, None, &mut Progress::none())
+    });
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(None, output_file_path, done.is_some(), handle.keys())
+    })
+}
+
+/// Encrypt a file to loaded recipients into a raw vector
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_file_to_raw_with_recipients(
+    input_file_path: &str,
+    handle: ExternalPtr<RecipientsHandle>,
+    armor: bool,
+    mmap: bool,
+    metadata: bool,
+    progress: Nullable<Function>,
+) -> Result<Raw> {
+    let result = handle.encryptor()
+        .and_then(|encryptor| encrypt_file_to_raw(encryptor, input_file_path, armor, mmap, metadata, progress));
+    audit::record("encrypt", "key", result, |encrypted| audit::Details {
+        input: Some(input_file_path.to_string()),
+        ..audit::Details::raw(encrypted.map(Raw::as_slice), None, handle.keys())
+    })
+}
+
+extendr_module! {
+    mod identity;
+    fn age_load_identity;
+    fn age_identity_path;
+    fn age_identity_count;
+    fn age_decrypt_string_with_identity;
+    fn age_decrypt_bundle_with_identity;
+    fn age_decrypt_raw_with_identity;
+    fn age_decrypt_raw_to_file_with_identity;
+    fn age_decrypt_with_identity;
+    fn age_decrypt_file_with_identity;
+    fn age_load_recipients;
+    fn age_recipients_keys;
+    fn age_encrypt_string_with_recipients;
+    fn age_encrypt_bundle_with_recipients;
+    fn age_encrypt_raw_with_recipients;
+    fn age_encrypt_with_recipients;
+    fn age_encrypt_raw_to_file_with_recipients;
+    fn age_encrypt_file_to_raw_with_recipients;
+}
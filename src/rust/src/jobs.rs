@@ -0,0 +1,386 @@
+// Background encryption and decryption jobs
+//
+// Encrypting a 30 GB file takes minutes, and R is single-threaded: a Shiny app would
+// stop answering, an interactive session would block. A job runs the file pipeline on
+// a Rust thread instead and returns at once with an id, which R uses to ask how far
+// the job got or to wait for it.
+//
+// Workers never call into R, so everything that needs R or may fail for a reason the
+// user should see at once (parsing keys, the policy, creating the output file) is
+// done before the thread starts. The worker only streams bytes, counting them for
+// `age_job_status()`, and stores its outcome for `age_job_wait()`. Only x25519 keys
+// are supported: plugin identities and recipients cannot be moved to another thread.
+//
+// A job is cancelled through a flag that the worker checks between chunks; it then
+// fails like any other job, which removes its partial output.
+//
+// The registry is only locked on R's main thread. A process forked from R (by
+// parallel::mclapply() or future's multicore plan) inherits the registry but not the
+// worker threads, and a worker may have held its job's lock at the moment of the fork.
+// Jobs therefore remember the process that started them, and other processes are told
+// the job is not theirs before anything is locked.
+use extendr_api::prelude::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+use crate::access::Access;
+use crate::audit;
+use crate::buffers::{read_buffer_size, write_buffer_size};
+use crate::error::{error_parts, LockboxError};
+use crate::metadata::{metadata_block, metadata_length};
+use crate::paths::fs_path;
+use crate::stats::{self, Direction};
+use crate::stream::{age_input, check_age_file, check_interrupt, read_head, PartialOutput, CHUNK_SIZE};
+use crate::{create_output_file, parse_x25519_identities, parse_x25519_recipients, policy, read_key_file};
+
+/// How often `age_job_wait()` checks for interrupts while the worker runs
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+type Outcome = std::result::Result<Vec<u8>, LockboxError>;
+
+/// A job, shared by the registry and its worker thread
+struct Job {
+    id: i32,
+    /// The process that started the job, the only one running its thread
+    pid: u32,
+    operation: &'static str,
+    total: u64,
+    /// Bytes of the input read so far
+    processed: Arc<AtomicU64>,
+    /// Set to ask the worker to stop
+    cancelled: AtomicBool,
+    /// None while the worker runs; the metadata block of a decryption, or empty, once done
+    outcome: Mutex<Option<Outcome>>,
+}
+
+impl Job {
+    fn outcome(&self) -> MutexGuard<'_, Option<Outcome>> {
+        // A worker that panicked while holding the lock left no outcome to protect
+        self.outcome.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn state(&self) -> &'static str {
+        match &*self.outcome() {
+            None => "running",
+            Some(Ok(_)) => "done",
+            Some(Err(LockboxError::Interrupted(_))) => "cancelled",
+            Some(Err(_)) => "failed",
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.outcome().is_some()
+    }
+}
+
+static NEXT_ID: AtomicI32 = AtomicI32::new(1);
+static JOBS: Mutex<Vec<Arc<Job>>> = Mutex::new(Vec::new());
+
+fn jobs() -> MutexGuard<'static, Vec<Arc<Job>>> {
+    JOBS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn find(id: i32) -> Result<Arc<Job>> {
+    let job = jobs().iter()
+        .find(|job| job.id == id)
+        .cloned()
+        .ok_or_else(|| LockboxError::NotFound(format!("There is no job {} in this session", id)))?;
+    if job.pid != std::process::id() {
+        return Err(LockboxError::NotFound(format!(
+            "Job {} was started by the parent of this forked process and only runs there",
+            id
+        )).into());
+    }
+    Ok(job)
+}
+
+/// Register a job and start `work` for it on a new thread
+///
+/// `processed` counts the bytes of its input read so far, out of `total`.
+fn spawn<F>(operation: &'static str, processed: Arc<AtomicU64>, total: u64, work: F) -> Result<i32>
+where
+    F: FnOnce(&Job) -> Outcome + Send + 'static,
+{
+    let job = Arc::new(Job {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        pid: std::process::id(),
+        operation,
+        total,
+        processed,
+        cancelled: AtomicBool::new(false),
+        outcome: Mutex::new(None),
+    });
+    let worker_job = Arc::clone(&job);
+    std::thread::Builder::new()
+        .name(format!("lockbox-job-{}", job.id))
+        .spawn(move || {
+            // A panic must still end the job, or waiting for it would never return
+            let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| work(&worker_job)))
+                .unwrap_or_else(|_| Err(LockboxError::Io("The background job stopped unexpectedly".to_string())));
+            *worker_job.outcome() = Some(outcome);
+        })
+        .map_err(|e| LockboxError::io("Failed to start a background thread", &e))?;
+    let id = job.id;
+    jobs().push(job);
+    Ok(id)
+}
+
+/// Copy `reader` into `writer` in chunks, stopping if the job is cancelled
+///
+/// Returns the number of bytes copied.
+fn copy_chunks<R: Read, W: Write>(reader: &mut R, writer: &mut W, job: &Job, read_error: fn(std::io::Error) -> LockboxError) -> std::result::Result<u64, LockboxError> {
+    let mut buffer = Zeroizing::new(vec![0u8; CHUNK_SIZE]);
+    let mut copied = 0u64;
+    loop {
+        if job.cancelled.load(Ordering::Relaxed) {
+            return Err(LockboxError::Interrupted(format!("Job {} was cancelled", job.id)));
+        }
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(read_error(e)),
+        };
+        writer.write_all(&buffer[..n])
+            .map_err(|e| LockboxError::io("Failed to write output", &e))?;
+        copied += n as u64;
+    }
+}
+
+/// Reader that counts the bytes read from the input, the measure of a job's progress
+struct CountedReader<R> {
+    inner: R,
+    processed: Arc<AtomicU64>,
+}
+
+impl<R> CountedReader<R> {
+    fn new(inner: R) -> Self {
+        CountedReader { inner, processed: Arc::new(AtomicU64::new(0)) }
+    }
+}
+
+impl<R: Read> Read for CountedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.processed.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for CountedReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.processed.fetch_add(amt as u64, Ordering::Relaxed);
+        self.inner.consume(amt);
+    }
+}
+
+fn file_size(file: &File) -> u64 {
+    file.metadata().map(|m| m.len()).unwrap_or(0)
+}
+
+fn open_input(path: &str, what: &str) -> Result<File> {
+    File::open(fs_path(path))
+        .map_err(|e| LockboxError::io(format!("Failed to read {}", what), &e).into())
+}
+
+/// Log the outcome of a job to the audit log, from its worker thread
+///
+/// A job whose entry cannot be written fails, as an operation in the foreground does.
+fn record_job(operation: &'static str, outcome: Outcome, details: impl FnOnce(Option<&Vec<u8>>) -> audit::Details) -> Outcome {
+    match audit::record(operation, "key", outcome.clone().map_err(Error::from), details) {
+        Err(e) if outcome.is_ok() => Err(LockboxError::Io(error_parts(&e).1)),
+        _ => outcome,
+    }
+}
+
+/// Start encrypting a file to public keys on a background thread, returning the job id
+///
+/// `metadata` records the input's name, mode and modification time, as for files
+/// encrypted in the foreground. The output is removed if the job fails.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_async(input_file_path: &str, output_file_path: &str, recipients: Vec<String>, armor: bool, overwrite: bool, metadata: bool) -> Result<i32> {
+    use age::armor::{ArmoredWriter, Format};
+
+    let keys = recipients.clone();
+    let started = (|| {
+        policy::check_armor(armor)?;
+        let recipients = parse_x25519_recipients(&recipients)?;
+        let input = open_input(input_file_path, "input file")?;
+        let total = file_size(&input);
+        let mut input = CountedReader::new(BufReader::with_capacity(read_buffer_size(Path::new(input_file_path)), input));
+        let block = if metadata { metadata_block(input_file_path)? } else { Vec::new() };
+        let write_capacity = write_buffer_size(Path::new(output_file_path));
+        let output = create_output_file(output_file_path, overwrite, Access::default())?;
+        let partial = PartialOutput::new(output_file_path);
+
+        let processed = Arc::clone(&input.processed);
+        let (input_path, output_path, keys) = (input_file_path.to_string(), output_file_path.to_string(), keys.clone());
+
+        spawn("encrypt", processed, total, move |job| {
+            let outcome = (|| {
+                let encryptor = policy::encryptor(recipients.iter().map(|r| r as &dyn age::Recipient))?;
+                let format = if armor { Format::AsciiArmor } else { Format::Binary };
+                let armored_writer = ArmoredWriter::wrap_output(BufWriter::with_capacity(write_capacity, output), format)
+                    .map_err(|e| LockboxError::Armor(format!("Failed to create armored writer: {}", e)))?;
+                let mut writer = encryptor.wrap_output(armored_writer)
+                    .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+                writer.write_all(&block)
+                    .map_err(|e| LockboxError::io("Failed to write encrypted data", &e))?;
+                let bytes = copy_chunks(&mut input, &mut writer, job, |e| LockboxError::io("Failed to read input file", &e))?;
+                writer.finish()
+                    .and_then(|armored| armored.finish())
+                    .and_then(|mut buffered| buffered.flush())
+                    .map_err(|e| LockboxError::io("Failed to finalize encryption", &e))?;
+                stats::add_bytes(Direction::Encrypt, bytes);
+                partial.complete();
+                Ok(Vec::new())
+            })();
+            record_job("encrypt", outcome, |done| {
+                audit::Details::encrypted_file(Some(&input_path), &output_path, done.is_some(), audit::recipient_keys(keys))
+            })
+        })
+    })();
+    // Once started, the job logs its own outcome
+    started.or_else(|e| {
+        audit::record("encrypt", "key", Err(e), |_| {
+            audit::Details::encrypted_file(Some(input_file_path), output_file_path, false, audit::recipient_keys(recipients))
+        })
+    })
+}
+
+/// Start decrypting a file with a private key file on a background thread, returning the job id
+///
+/// The key is checked against the file before the job starts, so a wrong key fails at
+/// once. The metadata block is not written out; `age_job_wait()` returns it.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_async(encrypted_file_path: &str, output_file_path: &str, private_key_path: &str, strict_permissions: bool) -> Result<i32> {
+    let started = (|| {
+        policy::check_decrypt_from(&fs_path(encrypted_file_path))?;
+        check_age_file(&fs_path(encrypted_file_path))?;
+        policy::check_plaintext_to(&fs_path(output_file_path))?;
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_x25519_identities(&key_content)?;
+        let keys = audit::identity_public_keys(&key_content);
+
+        let input = open_input(encrypted_file_path, "encrypted file")?;
+        let total = file_size(&input);
+        let input = CountedReader::new(BufReader::with_capacity(read_buffer_size(Path::new(encrypted_file_path)), input));
+        let processed = Arc::clone(&input.processed);
+        let decryptor = age::Decryptor::new(age_input(input)?)
+            .map_err(|e| LockboxError::from_decrypt(e, false))?;
+        let mut reader = decryptor.decrypt(identities.iter().map(|i| i as &dyn age::Identity))
+            .map_err(|e| LockboxError::from_decrypt(e, false))?;
+        let head = read_head(&mut reader, LockboxError::from_payload)?;
+        let skip = metadata_length(&head)?;
+        let write_capacity = write_buffer_size(Path::new(output_file_path));
+        let output = create_output_file(output_file_path, false, Access::OWNER_ONLY)?;
+        let partial = PartialOutput::new(output_file_path);
+        let (input_path, output_path) = (encrypted_file_path.to_string(), output_file_path.to_string());
+
+        spawn("decrypt", processed, total, move |job| {
+            let outcome = (|| {
+                let mut writer = BufWriter::with_capacity(write_capacity, output);
+                writer.write_all(&head[skip..])
+                    .map_err(|e| LockboxError::io("Failed to write output", &e))?;
+                let bytes = copy_chunks(&mut reader, &mut writer, job, LockboxError::from_payload)?;
+                writer.flush()
+                    .map_err(|e| LockboxError::io("Failed to write output", &e))?;
+                stats::add_bytes(Direction::Decrypt, bytes + (head.len() - skip) as u64);
+                partial.complete();
+                Ok(head[..skip].to_vec())
+            })();
+            record_job("decrypt", outcome, |_| audit::Details::file(&input_path, Some(&output_path), keys))
+        })
+    })();
+    // Once started, the job logs its own outcome
+    started.or_else(|e| {
+        audit::record("decrypt", "key", Err(e), |_| {
+            audit::Details::file(encrypted_file_path, Some(output_file_path), audit::key_file_public_keys(private_key_path))
+        })
+    })
+}
+
+/// The state of a job: its operation, `state` ("running", "done", "failed" or
+/// "cancelled"), bytes
+/// `processed` out of `total`, and for a failed job the error
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_job_status(id: i32) -> Result<List> {
+    let job = find(id)?;
+    let state = job.state();
+    let error = match &*job.outcome() {
+        Some(Err(e)) => Rstr::from(e.to_string()),
+        _ => Rstr::na(),
+    };
+    let processed = if state == "done" { job.total } else { job.processed.load(Ordering::Relaxed).min(job.total) };
+    Ok(list!(
+        id = job.id,
+        operation = job.operation,
+        state = state,
+        processed = processed as f64,
+        total = job.total as f64,
+        error = Strings::from_values([error])
+    ))
+}
+
+/// Wait for a job to finish, returning the metadata block of a decryption (empty otherwise)
+///
+/// A failed job raises its error. An interrupt stops the waiting, not the job.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_job_wait(id: i32) -> Result<Raw> {
+    let job = find(id)?;
+    loop {
+        match &*job.outcome() {
+            Some(Ok(block)) => return Ok(Raw::from_bytes(block)),
+            Some(Err(e)) => return Err(e.clone().into()),
+            None => {}
+        }
+        check_interrupt()?;
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Cancel a job and wait for its worker to stop, which removes its partial output
+///
+/// Returns false if the job had already finished, which leaves it as it was.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_job_cancel(id: i32) -> Result<bool> {
+    let job = find(id)?;
+    if job.is_finished() {
+        return Ok(false);
+    }
+    job.cancelled.store(true, Ordering::Relaxed);
+    // The worker checks the flag between chunks, so this is short unless a read hangs
+    while !job.is_finished() {
+        check_interrupt()?;
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    Ok(job.state() == "cancelled")
+}
+
+extendr_module! {
+    mod jobs;
+    fn age_encrypt_async;
+    fn age_decrypt_async;
+    fn age_job_status;
+    fn age_job_wait;
+    fn age_job_cancel;
+}
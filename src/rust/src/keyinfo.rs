@@ -0,0 +1,155 @@
+// Provenance comments of private key files
+//
+// age-keygen and key_generate() start a key file with comments such as
+// `# created: 2024-05-01T10:00:00Z` and `# public key: age1...`, and people add their
+// own, such as `# label: CI deploy key`. They are not part of the key and age ignores
+// them, but they tell when a key was made and what it is for; key_generate() can
+// write more of them, such as an owner or a project. Only `# name: value`
+// comments are read; the secret lines are parsed for their public keys and nothing
+// else of them is returned.
+use extendr_api::prelude::*;
+
+use crate::error::LockboxError;
+use crate::{parse_x25519_identities, read_key_file};
+
+/// Comments that lockbox writes itself
+const RESERVED: &[&str] = &["created", "public key", "expires"];
+
+/// The `# name: value` comments of a key file, in order, with lowercase names
+pub(crate) fn key_comments(key_content: &str) -> Vec<(String, String)> {
+    key_content.lines()
+        .map(|line| line.trim_matches(|c: char| c.is_whitespace() || c == '\u{feff}'))
+        .filter_map(|line| line.strip_prefix('#'))
+        .filter_map(|comment| comment.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .filter(|(name, value)| !name.is_empty() && !value.is_empty())
+        .collect()
+}
+
+/// Format `# name: value` comments for a new key file, one line each
+///
+/// Names are case-insensitive and cannot hold `:` or `#`; neither names nor values
+/// can span lines, which could otherwise slip a key line into the file.
+pub(crate) fn metadata_comments(names: &[String], values: &[String]) -> std::result::Result<String, LockboxError> {
+    if names.len() != values.len() {
+        return Err(LockboxError::InvalidArgument("Every metadata entry needs a name and a value".to_string()));
+    }
+    let mut comments = String::new();
+    for (name, value) in names.iter().zip(values) {
+        let name = name.trim();
+        if name.is_empty() || name.contains([':', '#', '\n', '\r']) {
+            return Err(LockboxError::InvalidArgument(format!(
+                "Invalid metadata name '{}': names cannot be empty or contain ':', '#' or line breaks",
+                name.escape_debug()
+            )));
+        }
+        if RESERVED.contains(&name.to_lowercase().as_str()) {
+            return Err(LockboxError::InvalidArgument(format!(
+                "The metadata name '{}' is reserved for the comments lockbox writes itself",
+                name
+            )));
+        }
+        if value.trim().is_empty() || value.contains(['\n', '\r']) {
+            return Err(LockboxError::InvalidArgument(format!(
+                "The metadata value of '{}' cannot be empty or contain line breaks",
+                name
+            )));
+        }
+        comments.push_str(&format!("# {}: {}\n", name, value.trim()));
+    }
+    Ok(comments)
+}
+
+/// Seconds since the epoch of a time in a comment, such as `# created:`
+///
+/// age-keygen writes RFC 3339 times, lockbox `2024-05-01 10:00:00 UTC`.
+pub(crate) fn parse_time(value: &str) -> Option<f64> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Some(time.timestamp() as f64);
+    }
+    chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S UTC")
+        .ok()
+        .map(|time| time.and_utc().timestamp() as f64)
+}
+
+/// Seconds since the epoch from which a key counts as expired, from `# expires:`
+///
+/// A date without a time lets the key be used through that day, in UTC.
+pub(crate) fn parse_expires(value: &str) -> Option<f64> {
+    match chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(date) => date.succ_opt()?.and_hms_opt(0, 0, 0).map(|time| time.and_utc().timestamp() as f64),
+        Err(_) => parse_time(value),
+    }
+}
+
+/// Why a key file should no longer be used, if its `# expires:` comment says so
+fn expiry_problem(key_file_path: &str, key_content: &str) -> Option<String> {
+    let (_, value) = key_comments(key_content).into_iter().find(|(name, _)| name == "expires")?;
+    match parse_expires(&value) {
+        Some(expires) if (chrono::Utc::now().timestamp() as f64) < expires => None,
+        Some(_) => Some(format!(
+            "The private key file '{}' expired on {}; generate a new key with key_generate() and re-encrypt to it",
+            key_file_path, value
+        )),
+        None => Some(format!(
+            "The private key file '{}' has an expiry date that cannot be read, '{}'; use the form 2030-12-31",
+            key_file_path, value
+        )),
+    }
+}
+
+/// Read the provenance of a private key file
+///
+/// Returns the public keys of its identities, the `# created:` time (as recorded and
+/// in seconds since the epoch, NA without one), the time from which it counts as
+/// expired (NA without an `# expires:` comment), the public keys recorded in
+/// `# public key:` comments, the `# label:` comments, and every other `# name: value`
+/// comment as metadata, names and values apart.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_key_info(key_file_path: &str, strict_permissions: bool) -> Result<List> {
+    let key_content = read_key_file(key_file_path, strict_permissions)?;
+    let public_keys: Vec<String> = parse_x25519_identities(&key_content)?
+        .iter()
+        .map(|identity| identity.to_public().to_string())
+        .collect();
+
+    let comments = key_comments(&key_content);
+    let values = |name: &str| -> Vec<String> {
+        comments.iter().filter(|(n, _)| n == name).map(|(_, value)| value.clone()).collect()
+    };
+    let created = values("created").into_iter().next();
+    let expires = values("expires").into_iter().next();
+    let metadata: Vec<&(String, String)> = comments.iter()
+        .filter(|(name, _)| !RESERVED.contains(&name.as_str()) && name != "label")
+        .collect();
+
+    Ok(list!(
+        public_key = public_keys,
+        created = Strings::from_values([created.clone().map_or_else(Rstr::na, Rstr::from)]),
+        created_time = Doubles::from_values([created.as_deref().and_then(parse_time).map_or_else(Rfloat::na, Rfloat::from)]),
+        expires_time = Doubles::from_values([expires.as_deref().and_then(parse_expires).map_or_else(Rfloat::na, Rfloat::from)]),
+        recorded_public_key = values("public key"),
+        labels = values("label"),
+        metadata_names = metadata.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>(),
+        metadata = metadata.iter().map(|(_, value)| value.clone()).collect::<Vec<_>>()
+    ))
+}
+
+/// Check whether a private key file has expired
+///
+/// Returns why it should no longer be used, or an empty string.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_check_key_expiry(key_file_path: &str) -> Result<String> {
+    let key_content = read_key_file(key_file_path, false)?;
+    Ok(expiry_problem(key_file_path, &key_content).unwrap_or_default())
+}
+
+extendr_module! {
+    mod keyinfo;
+    fn age_key_info;
+    fn age_check_key_expiry;
+}
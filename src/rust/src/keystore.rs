@@ -0,0 +1,213 @@
+// Passphrase-protected exports of a keystore
+//
+// The private keys a user keeps, typically the `*.key` files of ~/.config/lockbox,
+// have to move with them to a new laptop or a cluster account. Copying the files one
+// by one leaves them in the clear on whatever carries them; an export puts them all
+// in one armored age file, encrypted with a passphrase, which can travel by email or
+// a shared drive and is unpacked into the keystore of the other machine.
+//
+// Inside the age payload the keys are framed as:
+//
+// - the magic line `lockbox-keystore/v1\n`
+// - the number of key files, as a little-endian u64
+// - for each key file, the length of its name and its name, then the length of its
+//   content and its content, lengths as little-endian u64 and text as UTF-8
+//
+// Only file names are kept, so a keystore can be imported into any directory, and
+// each file's comments, such as its creation time, travel with its keys.
+use extendr_api::prelude::*;
+use std::io::{Cursor, Write};
+use std::iter;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::access::Access;
+use crate::audit;
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stream::Progress;
+use crate::{
+    create_output_file, decrypt_content, encrypt_reader_to_file, parse_x25519_identities, passphrase_from,
+    read_key_file, scrypt_identity, scrypt_recipient,
+};
+
+const KEYSTORE_MAGIC: &[u8] = b"lockbox-keystore/v1\n";
+
+/// The file name a key is exported under, refusing names that would escape the
+/// directory it is imported into
+fn key_file_name(path: &str) -> std::result::Result<String, LockboxError> {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| LockboxError::InvalidArgument(format!("'{}' has no file name", path)))
+}
+
+fn corrupt(detail: &str) -> LockboxError {
+    LockboxError::CorruptPayload(format!("The keystore export is damaged: {}", detail))
+}
+
+/// Take a little-endian u64 length from the front of `rest`
+fn take_length(rest: &mut &[u8]) -> std::result::Result<usize, LockboxError> {
+    if rest.len() < 8 {
+        return Err(corrupt("it ends early"));
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&rest[..8]);
+    *rest = &rest[8..];
+    usize::try_from(u64::from_le_bytes(bytes)).map_err(|_| corrupt("a length is too large"))
+}
+
+/// Take a length-prefixed UTF-8 text from the front of `rest`
+fn take_text(rest: &mut &[u8]) -> std::result::Result<Zeroizing<String>, LockboxError> {
+    let length = take_length(rest)?;
+    if rest.len() < length {
+        return Err(corrupt("it ends early"));
+    }
+    let text = std::str::from_utf8(&rest[..length]).map_err(|_| corrupt("a key file is not valid UTF-8"))?;
+    *rest = &rest[length..];
+    Ok(Zeroizing::new(text.to_string()))
+}
+
+/// Split keystore plaintext into file names and contents
+fn unpack(packed: &[u8]) -> std::result::Result<Vec<(String, Zeroizing<String>)>, LockboxError> {
+    let mut rest = packed.strip_prefix(KEYSTORE_MAGIC)
+        .ok_or_else(|| LockboxError::InvalidArgument("The file is not a keystore export".to_string()))?;
+    let count = take_length(&mut rest)?;
+    // Every key file takes at least sixteen bytes, which bounds a forged count
+    if count > rest.len() / 16 {
+        return Err(corrupt("the key file count is too large"));
+    }
+    let mut files = Vec::with_capacity(count);
+    for _ in 0..count {
+        let name = take_text(&mut rest)?;
+        let content = take_text(&mut rest)?;
+        files.push((name.to_string(), content));
+    }
+    if !rest.is_empty() {
+        return Err(corrupt("there is data after the last key file"));
+    }
+    Ok(files)
+}
+
+/// Export key files into one armored file encrypted with a passphrase
+///
+/// Each file must hold at least one private key, and files are stored under their
+/// names, which must therefore differ. Identity files of keys held by age plugins are
+/// skipped. Returns the names of the exported files.
+/// `passphrase_source`, if not NULL, names where to read the passphrase instead (`env:NAME`, `file:PATH` or `fd:N`).
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_keystore_export(
+    key_file_paths: Vec<String>,
+    strict_permissions: bool,
+    output_file_path: &str,
+    passphrase: &str,
+    passphrase_source: Nullable<String>,
+    work_factor: Nullable<i32>,
+    overwrite: bool,
+) -> Result<Vec<String>> {
+    let result = (|| {
+        let mut names: Vec<String> = Vec::with_capacity(key_file_paths.len());
+        let mut entries = Zeroizing::new(Vec::new());
+        for path in &key_file_paths {
+            let name = key_file_name(path)?;
+            if names.contains(&name) {
+                return Err(LockboxError::InvalidArgument(format!(
+                    "Two key files are named '{}'; an export keeps file names only, so they must differ",
+                    name
+                )).into());
+            }
+            let key_content = read_key_file(path, strict_permissions)?;
+            // A key held in hardware cannot leave this machine, so its identity file stays
+            if !key_content.contains("AGE-SECRET-KEY-") && key_content.contains("AGE-PLUGIN-") {
+                continue;
+            }
+            parse_x25519_identities(&key_content)?;
+            for text in [name.as_bytes(), key_content.as_bytes()] {
+                entries.extend_from_slice(&(text.len() as u64).to_le_bytes());
+                entries.extend_from_slice(text);
+            }
+            names.push(name);
+        }
+        if names.is_empty() {
+            return Err(LockboxError::InvalidArgument(
+                "The key files only hold keys kept in hardware, which cannot be exported".to_string(),
+            ).into());
+        }
+        let mut packed = Zeroizing::new(KEYSTORE_MAGIC.to_vec());
+        packed.extend_from_slice(&(names.len() as u64).to_le_bytes());
+        packed.extend_from_slice(&entries);
+
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+        let recipient = scrypt_recipient(&passphrase, work_factor)?;
+        let encryptor = policy::encryptor(iter::once(&recipient as _))?;
+        encrypt_reader_to_file(encryptor, &mut Cursor::new(&packed[..]), output_file_path, true, overwrite, Access::default(), None, &mut Progress::none())?;
+        Ok(names)
+    })();
+    audit::record("encrypt", "passphrase", result, |done| {
+        audit::Details::encrypted_file(None, output_file_path, done.is_some(), Vec::new())
+    })
+}
+
+/// Import the key files of a keystore export into a directory
+///
+/// Key files are written readable by their owner only. Unless `overwrite` is true,
+/// nothing is written when any of them already exists. Returns the paths written.
+/// `passphrase_source`, if not NULL, names where to read the passphrase instead (`env:NAME`, `file:PATH` or `fd:N`).
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_keystore_import(
+    input_file_path: &str,
+    key_dir: &str,
+    passphrase: &str,
+    passphrase_source: Nullable<String>,
+    max_work_factor: Nullable<i32>,
+    overwrite: bool,
+) -> Result<Vec<String>> {
+    let result = (|| {
+        let path = fs_path(input_file_path);
+        policy::check_decrypt_from(&path)?;
+        let encrypted = std::fs::read(&path)
+            .map_err(|e| LockboxError::io(format!("Failed to read '{}'", input_file_path), &e))?;
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+        let identity = scrypt_identity(&passphrase, max_work_factor)?;
+        let packed = decrypt_content(&encrypted, iter::once(&identity as _), &mut Progress::none())?;
+        let files = unpack(&packed)?;
+
+        // Check every file before writing any, so a failed import leaves the directory as it was
+        let mut paths = Vec::with_capacity(files.len());
+        for (name, content) in &files {
+            if key_file_name(name).ok().as_deref() != Some(name.as_str()) {
+                return Err(corrupt(&format!("'{}' is not a plain file name", name)).into());
+            }
+            parse_x25519_identities(content)
+                .map_err(|_| corrupt(&format!("'{}' does not hold an age private key", name)))?;
+            let path = Path::new(key_dir).join(name).to_string_lossy().into_owned();
+            if !overwrite && fs_path(&path).exists() {
+                return Err(LockboxError::FileExists(format!(
+                    "Key file '{}' already exists. Choose a different directory or use `overwrite = TRUE`.",
+                    path
+                )).into());
+            }
+            paths.push(path);
+        }
+        for ((_, content), path) in files.iter().zip(&paths) {
+            let mut file = create_output_file(path, overwrite, Access::OWNER_ONLY)?;
+            file.write_all(content.as_bytes())
+                .map_err(|e| LockboxError::io("Failed to write key file", &e))?;
+        }
+        Ok(paths)
+    })();
+    audit::record("decrypt", "passphrase", result, |_| audit::Details::file(input_file_path, Some(key_dir), Vec::new()))
+}
+
+extendr_module! {
+    mod keystore;
+    fn age_keystore_export;
+    fn age_keystore_import;
+}
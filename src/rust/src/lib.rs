@@ -1,43 +1,129 @@
 // Bridge between Rust and R for age encryption/decryption functionality
 use extendr_api::prelude::*;
 use std::io::Read;
+use std::path::Path;
 use std::str::FromStr;
 use age::secrecy::ExposeSecret;
+use zeroize::Zeroizing;
+
+mod error;
+use error::LockboxError;
+
+mod stream;
+use stream::{check_age_file, copy_interruptible, decrypt_to_file, PartialOutput, Progress};
+
+mod source;
+use source::passphrase_from;
+
+mod metadata;
+use metadata::metadata_block;
+
+mod paths;
+use paths::fs_path;
+
+mod access;
+use access::Access;
+
+mod buffers;
+use buffers::{read_buffer_size, write_buffer_size};
+
+mod encoding;
+use encoding::{decode_encrypted, StringEncoding};
+
+mod hybrid;
+mod recipients;
+mod vault;
+mod env;
+mod structured;
+mod strings;
+mod convergent;
+mod manifest;
+mod signing;
+mod git;
+mod pipe;
+mod http;
+mod s3;
+mod cache;
+mod identity;
+mod prompt;
+mod tempfile;
+mod shred;
+mod convert;
+mod bundle;
+mod archive;
+mod raw;
+mod backup;
+mod parallel;
+mod bench;
+mod limit;
+mod logging;
+use logging::{Level, Timer, Value};
+mod batch;
+mod filter;
+mod project;
+mod team;
+mod stanzas;
+mod clipboard;
+mod keyinfo;
+mod keystore;
+mod mnemonic;
+mod plugin;
+mod qr;
+mod resolver;
+mod shamir;
+mod stats;
+use stats::Direction;
+use stanzas::Stanzas;
+mod audit;
+mod policy;
+mod blobs;
+mod split;
+mod jobs;
+mod resume;
+mod selftest;
+mod version;
+
+// BIP39 English wordlist, as used by rage for generated passphrases
+const BIP39_WORDLIST: &str = include_str!("../assets/bip39-english.txt");
 
 /// Decrypt file content using identities and return as bytes
 /// 
 /// This helper function handles both ASCII-armored and binary age files,
-/// decrypts them, and returns the content as raw bytes.
-fn decrypt_content<'a, I>(file_content: &[u8], identities: I) -> Result<Vec<u8>>
+/// decrypts them, and returns the content as raw bytes. The plaintext buffer
+/// is wiped from memory when dropped.
+fn decrypt_content<'a, I>(file_content: &[u8], identities: I, progress: &mut Progress) -> Result<Zeroizing<Vec<u8>>>
 where
     I: Iterator<Item = &'a dyn age::Identity>,
 {
-    use age::armor::ArmoredReader;
     use age::Decryptor;
-    use std::io::Cursor;
-
-    let mut decrypted_reader: Box<dyn Read> = if file_content.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----") {
-        // Handle ASCII-armored files
-        let cursor = Cursor::new(file_content);
-        let armored_reader = ArmoredReader::new(cursor);
-        let decryptor = Decryptor::new(armored_reader)
-            .map_err(|e| Error::Other(format!("Failed to create decryptor: {}", e)))?;
-        
-        Box::new(decryptor.decrypt(identities)
-            .map_err(|e| Error::Other(format!("Failed to decrypt: {}", e)))?)
-    } else {
-        // Handle binary age files
-        let cursor = Cursor::new(file_content);
-        let decryptor = Decryptor::new(cursor)
-            .map_err(|e| Error::Other(format!("Failed to create decryptor: {}", e)))?;
-        
-        Box::new(decryptor.decrypt(identities)
-            .map_err(|e| Error::Other(format!("Failed to decrypt: {}", e)))?)
-    };
+    use std::io::{BufReader, Cursor};
+
+    if let Some(reason) = stream::not_age_reason(file_content) {
+        return Err(LockboxError::NotAgeFile(format!("The input is not age-encrypted: {}", reason)).into());
+    }
+
+    // age_input() tells binary from armored content, past any blank lines in front
+    let timer = Timer::start();
+    let (input, truncation) = stream::Truncation::watch(progress.track(Cursor::new(file_content)));
+    let decryptor = Decryptor::new(stream::age_input(BufReader::new(input))?)
+        .map_err(|e| truncation.header_error(e, false))?;
+    let scrypt = decryptor.is_scrypt();
+    let stanzas = Stanzas::default();
+    let mut decrypted_reader = decryptor.decrypt(stanzas.observe(identities))
+        .map_err(|e| stanzas.explain(truncation.header_error(e, scrypt)))?;
+    logging::event(Level::Debug, "header", &[("scrypt", Value::Flag(scrypt)), ("ms", Value::Elapsed(timer))]);
 
-    let mut decrypted_content = Vec::new();
-    decrypted_reader.read_to_end(&mut decrypted_content)
-        .map_err(|e| Error::Other(format!("Failed to read decrypted content: {}", e)))?;
+    // The plaintext is never longer than the ciphertext, so reserving that much up
+    // front avoids reallocations that would leave unwiped copies on the heap
+    let timer = Timer::start();
+    let mut decrypted_content = Zeroizing::new(Vec::with_capacity(file_content.len()));
+    let bytes = copy_interruptible(&mut decrypted_reader, &mut *decrypted_content, progress, |e| truncation.payload_error(e))?;
+    stats::add_bytes(Direction::Decrypt, bytes);
+    logging::event(Level::Debug, "payload", &[
+        ("direction", Value::Label("decrypt")),
+        ("bytes", Value::Number(bytes)),
+        ("ms", Value::Elapsed(timer)),
+    ]);
 
     Ok(decrypted_content)
 }
@@ -47,101 +133,535 @@ where
 /// 
 /// This helper function reads through each line of a key file and extracts
 /// all valid age secret keys, returning them as boxed Identity trait objects.
+/// Identities of keys held by age plugins come after the x25519 keys, so that a
+/// plugin only runs, and perhaps asks for a PIN, when no x25519 key matched.
 fn parse_identities_from_key_file(key_content: &str) -> Result<Vec<Box<dyn age::Identity>>> {
-    let mut identities: Vec<Box<dyn age::Identity>> = Vec::new();
+    let plugin_identities = plugin::identities(key_content)?;
+    let mut identities: Vec<Box<dyn age::Identity>> =
+        if !plugin_identities.is_empty() && !key_content.contains("AGE-SECRET-KEY-") {
+            Vec::new()
+        } else {
+            parse_x25519_identities(key_content)?
+                .into_iter()
+                .map(|identity| Box::new(identity) as Box<dyn age::Identity>)
+                .collect()
+        };
+    // Plugin identities are counted by plugin, as each runs once for all of its keys
+    logging::event(Level::Debug, "identities", &[
+        ("x25519", Value::Number(identities.len() as u64)),
+        ("plugins", Value::Number(plugin_identities.len() as u64)),
+    ]);
+    identities.extend(plugin_identities);
+    Ok(identities)
+}
+
+/// Parse the x25519 identities of a private key file, as concrete types
+///
+/// Unlike boxed trait objects, these can be shared with worker threads. Key files
+/// edited on Windows or written by other tools may have CRLF line endings, a byte
+/// order mark, indentation or trailing spaces, which are all ignored, as are comments
+/// and lines that hold no x25519 key. A key line that does not parse is reported by
+/// its line number, never by its content.
+fn parse_x25519_identities(key_content: &str) -> Result<Vec<age::x25519::Identity>> {
+    let mut identities = Vec::new();
     
-    for line in key_content.lines() {
+    for (number, line) in key_content.lines().enumerate() {
+        let line = line.trim_matches(|c: char| c.is_whitespace() || c == '\u{feff}');
         if line.starts_with("AGE-SECRET-KEY-") {
             // Parse x25519 private key from the line
-            let identity = age::x25519::Identity::from_str(line)
-                .map_err(|e| Error::Other(format!("Failed to parse identity: {}", e)))?;
-            identities.push(Box::new(identity) as Box<dyn age::Identity>);
+            let identity = age::x25519::Identity::from_str(line).map_err(|e| {
+                LockboxError::InvalidIdentity(format!("Failed to parse the identity on line {} of the key file: {}", number + 1, e))
+            })?;
+            identities.push(identity);
         }
     }
 
     if identities.is_empty() {
-        return Err(Error::Other("No valid age identities found".to_string()));
+        if key_content.contains("AGE-PLUGIN-") {
+            return Err(LockboxError::InvalidIdentity(
+                "The key file only holds keys kept by age plugins, which cannot be used here".to_string(),
+            ).into());
+        }
+        return Err(LockboxError::InvalidIdentity("No valid age identities found".to_string()).into());
     }
 
     Ok(identities)
 }
 
+/// Parse age public keys (recipients)
+/// 
+/// At least one recipient is required, since age cannot encrypt to nobody. Keys held
+/// by age plugins, such as `age1tpm1...`, are encrypted to through their plugin.
+fn parse_recipients(recipients: Vec<String>) -> Result<Vec<Box<dyn age::Recipient>>> {
+    let recipients = team::resolve_recipients(&recipients, None)?;
+    let (plugin_recipients, recipients): (Vec<String>, Vec<String>) =
+        recipients.into_iter().partition(|r| plugin::is_plugin_recipient(r));
+    let mut parsed: Vec<Box<dyn age::Recipient>> = if recipients.is_empty() && !plugin_recipients.is_empty() {
+        Vec::new()
+    } else {
+        parse_x25519_recipients(&recipients)?
+            .into_iter()
+            .map(|recipient| Box::new(recipient) as Box<dyn age::Recipient>)
+            .collect()
+    };
+    logging::event(Level::Debug, "recipients", &[
+        ("x25519", Value::Number(parsed.len() as u64)),
+        ("plugin", Value::Number(plugin_recipients.len() as u64)),
+    ]);
+    parsed.extend(plugin::recipients(&plugin_recipients)?);
+    Ok(parsed)
+}
+
+/// Parse age public keys as concrete x25519 recipients, which are `Send + Sync`
+///
+/// Team member and group names are replaced by their keys first (see team.rs).
+fn parse_x25519_recipients(recipients: &[String]) -> Result<Vec<age::x25519::Recipient>> {
+    let recipients = team::resolve_recipients(recipients, None)?;
+    let mut parsed_recipients = Vec::new();
+    for recipient_str in &recipients {
+        let recipient = recipient_str.parse::<age::x25519::Recipient>().map_err(|e| {
+            if plugin::is_plugin_recipient(recipient_str) {
+                LockboxError::InvalidRecipient(format!(
+                    "Recipient '{}' is a key held by an age plugin, which cannot be used here",
+                    recipient_str
+                ))
+            } else {
+                LockboxError::InvalidRecipient(format!("Invalid recipient '{}': {}", recipient_str, e))
+            }
+        })?;
+        parsed_recipients.push(recipient);
+    }
+
+    if parsed_recipients.is_empty() {
+        return Err(LockboxError::InvalidRecipient("At least one recipient is required".to_string()).into());
+    }
+
+    Ok(parsed_recipients)
+}
+
+/// Read a private key file into a zeroizing buffer
+/// 
+/// With `strict_permissions`, key files that other users can access are refused
+/// before their content is read.
+fn read_key_file(key_file_path: &str, strict_permissions: bool) -> Result<Zeroizing<String>> {
+    if strict_permissions {
+        if let Some(problem) = key_permission_problem(key_file_path)? {
+            return Err(LockboxError::InsecureKey(problem).into());
+        }
+    }
+
+    let key_content = std::fs::read_to_string(fs_path(key_file_path))
+        .map_err(|e| LockboxError::io("Failed to read private key file", &e))?;
+    logging::event(Level::Debug, "key_file", &[
+        ("path", Value::Path(Path::new(key_file_path))),
+        ("strict_permissions", Value::Flag(strict_permissions)),
+    ]);
+    Ok(Zeroizing::new(key_content))
+}
+
+/// Describe why a key file's permissions are too lax, if they are
+/// 
+/// Any group or other permission bit counts as too lax: the file should be 0600.
+#[cfg(unix)]
+fn key_permission_problem(key_file_path: &str) -> Result<Option<String>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(fs_path(key_file_path))
+        .map_err(|e| LockboxError::io("Failed to read private key file", &e))?
+        .permissions()
+        .mode() & 0o777;
+
+    if mode & 0o077 == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(format!(
+        "Private key file '{}' has permissions {:04o}, which give other users access; restrict them with Sys.chmod(\"{}\", \"0600\")",
+        key_file_path, mode, key_file_path
+    )))
+}
+
+/// Permissions are not checked on platforms without Unix file modes
+#[cfg(not(unix))]
+fn key_permission_problem(_key_file_path: &str) -> Result<Option<String>> {
+    Ok(None)
+}
+
+/// Open an output file for writing
+/// 
+/// Unless `overwrite` is true the file is opened with `create_new`, so an existing
+/// file is never truncated, even if it appears between a check in R and this call.
+/// The file is given the mode, owner and group of `access` before anything is
+/// written to it, and removed if that fails.
+///
+/// A symbolic link is never overwritten: R resolves links it was asked to follow, so
+/// one found here was either refused there or put in place since. On unix the file is
+/// opened with `O_NOFOLLOW`, so a link put in place at any moment is refused by the
+/// open itself.
+fn create_output_file(path: &str, overwrite: bool, access: Access) -> Result<std::fs::File> {
+    let symlink_refused = || -> Error {
+        LockboxError::Symlink(format!(
+            "'{}' is a symbolic link; refusing to overwrite the file it points to",
+            path
+        )).into()
+    };
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true);
+    if overwrite {
+        #[cfg(not(unix))]
+        if std::fs::symlink_metadata(fs_path(path)).map(|m| m.file_type().is_symlink()).unwrap_or(false) {
+            return Err(symlink_refused());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_NOFOLLOW);
+        }
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    if let Some(mode) = access.creation_mode() {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+
+    let file = options.open(fs_path(path)).map_err(|e| -> Error {
+        if is_symlink_error(&e) {
+            symlink_refused()
+        } else if e.kind() == std::io::ErrorKind::AlreadyExists {
+            LockboxError::FileExists(format!(
+                "File '{}' already exists; use `overwrite = TRUE` to replace it",
+                path
+            ))
+            .into()
+        } else {
+            LockboxError::io(format!("Failed to create '{}'", path), &e).into()
+        }
+    })?;
+    if let Err(e) = access.apply(&file, path) {
+        drop(file);
+        let _ = std::fs::remove_file(fs_path(path));
+        return Err(e);
+    }
+    Ok(file)
+}
+
+/// Whether opening with `O_NOFOLLOW` failed because the path is a symbolic link
+fn is_symlink_error(e: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        e.raw_os_error() == Some(libc::ELOOP)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = e;
+        false
+    }
+}
+
+/// Open a file to encrypt, memory-mapped with `mmap`, with progress tracked against its size
+fn open_encrypt_input(input_file_path: &str, mmap: bool, progress: Nullable<Function>) -> Result<(Box<dyn Read>, Progress)> {
+    let input_file = std::fs::File::open(fs_path(input_file_path))
+        .map_err(|e| LockboxError::io(format!("Failed to read input file '{}'", input_file_path), &e))?;
+    let input_size = input_file.metadata().map(|m| m.len()).unwrap_or(0);
+    let progress = Progress::new(progress, input_size);
+
+    // A memory map lets the OS page the input in on demand instead of copying it
+    // through read() calls. Empty files cannot be mapped on every platform.
+    let input: Box<dyn Read> = if mmap && input_size > 0 {
+        // Safety: the map is only read, and R users are told not to modify the file meanwhile
+        let map = unsafe { memmap2::Mmap::map(&input_file) }
+            .map_err(|e| LockboxError::io("Failed to memory-map input file", &e))?;
+        Box::new(std::io::Cursor::new(map))
+    } else {
+        Box::new(std::io::BufReader::with_capacity(read_buffer_size(Path::new(input_file_path)), input_file))
+    };
+    Ok((input, progress))
+}
+
+/// Stream `input` through an encryptor into `output`, returning the output once finished
+///
+/// `metadata`, if given, is the block recorded before the content (see metadata.rs).
+/// The input is copied in chunks, so that R can interrupt and is told the progress.
+pub(crate) fn encrypt_stream<R: Read, W: std::io::Write>(
+    encryptor: age::Encryptor,
+    input: &mut R,
+    output: W,
+    armor: bool,
+    metadata: Option<Vec<u8>>,
+    progress: &mut Progress,
+) -> Result<W> {
+    use age::armor::{ArmoredWriter, Format};
+    use std::io::Write;
+
+    // With Format::Binary the armored writer passes bytes through unchanged
+    let format = if armor { Format::AsciiArmor } else { Format::Binary };
+    let armored_writer = ArmoredWriter::wrap_output(output, format)
+        .map_err(|e| LockboxError::Armor(format!("Failed to create armored writer: {}", e)))?;
+
+    let mut encrypted_writer = encryptor.wrap_output(armored_writer)
+        .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+
+    let timer = Timer::start();
+    if let Some(block) = metadata {
+        encrypted_writer.write_all(&block)
+            .map_err(|e| LockboxError::io("Failed to write encrypted data", &e))?;
+    }
+    let bytes = copy_interruptible(input, &mut encrypted_writer, progress, |e| {
+        LockboxError::io("Failed to read input file", &e)
+    })?;
+    stats::add_bytes(Direction::Encrypt, bytes);
+    logging::event(Level::Debug, "payload", &[
+        ("direction", Value::Label("encrypt")),
+        ("armor", Value::Flag(armor)),
+        ("bytes", Value::Number(bytes)),
+        ("ms", Value::Elapsed(timer)),
+    ]);
+
+    let armored_writer = encrypted_writer.finish()
+        .map_err(|e| LockboxError::io("Failed to finalize encryption", &e))?;
+
+    armored_writer.finish()
+        .map_err(|e| LockboxError::Armor(format!("Failed to finalize armored writer: {}", e)).into())
+}
+
+/// Encrypt `input` into a new output file, which is removed if encryption fails
+///
+/// The file is created with `access`, its mode, owner and group.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn encrypt_reader_to_file<R: Read>(
+    encryptor: age::Encryptor,
+    input: &mut R,
+    output_file_path: &str,
+    armor: bool,
+    overwrite: bool,
+    access: Access,
+    metadata: Option<Vec<u8>>,
+    progress: &mut Progress,
+) -> Result<()> {
+    use std::io::{BufWriter, Write};
+
+    policy::check_armor(armor)?;
+    let output_file = create_output_file(output_file_path, overwrite, access)?;
+    let partial = PartialOutput::new(output_file_path);
+
+    let output = BufWriter::with_capacity(write_buffer_size(Path::new(output_file_path)), output_file);
+    let mut writer = encrypt_stream(encryptor, input, output, armor, metadata, progress)?;
+    writer.flush()
+        .map_err(|e| LockboxError::io("Failed to flush output", &e))?;
+
+    partial.complete();
+    Ok(())
+}
+
+/// Stream a file through an encryptor into a new output file
+/// 
+/// The input is processed in chunks so that large files can be interrupted from R and
+/// report progress. With `mmap`, the input is memory-mapped rather than read. With
+/// `metadata`, the input's name, mode and modification time are recorded before it.
+/// If encryption fails or is interrupted, the partial output file is removed.
+#[allow(clippy::too_many_arguments)]
+fn encrypt_file(encryptor: age::Encryptor, input_file_path: &str, output_file_path: &str, armor: bool, overwrite: bool, access: Access, mmap: bool, metadata: bool, progress: Nullable<Function>) -> Result<()> {
+    let (input, mut progress) = open_encrypt_input(input_file_path, mmap, progress)?;
+    let mut input = progress.track(input);
+    let metadata = if metadata { Some(metadata_block(input_file_path)?) } else { None };
+    encrypt_reader_to_file(encryptor, &mut input, output_file_path, armor, overwrite, access, metadata, &mut progress)
+}
+
+/// Encrypt a file into memory, returning the binary age file or its armor
+pub(crate) fn encrypt_file_to_raw(encryptor: age::Encryptor, input_file_path: &str, armor: bool, mmap: bool, metadata: bool, progress: Nullable<Function>) -> Result<Raw> {
+    let (input, mut progress) = open_encrypt_input(input_file_path, mmap, progress)?;
+    let mut input = progress.track(input);
+    let metadata = if metadata { Some(metadata_block(input_file_path)?) } else { None };
+    let encrypted = encrypt_stream(encryptor, &mut input, Vec::new(), armor, metadata, &mut progress)?;
+    Ok(Raw::from_bytes(&encrypted))
+}
+
+/// Build a scrypt recipient from a passphrase and optional work factor
+/// 
+/// The work factor is the scrypt log-N parameter. When it is NULL, age picks a value
+/// that targets about one second of work on the current machine.
+fn scrypt_recipient(passphrase: &str, work_factor: Nullable<i32>) -> Result<age::scrypt::Recipient> {
+    use age::secrecy::SecretString;
+
+    policy::check_passphrase_encryption()?;
+    let secret_pass = SecretString::from(passphrase.to_owned());
+    let mut recipient = age::scrypt::Recipient::new(secret_pass);
+    if let Nullable::NotNull(log_n) = work_factor {
+        recipient.set_work_factor(scrypt_log_n(log_n, "work_factor")?);
+    }
+    Ok(recipient)
+}
+
+/// Build a scrypt identity from a passphrase and optional maximum work factor
+/// 
+/// Files whose header requests more scrypt work than `max_work_factor` are rejected
+/// before any key derivation happens. When it is NULL, age's default cap is used.
+fn scrypt_identity(passphrase: &str, max_work_factor: Nullable<i32>) -> Result<age::scrypt::Identity> {
+    use age::secrecy::SecretString;
+
+    limit::check_passphrase_attempt()?;
+    let secret_pass = SecretString::from(passphrase.to_owned());
+    let mut identity = age::scrypt::Identity::new(secret_pass);
+    if let Nullable::NotNull(max_log_n) = max_work_factor {
+        identity.set_max_work_factor(scrypt_log_n(max_log_n, "max_work_factor")?);
+    }
+    Ok(identity)
+}
+
+/// Validate a scrypt log-N parameter (age accepts 1 to 63)
+fn scrypt_log_n(value: i32, name: &str) -> Result<u8> {
+    if (1..64).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(LockboxError::InvalidArgument(format!("`{}` must be between 1 and 63, got {}", name, value)).into())
+    }
+}
+
 /// Decrypt an age-encrypted file using a passphrase
 /// 
 /// This function handles both ASCII-armored and binary age files encrypted with passphrases.
 /// It reads the entire file into memory, detects the format, and returns the decrypted content as raw bytes.
+/// `max_work_factor` caps the scrypt work a file header may request (NULL for age's default).
+/// `progress`, if not NULL, is an R function called with the bytes processed and the total.
+/// `passphrase_source`, if not NULL, names where to read the passphrase instead (`env:NAME`, `file:PATH` or `fd:N`).
 /// @keywords internal
 /// @noRd
 #[extendr]
-fn age_decrypt_with_passphrase(encrypted_file_path: &str, passphrase: &str) -> Result<Raw> {
-    use age::secrecy::SecretString;
+fn age_decrypt_with_passphrase(encrypted_file_path: &str, passphrase: &str, passphrase_source: Nullable<String>, max_work_factor: Nullable<i32>, progress: Nullable<Function>) -> Result<Raw> {
     use std::iter;
+    let result = (|| {
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
 
-    // Read the entire encrypted file into memory
-    let file_content = std::fs::read(encrypted_file_path)
-        .map_err(|_| Error::Other("Failed to read encrypted file".to_string()))?;
+        // Read the entire encrypted file into memory
+        policy::check_decrypt_from(&fs_path(encrypted_file_path))?;
+        check_age_file(&fs_path(encrypted_file_path))?;
+        let file_content = std::fs::read(fs_path(encrypted_file_path))
+            .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", encrypted_file_path), &e))?;
 
-    // Create scrypt identity from passphrase for secure decryption
-    let secret_pass = SecretString::from(passphrase.to_owned());
-    let identity = age::scrypt::Identity::new(secret_pass);
-    
-    // Decrypt and return content using the passphrase identity
-    let decrypted_bytes = decrypt_content(&file_content, iter::once(&identity as _))?;
-    Ok(Raw::from_bytes(&decrypted_bytes))
+        // Create scrypt identity from passphrase for secure decryption
+        let identity = scrypt_identity(&passphrase, max_work_factor)?;
+
+        // Decrypt and return content using the passphrase identity
+        let mut progress = Progress::new(progress, file_content.len() as u64);
+        let decrypted_bytes = decrypt_content(&file_content, iter::once(&identity as _), &mut progress)?;
+        Ok(Raw::from_bytes(&decrypted_bytes))
+    })();
+    audit::record("decrypt", "passphrase", result, |_| audit::Details::file(encrypted_file_path, None, Vec::new()))
+}
+
+/// Decrypt a passphrase-encrypted file straight into an output file
+///
+/// The file is streamed in constant memory, armored or not. See
+/// `age_decrypt_file_with_key()` for `into_directory` and the value returned.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_decrypt_file_with_passphrase(
+    encrypted_file_path: &str,
+    output_file_path: &str,
+    into_directory: bool,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+    passphrase: &str,
+    passphrase_source: Nullable<String>,
+    max_work_factor: Nullable<i32>,
+    progress: Nullable<Function>,
+) -> Result<Raw> {
+    let result = (|| {
+        let access = Access::from_r(mode, owner, group)?;
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+        let identity = scrypt_identity(&passphrase, max_work_factor)?;
+        let input = fs_path(encrypted_file_path);
+        let size = std::fs::metadata(&input).map(|m| m.len()).unwrap_or(0);
+        let mut progress = Progress::new(progress, size);
+        let metadata = decrypt_to_file(&input, std::iter::once(&identity as _), output_file_path, into_directory, access, &mut progress)?;
+        Ok(Raw::from_bytes(&metadata))
+    })();
+    audit::record("decrypt", "passphrase", result, |_| {
+        audit::Details::file(encrypted_file_path, Some(output_file_path), Vec::new())
+    })
 }
 
 /// Decrypt an age-encrypted file using a private key
 /// 
 /// This function handles both ASCII-armored and binary age files encrypted with public keys.
 /// It reads the private key file, parses all identities, and returns the decrypted content as raw bytes.
+/// `progress`, if not NULL, is an R function called with the bytes processed and the total.
 /// @keywords internal
 /// @noRd
 #[extendr]
-fn age_decrypt_with_key(encrypted_file_path: &str, private_key_path: &str) -> Result<Raw> {
-    // Read the encrypted file and private key file
-    let file_content = std::fs::read(encrypted_file_path)
-        .map_err(|_| Error::Other("Failed to read encrypted file".to_string()))?;
+fn age_decrypt_with_key(encrypted_file_path: &str, private_key_path: &str, strict_permissions: bool, progress: Nullable<Function>) -> Result<Raw> {
+    let result = (|| {
+        // Read the encrypted file and private key file
+        policy::check_decrypt_from(&fs_path(encrypted_file_path))?;
+        check_age_file(&fs_path(encrypted_file_path))?;
+        let file_content = std::fs::read(fs_path(encrypted_file_path))
+            .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", encrypted_file_path), &e))?;
 
-    let key_content = std::fs::read_to_string(private_key_path)
-        .map_err(|_| Error::Other("Failed to read private key file".to_string()))?;
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
 
-    // Parse all age identities from the key file
-    let identities = parse_identities_from_key_file(&key_content)?;
-    
-    // Decrypt and return content using all available identities
-    let decrypted_bytes = decrypt_content(&file_content, identities.iter().map(|i| i.as_ref()))?;
-    Ok(Raw::from_bytes(&decrypted_bytes))
+        // Parse all age identities from the key file
+        let identities = parse_identities_from_key_file(&key_content)?;
+
+        // Decrypt and return content using all available identities
+        let mut progress = Progress::new(progress, file_content.len() as u64);
+        let decrypted_bytes = decrypt_content(&file_content, identities.iter().map(|i| i.as_ref()), &mut progress)?;
+        Ok(Raw::from_bytes(&decrypted_bytes))
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::file(encrypted_file_path, None, audit::key_file_public_keys(private_key_path))
+    })
 }
 
 /// Generate a new age key pair and save to file
 /// 
 /// This function generates a new x25519 key pair, writes it to the specified file path,
-/// and returns the public key string. Assumes the file path is valid and writable.
+/// and returns the public key string. An existing file is only replaced when `overwrite` is true.
+/// `metadata_names` and `metadata_values` are written as `# name: value` comments,
+/// after `# expires:` when `expires` is not NULL.
 /// @keywords internal
 /// @noRd
 #[extendr]
-fn age_generate_key(key_file_path: &str) -> Result<String> {
+fn age_generate_key(key_file_path: &str, overwrite: bool, metadata_names: Vec<String>, metadata_values: Vec<String>, expires: Nullable<String>) -> Result<String> {
     use std::io::Write;
+
+    let mut metadata = keyinfo::metadata_comments(&metadata_names, &metadata_values)?;
+    if let Nullable::NotNull(expires) = expires {
+        if keyinfo::parse_expires(&expires).is_none() {
+            return Err(LockboxError::InvalidArgument(format!(
+                "Invalid expiry '{}': use a date such as 2030-12-31 or an RFC 3339 time",
+                expires
+            )).into());
+        }
+        metadata.insert_str(0, &format!("# expires: {}\n", expires));
+    }
     
-    // Generate a new x25519 identity (private key)
+    // Generate a new x25519 identity (private key). age draws it from the operating
+    // system's CSPRNG on every call, with no generator state kept in the process, so
+    // workers forked from one R session cannot produce related keys.
     let identity = age::x25519::Identity::generate();
     
     // Get the corresponding recipient (public key)
     let recipient = identity.to_public();
     
     // Format the private key for writing to file
-    let private_key_line = format!("# created: {}\n# public key: {}\n{}\n",
+    let private_key_line = Zeroizing::new(format!("# created: {}\n# public key: {}\n{}{}\n",
         chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC"),
         recipient,
+        metadata,
         identity.to_string().expose_secret()
-    );
+    ));
     
-    // Write the private key to the specified file
-    let mut file = std::fs::File::create(key_file_path)
-        .map_err(|_| Error::Other("Failed to create key file".to_string()))?;
+    // Write the private key to the specified file, readable by the owner only
+    let mut file = create_output_file(key_file_path, overwrite, Access::OWNER_ONLY)?;
     
     file.write_all(private_key_line.as_bytes())
-        .map_err(|_| Error::Other("Failed to write key file".to_string()))?;
+        .map_err(|e| LockboxError::io("Failed to write key file", &e))?;
     
     // Return the public key as a string
     Ok(recipient.to_string())
@@ -154,312 +674,427 @@ fn age_generate_key(key_file_path: &str) -> Result<String> {
 /// @keywords internal
 /// @noRd
 #[extendr]
-fn age_extract_public_key(key_file_path: &str) -> Result<String> {
+fn age_extract_public_key(key_file_path: &str, strict_permissions: bool) -> Result<String> {
     // Read the key file content
-    let key_content = std::fs::read_to_string(key_file_path)
-        .map_err(|_| Error::Other("Failed to read key file".to_string()))?;
+    let key_content = read_key_file(key_file_path, strict_permissions)?;
 
-    // Use the existing parse function to validate the file and get identities
-    let _identities = parse_identities_from_key_file(&key_content)?;
-    
-    // Extract public key from the first valid identity line
-    for line in key_content.lines() {
-        if line.starts_with("AGE-SECRET-KEY-") {
-            let identity = age::x25519::Identity::from_str(line)
-                .map_err(|e| Error::Other(format!("Failed to parse identity: {}", e)))?;
-            let recipient = identity.to_public();
-            return Ok(recipient.to_string());
-        }
-    }
-    
-    Err(Error::Other("No valid age identities found".to_string()))
+    // The parser fails without any identity, so there is a first one
+    let identities = parse_x25519_identities(&key_content)?;
+    Ok(identities[0].to_public().to_string())
+}
+
+/// Derive the public key of a private key held in memory
+///
+/// `secret_string` is an `AGE-SECRET-KEY-` line, or the whole content of a key file,
+/// such as one kept in a keyring or a vault. The public key of its first identity is
+/// returned, without the secret ever being written to disk.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_public_key_from_secret(secret_string: &str) -> Result<String> {
+    let identities = parse_x25519_identities(secret_string)?;
+    Ok(identities[0].to_public().to_string())
 }
 
 /// Encrypt a file using age with public keys
 /// 
 /// This function encrypts a file using one or more age public keys (recipients).
 /// Supports both ASCII-armored and binary output formats.
+/// `progress`, if not NULL, is an R function called with the bytes processed and the total.
+/// `metadata` records the input's name, mode and modification time in the encrypted file.
+/// `mode`, `owner` and `group`, if not NULL, are given to the encrypted file (see access.rs).
 /// @keywords internal
 /// @noRd
 #[extendr]
-fn age_encrypt_key(input_file_path: &str, output_file_path: &str, recipients: Vec<String>, armor: bool) -> Result<()> {
-    use age::armor::ArmoredWriter;
-    use std::io::{BufWriter, Write};
-    
-    // Parse recipients
-    let mut parsed_recipients = Vec::new();
-    for recipient_str in recipients {
-        let recipient = recipient_str.parse::<age::x25519::Recipient>()
-            .map_err(|e| Error::Other(format!("Invalid recipient '{}': {}", recipient_str, e)))?;
-        parsed_recipients.push(Box::new(recipient) as Box<dyn age::Recipient>);
-    }
-    
-    if parsed_recipients.is_empty() {
-        return Err(Error::Other("At least one recipient is required".to_string()));
-    }
-    
-    // Read input file
-    let input_data = std::fs::read(input_file_path)
-        .map_err(|_| Error::Other("Failed to read input file".to_string()))?;
-    
-    // Create encryptor
-    let encryptor = age::Encryptor::with_recipients(parsed_recipients.iter().map(|r| r.as_ref()))
-        .map_err(|e| Error::Other(format!("Failed to create encryptor: {}", e)))?;
-    
-    // Create output file
-    let output_file = std::fs::File::create(output_file_path)
-        .map_err(|_| Error::Other("Failed to create output file".to_string()))?;
-    
-    // Wrap output writer based on armor setting
-    let mut writer: Box<dyn Write> = if armor {
-        use age::armor::Format;
-        Box::new(ArmoredWriter::wrap_output(BufWriter::new(output_file), Format::AsciiArmor)
-            .map_err(|e| Error::Other(format!("Failed to create armored writer: {}", e)))?)
-    } else {
-        Box::new(BufWriter::new(output_file))
-    };
-    
-    // Encrypt and write
-    let mut encrypted_writer = encryptor.wrap_output(&mut writer)
-        .map_err(|e| Error::Other(format!("Failed to wrap output for encryption: {}", e)))?;
-    
-    encrypted_writer.write_all(&input_data)
-        .map_err(|e| Error::Other(format!("Failed to write encrypted data: {}", e)))?;
-    
-    encrypted_writer.finish()
-        .map_err(|e| Error::Other(format!("Failed to finalize encryption: {}", e)))?;
-    
-    writer.flush()
-        .map_err(|e| Error::Other(format!("Failed to flush output: {}", e)))?;
-    
-    Ok(())
+#[allow(clippy::too_many_arguments)]
+fn age_encrypt_key(
+    input_file_path: &str,
+    output_file_path: &str,
+    recipients: Vec<String>,
+    armor: bool,
+    overwrite: bool,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+    mmap: bool,
+    metadata: bool,
+    progress: Nullable<Function>,
+) -> Result<()> {
+    let keys = recipients.clone();
+    let result = (|| {
+        // Parse recipients
+        let parsed_recipients = parse_recipients(recipients)?;
+
+        // Create encryptor
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+
+        encrypt_file(encryptor, input_file_path, output_file_path, armor, overwrite, Access::from_r(mode, owner, group)?, mmap, metadata, progress)
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(Some(input_file_path), output_file_path, done.is_some(), audit::recipient_keys(keys))
+    })
 }
 
 /// Encrypt a file using age with a passphrase
 /// 
 /// This function encrypts a file using a passphrase-based encryption.
+/// `work_factor` sets the scrypt log-N parameter (NULL to let age target about one second).
+/// `progress`, if not NULL, is an R function called with the bytes processed and the total.
+/// `passphrase_source`, if not NULL, names where to read the passphrase instead (`env:NAME`, `file:PATH` or `fd:N`).
+/// `metadata` records the input's name, mode and modification time in the encrypted file.
+/// `mode`, `owner` and `group`, if not NULL, are given to the encrypted file (see access.rs).
 /// @keywords internal
 /// @noRd
 #[extendr]
-fn age_encrypt_passphrase(input_file_path: &str, output_file_path: &str, passphrase: &str) -> Result<()> {
-    use age::secrecy::SecretString;
-    use std::io::{BufWriter, Write};
-    
-    // Create scrypt encryptor from passphrase
-    let secret_pass = SecretString::from(passphrase.to_owned());
-    let encryptor = age::Encryptor::with_user_passphrase(secret_pass);
-    
-    // Read input file
-    let input_data = std::fs::read(input_file_path)
-        .map_err(|_| Error::Other("Failed to read input file".to_string()))?;
-    
-    // Create output file
-    let output_file = std::fs::File::create(output_file_path)
-        .map_err(|_| Error::Other("Failed to create output file".to_string()))?;
-    
-    let mut writer = BufWriter::new(output_file);
-    
-    // Encrypt and write
-    let mut encrypted_writer = encryptor.wrap_output(&mut writer)
-        .map_err(|e| Error::Other(format!("Failed to wrap output for encryption: {}", e)))?;
-    
-    encrypted_writer.write_all(&input_data)
-        .map_err(|e| Error::Other(format!("Failed to write encrypted data: {}", e)))?;
-    
-    encrypted_writer.finish()
-        .map_err(|e| Error::Other(format!("Failed to finalize encryption: {}", e)))?;
-    
-    writer.flush()
-        .map_err(|e| Error::Other(format!("Failed to flush output: {}", e)))?;
-    
-    Ok(())
+#[allow(clippy::too_many_arguments)]
+fn age_encrypt_passphrase(
+    input_file_path: &str,
+    output_file_path: &str,
+    passphrase: &str,
+    passphrase_source: Nullable<String>,
+    work_factor: Nullable<i32>,
+    overwrite: bool,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+    mmap: bool,
+    metadata: bool,
+    progress: Nullable<Function>,
+) -> Result<()> {
+    use std::iter;
+    let result = (|| {
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+
+        // Create scrypt encryptor from passphrase
+        let recipient = scrypt_recipient(&passphrase, work_factor)?;
+        let encryptor = policy::encryptor(iter::once(&recipient as _))?;
+
+        // Passphrase-encrypted files are always binary
+        encrypt_file(encryptor, input_file_path, output_file_path, false, overwrite, Access::from_r(mode, owner, group)?, mmap, metadata, progress)
+    })();
+    audit::record("encrypt", "passphrase", result, |done| {
+        audit::Details::encrypted_file(Some(input_file_path), output_file_path, done.is_some(), Vec::new())
+    })
 }
 
 /// Encrypt a string using age with public keys
 /// 
 /// This function encrypts a string using one or more age public keys (recipients).
-/// Returns the encrypted content as a base64-encoded string or ASCII armor.
+/// Returns the encrypted content as text in the given `encoding`.
 /// @keywords internal
 /// @noRd
 #[extendr]
-fn age_encrypt_string_with_key(input_string: &str, recipients: Vec<String>, armor: bool) -> Result<String> {
-    use age::armor::ArmoredWriter;
-    use std::io::Write;
-    
-    // Parse recipients (reuse logic from age_encrypt_key)
-    let mut parsed_recipients = Vec::new();
-    for recipient_str in recipients {
-        let recipient = recipient_str.parse::<age::x25519::Recipient>()
-            .map_err(|e| Error::Other(format!("Invalid recipient '{}': {}", recipient_str, e)))?;
-        parsed_recipients.push(Box::new(recipient) as Box<dyn age::Recipient>);
-    }
-    
-    if parsed_recipients.is_empty() {
-        return Err(Error::Other("At least one recipient is required".to_string()));
+fn age_encrypt_string_with_key(input_string: &str, recipients: Vec<String>, encoding: &str) -> Result<String> {
+    let keys = recipients.clone();
+    let result = (|| {
+        // Parse recipients (reuse logic from age_encrypt_key)
+        let parsed_recipients = parse_recipients(recipients)?;
+
+        // Create encryptor (reuse from age_encrypt_key)
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+
+        encrypt_string(encryptor, input_string, encoding)
+    })();
+    audit::record("encrypt", "key", result, |encrypted| {
+        audit::Details::string(encrypted.map(String::as_str), audit::recipient_keys(keys))
+    })
+}
+
+/// Encrypt a string, returning the binary age file in a text `encoding`
+fn encrypt_string(encryptor: age::Encryptor, input_string: &str, encoding: &str) -> Result<String> {
+    encrypt_bytes(encryptor, input_string.as_bytes(), encoding)
+}
+
+/// Encrypt bytes, returning the binary age file in a text `encoding`
+fn encrypt_bytes(encryptor: age::Encryptor, input: &[u8], encoding: &str) -> Result<String> {
+    let encoding = StringEncoding::parse(encoding)?;
+    let encoded = encoding.encode(&encrypt_binary(encryptor, input)?)?;
+    if encoded.len() > R_STRING_MAX {
+        return Err(LockboxError::Encoding(format!(
+            "The encrypted string would be {} bytes, more than an R string can hold; use raw_encrypt() instead",
+            encoded.len()
+        )).into());
     }
+    Ok(encoded)
+}
+
+/// Room for the binary age file of `len` bytes of plaintext
+///
+/// age seals the payload in 64 KiB chunks with a 16-byte tag each, after a header of a
+/// few hundred bytes. Reserving all of it up front means buffers of hundreds of
+/// megabytes, such as serialized Arrow tables, are encrypted without reallocating.
+pub(crate) fn encrypted_capacity(len: usize) -> usize {
+    const PAYLOAD_CHUNK: usize = 64 * 1024;
+    512 + 16 + len + (len / PAYLOAD_CHUNK + 1) * 16
+}
+
+/// Encrypt bytes in memory, returning the binary age file
+fn encrypt_binary(encryptor: age::Encryptor, input: &[u8]) -> Result<Vec<u8>> {
+    use std::io::Write;
+
+    let timer = Timer::start();
+    let mut output_buffer = Vec::with_capacity(encrypted_capacity(input.len()));
+    let mut encrypted_writer = encryptor.wrap_output(&mut output_buffer)
+        .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
     
-    // Create encryptor (reuse from age_encrypt_key)
-    let encryptor = age::Encryptor::with_recipients(parsed_recipients.iter().map(|r| r.as_ref()))
-        .map_err(|e| Error::Other(format!("Failed to create encryptor: {}", e)))?;
-    
-    // Use in-memory buffer instead of file
-    let mut output_buffer = Vec::new();
+    encrypted_writer.write_all(input)
+        .map_err(|e| LockboxError::io("Failed to write encrypted data", &e))?;
     
-    if armor {
-        // Handle ASCII armor case specially
-        use age::armor::Format;
-        let mut armored_writer = ArmoredWriter::wrap_output(&mut output_buffer, Format::AsciiArmor)
-            .map_err(|e| Error::Other(format!("Failed to create armored writer: {}", e)))?;
-        
-        // Encrypt and write to armored writer
-        let mut encrypted_writer = encryptor.wrap_output(&mut armored_writer)
-            .map_err(|e| Error::Other(format!("Failed to wrap output for encryption: {}", e)))?;
-        
-        encrypted_writer.write_all(input_string.as_bytes())
-            .map_err(|e| Error::Other(format!("Failed to write encrypted data: {}", e)))?;
-        
-        encrypted_writer.finish()
-            .map_err(|e| Error::Other(format!("Failed to finalize encryption: {}", e)))?;
-        
-        // Must finish the armored writer to get complete output
-        armored_writer.finish()
-            .map_err(|e| Error::Other(format!("Failed to finalize armored writer: {}", e)))?;
-        
-        // Return ASCII armor as string
-        Ok(String::from_utf8(output_buffer)
-            .map_err(|e| Error::Other(format!("Failed to convert armored output to string: {}", e)))?)
-    } else {
-        // Handle binary case - encrypt directly to buffer
-        let mut encrypted_writer = encryptor.wrap_output(&mut output_buffer)
-            .map_err(|e| Error::Other(format!("Failed to wrap output for encryption: {}", e)))?;
-        
-        encrypted_writer.write_all(input_string.as_bytes())
-            .map_err(|e| Error::Other(format!("Failed to write encrypted data: {}", e)))?;
-        
-        encrypted_writer.finish()
-            .map_err(|e| Error::Other(format!("Failed to finalize encryption: {}", e)))?;
-        
-        // Return binary as base64
-        use base64::{Engine as _, engine::general_purpose};
-        Ok(general_purpose::STANDARD.encode(&output_buffer))
-    }
+    encrypted_writer.finish()
+        .map_err(|e| LockboxError::io("Failed to finalize encryption", &e))?;
+    stats::add_bytes(Direction::Encrypt, input.len() as u64);
+    logging::event(Level::Debug, "payload", &[
+        ("direction", Value::Label("encrypt")),
+        ("bytes", Value::Number(input.len() as u64)),
+        ("ms", Value::Elapsed(timer)),
+    ]);
+
+    Ok(output_buffer)
 }
 
 /// Encrypt a string using age with a passphrase
 /// 
 /// This function encrypts a string using a passphrase-based encryption.
 /// Returns the encrypted content as a base64-encoded string.
+/// Returns the encrypted content as text in the given `encoding`.
+/// `passphrase_source`, if not NULL, names where to read the passphrase instead (`env:NAME`, `file:PATH` or `fd:N`).
 /// @keywords internal
 /// @noRd
 #[extendr]
-fn age_encrypt_string_with_passphrase(input_string: &str, passphrase: &str) -> Result<String> {
-    use age::secrecy::SecretString;
-    use std::io::Write;
-    
-    // Create scrypt encryptor (reuse from age_encrypt_passphrase)
-    let secret_pass = SecretString::from(passphrase.to_owned());
-    let encryptor = age::Encryptor::with_user_passphrase(secret_pass);
-    
-    // Use in-memory buffer instead of file
-    let mut output_buffer = Vec::new();
-    
-    // Encrypt and write (similar to age_encrypt_passphrase)
-    let mut encrypted_writer = encryptor.wrap_output(&mut output_buffer)
-        .map_err(|e| Error::Other(format!("Failed to wrap output for encryption: {}", e)))?;
-    
-    encrypted_writer.write_all(input_string.as_bytes())
-        .map_err(|e| Error::Other(format!("Failed to write encrypted data: {}", e)))?;
-    
-    encrypted_writer.finish()
-        .map_err(|e| Error::Other(format!("Failed to finalize encryption: {}", e)))?;
-    
-    // Return as base64-encoded string
-    use base64::{Engine as _, engine::general_purpose};
-    Ok(general_purpose::STANDARD.encode(&output_buffer))
+fn age_encrypt_string_with_passphrase(input_string: &str, passphrase: &str, passphrase_source: Nullable<String>, work_factor: Nullable<i32>, encoding: &str) -> Result<String> {
+    use std::iter;
+    let result = (|| {
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+
+        // Create scrypt encryptor (reuse from age_encrypt_passphrase)
+        let recipient = scrypt_recipient(&passphrase, work_factor)?;
+        let encryptor = policy::encryptor(iter::once(&recipient as _))?;
+
+        encrypt_string(encryptor, input_string, encoding)
+    })();
+    audit::record("encrypt", "passphrase", result, |encrypted| {
+        audit::Details::string(encrypted.map(String::as_str), Vec::new())
+    })
+}
+
+/// Bytes of an encrypted string in any of the text encodings
+///
+/// With `lenient`, the string is text with an armored age file somewhere in it (see
+/// `encoding::extract_armor()`).
+fn encrypted_string_bytes(encrypted_string: &str, lenient: bool) -> Result<Vec<u8>> {
+    if lenient {
+        return Ok(encoding::extract_armor(encrypted_string)?.into_bytes());
+    }
+    Ok(decode_encrypted(encrypted_string)?)
+}
+
+/// The longest string R can hold, in bytes
+const R_STRING_MAX: usize = i32::MAX as usize;
+
+/// Convert decrypted bytes to an R string
+///
+/// UTF-8 is validated in place and the bytes are copied straight into the R string,
+/// so no unwiped Rust copy of the plaintext outlives the call.
+fn decrypted_string(decrypted_bytes: &[u8]) -> Result<Robj> {
+    if decrypted_bytes.len() > R_STRING_MAX {
+        return Err(LockboxError::Encoding(format!(
+            "The decrypted content is {} bytes, more than an R string can hold; use raw_decrypt() instead",
+            decrypted_bytes.len()
+        )).into());
+    }
+    let decrypted_string = std::str::from_utf8(decrypted_bytes)
+        .map_err(|e| LockboxError::Encoding(format!("Failed to convert decrypted content to UTF-8: {}", e)))?;
+    Ok(Robj::from(decrypted_string))
 }
 
 /// Decrypt an encrypted string using a passphrase
 /// 
 /// This function decrypts a base64-encoded or ASCII-armored encrypted string using a passphrase.
 /// Returns the decrypted content as a string.
+/// `max_work_factor` caps the scrypt work a header may request (NULL for age's default).
+/// `passphrase_source`, if not NULL, names where to read the passphrase instead (`env:NAME`, `file:PATH` or `fd:N`).
+/// `lenient` looks for an armored block anywhere in the string, such as a pasted email.
 /// @keywords internal
 /// @noRd
 #[extendr]
-fn age_decrypt_string_with_passphrase(encrypted_string: &str, passphrase: &str) -> Result<String> {
-    use age::secrecy::SecretString;
+fn age_decrypt_string_with_passphrase(encrypted_string: &str, passphrase: &str, passphrase_source: Nullable<String>, max_work_factor: Nullable<i32>, lenient: bool) -> Result<Robj> {
     use std::iter;
-    
-    // Handle both ASCII armor and base64-encoded binary
-    let encrypted_bytes = if encrypted_string.starts_with("-----BEGIN AGE ENCRYPTED FILE-----") {
-        // For ASCII armor, we need to include the full string with newlines properly
-        encrypted_string.as_bytes().to_vec()
-    } else {
-        // For base64-encoded binary, decode first
-        use base64::{Engine as _, engine::general_purpose};
-        general_purpose::STANDARD.decode(encrypted_string)
-            .map_err(|e| Error::Other(format!("Failed to decode base64: {}", e)))?
-    };
-    
-    // Create scrypt identity (reuse from age_decrypt_with_passphrase)
-    let secret_pass = SecretString::from(passphrase.to_owned());
-    let identity = age::scrypt::Identity::new(secret_pass);
-    
-    
-    // Decrypt using existing decrypt_content function
-    let decrypted_bytes = decrypt_content(&encrypted_bytes, iter::once(&identity as _))?;
-    
-    // Convert to string
-    String::from_utf8(decrypted_bytes)
-        .map_err(|e| Error::Other(format!("Failed to convert decrypted content to UTF-8: {}", e)))
+    let result = (|| {
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+
+        let encrypted_bytes = encrypted_string_bytes(encrypted_string, lenient)?;
+
+        // Create scrypt identity (reuse from age_decrypt_with_passphrase)
+        let identity = scrypt_identity(&passphrase, max_work_factor)?;
+
+        // Decrypt using existing decrypt_content function
+        let decrypted_bytes = decrypt_content(&encrypted_bytes, iter::once(&identity as _), &mut Progress::none())?;
+
+        decrypted_string(&decrypted_bytes)
+    })();
+    audit::record("decrypt", "passphrase", result, |_| audit::Details::string(Some(encrypted_string), Vec::new()))
 }
 
 /// Decrypt an encrypted string using a private key
 /// 
 /// This function decrypts a base64-encoded or ASCII-armored encrypted string using a private key.
 /// Returns the decrypted content as a string.
+/// `lenient` looks for an armored block anywhere in the string, such as a pasted email.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_string_with_key(encrypted_string: &str, private_key_path: &str, strict_permissions: bool, lenient: bool) -> Result<Robj> {
+    let result = (|| {
+        let encrypted_bytes = encrypted_string_bytes(encrypted_string, lenient)?;
+
+        // Read private key file (reuse from age_decrypt_with_key)
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+
+        // Parse identities using existing function
+        let identities = parse_identities_from_key_file(&key_content)?;
+
+        // Decrypt using existing decrypt_content function
+        let decrypted_bytes = decrypt_content(&encrypted_bytes, identities.iter().map(|i| i.as_ref()), &mut Progress::none())?;
+
+        decrypted_string(&decrypted_bytes)
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::string(Some(encrypted_string), audit::key_file_public_keys(private_key_path))
+    })
+}
+
+/// Detect whether a file is age-encrypted
+///
+/// This function reads only the first bytes of a file and classifies it as a binary
+/// age file ("binary"), an ASCII-armored age file ("armored"), or anything else ("not-age").
 /// @keywords internal
 /// @noRd
 #[extendr]
-fn age_decrypt_string_with_key(encrypted_string: &str, private_key_path: &str) -> Result<String> {
-    // Handle both ASCII armor and base64-encoded binary
-    let encrypted_bytes = if encrypted_string.starts_with("-----BEGIN AGE ENCRYPTED FILE-----") {
-        // For ASCII armor, we need to include the full string with newlines properly
-        encrypted_string.as_bytes().to_vec()
+fn age_is_encrypted(file_path: &str) -> Result<String> {
+    const AGE_MAGIC: &[u8] = b"age-encryption.org/v1";
+
+    let file = std::fs::File::open(fs_path(file_path))
+        .map_err(|e| LockboxError::io("Failed to open file", &e))?;
+
+    // Armored files may have blank lines before their marker, so read a little more
+    let mut header = Vec::with_capacity(stream::HEAD_LENGTH as usize);
+    file.take(stream::HEAD_LENGTH).read_to_end(&mut header)
+        .map_err(|e| LockboxError::io("Failed to read file", &e))?;
+
+    let format = if header.starts_with(AGE_MAGIC) {
+        "binary"
+    } else if stream::is_armored(&header) {
+        "armored"
     } else {
-        // For base64-encoded binary, decode first
-        use base64::{Engine as _, engine::general_purpose};
-        general_purpose::STANDARD.decode(encrypted_string)
-            .map_err(|e| Error::Other(format!("Failed to decode base64: {}", e)))?
+        "not-age"
     };
-    
-    // Read private key file (reuse from age_decrypt_with_key)
-    let key_content = std::fs::read_to_string(private_key_path)
-        .map_err(|_| Error::Other("Failed to read private key file".to_string()))?;
-    
-    // Parse identities using existing function
-    let identities = parse_identities_from_key_file(&key_content)?;
-    
-    
-    // Decrypt using existing decrypt_content function
-    let decrypted_bytes = decrypt_content(&encrypted_bytes, identities.iter().map(|i| i.as_ref()))?;
-    
-    // Convert to string
-    String::from_utf8(decrypted_bytes)
-        .map_err(|e| Error::Other(format!("Failed to convert decrypted content to UTF-8: {}", e)))
+
+    Ok(format.to_string())
+}
+
+/// Generate a random passphrase from the BIP39 wordlist
+/// 
+/// This function draws `word_count` words uniformly from the 2048-word BIP39 list using
+/// the operating system's CSPRNG and joins them with hyphens, like `rage -p`.
+/// Each word contributes 11 bits of entropy.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_generate_passphrase(word_count: i32) -> Result<String> {
+    use rand::distributions::{Distribution, Uniform};
+    use rand::rngs::OsRng;
+
+    if word_count < 1 {
+        return Err(LockboxError::InvalidArgument("`word_count` must be a positive integer".to_string()).into());
+    }
+
+    let words: Vec<&str> = BIP39_WORDLIST.lines().collect();
+    let between = Uniform::from(0..words.len());
+    let passphrase = (0..word_count)
+        .map(|_| words[between.sample(&mut OsRng)])
+        .collect::<Vec<_>>()
+        .join("-");
+
+    Ok(passphrase)
+}
+
+/// Check that a private key file is only accessible by its owner
+/// 
+/// Returns a description of the problem when group or other permission bits are set,
+/// or an empty string when the permissions are fine (always on non-Unix platforms).
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_check_key_permissions(key_file_path: &str) -> Result<String> {
+    Ok(key_permission_problem(key_file_path)?.unwrap_or_default())
 }
 
 // Register the Rust functions with R's extendr system
 // This macro generates the necessary C bindings for R to call our Rust functions
 extendr_module! {
     mod lockbox;
+    use hybrid;
+    use recipients;
+    use vault;
+    use env;
+    use structured;
+    use strings;
+    use convergent;
+    use manifest;
+    use signing;
+    use git;
+    use pipe;
+    use http;
+    use s3;
+    use cache;
+    use identity;
+    use prompt;
+    use tempfile;
+    use shred;
+    use convert;
+    use bundle;
+    use archive;
+    use raw;
+    use metadata;
+    use backup;
+    use parallel;
+    use bench;
+    use limit;
+    use logging;
+    use batch;
+    use project;
+    use team;
+    use audit;
+    use policy;
+    use blobs;
+    use split;
+    use resume;
+    use buffers;
+    use jobs;
+    use selftest;
+    use version;
+    use clipboard;
+    use keyinfo;
+    use keystore;
+    use mnemonic;
+    use plugin;
+    use qr;
+    use resolver;
+    use shamir;
+    use stats;
     fn age_decrypt_with_passphrase;
     fn age_decrypt_with_key;
+    fn age_decrypt_file_with_passphrase;
     fn age_generate_key;
     fn age_extract_public_key;
+    fn age_public_key_from_secret;
     fn age_encrypt_key;
     fn age_encrypt_passphrase;
     fn age_encrypt_string_with_key;
     fn age_encrypt_string_with_passphrase;
     fn age_decrypt_string_with_passphrase;
     fn age_decrypt_string_with_key;
+    fn age_is_encrypted;
+    fn age_generate_passphrase;
+    fn age_check_key_permissions;
 }
@@ -0,0 +1,103 @@
+// Limits on failed passphrase decryptions
+//
+// scrypt already makes each guess cost about a second, but an app that decrypts
+// whatever passphrase a form submits still lets its users guess for as long as they
+// like. When a limit is set, failures are counted for the rest of the session: after
+// `attempts` of them within `period`, passphrase decryption is refused until the
+// oldest one expires, and after any failure the next attempt must wait `delay`.
+// Refused attempts never run scrypt, so they cost the server nothing, and nothing
+// sleeps, so one user cannot stall an R process that serves others.
+use extendr_api::prelude::*;
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::error::LockboxError;
+
+struct Limit {
+    attempts: usize,
+    period: Duration,
+    delay: Duration,
+    failures: VecDeque<Instant>,
+}
+
+static LIMIT: Mutex<Option<Limit>> = Mutex::new(None);
+
+fn limit() -> MutexGuard<'static, Option<Limit>> {
+    LIMIT.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Refuse a passphrase decryption that the limit does not allow yet
+pub(crate) fn check_passphrase_attempt() -> Result<()> {
+    let mut guard = limit();
+    let limit = match guard.as_mut() {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    let now = Instant::now();
+    while limit.failures.front().map_or(false, |t| now.duration_since(*t) >= limit.period) {
+        limit.failures.pop_front();
+    }
+
+    if limit.failures.len() >= limit.attempts {
+        let wait = limit.failures.front().map_or(Duration::ZERO, |t| limit.period - now.duration_since(*t));
+        return Err(LockboxError::RateLimited(format!(
+            "Too many failed passphrase attempts ({} in {} seconds); try again in {} seconds",
+            limit.failures.len(),
+            limit.period.as_secs_f64().ceil(),
+            wait.as_secs_f64().ceil()
+        )).into());
+    }
+    if let Some(since) = limit.failures.back().map(|t| now.duration_since(*t)).filter(|since| *since < limit.delay) {
+        return Err(LockboxError::RateLimited(format!(
+            "The last passphrase attempt failed; try again in {} seconds",
+            (limit.delay - since).as_secs_f64().ceil()
+        )).into());
+    }
+    Ok(())
+}
+
+/// Count a failed passphrase decryption against the limit, if one is set
+pub(crate) fn record_passphrase_failure() {
+    if let Some(limit) = limit().as_mut() {
+        limit.failures.push_back(Instant::now());
+        // Only the latest `attempts` failures can matter
+        while limit.failures.len() > limit.attempts {
+            limit.failures.pop_front();
+        }
+    }
+}
+
+/// Set or remove the session's limit on failed passphrase decryptions
+///
+/// With `attempts` NULL, the limit is removed. Setting a limit forgets earlier failures.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_passphrase_limit(attempts: Nullable<i32>, period: f64, delay: f64) -> Result<()> {
+    let attempts = match attempts {
+        Nullable::Null => {
+            *limit() = None;
+            return Ok(());
+        }
+        Nullable::NotNull(n) if n < 1 => {
+            return Err(LockboxError::InvalidArgument(format!("`attempts` must be at least 1, not {}", n)).into());
+        }
+        Nullable::NotNull(n) => n as usize,
+    };
+    let seconds = |value: f64, name: &str| {
+        if value.is_finite() && (0.0..1e12).contains(&value) {
+            Ok(Duration::from_secs_f64(value))
+        } else {
+            Err(Error::from(LockboxError::InvalidArgument(format!("`{}` must be a non-negative number of seconds", name))))
+        }
+    };
+    let (period, delay) = (seconds(period, "period")?, seconds(delay, "delay")?);
+    *limit() = Some(Limit { attempts, period, delay, failures: VecDeque::new() });
+    Ok(())
+}
+
+extendr_module! {
+    mod limit;
+    fn age_passphrase_limit;
+}
@@ -0,0 +1,186 @@
+// Debug log of the encryption pipeline
+//
+// When an ETL job fails at 3am with "the file is corrupt", the question is where:
+// reading the key, parsing the header, or halfway through the payload. Once a session
+// sets a log level, the pipeline writes one JSON object per event, to a file or to the
+// process's standard error, which is what a scheduler captures:
+//
+//     {"time":"2026-01-05T03:12:44.118Z","pid":4242,"level":"debug","event":"payload",
+//      "direction":"decrypt","bytes":104857600,"ms":812.4}
+//
+// At "info", each encryption or decryption logs its outcome and error code; at
+// "debug", the stages in between do too: key files read, identities and recipients
+// parsed by kind, headers opened, payloads streamed with their sizes and timings.
+//
+// A log is only useful if it can be handed to someone, so nothing secret may reach
+// it. Rather than relying on each call site to leave secrets out, fields can only be
+// numbers, flags, labels fixed in the source, paths and error codes (see `Value`):
+// there is no way to log a string built at run time, so no key, passphrase,
+// plaintext or error message, which may quote a mistyped key, can be written.
+//
+// Events come from worker threads too, so writing never calls into R.
+use extendr_api::prelude::*;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+use crate::error::{error_parts, LockboxError};
+use crate::paths::fs_path;
+
+/// How much is logged; events above the session's level are skipped
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Level {
+    Off = 0,
+    Info = 1,
+    Debug = 2,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    fn current() -> Self {
+        match LEVEL.load(Ordering::Relaxed) {
+            0 => Level::Off,
+            1 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Off as u8);
+
+/// Where events go: a file opened for appending, or standard error when None
+static SINK: Mutex<Option<File>> = Mutex::new(None);
+
+fn sink() -> MutexGuard<'static, Option<File>> {
+    SINK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The value of an event field, restricted to what cannot hold a secret
+pub(crate) enum Value<'a> {
+    /// A count or a size in bytes
+    Number(u64),
+    Flag(bool),
+    /// A word written in the source, such as "armor"
+    Label(&'static str),
+    /// A file path, which names data without holding it
+    Path(&'a Path),
+    /// Milliseconds since a `Timer` started
+    Elapsed(Timer),
+    /// The code of a failed operation, such as "wrong_key", without its message
+    Failure(&'a Error),
+}
+
+impl Value<'_> {
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Number(n) => (*n).into(),
+            Value::Flag(flag) => (*flag).into(),
+            Value::Label(label) => (*label).into(),
+            Value::Path(path) => path.to_string_lossy().into(),
+            Value::Elapsed(timer) => ((timer.0.elapsed().as_secs_f64() * 10_000.0).round() / 10.0).into(),
+            // Codes are `[a-z_]` words from error.rs; anything else is not ours to log
+            Value::Failure(err) => {
+                let (code, _) = error_parts(err);
+                let code = if code.bytes().all(|b| b.is_ascii_lowercase() || b == b'_') { code } else { "error".to_string() };
+                code.into()
+            }
+        }
+    }
+}
+
+/// The start of a stage, for logging how long it took
+#[derive(Clone, Copy)]
+pub(crate) struct Timer(Instant);
+
+impl Timer {
+    pub(crate) fn start() -> Self {
+        Timer(Instant::now())
+    }
+}
+
+/// Whether events at `level` are logged, for call sites that must work to gather fields
+pub(crate) fn enabled(level: Level) -> bool {
+    level != Level::Off && level <= Level::current()
+}
+
+/// Log an event, if the session's level includes `level`
+///
+/// A log that cannot be written is left behind silently: debugging output must never
+/// make an operation fail.
+pub(crate) fn event(level: Level, name: &'static str, fields: &[(&'static str, Value)]) {
+    if !enabled(level) {
+        return;
+    }
+    let mut entry = serde_json::Map::new();
+    entry.insert("time".into(), chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string().into());
+    entry.insert("pid".into(), std::process::id().into());
+    entry.insert("level".into(), level.name().into());
+    entry.insert("event".into(), name.into());
+    for (key, value) in fields {
+        entry.insert((*key).into(), value.to_json());
+    }
+    let line = format!("{}\n", serde_json::Value::Object(entry));
+    let _ = match sink().as_mut() {
+        Some(file) => file.write_all(line.as_bytes()),
+        None => std::io::stderr().write_all(line.as_bytes()),
+    };
+}
+
+/// Set the session's log level, and the file events are appended to
+///
+/// `level` is "off", "info" or "debug". With `path` NULL, events go to standard
+/// error. The file is created readable by its owner only.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_set_log_level(level: &str, path: Nullable<String>) -> Result<()> {
+    let level = match level {
+        "off" => Level::Off,
+        "info" => Level::Info,
+        "debug" => Level::Debug,
+        _ => {
+            return Err(LockboxError::InvalidArgument(format!(
+                "The log level must be \"off\", \"info\" or \"debug\", got \"{}\"",
+                level
+            )).into());
+        }
+    };
+    let file = match path {
+        Nullable::NotNull(path) => {
+            let mut options = std::fs::OpenOptions::new();
+            options.create(true).append(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            Some(options.open(fs_path(&path))
+                .map_err(|e| LockboxError::io(format!("Failed to open the log file '{}'", path), &e))?)
+        }
+        Nullable::Null => None,
+    };
+    *sink() = file;
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// The session's log level
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_log_level() -> &'static str {
+    Level::current().name()
+}
+
+extendr_module! {
+    mod logging;
+    fn age_set_log_level;
+    fn age_log_level;
+}
@@ -0,0 +1,268 @@
+// Integrity manifests for encrypted deliverables
+//
+// A manifest lists encrypted files with the size and hash of their ciphertext and,
+// when a private key is available, of their plaintext. The ciphertext hash lets
+// anyone check that a delivered file is byte-for-byte what was produced; the
+// plaintext hash lets a recipient check the content even after it was re-encrypted.
+//
+// Manifests are plain JSON with a fixed field order and paths relative to the
+// manifest, so the same manifest file can be signed and moved with its files.
+use extendr_api::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::access::Access;
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stream::{age_input, copy_interruptible, Progress};
+use crate::{create_output_file, parse_identities_from_key_file, read_key_file};
+
+/// Version of the manifest format
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    algorithm: String,
+    created: String,
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    path: String,
+    size: u64,
+    hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    plaintext_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    plaintext_hash: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum HashAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    fn parse(algorithm: &str) -> Result<Self> {
+        match algorithm {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(LockboxError::InvalidArgument(format!(
+                "Unsupported hash algorithm '{}': use \"sha256\" or \"blake3\"",
+                algorithm
+            )).into()),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+/// A running hash that bytes can be copied into
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    /// Lowercase hex digest
+    fn finish(self) -> String {
+        let digest: Vec<u8> = match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        };
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(buf),
+            Hasher::Blake3(hasher) => {
+                hasher.update(buf);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Size and hash of a file's bytes
+fn hash_file(path: &Path, algorithm: HashAlgorithm) -> Result<(u64, String)> {
+    let mut file = File::open(path)
+        .map_err(|e| LockboxError::io(format!("Failed to read '{}'", path.display()), &e))?;
+    let mut hasher = algorithm.hasher();
+    let size = copy_interruptible(&mut file, &mut hasher, &mut Progress::none(), |e| {
+        LockboxError::io(format!("Failed to read '{}'", path.display()), &e)
+    })?;
+    Ok((size, hasher.finish()))
+}
+
+/// Size and hash of an encrypted file's plaintext, decrypted as a stream
+fn hash_plaintext(path: &Path, identities: &[Box<dyn age::Identity>], algorithm: HashAlgorithm) -> Result<(u64, String)> {
+    policy::check_decrypt_from(path)?;
+    let file = File::open(path)
+        .map_err(|e| LockboxError::io(format!("Failed to read '{}'", path.display()), &e))?;
+    let decryptor = age::Decryptor::new(age_input(BufReader::new(file))?)
+        .map_err(|e| LockboxError::from_decrypt(e, false))?;
+    let scrypt = decryptor.is_scrypt();
+    let mut decrypted_reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))
+        .map_err(|e| LockboxError::from_decrypt(e, scrypt))?;
+
+    let mut hasher = algorithm.hasher();
+    let size = copy_interruptible(&mut decrypted_reader, &mut hasher, &mut Progress::none(), LockboxError::from_payload)?;
+    Ok((size, hasher.finish()))
+}
+
+/// Identities from an optional private key file
+fn optional_identities(private_key_path: Nullable<String>, strict_permissions: bool) -> Result<Option<Vec<Box<dyn age::Identity>>>> {
+    match private_key_path {
+        Nullable::NotNull(path) => {
+            let key_content = read_key_file(&path, strict_permissions)?;
+            Ok(Some(parse_identities_from_key_file(&key_content)?))
+        }
+        Nullable::Null => Ok(None),
+    }
+}
+
+/// Directory that the paths in a manifest are relative to
+fn manifest_dir(manifest_path: &str) -> PathBuf {
+    Path::new(manifest_path).parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+/// Create a manifest of encrypted files
+///
+/// Paths inside the manifest's directory are recorded relative to it, others as
+/// given. With a private key, plaintext sizes and hashes are recorded as well.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_manifest(
+    file_paths: Vec<String>,
+    manifest_path: &str,
+    private_key_path: Nullable<String>,
+    strict_permissions: bool,
+    algorithm: &str,
+    overwrite: bool,
+) -> Result<()> {
+    let algorithm = HashAlgorithm::parse(algorithm)?;
+    let identities = optional_identities(private_key_path, strict_permissions)?;
+    let base = manifest_dir(manifest_path);
+
+    let mut files = Vec::with_capacity(file_paths.len());
+    for file_path in &file_paths {
+        let path = Path::new(file_path);
+        let (size, hash) = hash_file(&fs_path(file_path), algorithm)?;
+        let (plaintext_size, plaintext_hash) = match &identities {
+            Some(identities) => {
+                let (size, hash) = hash_plaintext(&fs_path(file_path), identities, algorithm)?;
+                (Some(size), Some(hash))
+            }
+            None => (None, None),
+        };
+
+        // Forward slashes keep manifests portable between platforms
+        let recorded = path.strip_prefix(&base).unwrap_or(path);
+        let recorded = recorded.to_string_lossy().replace('\\', "/");
+        files.push(ManifestEntry { path: recorded, size, hash, plaintext_size, plaintext_hash });
+    }
+
+    let manifest = Manifest {
+        version: MANIFEST_VERSION,
+        algorithm: algorithm.name().to_string(),
+        created: chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        files,
+    };
+    let json = serde_json::to_string_pretty(&manifest)
+        .map_err(|e| LockboxError::Encoding(format!("Failed to write manifest: {}", e)))?;
+
+    let mut output = create_output_file(manifest_path, overwrite, Access::default())?;
+    output.write_all(json.as_bytes())
+        .and_then(|_| output.write_all(b"\n"))
+        .map_err(|e| LockboxError::io("Failed to write manifest", &e))?;
+    Ok(())
+}
+
+/// Check every entry of a manifest, returning `(path, status)` pairs
+fn verify_entries(manifest_path: &str, identities: Option<&[Box<dyn age::Identity>]>) -> Result<Vec<(String, &'static str)>> {
+    let content = std::fs::read_to_string(fs_path(manifest_path))
+        .map_err(|e| LockboxError::io("Failed to read manifest", &e))?;
+    let manifest: Manifest = serde_json::from_str(&content)
+        .map_err(|e| LockboxError::Encoding(format!("The file is not a lockbox manifest: {}", e)))?;
+    if manifest.version > MANIFEST_VERSION {
+        return Err(LockboxError::Encoding(format!(
+            "The manifest uses format version {}, which this version of lockbox cannot read",
+            manifest.version
+        )).into());
+    }
+    let algorithm = HashAlgorithm::parse(&manifest.algorithm)?;
+    let base = manifest_dir(manifest_path);
+
+    let mut results = Vec::with_capacity(manifest.files.len());
+    for entry in manifest.files {
+        let path = fs_path(&base.join(&entry.path).to_string_lossy());
+        let status = if !path.is_file() {
+            "missing"
+        } else if hash_file(&path, algorithm)? == (entry.size, entry.hash.clone()) {
+            "ok"
+        } else {
+            // A re-encrypted file differs byte for byte, but its content may not. If the
+            // key cannot decrypt it any more, the content cannot be vouched for either.
+            match (identities, entry.plaintext_size, &entry.plaintext_hash) {
+                (Some(identities), Some(size), Some(hash)) => match hash_plaintext(&path, identities, algorithm) {
+                    Ok(plaintext) if plaintext == (size, hash.clone()) => "reencrypted",
+                    _ => "modified",
+                },
+                _ => "modified",
+            }
+        };
+        results.push((entry.path, status));
+    }
+    Ok(results)
+}
+
+/// Verify the files listed in a manifest
+///
+/// Returns a list with the recorded `path` of each file and its `status`: "ok",
+/// "missing", "modified", or "reencrypted" (different ciphertext, same plaintext;
+/// only detected with a private key).
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_verify_manifest(manifest_path: &str, private_key_path: Nullable<String>, strict_permissions: bool) -> Result<List> {
+    let identities = optional_identities(private_key_path, strict_permissions)?;
+    let results = verify_entries(manifest_path, identities.as_deref())?;
+
+    let (paths, statuses): (Vec<String>, Vec<String>) = results.into_iter()
+        .map(|(path, status)| (path, status.to_string()))
+        .unzip();
+    Ok(list!(path = paths, status = statuses))
+}
+
+extendr_module! {
+    mod manifest;
+    fn age_manifest;
+    fn age_verify_manifest;
+}
@@ -0,0 +1,127 @@
+// File metadata inside encrypted files
+//
+// age encrypts content only: the original name, permissions and modification time
+// are lost, so a decrypted backup comes back as a new file. On request they are
+// recorded in a small block at the start of the plaintext, where age authenticates
+// them along with the content:
+//
+//     lockbox-metadata/v1\n{"name":"report.csv","mode":420,"mtime":1718000000.5}\n
+//
+// The JSON is compact and so holds no newline of its own. `file_decrypt()` strips the
+// block and restores what it records; other age tools see it as part of the content.
+// The record comes from whoever encrypted the file, so a mode outside 0000-7777 marks
+// it as damaged, and R restores a mode only within the permissions the caller allows.
+use extendr_api::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+
+const METADATA_MAGIC: &[u8] = b"lockbox-metadata/v1\n";
+/// Longest metadata block accepted when reading
+const METADATA_MAX: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct FileMetadata {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<f64>,
+}
+
+#[cfg(unix)]
+pub(crate) fn file_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Modification time in seconds since the Unix epoch
+pub(crate) fn file_mtime(metadata: &std::fs::Metadata) -> Option<f64> {
+    metadata.modified().ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|since| since.as_secs_f64())
+}
+
+/// The metadata block for a file about to be encrypted
+pub(crate) fn metadata_block(path: &str) -> Result<Vec<u8>> {
+    let path = fs_path(path);
+    let metadata = std::fs::metadata(&path)
+        .map_err(|e| LockboxError::io(format!("Failed to read the metadata of '{}'", path.display()), &e))?;
+    let record = FileMetadata {
+        name: path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+        mode: file_mode(&metadata),
+        mtime: file_mtime(&metadata),
+    };
+
+    let mut block = METADATA_MAGIC.to_vec();
+    serde_json::to_writer(&mut block, &record)
+        .map_err(|e| LockboxError::Encoding(format!("Failed to record file metadata: {}", e)))?;
+    block.push(b'\n');
+    Ok(block)
+}
+
+/// Longest metadata block, so that reading this much of the content is always enough
+pub(crate) const METADATA_BLOCK_MAX: usize = METADATA_MAGIC.len() + METADATA_MAX + 1;
+
+/// The record and length of the metadata block at the start of `head`, if there is one
+fn parse_block(head: &[u8]) -> std::result::Result<Option<(FileMetadata, usize)>, LockboxError> {
+    let rest = match head.strip_prefix(METADATA_MAGIC) {
+        Some(rest) => rest,
+        None => return Ok(None),
+    };
+    let damaged = || LockboxError::CorruptPayload("The file metadata recorded at encryption is damaged".to_string());
+    let end = rest.iter().take(METADATA_MAX).position(|b| *b == b'\n').ok_or_else(damaged)?;
+    let record: FileMetadata = serde_json::from_slice(&rest[..end]).map_err(|_| damaged())?;
+    if record.mode.map_or(false, |mode| mode > 0o7777) {
+        return Err(damaged());
+    }
+    Ok(Some((record, METADATA_MAGIC.len() + end + 1)))
+}
+
+/// Length of the metadata block at the start of decrypted content, or 0 without one
+pub(crate) fn metadata_length(head: &[u8]) -> Result<usize> {
+    Ok(parse_block(head)?.map_or(0, |(_, length)| length))
+}
+
+/// Path of the file to decrypt into `dir`, under the name recorded in `head`
+pub(crate) fn recorded_output(dir: &str, head: &[u8]) -> Result<String> {
+    let name = parse_block(head)?.map(|(record, _)| record.name).unwrap_or_default();
+    let usable = !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\']);
+    if !usable {
+        return Err(LockboxError::InvalidArgument(format!(
+            "'{}' is a directory, and the encrypted file records no usable file name",
+            dir
+        )).into());
+    }
+    Ok(std::path::Path::new(dir).join(name).to_string_lossy().into_owned())
+}
+
+/// Read the metadata block at the start of decrypted content
+///
+/// `head` is the start of the content, which must begin with the block. Returns
+/// the recorded name, mode and modification time, and the size of the block.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_parse_metadata(head: Raw) -> Result<List> {
+    let (record, size) = parse_block(head.as_slice())?
+        .ok_or_else(|| LockboxError::CorruptPayload("The file metadata recorded at encryption is damaged".to_string()))?;
+
+    Ok(list!(
+        name = record.name,
+        mode = record.mode.map(|mode| mode as i32),
+        mtime = record.mtime,
+        size = size as f64
+    ))
+}
+
+extendr_module! {
+    mod metadata;
+    fn age_parse_metadata;
+}
@@ -0,0 +1,155 @@
+// Paper backups of private keys as BIP39 words
+//
+// An x25519 private key is 32 random bytes, which BIP39 writes as 24 words from its
+// 2048-word list: 256 bits of key and an 8-bit checksum, the first byte of the key's
+// SHA-256, 11 bits per word. Written down, the words are a backup that never touches
+// a disk or a cloud drive; typed back in, they give the same key file line, and a
+// mistyped or swapped word fails the checksum instead of yielding a wrong key.
+//
+// The words are the wordlist lockbox already uses for passphrases, so a backup reads
+// like one, but only these 24 words in this order make up the key.
+use age::secrecy::ExposeSecret;
+use bech32::{FromBase32, ToBase32, Variant};
+use extendr_api::prelude::*;
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+use crate::access::Access;
+use crate::error::LockboxError;
+use crate::{create_output_file, parse_x25519_identities, read_key_file, BIP39_WORDLIST};
+
+/// Words of a mnemonic for a 32-byte key
+const MNEMONIC_WORDS: usize = 24;
+
+/// Bech32 prefix of age private keys, which age writes in upper case
+const SECRET_KEY_HRP: &str = "age-secret-key-";
+
+/// The 32 bytes of an x25519 private key
+pub(crate) fn secret_bytes(identity: &age::x25519::Identity) -> Result<Zeroizing<[u8; 32]>> {
+    let encoded = identity.to_string();
+    let (_, data, _) = bech32::decode(encoded.expose_secret())
+        .map_err(|e| LockboxError::InvalidIdentity(format!("Failed to read the private key: {}", e)))?;
+    let bytes = Zeroizing::new(Vec::<u8>::from_base32(&data)
+        .map_err(|e| LockboxError::InvalidIdentity(format!("Failed to read the private key: {}", e)))?);
+    let mut secret = Zeroizing::new([0u8; 32]);
+    if bytes.len() != secret.len() {
+        return Err(LockboxError::InvalidIdentity("The private key is not 32 bytes long".to_string()).into());
+    }
+    secret.copy_from_slice(&bytes);
+    Ok(secret)
+}
+
+/// The 24 words of a private key, the key's bits followed by its checksum
+fn to_words(secret: &[u8; 32]) -> Vec<&'static str> {
+    let words: Vec<&str> = BIP39_WORDLIST.lines().collect();
+    let checksum = Sha256::digest(secret)[0];
+    let bit = |i: usize| -> usize {
+        let byte = if i < 256 { secret[i / 8] } else { checksum };
+        ((byte >> (7 - i % 8)) & 1) as usize
+    };
+    (0..MNEMONIC_WORDS)
+        .map(|w| words[(0..11).fold(0, |index, b| (index << 1) | bit(w * 11 + b))])
+        .collect()
+}
+
+/// The private key of 24 words, checking their checksum
+///
+/// Errors name the position of a word, not the word itself, which is part of the key.
+fn from_words(mnemonic: &[&str]) -> std::result::Result<Zeroizing<[u8; 32]>, LockboxError> {
+    if mnemonic.len() != MNEMONIC_WORDS {
+        return Err(LockboxError::InvalidArgument(format!(
+            "A key mnemonic has {} words, not {}",
+            MNEMONIC_WORDS,
+            mnemonic.len()
+        )));
+    }
+    let words: Vec<&str> = BIP39_WORDLIST.lines().collect();
+    let mut bits = Zeroizing::new(Vec::with_capacity(MNEMONIC_WORDS * 11));
+    for (position, word) in mnemonic.iter().enumerate() {
+        let index = words.iter().position(|w| w.eq_ignore_ascii_case(word)).ok_or_else(|| {
+            LockboxError::InvalidArgument(format!("Word {} of the mnemonic is not in the BIP39 English wordlist", position + 1))
+        })?;
+        bits.extend((0..11).rev().map(|b| (index >> b) & 1 == 1));
+    }
+    let byte = |i: usize| bits[i * 8..i * 8 + 8].iter().fold(0u8, |byte, &bit| (byte << 1) | bit as u8);
+
+    let mut secret = Zeroizing::new([0u8; 32]);
+    for (i, b) in secret.iter_mut().enumerate() {
+        *b = byte(i);
+    }
+    if Sha256::digest(*secret)[0] != byte(32) {
+        return Err(LockboxError::InvalidArgument(
+            "The mnemonic's checksum does not match: a word is wrong or the words are out of order".to_string(),
+        ));
+    }
+    Ok(secret)
+}
+
+/// Encode the private key of a key file as 24 BIP39 words
+///
+/// The key file must hold exactly one private key.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_key_to_mnemonic(key_file_path: &str, strict_permissions: bool) -> Result<Strings> {
+    let key_content = read_key_file(key_file_path, strict_permissions)?;
+    let identities = parse_x25519_identities(&key_content)?;
+    if identities.len() != 1 {
+        return Err(LockboxError::InvalidIdentity(format!(
+            "The key file '{}' holds {} private keys; a mnemonic covers exactly one",
+            key_file_path,
+            identities.len()
+        )).into());
+    }
+    let secret = secret_bytes(&identities[0])?;
+    Ok(Strings::from_values(to_words(&secret)))
+}
+
+/// The x25519 private key made of 32 bytes
+pub(crate) fn identity_from_bytes(secret: &[u8; 32]) -> Result<age::x25519::Identity> {
+    let encoded = Zeroizing::new(bech32::encode(SECRET_KEY_HRP, secret.to_base32(), Variant::Bech32)
+        .map_err(|e| LockboxError::InvalidIdentity(format!("Failed to encode the private key: {}", e)))?
+        .to_uppercase());
+    age::x25519::Identity::from_str(&encoded)
+        .map_err(|e| LockboxError::InvalidIdentity(format!("Failed to encode the private key: {}", e)).into())
+}
+
+/// Save a recovered private key to a new key file, readable by its owner only
+///
+/// The file records when the key was recovered and its public key, which is returned.
+pub(crate) fn write_recovered_key(identity: &age::x25519::Identity, key_file_path: &str, overwrite: bool) -> Result<String> {
+    use std::io::Write;
+
+    let recipient = identity.to_public();
+    let key_file = Zeroizing::new(format!("# recovered: {}\n# public key: {}\n{}\n",
+        chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC"),
+        recipient,
+        identity.to_string().expose_secret()
+    ));
+    let mut file = create_output_file(key_file_path, overwrite, Access::OWNER_ONLY)?;
+    file.write_all(key_file.as_bytes())
+        .map_err(|e| LockboxError::io("Failed to write key file", &e))?;
+
+    Ok(recipient.to_string())
+}
+
+/// Recover a private key from its 24 BIP39 words and save it to a key file
+///
+/// Words may be given one per element or separated by spaces, in any case. An existing
+/// file is only replaced when `overwrite` is true. Returns the public key.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_key_from_mnemonic(words: Vec<String>, key_file_path: &str, overwrite: bool) -> Result<String> {
+    let words = Zeroizing::new(words);
+    let mnemonic: Vec<&str> = words.iter().flat_map(|w| w.split_whitespace()).collect();
+    let secret = from_words(&mnemonic)?;
+    write_recovered_key(&identity_from_bytes(&secret)?, key_file_path, overwrite)
+}
+
+extendr_module! {
+    mod mnemonic;
+    fn age_key_to_mnemonic;
+    fn age_key_from_mnemonic;
+}
@@ -0,0 +1,256 @@
+// Decryption of large files straight to disk, in parallel
+//
+// age encrypts the payload in 64 KiB chunks, each sealed on its own with a nonce that
+// is just its position, so once the payload key is known any chunk can be decrypted
+// independently of the others. The age crate keeps the key to itself, but its
+// decrypting reader can seek: each worker opens its own decryptor on the file, seeks
+// to its share of the chunks, and writes them to the same place in the output file.
+// Every worker checks the header MAC, and seeking to the end authenticates the final
+// chunk, so truncated or reordered files fail exactly as they do when read in order.
+//
+// Only binary files take the parallel path. Armored files cannot be seeked into
+// cheaply, and small files are not worth the threads; both are streamed to disk on
+// one thread. Workers use concrete x25519 identities, never call into R, and stop
+// when the main thread, which watches for interrupts, asks them to.
+use extendr_api::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+use crate::access::Access;
+use crate::audit;
+use crate::buffers::{read_buffer_size, write_buffer_size};
+use crate::error::LockboxError;
+use crate::metadata::{metadata_length, recorded_output};
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stats::{self, Direction};
+use crate::stream::{check_age_file, check_interrupt, decrypt_to_file, read_head, PartialOutput, Progress, CHUNK_SIZE};
+use crate::{create_output_file, parse_identities_from_key_file, parse_x25519_identities, read_key_file};
+
+/// Plaintext size of an age payload chunk
+const AGE_CHUNK_SIZE: u64 = 64 * 1024;
+/// Files smaller than this are decrypted on one thread
+const PARALLEL_MIN_SIZE: u64 = 8 * 1024 * 1024;
+/// How often the main thread checks for interrupts and reports progress while workers run
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const BINARY_MAGIC: &[u8] = b"age-encryption.org/v1\n";
+
+type Decrypting = age::stream::StreamReader<BufReader<File>>;
+
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// A decrypting reader over a binary age file
+fn open_binary(path: &Path, identities: &[age::x25519::Identity]) -> std::result::Result<Decrypting, LockboxError> {
+    let input = File::open(path)
+        .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", path.display()), &e))?;
+    let decryptor = age::Decryptor::new(BufReader::with_capacity(read_buffer_size(path), input))
+        .map_err(|e| LockboxError::from_decrypt(e, false))?;
+    let scrypt = decryptor.is_scrypt();
+    decryptor.decrypt(identities.iter().map(|i| i as &dyn age::Identity))
+        .map_err(|e| LockboxError::from_decrypt(e, scrypt))
+}
+
+fn is_binary(path: &Path) -> Result<bool> {
+    let mut magic = Vec::with_capacity(BINARY_MAGIC.len());
+    File::open(path)
+        .and_then(|file| file.take(BINARY_MAGIC.len() as u64).read_to_end(&mut magic))
+        .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", path.display()), &e))?;
+    Ok(magic == BINARY_MAGIC)
+}
+
+/// Decrypt the plaintext bytes `from..to` into the output file, `skip` bytes earlier
+fn decrypt_range(
+    input: &Path,
+    identities: &[age::x25519::Identity],
+    output: &Path,
+    (from, to): (u64, u64),
+    skip: u64,
+    cancelled: &AtomicBool,
+    done: &AtomicU64,
+) -> std::result::Result<(), LockboxError> {
+    let mut reader = open_binary(input, identities)?;
+    reader.seek(SeekFrom::Start(from)).map_err(LockboxError::from_payload)?;
+    let write_failed = |e: std::io::Error| LockboxError::io("Failed to write output", &e);
+    let mut writer = OpenOptions::new().write(true).open(output).map_err(write_failed)?;
+    writer.seek(SeekFrom::Start(from - skip)).map_err(write_failed)?;
+    let mut writer = BufWriter::with_capacity(write_buffer_size(output), writer);
+
+    let mut buffer = Zeroizing::new(vec![0u8; CHUNK_SIZE]);
+    let mut remaining = to - from;
+    while remaining > 0 && !cancelled.load(Ordering::Relaxed) {
+        let n = remaining.min(CHUNK_SIZE as u64) as usize;
+        reader.read_exact(&mut buffer[..n]).map_err(LockboxError::from_payload)?;
+        writer.write_all(&buffer[..n]).map_err(write_failed)?;
+        remaining -= n as u64;
+        done.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    writer.flush().map_err(write_failed)
+}
+
+/// Decrypt a binary file on `threads` workers, returning the metadata block it starts with
+fn decrypt_parallel(
+    input: &Path,
+    identities: &[age::x25519::Identity],
+    output: &str,
+    into_directory: bool,
+    access: Access,
+    threads: usize,
+    progress: &mut Progress,
+) -> Result<Zeroizing<Vec<u8>>> {
+    policy::check_decrypt_from(input)?;
+    check_age_file(input)?;
+    let mut reader = open_binary(input, identities)?;
+    let length = reader.seek(SeekFrom::End(0)).map_err(LockboxError::from_payload)?;
+    reader.seek(SeekFrom::Start(0)).map_err(LockboxError::from_payload)?;
+    let head = read_head(&mut reader, LockboxError::from_payload)?;
+    let skip = metadata_length(&head)? as u64;
+    drop(reader);
+
+    let output_file_path = &if into_directory { recorded_output(output, &head)? } else { output.to_string() };
+    policy::check_plaintext_to(&fs_path(output_file_path))?;
+    let output = create_output_file(output_file_path, false, access)?;
+    let partial = PartialOutput::new(output_file_path);
+    output.set_len(length - skip)
+        .map_err(|e| LockboxError::io("Failed to write output", &e))?;
+    drop(output);
+    let output = fs_path(output_file_path);
+
+    // Whole chunks per worker, so that no chunk is decrypted twice
+    let chunks = (length + AGE_CHUNK_SIZE - 1) / AGE_CHUNK_SIZE;
+    let workers = (threads as u64).min(chunks).max(1);
+    let ranges: Vec<(u64, u64)> = (0..workers)
+        .map(|w| ((chunks * w / workers * AGE_CHUNK_SIZE).max(skip), (chunks * (w + 1) / workers * AGE_CHUNK_SIZE).min(length)))
+        .filter(|(from, to)| from < to)
+        .collect();
+
+    let cancelled = AtomicBool::new(false);
+    let done = AtomicU64::new(0);
+    let ciphertext_size = file_size(input);
+    let (finished, finishing) = std::sync::mpsc::channel();
+    let result: Result<()> = std::thread::scope(|scope| {
+        let handles: Vec<_> = ranges.iter()
+            .map(|range| {
+                let (cancelled, done, output, finished) = (&cancelled, &done, &output, finished.clone());
+                scope.spawn(move || {
+                    let result = decrypt_range(input, identities, output, *range, skip, cancelled, done);
+                    if result.is_err() {
+                        cancelled.store(true, Ordering::Relaxed);
+                    }
+                    let _ = finished.send(());
+                    result
+                })
+            })
+            .collect();
+
+        let mut running = handles.len();
+        let mut stopped: Result<()> = Ok(());
+        while running > 0 {
+            match finishing.recv_timeout(POLL_INTERVAL) {
+                Ok(()) => running -= 1,
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+            // Progress counts ciphertext, like the other decryption functions
+            let processed = done.load(Ordering::Relaxed) as f64 / (length - skip).max(1) as f64;
+            progress.set_processed((processed * ciphertext_size as f64) as u64);
+            if stopped.is_ok() {
+                stopped = check_interrupt().and_then(|_| progress.update());
+                if stopped.is_err() {
+                    cancelled.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+        let mut failure = None;
+        for handle in handles {
+            match handle.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => failure = failure.or(Some(e)),
+                Err(_) => failure = failure.or_else(|| Some(LockboxError::DecryptFailed("A decryption worker failed".to_string()))),
+            }
+        }
+        stopped?;
+        match failure {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    });
+    result?;
+    stats::add_bytes(Direction::Decrypt, length - skip);
+
+    progress.set_processed(ciphertext_size);
+    progress.finish()?;
+    partial.complete();
+    Ok(Zeroizing::new(head[..skip as usize].to_vec()))
+}
+
+/// Decrypt a file with a private key straight into an output file
+///
+/// Large binary files are decrypted on `threads` workers (one per core if NULL), unless
+/// the key file holds keys kept by age plugins, which decrypt on this thread. With
+/// `into_directory`, the output path is a directory that receives the file under its
+/// recorded name. The metadata block recorded at encryption, if any, is not written to
+/// the output but returned, for `age_parse_metadata()`; without one, an empty raw
+/// vector is returned. `mode`, `owner` and `group`, if not NULL, are given to the output.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_decrypt_file_with_key(
+    encrypted_file_path: &str,
+    output_file_path: &str,
+    into_directory: bool,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+    private_key_path: &str,
+    strict_permissions: bool,
+    threads: Nullable<i32>,
+    progress: Nullable<Function>,
+) -> Result<Raw> {
+    let result = (|| {
+        let access = Access::from_r(mode, owner, group)?;
+        let threads = match threads {
+            Nullable::NotNull(n) if n < 1 => {
+                return Err(LockboxError::InvalidArgument(format!("`threads` must be at least 1, not {}", n)).into());
+            }
+            Nullable::NotNull(n) => n as usize,
+            Nullable::Null => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let input = fs_path(encrypted_file_path);
+        let size = file_size(&input);
+        let mut progress = Progress::new(progress, size);
+
+        // Keys kept by age plugins cannot be handed to worker threads
+        if key_content.contains("AGE-PLUGIN-") {
+            let identities = parse_identities_from_key_file(&key_content)?;
+            let identities = identities.iter().map(|i| i.as_ref());
+            let metadata = decrypt_to_file(&input, identities, output_file_path, into_directory, access, &mut progress)?;
+            return Ok(Raw::from_bytes(&metadata));
+        }
+        let identities = parse_x25519_identities(&key_content)?;
+
+        let parallel = threads > 1 && size >= PARALLEL_MIN_SIZE && is_binary(&input)?;
+        let metadata = if parallel {
+            decrypt_parallel(&input, &identities, output_file_path, into_directory, access, threads, &mut progress)?
+        } else {
+            let identities = identities.iter().map(|i| i as &dyn age::Identity);
+            decrypt_to_file(&input, identities, output_file_path, into_directory, access, &mut progress)?
+        };
+        Ok(Raw::from_bytes(&metadata))
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::file(encrypted_file_path, Some(output_file_path), audit::key_file_public_keys(private_key_path))
+    })
+}
+
+extendr_module! {
+    mod parallel;
+    fn age_decrypt_file_with_key;
+}
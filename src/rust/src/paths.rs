@@ -0,0 +1,43 @@
+// File system paths from R
+//
+// R hands paths over as UTF-8 strings (the R side converts them with `enc2utf8()`, as
+// `normalizePath()` returns the native encoding on Windows). Rust's standard library
+// then opens them through the wide-character Windows API, so accented folder names
+// work whatever the locale.
+//
+// Windows still limits ordinary paths to 260 characters, and deep project folders on
+// network shares exceed that. Longer absolute paths are turned into extended-length
+// paths (`\\?\C:\...` and `\\?\UNC\server\share\...`), which lift the limit but are
+// passed to the file system verbatim: separators must be backslashes and `.` or `..`
+// components are not resolved, which `normalizePath()` has already done.
+use std::path::PathBuf;
+
+/// Paths at least this long need the extended-length form on Windows
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+/// The path to hand to the file system for a path received from R
+#[cfg(windows)]
+pub(crate) fn fs_path(path: &str) -> PathBuf {
+    if path.len() < MAX_PATH || path.starts_with(r"\\?\") || path.starts_with(r"\\.\") {
+        return PathBuf::from(path);
+    }
+    let path = path.replace('/', "\\");
+    if path.split('\\').any(|component| component == "." || component == "..") {
+        return PathBuf::from(path);
+    }
+    let bytes = path.as_bytes();
+    if let Some(unc) = path.strip_prefix(r"\\") {
+        PathBuf::from(format!(r"\\?\UNC\{}", unc))
+    } else if bytes.len() > 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && bytes[2] == b'\\' {
+        PathBuf::from(format!(r"\\?\{}", path))
+    } else {
+        PathBuf::from(path)
+    }
+}
+
+/// The path to hand to the file system for a path received from R
+#[cfg(not(windows))]
+pub(crate) fn fs_path(path: &str) -> PathBuf {
+    PathBuf::from(path)
+}
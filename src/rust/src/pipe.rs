@@ -0,0 +1,143 @@
+// Streaming between pipes, for use in shell pipelines
+//
+// Inputs and outputs may be standard input and output ("-"), named pipes, character
+// devices such as /dev/fd/3, or regular files. Data is processed in chunks as it
+// arrives, so neither plaintext nor ciphertext is ever held in full or written to a
+// temporary file.
+//
+// Unlike regular files, a pipe cannot be taken back: when decrypting, each payload
+// chunk is written out as soon as it is authenticated, and a stream that turns out to
+// be truncated or corrupted fails only after the preceding chunks were delivered.
+use extendr_api::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use crate::access::Access;
+use crate::audit;
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stats::{self, Direction};
+use crate::stream::{age_input, check_age_file, copy_interruptible, PartialOutput, Progress};
+use crate::{create_output_file, parse_identities_from_key_file, parse_recipients, read_key_file};
+
+/// Name that stands for standard input or output
+const STANDARD_STREAM: &str = "-";
+
+fn open_input(input: &str) -> Result<Box<dyn Read>> {
+    if input == STANDARD_STREAM {
+        return Ok(Box::new(std::io::stdin().lock()));
+    }
+    let file = File::open(fs_path(input))
+        .map_err(|e| LockboxError::io(format!("Failed to open '{}' for reading", input), &e))?;
+    Ok(Box::new(file))
+}
+
+/// Open an output, returning it and, for a new regular file, a guard that removes it on failure
+///
+/// Existing pipes and devices are written to as they are; regular files follow the
+/// usual rules of `overwrite` and are created with `access`.
+fn open_output(output: &str, overwrite: bool, access: Access) -> Result<(Box<dyn Write>, Option<PartialOutput>)> {
+    if output == STANDARD_STREAM {
+        return Ok((Box::new(std::io::stdout().lock()), None));
+    }
+    let is_special = std::fs::metadata(fs_path(output)).map(|m| !m.is_file()).unwrap_or(false);
+    if is_special {
+        let file = OpenOptions::new().write(true).open(fs_path(output))
+            .map_err(|e| LockboxError::io(format!("Failed to open '{}' for writing", output), &e))?;
+        return Ok((Box::new(file), None));
+    }
+    let file = create_output_file(output, overwrite, access)?;
+    Ok((Box::new(file), Some(PartialOutput::new(output))))
+}
+
+/// Encrypt a stream to public keys
+///
+/// `input` and `output` are paths, named pipes, or "-" for standard input and output.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_pipe_encrypt(input: &str, output: &str, recipients: Vec<String>, armor: bool, overwrite: bool) -> Result<()> {
+    use age::armor::{ArmoredWriter, Format};
+
+    let keys = recipients.clone();
+    let result = (|| {
+        policy::check_armor(armor)?;
+
+        let parsed_recipients = parse_recipients(recipients)?;
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+
+        let mut reader = open_input(input)?;
+        let (writer, partial) = open_output(output, overwrite, Access::default())?;
+
+        let format = if armor { Format::AsciiArmor } else { Format::Binary };
+        let armored_writer = ArmoredWriter::wrap_output(BufWriter::new(writer), format)
+            .map_err(|e| LockboxError::Armor(format!("Failed to create armored writer: {}", e)))?;
+        let mut encrypted_writer = encryptor.wrap_output(armored_writer)
+            .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+
+        let bytes = copy_interruptible(&mut reader, &mut encrypted_writer, &mut Progress::none(), |e| {
+            LockboxError::io(format!("Failed to read '{}'", input), &e)
+        })?;
+        encrypted_writer.finish()
+            .and_then(|armored_writer| armored_writer.finish())
+            .and_then(|mut writer| writer.flush())
+            .map_err(|e| LockboxError::io("Failed to finalize encryption", &e))?;
+        stats::add_bytes(Direction::Encrypt, bytes);
+
+        if let Some(partial) = partial {
+            partial.complete();
+        }
+        Ok(())
+    })();
+    audit::record("encrypt", "key", result, |_| audit::Details::stream(input, output, audit::recipient_keys(keys)))
+}
+
+/// Decrypt a binary or armored stream with a private key
+///
+/// `input` and `output` are paths, named pipes, or "-" for standard input and output.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_pipe_decrypt(input: &str, output: &str, private_key_path: &str, strict_permissions: bool, overwrite: bool) -> Result<()> {
+    let result = (|| {
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+
+        if input != "-" {
+            policy::check_decrypt_from(&fs_path(input))?;
+            check_age_file(&fs_path(input))?;
+        }
+        if output != "-" {
+            policy::check_plaintext_to(&fs_path(output))?;
+        }
+        let reader = open_input(input)?;
+        let decryptor = age::Decryptor::new(age_input(BufReader::new(reader))?)
+            .map_err(|e| LockboxError::from_decrypt(e, false))?;
+        let scrypt = decryptor.is_scrypt();
+        let mut decrypted_reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))
+            .map_err(|e| LockboxError::from_decrypt(e, scrypt))?;
+
+        // The output is only opened once the key is known to match
+        let (writer, partial) = open_output(output, overwrite, Access::OWNER_ONLY)?;
+        let mut writer = BufWriter::new(writer);
+        let bytes = copy_interruptible(&mut decrypted_reader, &mut writer, &mut Progress::none(), LockboxError::from_payload)?;
+        writer.flush()
+            .map_err(|e| LockboxError::io("Failed to flush output", &e))?;
+        stats::add_bytes(Direction::Decrypt, bytes);
+
+        if let Some(partial) = partial {
+            partial.complete();
+        }
+        Ok(())
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::stream(input, output, audit::key_file_public_keys(private_key_path))
+    })
+}
+
+extendr_module! {
+    mod pipe;
+    fn age_pipe_encrypt;
+    fn age_pipe_decrypt;
+}
@@ -0,0 +1,149 @@
+// Keys held by age plugins
+//
+// A private key in a file can be copied by anything that reads the disk. An age
+// plugin keeps it in hardware instead: `age-plugin-tpm` in the TPM of a Linux or
+// Windows laptop, `age-plugin-se` in the Secure Enclave of a Mac, and
+// `age-plugin-fido2-hmac` in a FIDO2 security key, which has to be touched for every
+// decryption. The key file then only holds an `AGE-PLUGIN-...` identity naming the
+// hardware key, and the public key is a recipient such as `age1tpm1...`.
+//
+// age runs the plugin binary, found on the PATH as `age-plugin-<name>`, for every
+// encryption to such a recipient and every decryption with such an identity. The
+// plugin may ask for a PIN, which is prompted for like a passphrase, ask a question
+// on the terminal, or show a message, such as a request to touch the security key,
+// which goes to the R console.
+use age::secrecy::SecretString;
+use extendr_api::prelude::*;
+use std::collections::BTreeMap;
+
+use crate::error::LockboxError;
+use crate::prompt;
+use crate::read_key_file;
+
+/// The user interface offered to plugins
+#[derive(Clone, Copy)]
+pub(crate) struct PluginCallbacks;
+
+impl age::Callbacks for PluginCallbacks {
+    fn display_message(&self, message: &str) {
+        extendr_api::reprintln!("{}", message);
+    }
+
+    // Questions are asked on the terminal; without one, the plugin is told there is no
+    // one to ask, and decides for itself. Only an explicit yes approves; an empty answer
+    // declines
+    fn confirm(&self, message: &str, yes_string: &str, no_string: Option<&str>) -> Option<bool> {
+        let no_string = no_string.unwrap_or("no");
+        let answer = prompt::prompt_line(&format!("{} [{}/{}] ", message, yes_string, no_string)).ok()?;
+        let answer = answer.trim();
+        let approved = ["y", "yes", yes_string].iter()
+            .any(|yes| !answer.is_empty() && answer.eq_ignore_ascii_case(yes));
+        Some(approved)
+    }
+
+    fn request_public_string(&self, description: &str) -> Option<String> {
+        prompt::prompt_line(&format!("{} ", description)).ok()
+    }
+
+    fn request_passphrase(&self, description: &str) -> Option<SecretString> {
+        prompt::prompt_passphrase(description)
+            .ok()
+            .map(|pin| SecretString::from(pin.as_str().to_owned()))
+    }
+}
+
+/// Whether a public key is a plugin recipient, such as `age1tpm1...`
+pub(crate) fn is_plugin_recipient(recipient: &str) -> bool {
+    recipient.parse::<age::plugin::Recipient>().is_ok()
+}
+
+/// One recipient per plugin for the plugin recipients among `recipients`
+///
+/// Each plugin encrypts to all of its recipients at once.
+pub(crate) fn recipients(recipients: &[String]) -> Result<Vec<Box<dyn age::Recipient>>> {
+    let mut by_plugin: BTreeMap<String, Vec<age::plugin::Recipient>> = BTreeMap::new();
+    for recipient in recipients {
+        let parsed = recipient.parse::<age::plugin::Recipient>()
+            .map_err(|e| LockboxError::InvalidRecipient(format!("Invalid recipient '{}': {}", recipient, e)))?;
+        by_plugin.entry(parsed.plugin().to_string()).or_default().push(parsed);
+    }
+    let mut parsed: Vec<Box<dyn age::Recipient>> = Vec::new();
+    for (name, plugin_recipients) in &by_plugin {
+        let recipient = age::plugin::RecipientPluginV1::new(name, plugin_recipients, &[], PluginCallbacks)
+            .map_err(|e| match e {
+                // age's own message wraps the name in Unicode isolation marks
+                age::EncryptError::MissingPlugin { binary_name } => LockboxError::InvalidRecipient(format!(
+                    "Cannot encrypt to {}: {} is not installed, or not on the PATH",
+                    plugin_recipients[0],
+                    binary_name
+                )),
+                e => LockboxError::InvalidRecipient(format!("Cannot encrypt to {}: {}", plugin_recipients[0], e)),
+            })?;
+        parsed.push(Box::new(recipient));
+    }
+    Ok(parsed)
+}
+
+/// The plugin identities of a key file, by plugin name
+fn parse_identities(key_content: &str) -> Result<BTreeMap<String, Vec<age::plugin::Identity>>> {
+    let mut by_plugin: BTreeMap<String, Vec<age::plugin::Identity>> = BTreeMap::new();
+    for (number, line) in key_content.lines().enumerate() {
+        let line = line.trim_matches(|c: char| c.is_whitespace() || c == '\u{feff}');
+        if line.starts_with("AGE-PLUGIN-") {
+            let identity = line.parse::<age::plugin::Identity>().map_err(|e| {
+                LockboxError::InvalidIdentity(format!("Failed to parse the identity on line {} of the key file: {}", number + 1, e))
+            })?;
+            by_plugin.entry(identity.plugin().to_string()).or_default().push(identity);
+        }
+    }
+    Ok(by_plugin)
+}
+
+/// One identity per plugin for the plugin identities of a key file
+pub(crate) fn identities(key_content: &str) -> Result<Vec<Box<dyn age::Identity>>> {
+    let mut parsed: Vec<Box<dyn age::Identity>> = Vec::new();
+    for (name, plugin_identities) in &parse_identities(key_content)? {
+        let identity = age::plugin::IdentityPluginV1::new(name, plugin_identities, PluginCallbacks)
+            .map_err(|e| match e {
+                age::DecryptError::MissingPlugin { binary_name } => LockboxError::InvalidIdentity(format!(
+                    "The key file holds a key kept by {}, which is not installed, or not on the PATH",
+                    binary_name
+                )),
+                e => LockboxError::InvalidIdentity(format!("Cannot use the key held by age-plugin-{}: {}", name, e)),
+            })?;
+        parsed.push(Box::new(identity));
+    }
+    Ok(parsed)
+}
+
+/// The identity that makes a plugin find its keys on its own, such as those of a FIDO2
+/// security key whose credential is kept in the recipient
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_plugin_default_identity(plugin_name: &str) -> Result<String> {
+    let valid = !plugin_name.is_empty()
+        && plugin_name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'-' | b'.' | b'_'));
+    if !valid {
+        return Err(LockboxError::InvalidArgument(format!("'{}' is not a valid age plugin name", plugin_name)).into());
+    }
+    Ok(age::plugin::Identity::default_for_plugin(plugin_name).to_string())
+}
+
+/// The plugins holding the keys of a key file, one name per plugin identity
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_key_plugins(key_file_path: &str, strict_permissions: bool) -> Result<Vec<String>> {
+    let key_content = read_key_file(key_file_path, strict_permissions)?;
+    Ok(parse_identities(&key_content)?
+        .into_iter()
+        .flat_map(|(name, identities)| std::iter::repeat(name).take(identities.len()))
+        .collect())
+}
+
+extendr_module! {
+    mod plugin;
+    fn age_plugin_default_identity;
+    fn age_key_plugins;
+}
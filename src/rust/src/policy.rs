@@ -0,0 +1,258 @@
+// Decryption policy for locked-down environments
+//
+// Where confidential data is analysed on shared servers, administrators need limits
+// that hold whatever an analysis script asks for: decrypt only data from the secure
+// store, never leave plaintext outside a sandbox, never write encrypted files that
+// cannot be pasted into a ticket, never encrypt data the institution cannot recover.
+// A policy is a TOML file:
+//
+//     decrypt_dirs = ["/secure/data"]         # encrypted files are read from here only
+//     plaintext_dirs = ["/secure/sandbox"]    # decrypted files are written here only
+//     require_armor = true                    # encrypted files are written armored
+//     escrow_recipients = ["age1..."]         # everything is also encrypted to these
+//
+// It is named by the LOCKBOX_POLICY environment variable, typically set in
+// Renviron.site, or by `age_policy_use()`. Once a policy is in effect it stays for the
+// rest of the session: it cannot be replaced or removed. A policy that cannot be read
+// refuses every operation it would check rather than allowing them all.
+//
+// Paths are compared after resolving symbolic links and `..`, so a link inside an
+// allowed directory does not lead outside it. The checks sit in the functions that
+// open encrypted files for decryption and create plaintext or encrypted files, so
+// every R function going through them is covered. The policy applies to files;
+// decrypting strings and raw vectors in memory is not restricted.
+//
+// Escrow recipients are the exception: they are added by `encryptor()`, through which
+// every encryption to public keys goes, so strings, raw vectors and files alike can be
+// recovered with the escrow key. A file encrypted with a passphrase cannot have other
+// recipients, so passphrase encryption is refused while escrow recipients are set.
+use extendr_api::prelude::*;
+use serde::Deserialize;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+
+const POLICY_VARIABLE: &str = "LOCKBOX_POLICY";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    decrypt_dirs: Vec<String>,
+    #[serde(default)]
+    plaintext_dirs: Vec<String>,
+    #[serde(default)]
+    require_armor: bool,
+    #[serde(default)]
+    escrow_recipients: Vec<String>,
+}
+
+struct Policy {
+    source: PathBuf,
+    decrypt_dirs: Vec<PathBuf>,
+    plaintext_dirs: Vec<PathBuf>,
+    require_armor: bool,
+    escrow_recipients: Vec<age::x25519::Recipient>,
+}
+
+enum State {
+    /// LOCKBOX_POLICY has not been looked at yet
+    Unread,
+    /// No policy is in effect
+    Unset,
+    Active(Policy),
+    /// The policy file could not be read; every check fails with this message
+    Broken(String),
+}
+
+static POLICY: Mutex<State> = Mutex::new(State::Unread);
+
+/// The session's policy, reading LOCKBOX_POLICY on first use
+fn policy() -> MutexGuard<'static, State> {
+    let mut state = POLICY.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let State::Unread = *state {
+        *state = match std::env::var_os(POLICY_VARIABLE).filter(|path| !path.is_empty()) {
+            Some(path) => match Policy::read(Path::new(&path)) {
+                Ok(policy) => State::Active(policy),
+                Err(e) => State::Broken(e.message().to_string()),
+            },
+            None => State::Unset,
+        };
+    }
+    state
+}
+
+impl Policy {
+    fn read(path: &Path) -> std::result::Result<Self, LockboxError> {
+        let invalid = |why: String| LockboxError::InvalidArgument(format!("Invalid lockbox policy '{}': {}", path.display(), why));
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| LockboxError::io(format!("Failed to read the lockbox policy '{}'", path.display()), &e))?;
+        let file: PolicyFile = toml::from_str(&content).map_err(|e| invalid(e.to_string().trim_end().to_string()))?;
+        let resolve = |dirs: Vec<String>| {
+            dirs.iter()
+                .map(|dir| std::fs::canonicalize(fs_path(dir)).map_err(|e| invalid(format!("'{}': {}", dir, e))))
+                .collect::<std::result::Result<Vec<_>, _>>()
+        };
+        let escrow_recipients = file.escrow_recipients.iter()
+            .map(|recipient| recipient.trim().parse().map_err(|e| invalid(format!("escrow recipient '{}': {}", recipient, e))))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(Policy {
+            source: path.to_path_buf(),
+            decrypt_dirs: resolve(file.decrypt_dirs)?,
+            plaintext_dirs: resolve(file.plaintext_dirs)?,
+            require_armor: file.require_armor,
+            escrow_recipients,
+        })
+    }
+}
+
+/// `path` with links and `..` resolved, for a file that may not exist yet
+///
+/// The deepest existing ancestor is resolved and the rest appended, which must then
+/// be plain names.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    let path = std::env::current_dir().ok()?.join(path);
+    for ancestor in path.ancestors() {
+        if let Ok(resolved) = std::fs::canonicalize(ancestor) {
+            let rest = path.strip_prefix(ancestor).ok()?;
+            if rest.components().all(|c| matches!(c, Component::Normal(_))) {
+                return Some(resolved.join(rest));
+            }
+            return None;
+        }
+    }
+    None
+}
+
+fn list(dirs: &[PathBuf]) -> String {
+    dirs.iter().map(|dir| format!("'{}'", dir.display())).collect::<Vec<_>>().join(", ")
+}
+
+/// Check a path against a list of allowed directories, if the policy has one
+fn check_within(path: &Path, dirs: impl Fn(&Policy) -> &[PathBuf], what: &str) -> Result<()> {
+    let state = policy();
+    let policy = match &*state {
+        State::Active(policy) => policy,
+        State::Broken(message) => return Err(LockboxError::PolicyDenied(message.clone()).into()),
+        _ => return Ok(()),
+    };
+    let dirs = dirs(policy);
+    if dirs.is_empty() {
+        return Ok(());
+    }
+    match resolve(path) {
+        Some(resolved) if dirs.iter().any(|dir| resolved.starts_with(dir)) => Ok(()),
+        _ => Err(LockboxError::PolicyDenied(format!(
+            "The lockbox policy '{}' does not allow {} '{}'; only under {}",
+            policy.source.display(),
+            what,
+            path.display(),
+            list(dirs)
+        )).into()),
+    }
+}
+
+/// Check that the policy lets an encrypted file be decrypted
+pub(crate) fn check_decrypt_from(path: &Path) -> Result<()> {
+    check_within(path, |policy| &policy.decrypt_dirs, "decrypting")
+}
+
+/// Check that the policy lets decrypted plaintext be written to `path`
+pub(crate) fn check_plaintext_to(path: &Path) -> Result<()> {
+    check_within(path, |policy| &policy.plaintext_dirs, "writing plaintext to")
+}
+
+/// Check that the policy lets an encrypted file be written armored or not
+pub(crate) fn check_armor(armor: bool) -> Result<()> {
+    match &*policy() {
+        State::Active(policy) if policy.require_armor && !armor => Err(LockboxError::PolicyDenied(format!(
+            "The lockbox policy '{}' requires encrypted files to be ASCII-armored",
+            policy.source.display()
+        )).into()),
+        State::Broken(message) => Err(LockboxError::PolicyDenied(message.clone()).into()),
+        _ => Ok(()),
+    }
+}
+
+/// The escrow recipients every file must also be encrypted to, none without a policy
+pub(crate) fn escrow_recipients() -> std::result::Result<Vec<age::x25519::Recipient>, LockboxError> {
+    match &*policy() {
+        State::Active(policy) => Ok(policy.escrow_recipients.clone()),
+        State::Broken(message) => Err(LockboxError::PolicyDenied(message.clone())),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// An encryptor for `recipients` and the policy's escrow recipients
+pub(crate) fn encryptor<'a>(recipients: impl Iterator<Item = &'a dyn age::Recipient>) -> std::result::Result<age::Encryptor, LockboxError> {
+    let escrow = escrow_recipients()?;
+    let recipients = recipients
+        .map(|r| -> &dyn age::Recipient { r })
+        .chain(escrow.iter().map(|r| r as &dyn age::Recipient));
+    age::Encryptor::with_recipients(recipients)
+        .map_err(|e| LockboxError::EncryptFailed(format!("Failed to create encryptor: {}", e)))
+}
+
+/// Check that the policy lets files be encrypted with a passphrase
+pub(crate) fn check_passphrase_encryption() -> Result<()> {
+    match &*policy() {
+        State::Active(policy) if !policy.escrow_recipients.is_empty() => Err(LockboxError::PolicyDenied(format!(
+            "The lockbox policy '{}' sets escrow_recipients, which everything must be encrypted to \
+             and a passphrase cannot be combined with; encrypt to public keys instead",
+            policy.source.display()
+        )).into()),
+        State::Broken(message) => Err(LockboxError::PolicyDenied(message.clone()).into()),
+        _ => Ok(()),
+    }
+}
+
+/// Put a policy in effect for the rest of the session
+///
+/// Fails if one is already in effect, including from LOCKBOX_POLICY.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_policy_use(path: &str) -> Result<()> {
+    let mut state = policy();
+    match &*state {
+        State::Active(policy) => Err(LockboxError::PolicyDenied(format!(
+            "The lockbox policy '{}' is in effect and cannot be replaced in this session",
+            policy.source.display()
+        )).into()),
+        State::Broken(message) => Err(LockboxError::PolicyDenied(message.clone()).into()),
+        _ => {
+            *state = State::Active(Policy::read(&fs_path(path))?);
+            Ok(())
+        }
+    }
+}
+
+/// The policy in effect
+///
+/// Returns `source`, `decrypt_dirs`, `plaintext_dirs`, `require_armor` and
+/// `escrow_recipients`, or NULL without a policy.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_policy_info() -> Result<Robj> {
+    let to_strings = |dirs: &[PathBuf]| dirs.iter().map(|dir| dir.to_string_lossy().into_owned()).collect::<Vec<_>>();
+    match &*policy() {
+        State::Active(policy) => Ok(list!(
+            source = policy.source.to_string_lossy().into_owned(),
+            decrypt_dirs = to_strings(&policy.decrypt_dirs),
+            plaintext_dirs = to_strings(&policy.plaintext_dirs),
+            require_armor = policy.require_armor,
+            escrow_recipients = policy.escrow_recipients.iter().map(|r| r.to_string()).collect::<Vec<_>>()
+        ).into()),
+        State::Broken(message) => Err(LockboxError::PolicyDenied(message.clone()).into()),
+        _ => Ok(Robj::from(())),
+    }
+}
+
+extendr_module! {
+    mod policy;
+    fn age_policy_use;
+    fn age_policy_info;
+}
@@ -0,0 +1,308 @@
+// Project configuration in lockbox.toml
+//
+// A project declares at its root who its files are encrypted to and which files are
+// kept encrypted, so every member of a team runs the same two calls instead of
+// repeating recipients and file lists in scripts:
+//
+//     recipients = ["age1...", "alice", "analysts"]
+//     team = "team/recipients.toml"
+//     armor = false
+//
+//     [files]
+//     encrypt = ["data/**/*.csv", "secrets/*"]
+//     exclude = ["scratch/"]
+//
+//     [keys]
+//     private = ["~/.config/lockbox/identity.key", "keys/ci.key"]
+//
+// `encrypt` and `exclude` are patterns as in filter.rs. Each selected file is kept as
+// `<name>.age` next to its plaintext, which is typically left out of version control;
+// `.gitignore` files are therefore not obeyed, or they would hide the very files to
+// encrypt.
+//
+// Recipients may name members and groups of the `team` registry (see team.rs), which
+// then travels with the project. Paths may start with `~` or be relative to the
+// project. Private keys are tried in order and missing ones skipped, so one file
+// serves team members who keep their keys in different places.
+//
+// Syncing is by modification time. Encrypting skips files whose `.age` is at least as
+// new as the plaintext, and decrypting skips plaintext at least as new as its `.age`,
+// so the R side gives both files of a pair the same time after each sync. Plaintext
+// that is older than its `.age` was not edited since, but is only replaced on
+// request: the `.age` may be newer only because git checked it out.
+use extendr_api::prelude::*;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::access::Access;
+use crate::audit;
+use crate::batch::{encrypt_planned, encrypted_size, plan, BatchResults};
+use crate::error::LockboxError;
+use crate::filter::{walk_filtered, PathFilter};
+use crate::metadata::file_mtime;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stream::{age_input, check_interrupt, decrypt_to_file, temp_path_for, PartialOutput, Progress};
+use crate::team::resolve_recipients;
+use crate::{parse_identities_from_key_file, parse_recipients, read_key_file};
+
+const CONFIG_FILE: &str = "lockbox.toml";
+/// Modification times closer than this count as equal, allowing for rounding in R
+const MTIME_TOLERANCE: f64 = 0.001;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProjectConfig {
+    recipients: Vec<String>,
+    #[serde(default)]
+    team: Option<String>,
+    #[serde(default)]
+    armor: bool,
+    files: FilesConfig,
+    #[serde(default)]
+    keys: KeysConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilesConfig {
+    encrypt: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct KeysConfig {
+    #[serde(default)]
+    private: Vec<String>,
+}
+
+impl ProjectConfig {
+    /// Read and check the configuration of the project at `root`
+    fn read(root: &Path) -> Result<Self> {
+        let path = root.join(CONFIG_FILE);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(LockboxError::NotFound(format!("No {} in '{}'", CONFIG_FILE, root.display())).into());
+            }
+            Err(e) => return Err(LockboxError::io(format!("Failed to read '{}'", path.display()), &e).into()),
+        };
+        let invalid = |why: String| LockboxError::InvalidArgument(format!("Invalid {}: {}", CONFIG_FILE, why));
+        let config: ProjectConfig = toml::from_str(&content).map_err(|e| invalid(e.to_string().trim_end().to_string()))?;
+        if config.recipients.is_empty() {
+            return Err(invalid("`recipients` lists no public keys".to_string()).into());
+        }
+        if config.files.encrypt.is_empty() {
+            return Err(invalid("`encrypt` in [files] lists no patterns".to_string()).into());
+        }
+        // Report bad patterns now rather than at the first sync
+        config.selection()?;
+        Ok(config)
+    }
+
+    /// The plaintext files to keep encrypted
+    fn selection(&self) -> Result<PathFilter> {
+        PathFilter::new(
+            Nullable::NotNull(self.files.encrypt.clone()),
+            Nullable::NotNull(self.files.exclude.clone()),
+            false,
+        )
+    }
+
+    /// Private key paths, with `~` expanded and relative paths resolved against `root`
+    fn private_keys(&self, root: &Path) -> Vec<PathBuf> {
+        self.keys.private.iter().map(|path| resolve_path(root, path)).collect()
+    }
+
+    /// The public keys of the recipients, with team names resolved
+    fn public_keys(&self, root: &Path) -> Result<Vec<String>> {
+        let team = self.team.as_ref().map(|path| resolve_path(root, path));
+        resolve_recipients(&self.recipients, team.as_deref())
+    }
+}
+
+fn resolve_path(root: &Path, path: &str) -> PathBuf {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")).map(PathBuf::from);
+    match (path.strip_prefix('~'), home) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') || rest.starts_with('\\') => {
+            home.join(rest.trim_start_matches(['/', '\\']))
+        }
+        _ => root.join(path),
+    }
+}
+
+/// Whether `newer` was modified at least as recently as `older`
+fn is_up_to_date(newer: &Path, older: &Path) -> bool {
+    let mtime = |path: &Path| std::fs::metadata(path).ok().as_ref().and_then(file_mtime);
+    match (mtime(newer), mtime(older)) {
+        (Some(newer), Some(older)) => newer + MTIME_TOLERANCE >= older,
+        _ => false,
+    }
+}
+
+/// Read the lockbox.toml of a project
+///
+/// Returns `recipients` as public keys, `team`, `armor`, `encrypt`, `exclude` and
+/// the `private` key paths, with paths resolved.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_project_config(root: &str) -> Result<List> {
+    let root = fs_path(root);
+    let config = ProjectConfig::read(&root)?;
+    let private: Vec<String> = config.private_keys(&root).iter().map(|path| path.to_string_lossy().into_owned()).collect();
+    let team = config.team.as_ref().map(|path| resolve_path(&root, path).to_string_lossy().into_owned());
+    Ok(list!(
+        recipients = config.public_keys(&root)?,
+        team = Strings::from_values([team.map_or_else(Rstr::na, Rstr::from)]),
+        armor = config.armor,
+        encrypt = config.files.encrypt,
+        exclude = config.files.exclude,
+        private = private
+    ))
+}
+
+/// Encrypt the files of a project that changed since they were last encrypted
+///
+/// With `force`, every selected file is encrypted again. Returns batch rows as
+/// `age_dir_encrypt` does, for the files encrypted only.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_project_encrypt(root: &str, force: bool, dry_run: bool) -> Result<List> {
+    let root = fs_path(root);
+    let config = ProjectConfig::read(&root)?;
+    let keys = config.public_keys(&root)?;
+    let recipients = parse_recipients(keys.clone())?;
+    let planned = plan(&root, &config.selection()?, None, true)?;
+
+    let mut results = BatchResults::default();
+    for file in planned {
+        check_interrupt()?;
+        if !force && is_up_to_date(&file.output, &file.input) {
+            continue;
+        }
+        let started = Instant::now();
+        let outcome = if dry_run {
+            encrypted_size(file.size, &recipients, config.armor)
+        } else {
+            encrypt_planned(&file, &recipients, &keys, config.armor, true, false)
+        };
+        results.record(&file.input, &file.output, file.size, outcome.map(Some), started)?;
+    }
+    Ok(results.into_list())
+}
+
+/// Decrypt one `.age` file of a project over its plaintext
+///
+/// The plaintext is written next to it under a temporary name and renamed into place,
+/// so an existing plaintext is only replaced once decryption has succeeded. `keys` are
+/// the public keys of `identities`, for the audit log.
+fn decrypt_into_place(input: &Path, output: &Path, identities: &[Box<dyn age::Identity>], keys: &[String]) -> Result<Option<u64>> {
+    let result = (|| {
+        let temp = temp_path_for(&output.to_string_lossy());
+        let identities = identities.iter().map(|i| i.as_ref() as &dyn age::Identity);
+        decrypt_to_file(input, identities, &temp, false, Access::OWNER_ONLY, &mut Progress::none())?;
+        let partial = PartialOutput::new(&temp);
+        std::fs::rename(fs_path(&temp), output)
+            .map_err(|e| LockboxError::io(format!("Failed to replace '{}' with the temporary file '{}'", output.display(), temp), &e))?;
+        partial.complete();
+        Ok(std::fs::metadata(output).ok().map(|m| m.len()))
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::file(&input.to_string_lossy(), Some(&output.to_string_lossy()), keys.to_vec())
+    })
+}
+
+/// Check that one of the keys opens an encrypted file, without decrypting its payload
+fn check_key(input: &Path, identities: &[Box<dyn age::Identity>]) -> Result<Option<u64>> {
+    policy::check_decrypt_from(input)?;
+    let file = std::fs::File::open(input)
+        .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", input.display()), &e))?;
+    let armored = age_input(std::io::BufReader::new(file))?;
+    let decryptor = age::Decryptor::new(armored)
+        .map_err(|e| LockboxError::from_decrypt(e, false))?;
+    decryptor.decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
+        .map_err(|e| LockboxError::from_decrypt(e, false))?;
+    Ok(None)
+}
+
+/// Decrypt the encrypted files of a project whose plaintext is missing or stale
+///
+/// `private_key_paths` are read in order and all of their keys tried. Plaintext at
+/// least as new as its `.age` is left alone; older plaintext is reported as a
+/// `file_exists` failure unless `overwrite`. With `dry_run`, each file is only checked
+/// to open with the keys, and `output_size` is NA.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_project_decrypt(root: &str, private_key_paths: Vec<String>, strict_permissions: bool, overwrite: bool, dry_run: bool) -> Result<List> {
+    let root = fs_path(root);
+    let config = ProjectConfig::read(&root)?;
+    if private_key_paths.is_empty() {
+        return Err(LockboxError::InvalidArgument(format!(
+            "No private key to decrypt with: list one under [keys] in {} or pass `private`",
+            CONFIG_FILE
+        )).into());
+    }
+    let (mut identities, mut keys) = (Vec::new(), Vec::new());
+    for path in &private_key_paths {
+        let key_content = read_key_file(path, strict_permissions)?;
+        identities.extend(parse_identities_from_key_file(&key_content)?);
+        keys.extend(audit::identity_public_keys(&key_content));
+    }
+
+    // Encrypted files are found by walking with the exclusions, then matched by the
+    // name of their plaintext
+    let selection = PathFilter::new(Nullable::NotNull(config.files.encrypt.clone()), Nullable::Null, false)?;
+    let walking = PathFilter::new(Nullable::Null, Nullable::NotNull(config.files.exclude.clone()), false)?;
+    let mut paths = Vec::new();
+    walk_filtered(&root, &walking, &mut paths)
+        .map_err(|e| LockboxError::io(format!("Failed to read '{}'", root.display()), &e))?;
+
+    let mut results = BatchResults::default();
+    for input in paths {
+        check_interrupt()?;
+        let metadata = match std::fs::symlink_metadata(&input) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => continue,
+        };
+        let output = match input.to_str().and_then(|path| path.strip_suffix(".age")) {
+            Some(output) => PathBuf::from(output),
+            None => continue,
+        };
+        let relative = output.strip_prefix(&root).unwrap_or(&output).to_string_lossy().replace('\\', "/");
+        if !selection.selects_file(&relative) {
+            continue;
+        }
+        let exists = std::fs::symlink_metadata(&output).is_ok();
+        if exists && is_up_to_date(&output, &input) {
+            continue;
+        }
+
+        let started = Instant::now();
+        let outcome = if exists && !overwrite {
+            Err(LockboxError::FileExists(format!(
+                "'{}' is older than '{}'; use `overwrite = TRUE` to replace it",
+                output.display(),
+                input.display()
+            )).into())
+        } else if dry_run {
+            check_key(&input, &identities)
+        } else {
+            decrypt_into_place(&input, &output, &identities, &keys)
+        };
+        results.record(&input, &output, metadata.len(), outcome, started)?;
+    }
+    Ok(results.into_list())
+}
+
+extendr_module! {
+    mod project;
+    fn age_project_config;
+    fn age_project_encrypt;
+    fn age_project_decrypt;
+}
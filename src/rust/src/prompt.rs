@@ -0,0 +1,190 @@
+// Passphrase prompts outside of R
+//
+// A passphrase read by R's console ends up in an R string, and in terminals that do
+// not support hidden input it is echoed. These prompts read it from a pinentry
+// program (the same helper GnuPG uses, which shows a native dialog on desktops) or,
+// failing that, straight from the controlling terminal with echo turned off.
+//
+// pinentry speaks the Assuan protocol: one command per line, answered by `OK`, `ERR`,
+// or data lines starting with `D `, with `%`, CR and LF percent-escaped.
+use extendr_api::prelude::*;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use zeroize::Zeroizing;
+
+use crate::error::LockboxError;
+
+/// Assuan error code for a dialog closed with Cancel
+const PINENTRY_CANCELLED: &str = "83886179";
+
+/// The pinentry program: `LOCKBOX_PINENTRY`, or the first pinentry on the PATH
+fn pinentry_program() -> Option<PathBuf> {
+    if let Some(program) = std::env::var_os("LOCKBOX_PINENTRY").filter(|p| !p.is_empty()) {
+        return Some(PathBuf::from(program));
+    }
+    let names: &[&str] = if cfg!(target_os = "macos") {
+        &["pinentry-mac", "pinentry"]
+    } else if cfg!(windows) {
+        &["pinentry.exe"]
+    } else {
+        &["pinentry"]
+    };
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Whether this process has a terminal to prompt on
+fn has_terminal() -> bool {
+    if cfg!(unix) {
+        std::fs::File::open("/dev/tty").is_ok()
+    } else {
+        false
+    }
+}
+
+fn assuan_escape(text: &str) -> String {
+    text.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+fn assuan_unescape(data: &str) -> Zeroizing<String> {
+    let bytes = data.as_bytes();
+    let mut decoded = Zeroizing::new(Vec::with_capacity(bytes.len()));
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%' && i + 2 < bytes.len())
+            .then(|| std::str::from_utf8(&bytes[i + 1..i + 3]).ok())
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    Zeroizing::new(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Ask a pinentry program for a passphrase
+fn pinentry_prompt(program: &PathBuf, prompt: &str) -> Result<Zeroizing<String>> {
+    let failed = |detail: String| LockboxError::Io(format!("pinentry ({}) failed: {}", program.display(), detail));
+    let mut child = Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| failed(e.to_string()))?;
+    let mut input = child.stdin.take().ok_or_else(|| failed("no input pipe".to_string()))?;
+    let mut output = BufReader::new(child.stdout.take().ok_or_else(|| failed("no output pipe".to_string()))?);
+
+    let mut commands = vec![
+        "SETTITLE lockbox".to_string(),
+        format!("SETDESC {}", assuan_escape(prompt)),
+        "SETPROMPT Passphrase:".to_string(),
+    ];
+    // Text-mode pinentries draw on the terminal they are told about
+    if has_terminal() {
+        commands.insert(0, "OPTION ttyname=/dev/tty".to_string());
+    }
+    if let Ok(term) = std::env::var("TERM") {
+        commands.insert(0, format!("OPTION ttytype={}", term));
+    }
+
+    // Replies to one command, up to its final OK or ERR; returns the data lines
+    let reply = |output: &mut BufReader<std::process::ChildStdout>| -> Result<Zeroizing<String>> {
+        let mut data = Zeroizing::new(String::new());
+        loop {
+            let mut line = Zeroizing::new(String::new());
+            let read = output.read_line(&mut line).map_err(|e| failed(e.to_string()))?;
+            if read == 0 {
+                return Err(failed("it exited without answering".to_string()).into());
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line == "OK" || line.starts_with("OK ") {
+                return Ok(data);
+            } else if let Some(chunk) = line.strip_prefix("D ") {
+                data.push_str(&assuan_unescape(chunk));
+            } else if let Some(error) = line.strip_prefix("ERR ") {
+                if error.starts_with(PINENTRY_CANCELLED) {
+                    return Err(LockboxError::Interrupted("The passphrase prompt was cancelled".to_string()).into());
+                }
+                return Err(failed(error.to_string()).into());
+            }
+            // Status (`S`) and comment (`#`) lines carry nothing we need
+        }
+    };
+
+    reply(&mut output)?;
+    for command in &commands {
+        writeln!(input, "{}", command).map_err(|e| failed(e.to_string()))?;
+        // Unknown options are not fatal; some pinentries lack a terminal mode
+        match reply(&mut output) {
+            Err(_) if command.starts_with("OPTION ") => {}
+            other => {
+                other?;
+            }
+        }
+    }
+    writeln!(input, "GETPIN").map_err(|e| failed(e.to_string()))?;
+    let passphrase = reply(&mut output);
+    let _ = writeln!(input, "BYE");
+    drop(input);
+    let _ = child.wait();
+    passphrase
+}
+
+/// Read a passphrase from the terminal without echoing it
+fn terminal_prompt(prompt: &str) -> Result<Zeroizing<String>> {
+    rpassword::prompt_password(prompt)
+        .map(Zeroizing::new)
+        .map_err(|e| LockboxError::io("Failed to read the passphrase from the terminal", &e).into())
+}
+
+/// Prompt for a passphrase with pinentry, or on the terminal if there is no pinentry
+pub(crate) fn prompt_passphrase(prompt: &str) -> Result<Zeroizing<String>> {
+    match pinentry_program() {
+        Some(program) => pinentry_prompt(&program, prompt),
+        None => terminal_prompt(prompt),
+    }
+}
+
+/// Read a line of visible input from the terminal, such as an answer to a question
+/// asked by an age plugin
+pub(crate) fn prompt_line(prompt: &str) -> Result<String> {
+    let failed = |e: std::io::Error| LockboxError::io("Failed to read from the terminal", &e);
+    let mut tty = std::fs::OpenOptions::new().read(true).write(true).open("/dev/tty").map_err(failed)?;
+    write!(tty, "{}", prompt).map_err(failed)?;
+    let mut line = String::new();
+    BufReader::new(tty).read_line(&mut line).map_err(failed)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Whether a passphrase can be read without going through R's console
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_prompt_available() -> bool {
+    pinentry_program().is_some() || has_terminal()
+}
+
+/// Prompt for a passphrase with pinentry or on the terminal, without echo
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_prompt_passphrase(prompt: &str) -> Result<String> {
+    let passphrase = prompt_passphrase(prompt)?;
+    Ok(passphrase.as_str().to_string())
+}
+
+extendr_module! {
+    mod prompt;
+    fn age_prompt_available;
+    fn age_prompt_passphrase;
+}
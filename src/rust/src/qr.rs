@@ -0,0 +1,228 @@
+// QR codes of private keys and small ciphertexts
+//
+// An air-gapped machine has no network and, ideally, no USB stick plugged into it.
+// A QR code shown on one screen, or printed, and photographed by the other machine
+// moves a key or a short armored ciphertext without either. Codes are written as
+// black-on-white grayscale PNGs, 8 pixels per module with the 4-module quiet zone
+// scanners expect; reading accepts any PNG with a single code in it, such as a
+// screenshot or a cropped photo.
+//
+// A QR code holds at most a few kilobytes, at the medium error correction level used
+// here: enough for a key file or an armored secret, not for files.
+//
+// Support is only compiled with the `qr` feature. Without it, every function here
+// fails with an explanation, so the R wrappers exist in every build.
+use extendr_api::prelude::*;
+
+#[cfg(feature = "qr")]
+use crate::access::Access;
+use crate::error::LockboxError;
+#[cfg(feature = "qr")]
+use crate::paths::fs_path;
+#[cfg(feature = "qr")]
+use crate::{create_output_file, parse_x25519_identities, read_key_file};
+#[cfg(feature = "qr")]
+use zeroize::Zeroizing;
+
+/// Pixels per module of a written code
+#[cfg(feature = "qr")]
+const MODULE_PIXELS: usize = 8;
+
+/// Light modules around a written code
+#[cfg(feature = "qr")]
+const QUIET_ZONE: usize = 4;
+
+#[cfg(not(feature = "qr"))]
+fn unsupported<T>() -> Result<T> {
+    Err(LockboxError::InvalidArgument(
+        "lockbox was built without QR code support; reinstall it with the environment variable LOCKBOX_QR=true".to_string(),
+    )
+    .into())
+}
+
+/// Write `content` as a QR code to a PNG file
+#[cfg(feature = "qr")]
+fn write_qr(content: &[u8], png_path: &str, overwrite: bool, owner_only: bool) -> Result<()> {
+    use qrcode::{Color, EcLevel, QrCode, types::QrError};
+
+    let code = QrCode::with_error_correction_level(content, EcLevel::M).map_err(|e| match e {
+        QrError::DataTooLong => LockboxError::InvalidArgument(format!(
+            "{} bytes do not fit in a QR code, which holds at most about 2300",
+            content.len()
+        )),
+        e => LockboxError::EncryptFailed(format!("Failed to make the QR code: {}", e)),
+    })?;
+    let modules = code.width();
+    let colors = code.to_colors();
+    let size = (modules + 2 * QUIET_ZONE) * MODULE_PIXELS;
+    let mut pixels = Zeroizing::new(vec![255u8; size * size]);
+    for (y, row) in pixels.chunks_mut(size).enumerate() {
+        let my = (y / MODULE_PIXELS).wrapping_sub(QUIET_ZONE);
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let mx = (x / MODULE_PIXELS).wrapping_sub(QUIET_ZONE);
+            if mx < modules && my < modules && colors[my * modules + mx] == Color::Dark {
+                *pixel = 0;
+            }
+        }
+    }
+
+    let file = create_output_file(png_path, overwrite, if owner_only { Access::OWNER_ONLY } else { Access::default() })?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()
+        .and_then(|mut writer| writer.write_image_data(&pixels))
+        .map_err(|e| {
+            let e = match e {
+                png::EncodingError::IoError(e) => e,
+                e => std::io::Error::new(std::io::ErrorKind::Other, e.to_string()),
+            };
+            LockboxError::io(format!("Failed to write '{}'", png_path), &e).into()
+        })
+}
+
+/// The content of the single QR code in a PNG file
+#[cfg(feature = "qr")]
+fn read_qr(png_path: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let file = std::fs::File::open(fs_path(png_path))
+        .map_err(|e| LockboxError::io(format!("Failed to read '{}'", png_path), &e))?;
+    let mut decoder = png::Decoder::new(std::io::BufReader::new(file));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let not_png = |e: png::DecodingError| LockboxError::InvalidArgument(format!("'{}' is not a readable PNG image: {}", png_path, e));
+    let mut reader = decoder.read_info().map_err(not_png)?;
+    let mut buffer = Zeroizing::new(vec![0; reader.output_buffer_size()]);
+    let frame = reader.next_frame(&mut buffer).map_err(not_png)?;
+
+    let channels = frame.color_type.samples();
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    // Gray is the first channel, or the mean of red, green and blue; alpha is ignored
+    let gray = |x: usize, y: usize| -> u8 {
+        let pixel = &buffer[y * frame.line_size + x * channels..][..channels];
+        match channels {
+            1 | 2 => pixel[0],
+            _ => ((pixel[0] as u16 + pixel[1] as u16 + pixel[2] as u16) / 3) as u8,
+        }
+    };
+    let mut image = rqrr::PreparedImage::prepare_from_greyscale(width, height, gray);
+    let grids = image.detect_grids();
+    let grid = match grids.len() {
+        1 => &grids[0],
+        0 => return Err(LockboxError::InvalidArgument(format!("No QR code found in '{}'", png_path)).into()),
+        n => return Err(LockboxError::InvalidArgument(format!("'{}' holds {} QR codes; crop it to one", png_path, n)).into()),
+    };
+    let mut content = Zeroizing::new(Vec::new());
+    grid.decode_to(&mut *content)
+        .map_err(|e| LockboxError::InvalidArgument(format!("Failed to read the QR code in '{}': {}", png_path, e)))?;
+    Ok(content)
+}
+
+/// Whether this build includes QR code support
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_qr_enabled() -> bool {
+    cfg!(feature = "qr")
+}
+
+/// Write the private keys of a key file as a QR code
+///
+/// Only the `AGE-SECRET-KEY-` lines are encoded, one per line. The PNG, which holds
+/// the keys as plainly as the key file, is created readable by its owner only.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_key_to_qr(key_file_path: &str, strict_permissions: bool, png_path: &str, overwrite: bool) -> Result<()> {
+    #[cfg(feature = "qr")]
+    {
+        use age::secrecy::ExposeSecret;
+
+        let key_content = read_key_file(key_file_path, strict_permissions)?;
+        let identities = parse_x25519_identities(&key_content)?;
+        let mut lines = Zeroizing::new(String::new());
+        for identity in &identities {
+            if !lines.is_empty() {
+                lines.push('\n');
+            }
+            lines.push_str(identity.to_string().expose_secret());
+        }
+        write_qr(lines.as_bytes(), png_path, overwrite, true)
+    }
+    #[cfg(not(feature = "qr"))]
+    {
+        let _ = (key_file_path, strict_permissions, png_path, overwrite);
+        unsupported()
+    }
+}
+
+/// Read private keys from a QR code and save them to a key file
+///
+/// Returns the public key of the first one.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_qr_to_key(png_path: &str, key_file_path: &str, overwrite: bool) -> Result<String> {
+    #[cfg(feature = "qr")]
+    {
+        use std::io::Write;
+
+        let content = read_qr(png_path)?;
+        let not_key = || LockboxError::InvalidIdentity(format!("The QR code in '{}' does not hold an age private key", png_path));
+        let lines = Zeroizing::new(String::from_utf8(content.to_vec()).map_err(|_| not_key())?);
+        let identities = parse_x25519_identities(&lines).map_err(|_| not_key())?;
+        let recipient = identities.first().ok_or_else(not_key)?.to_public();
+
+        let key_file = Zeroizing::new(format!("# public key: {}\n{}\n", recipient, lines.trim()));
+        let mut file = create_output_file(key_file_path, overwrite, Access::OWNER_ONLY)?;
+        file.write_all(key_file.as_bytes())
+            .map_err(|e| LockboxError::io("Failed to write key file", &e))?;
+        Ok(recipient.to_string())
+    }
+    #[cfg(not(feature = "qr"))]
+    {
+        let _ = (png_path, key_file_path, overwrite);
+        unsupported()
+    }
+}
+
+/// Write a string, such as an armored ciphertext, as a QR code
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_text_to_qr(text: &str, png_path: &str, overwrite: bool) -> Result<()> {
+    #[cfg(feature = "qr")]
+    {
+        write_qr(text.as_bytes(), png_path, overwrite, false)
+    }
+    #[cfg(not(feature = "qr"))]
+    {
+        let _ = (text, png_path, overwrite);
+        unsupported()
+    }
+}
+
+/// Read the string held by a QR code
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_qr_to_text(png_path: &str) -> Result<String> {
+    #[cfg(feature = "qr")]
+    {
+        let content = read_qr(png_path)?;
+        String::from_utf8(content.to_vec())
+            .map_err(|_| LockboxError::InvalidArgument(format!("The QR code in '{}' does not hold text", png_path)).into())
+    }
+    #[cfg(not(feature = "qr"))]
+    {
+        let _ = png_path;
+        unsupported()
+    }
+}
+
+extendr_module! {
+    mod qr;
+    fn age_qr_enabled;
+    fn age_key_to_qr;
+    fn age_qr_to_key;
+    fn age_text_to_qr;
+    fn age_qr_to_text;
+}
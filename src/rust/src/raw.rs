@@ -0,0 +1,273 @@
+// Encryption of raw vectors
+//
+// The string functions return text, and an R string holds at most 2^31 - 1 bytes;
+// base64 inflates the ciphertext by a third on top of that. Serialized models or data
+// frames easily outgrow it. Raw vectors can be far longer, and hold the binary age
+// format (or its armor) without any encoding, so these functions take and return
+// them instead.
+use extendr_api::prelude::*;
+use std::iter;
+
+use crate::access::Access;
+use crate::audit;
+use crate::encoding::StringEncoding;
+use crate::policy;
+use crate::stream::{decrypt_reader_to_file, Progress};
+use crate::{
+    decrypt_content, encrypt_binary, encrypt_file_to_raw, encrypt_reader_to_file,
+    parse_identities_from_key_file, parse_recipients, passphrase_from, read_key_file, scrypt_identity, scrypt_recipient,
+};
+
+/// Encrypt bytes in memory, returning the binary age file or its armor
+pub(crate) fn encrypt_raw(encryptor: age::Encryptor, input: &[u8], armor: bool) -> Result<Raw> {
+    let encrypted = encrypt_binary(encryptor, input)?;
+    if armor {
+        Ok(Raw::from_bytes(StringEncoding::Armor.encode(&encrypted)?.as_bytes()))
+    } else {
+        Ok(Raw::from_bytes(&encrypted))
+    }
+}
+
+/// Encrypt a raw vector to public keys
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_raw_with_key(input: Raw, recipients: Vec<String>, armor: bool) -> Result<Raw> {
+    let keys = recipients.clone();
+    let result = (|| {
+        let parsed_recipients = parse_recipients(recipients)?;
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+        encrypt_raw(encryptor, input.as_slice(), armor)
+    })();
+    audit::record("encrypt", "key", result, |encrypted| {
+        audit::Details::raw(encrypted.map(Raw::as_slice), None, audit::recipient_keys(keys))
+    })
+}
+
+/// Encrypt a raw vector with a passphrase
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_raw_with_passphrase(input: Raw, passphrase: &str, passphrase_source: Nullable<String>, work_factor: Nullable<i32>, armor: bool) -> Result<Raw> {
+    let result = (|| {
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+        let recipient = scrypt_recipient(&passphrase, work_factor)?;
+        let encryptor = policy::encryptor(iter::once(&recipient as _))?;
+        encrypt_raw(encryptor, input.as_slice(), armor)
+    })();
+    audit::record("encrypt", "passphrase", result, |encrypted| {
+        audit::Details::raw(encrypted.map(Raw::as_slice), None, Vec::new())
+    })
+}
+
+/// Encrypt a raw vector to public keys straight into a new output file
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_encrypt_raw_to_file_with_key(
+    input: Raw,
+    output_file_path: &str,
+    recipients: Vec<String>,
+    armor: bool,
+    overwrite: bool,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+) -> Result<()> {
+    let keys = recipients.clone();
+    let result = (|| {
+        let parsed_recipients = parse_recipients(recipients)?;
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+        encrypt_reader_to_file(encryptor, &mut input.as_slice(), output_file_path, armor, overwrite, Access::from_r(mode, owner, group)?, None, &mut Progress::none())
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(None, output_file_path, done.is_some(), audit::recipient_keys(keys))
+    })
+}
+
+/// Encrypt a raw vector with a passphrase straight into a new output file
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_encrypt_raw_to_file_with_passphrase(
+    input: Raw,
+    output_file_path: &str,
+    passphrase: &str,
+    passphrase_source: Nullable<String>,
+    work_factor: Nullable<i32>,
+    armor: bool,
+    overwrite: bool,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+) -> Result<()> {
+    let result = (|| {
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+        let recipient = scrypt_recipient(&passphrase, work_factor)?;
+        let encryptor = policy::encryptor(iter::once(&recipient as _))?;
+        encrypt_reader_to_file(encryptor, &mut input.as_slice(), output_file_path, armor, overwrite, Access::from_r(mode, owner, group)?, None, &mut Progress::none())
+    })();
+    audit::record("encrypt", "passphrase", result, |done| {
+        audit::Details::encrypted_file(None, output_file_path, done.is_some(), Vec::new())
+    })
+}
+
+/// Encrypt a file to public keys into a raw vector
+///
+/// See `age_encrypt_key()` for `mmap`, `metadata` and `progress`.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_encrypt_file_to_raw_with_key(
+    input_file_path: &str,
+    recipients: Vec<String>,
+    armor: bool,
+    mmap: bool,
+    metadata: bool,
+    progress: Nullable<Function>,
+) -> Result<Raw> {
+    let keys = recipients.clone();
+    let result = (|| {
+        let parsed_recipients = parse_recipients(recipients)?;
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+        encrypt_file_to_raw(encryptor, input_file_path, armor, mmap, metadata, progress)
+    })();
+    audit::record("encrypt", "key", result, |encrypted| audit::Details {
+        input: Some(input_file_path.to_string()),
+        ..audit::Details::raw(encrypted.map(Raw::as_slice), None, audit::recipient_keys(keys))
+    })
+}
+
+/// Encrypt a file with a passphrase into a raw vector
+///
+/// See `age_encrypt_key()` for `mmap`, `metadata` and `progress`.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_encrypt_file_to_raw_with_passphrase(
+    input_file_path: &str,
+    passphrase: &str,
+    passphrase_source: Nullable<String>,
+    work_factor: Nullable<i32>,
+    armor: bool,
+    mmap: bool,
+    metadata: bool,
+    progress: Nullable<Function>,
+) -> Result<Raw> {
+    let result = (|| {
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+        let recipient = scrypt_recipient(&passphrase, work_factor)?;
+        let encryptor = policy::encryptor(iter::once(&recipient as _))?;
+        encrypt_file_to_raw(encryptor, input_file_path, armor, mmap, metadata, progress)
+    })();
+    audit::record("encrypt", "passphrase", result, |encrypted| audit::Details {
+        input: Some(input_file_path.to_string()),
+        ..audit::Details::raw(encrypted.map(Raw::as_slice), None, Vec::new())
+    })
+}
+
+/// Decrypt a raw vector, binary or armored, with a private key file
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_raw_with_key(input: Raw, private_key_path: &str, strict_permissions: bool) -> Result<Raw> {
+    let result = (|| {
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+        let decrypted = decrypt_content(input.as_slice(), identities.iter().map(|i| i.as_ref()), &mut Progress::none())?;
+        Ok(Raw::from_bytes(&decrypted))
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::raw(Some(input.as_slice()), None, audit::key_file_public_keys(private_key_path))
+    })
+}
+
+/// Decrypt a raw vector, binary or armored, with a passphrase
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_raw_with_passphrase(input: Raw, passphrase: &str, passphrase_source: Nullable<String>, max_work_factor: Nullable<i32>) -> Result<Raw> {
+    let result = (|| {
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+        let identity = scrypt_identity(&passphrase, max_work_factor)?;
+        let decrypted = decrypt_content(input.as_slice(), iter::once(&identity as _), &mut Progress::none())?;
+        Ok(Raw::from_bytes(&decrypted))
+    })();
+    audit::record("decrypt", "passphrase", result, |_| audit::Details::raw(Some(input.as_slice()), None, Vec::new()))
+}
+
+/// Decrypt a raw vector with a private key file straight into an output file
+///
+/// See `age_decrypt_file_with_key()` for `into_directory` and the value returned.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_decrypt_raw_to_file_with_key(
+    input: Raw,
+    output_file_path: &str,
+    into_directory: bool,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+    private_key_path: &str,
+    strict_permissions: bool,
+) -> Result<Raw> {
+    let result = (|| {
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+        let identities = identities.iter().map(|i| i.as_ref());
+        let metadata = decrypt_reader_to_file(input.as_slice(), identities, output_file_path, into_directory, Access::from_r(mode, owner, group)?, &mut Progress::none())?;
+        Ok(Raw::from_bytes(&metadata))
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::raw(Some(input.as_slice()), Some(output_file_path), audit::key_file_public_keys(private_key_path))
+    })
+}
+
+/// Decrypt a raw vector with a passphrase straight into an output file
+///
+/// See `age_decrypt_file_with_key()` for `into_directory` and the value returned.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_decrypt_raw_to_file_with_passphrase(
+    input: Raw,
+    output_file_path: &str,
+    into_directory: bool,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+    passphrase: &str,
+    passphrase_source: Nullable<String>,
+    max_work_factor: Nullable<i32>,
+) -> Result<Raw> {
+    let result = (|| {
+        let passphrase = passphrase_from(passphrase, passphrase_source)?;
+        let identity = scrypt_identity(&passphrase, max_work_factor)?;
+        let metadata = decrypt_reader_to_file(input.as_slice(), iter::once(&identity as _), output_file_path, into_directory, Access::from_r(mode, owner, group)?, &mut Progress::none())?;
+        Ok(Raw::from_bytes(&metadata))
+    })();
+    audit::record("decrypt", "passphrase", result, |_| {
+        audit::Details::raw(Some(input.as_slice()), Some(output_file_path), Vec::new())
+    })
+}
+
+extendr_module! {
+    mod raw;
+    fn age_encrypt_raw_with_key;
+    fn age_encrypt_raw_with_passphrase;
+    fn age_encrypt_raw_to_file_with_key;
+    fn age_encrypt_raw_to_file_with_passphrase;
+    fn age_encrypt_file_to_raw_with_key;
+    fn age_encrypt_file_to_raw_with_passphrase;
+    fn age_decrypt_raw_with_key;
+    fn age_decrypt_raw_with_passphrase;
+    fn age_decrypt_raw_to_file_with_key;
+    fn age_decrypt_raw_to_file_with_passphrase;
+}
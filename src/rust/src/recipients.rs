@@ -0,0 +1,301 @@
+// Recipient management on existing age files
+//
+// An age header lists one stanza per recipient, each wrapping the same file key, and
+// is authenticated by an HMAC keyed from that file key. Adding a recipient therefore
+// only needs the file key: unwrap it with an existing identity, wrap it for the new
+// recipient, and write a new header in front of the unchanged payload.
+//
+// Removing a recipient cannot work that way. Stanzas do not say which recipient they
+// belong to, and a removed recipient may already know the file key, so removal
+// re-encrypts the content under a new file key for the recipients that remain.
+use extendr_api::prelude::*;
+use age::secrecy::ExposeSecret;
+use age_core::format::{FileKey, Stanza};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use age::armor::ArmoredReader;
+use std::fs::{File, Permissions};
+use std::io::{BufRead, BufReader, Write};
+
+use crate::audit;
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stats::{self, Direction};
+use crate::stream::{age_input, copy_interruptible, is_armored, replace_file, Progress};
+use crate::{parse_identities_from_key_file, parse_recipients, read_key_file};
+
+const HEADER_V1: &[u8] = b"age-encryption.org/v1\n";
+const ARMOR_BEGIN: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// Headers larger than this are rejected rather than read into memory
+const MAX_HEADER_SIZE: usize = 1024 * 1024;
+
+/// A parsed age v1 header
+struct Header {
+    stanzas: Vec<Stanza>,
+    /// The header bytes covered by the MAC, up to and including `---`
+    mac_input: Vec<u8>,
+    mac: Vec<u8>,
+}
+
+fn corrupt_header(detail: &str) -> LockboxError {
+    LockboxError::CorruptHeader(format!("Failed to read age header: {}", detail))
+}
+
+/// Read one header line, including its trailing newline
+fn read_line<R: BufRead>(input: &mut R, raw: &mut Vec<u8>) -> Result<Vec<u8>> {
+    let mut line = Vec::new();
+    input.read_until(b'\n', &mut line)
+        .map_err(|e| LockboxError::io("Failed to read encrypted file", &e))?;
+    if !line.ends_with(b"\n") {
+        return Err(corrupt_header("the header is truncated").into());
+    }
+    raw.extend_from_slice(&line);
+    if raw.len() > MAX_HEADER_SIZE {
+        return Err(corrupt_header("the header is too large").into());
+    }
+    Ok(line)
+}
+
+/// Read an age v1 header, leaving `input` positioned at the start of the payload
+fn read_header<R: BufRead>(input: &mut R) -> Result<Header> {
+    let mut raw = Vec::new();
+
+    if read_line(input, &mut raw)? != HEADER_V1 {
+        return Err(LockboxError::NotAgeFile("The file is not an age v1 encrypted file".to_string()).into());
+    }
+
+    let mut stanzas = Vec::new();
+    loop {
+        let line = read_line(input, &mut raw)?;
+        let line = &line[..line.len() - 1];
+
+        if let Some(encoded_mac) = line.strip_prefix(b"--- ") {
+            let mac = STANDARD_NO_PAD.decode(encoded_mac)
+                .map_err(|_| corrupt_header("the MAC is not valid base64"))?;
+            let mac_input_len = raw.len() - encoded_mac.len() - 2;
+            raw.truncate(mac_input_len);
+            return Ok(Header { stanzas, mac_input: raw, mac });
+        }
+
+        let stanza_line = line.strip_prefix(b"-> ")
+            .ok_or_else(|| corrupt_header("expected a recipient stanza or the MAC line"))?;
+        let stanza_line = std::str::from_utf8(stanza_line)
+            .map_err(|_| corrupt_header("a stanza line is not valid text"))?;
+        let mut fields = stanza_line.split(' ').map(str::to_string);
+        let tag = fields.next().unwrap_or_default();
+        let args = fields.collect();
+
+        // The body is wrapped at 64 columns and ends with the first shorter line
+        let mut encoded_body = Vec::new();
+        loop {
+            let body_line = read_line(input, &mut raw)?;
+            let body_line = &body_line[..body_line.len() - 1];
+            encoded_body.extend_from_slice(body_line);
+            if body_line.len() < 64 {
+                break;
+            }
+        }
+        let body = STANDARD_NO_PAD.decode(&encoded_body)
+            .map_err(|_| corrupt_header("a stanza body is not valid base64"))?;
+
+        stanzas.push(Stanza { tag, args, body });
+    }
+}
+
+/// HMAC over the header, keyed from the file key as in the age specification
+fn header_mac(file_key: &FileKey) -> Hmac<Sha256> {
+    let mac_key = age_core::primitives::hkdf(&[], b"header", file_key.expose_secret());
+    Hmac::<Sha256>::new_from_slice(&mac_key).expect("HMAC accepts keys of any length")
+}
+
+/// Serialize an age v1 header with a fresh MAC
+pub(crate) fn write_header(stanzas: &[Stanza], file_key: &FileKey) -> Vec<u8> {
+    let mut header = HEADER_V1.to_vec();
+
+    for stanza in stanzas {
+        header.extend_from_slice(b"-> ");
+        header.extend_from_slice(stanza.tag.as_bytes());
+        for arg in &stanza.args {
+            header.push(b' ');
+            header.extend_from_slice(arg.as_bytes());
+        }
+        header.push(b'\n');
+
+        // Full 64-column lines, then a final short (possibly empty) line
+        let encoded = STANDARD_NO_PAD.encode(&stanza.body);
+        let mut rest = encoded.as_bytes();
+        while rest.len() >= 64 {
+            header.extend_from_slice(&rest[..64]);
+            header.push(b'\n');
+            rest = &rest[64..];
+        }
+        header.extend_from_slice(rest);
+        header.push(b'\n');
+    }
+
+    header.extend_from_slice(b"---");
+    let mut mac = header_mac(file_key);
+    mac.update(&header);
+    let tag = mac.finalize().into_bytes();
+    header.push(b' ');
+    header.extend_from_slice(STANDARD_NO_PAD.encode(tag).as_bytes());
+    header.push(b'\n');
+
+    header
+}
+
+/// Reader over an encrypted file, removing the armor if there is any
+type EncryptedReader = ArmoredReader<BufReader<BufReader<File>>>;
+
+/// Open an encrypted file for streaming, noting its format and permissions
+fn open_encrypted(encrypted_file_path: &str) -> Result<(EncryptedReader, bool, Permissions)> {
+    let path = fs_path(encrypted_file_path);
+    policy::check_decrypt_from(&path)?;
+    let encrypted_file = File::open(&path)
+        .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", encrypted_file_path), &e))?;
+    let permissions = encrypted_file.metadata()
+        .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", encrypted_file_path), &e))?
+        .permissions();
+    let mut encrypted_file = BufReader::new(encrypted_file);
+    let armored = is_armored(encrypted_file.fill_buf()
+        .map_err(|e| LockboxError::io("Failed to read encrypted file", &e))?);
+    Ok((age_input(encrypted_file)?, armored, permissions))
+}
+
+/// Rewrite the header of an encrypted file in place, keeping its payload
+///
+/// `update` receives the current stanzas and the unwrapped file key, and returns the
+/// stanzas of the new header.
+fn rewrite_header<F>(encrypted_file_path: &str, private_key_path: &str, strict_permissions: bool, update: F) -> Result<()>
+where
+    F: FnOnce(Vec<Stanza>, &FileKey) -> Result<Vec<Stanza>>,
+{
+    let key_content = read_key_file(private_key_path, strict_permissions)?;
+    let identities = parse_identities_from_key_file(&key_content)?;
+
+    let (mut input, armored, permissions) = open_encrypted(encrypted_file_path)?;
+
+    let header = read_header(&mut input)?;
+    if header.stanzas.iter().any(|s| s.tag == "scrypt") {
+        return Err(LockboxError::InvalidArgument(
+            "Recipients cannot be changed on a passphrase-encrypted file".to_string(),
+        ).into());
+    }
+
+    let file_key = identities.iter()
+        .find_map(|identity| identity.unwrap_stanzas(&header.stanzas))
+        .unwrap_or(Err(age::DecryptError::NoMatchingKeys))
+        .map_err(|e| LockboxError::from_decrypt(e, false))?;
+
+    let mut mac = header_mac(&file_key);
+    mac.update(&header.mac_input);
+    mac.verify_slice(&header.mac)
+        .map_err(|_| LockboxError::from_decrypt(age::DecryptError::InvalidMac, false))?;
+
+    let stanzas = update(header.stanzas, &file_key)?;
+    let new_header = write_header(&stanzas, &file_key);
+
+    replace_file(encrypted_file_path, armored, permissions, |output| {
+        output.write_all(&new_header)
+            .map_err(|e| LockboxError::io("Failed to write header", &e))?;
+        copy_interruptible(&mut input, output, &mut Progress::none(), |e| {
+            LockboxError::io("Failed to read encrypted file", &e)
+        })?;
+        Ok(())
+    })
+}
+
+/// Add recipients to an existing encrypted file
+///
+/// Unwraps the file key with a private key that can already decrypt the file, wraps it
+/// for each new recipient, and rewrites the header. The payload is copied unchanged and
+/// the existing recipients keep access.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_add_recipient(encrypted_file_path: &str, private_key_path: &str, new_recipients: Vec<String>, strict_permissions: bool) -> Result<()> {
+    let keys = new_recipients.clone();
+    let result = (|| {
+        let new_recipients = parse_recipients(new_recipients)?;
+
+        rewrite_header(encrypted_file_path, private_key_path, strict_permissions, |mut stanzas, file_key| {
+            for recipient in &new_recipients {
+                let (new_stanzas, _labels) = recipient.wrap_file_key(file_key)
+                    .map_err(|e| LockboxError::EncryptFailed(format!("Failed to wrap file key: {}", e)))?;
+                stanzas.extend(new_stanzas);
+            }
+            Ok(stanzas)
+        })
+    })();
+    // Recorded as encrypting the file to the recipients added
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(None, encrypted_file_path, done.is_some(), audit::recipient_keys(keys))
+    })
+}
+
+/// Re-encrypt a file for a new set of recipients, dropping others
+///
+/// Decrypts with a private key that can read the file and re-encrypts the content under
+/// a new file key for `keep_recipients` only, so the dropped recipients' keys, and any
+/// copy of the old file key, no longer decrypt it. Fails if a recipient appears in
+/// both lists, since the file's current recipients cannot be read from its header.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_remove_recipient(
+    encrypted_file_path: &str,
+    private_key_path: &str,
+    keep_recipients: Vec<String>,
+    drop_recipients: Vec<String>,
+    strict_permissions: bool,
+) -> Result<()> {
+    let keys = keep_recipients.clone();
+    let result = (|| {
+        if let Some(both) = drop_recipients.iter().find(|r| keep_recipients.contains(r)) {
+            return Err(LockboxError::InvalidArgument(format!(
+                "Recipient '{}' is both kept and removed",
+                both
+            )).into());
+        }
+        let keep_recipients = parse_recipients(keep_recipients)?;
+        parse_recipients(drop_recipients)?;
+
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+
+        let (input, armored, permissions) = open_encrypted(encrypted_file_path)?;
+        let decryptor = age::Decryptor::new_buffered(input)
+            .map_err(|e| LockboxError::from_decrypt(e, false))?;
+        if decryptor.is_scrypt() {
+            return Err(LockboxError::InvalidArgument(
+                "Recipients cannot be changed on a passphrase-encrypted file".to_string(),
+            ).into());
+        }
+        let mut decrypted_reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))
+            .map_err(|e| LockboxError::from_decrypt(e, false))?;
+
+        let encryptor = policy::encryptor(keep_recipients.iter().map(|r| r.as_ref()))?;
+
+        replace_file(encrypted_file_path, armored, permissions, |output| {
+            let mut encrypted_writer = encryptor.wrap_output(output)
+                .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+            let bytes = copy_interruptible(&mut decrypted_reader, &mut encrypted_writer, &mut Progress::none(), LockboxError::from_payload)?;
+            encrypted_writer.finish()
+                .map_err(|e| LockboxError::io("Failed to finalize encryption", &e))?;
+            stats::add_bytes(Direction::Encrypt, bytes);
+            Ok(())
+        })
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(None, encrypted_file_path, done.is_some(), audit::recipient_keys(keys))
+    })
+}
+
+extendr_module! {
+    mod recipients;
+    fn age_add_recipient;
+    fn age_remove_recipient;
+}
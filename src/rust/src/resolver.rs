@@ -0,0 +1,205 @@
+// Recipient resolvers
+//
+// A team registry only knows the people listed in it. To encrypt to someone outside
+// it, their public key has to be found first, and people increasingly publish their
+// age keys the way they publish PGP keys: on their own domain, or with a key
+// directory in the style of Keybase or keys.pub. A resolver turns a name such as an
+// email address into public keys at encryption time, so `public = "ana@example.org"`
+// works like a public key.
+//
+// The session holds a chain of resolvers, tried in order for each name that is
+// neither a public key nor known to the team registry; the first to return keys wins.
+// Two kinds are built in:
+//
+// - an HTTPS resolver, which fills the email address into a URL template such as
+//   `https://{domain}/.well-known/age/{user}` and reads the response as a recipients
+//   file, one key per line with `#` comments; a 404 means the address is unknown
+// - an R function, called with the name and returning public keys or NULL, for
+//   directories that need more than a GET request
+//
+// Keys are fetched again at each call, as registry files are read again: a rotated
+// key takes effect at once, and a resolver that cannot be reached fails the call.
+// Resolution runs on R's main thread, which is where the chain lives.
+use extendr_api::prelude::*;
+use std::cell::RefCell;
+use std::io::Read;
+use std::rc::Rc;
+
+use crate::error::LockboxError;
+use crate::http;
+
+/// Largest response read from an HTTPS resolver; a recipients file is a few lines
+const MAX_RESPONSE: u64 = 64 * 1024;
+
+trait Resolver {
+    /// How the resolver is shown by `age_recipient_resolvers()`
+    fn describe(&self) -> String;
+
+    /// The public keys published for `name`, or None if the resolver knows no such name
+    fn resolve(&self, name: &str) -> Result<Option<Vec<String>>>;
+}
+
+thread_local! {
+    static RESOLVERS: RefCell<Vec<Rc<dyn Resolver>>> = RefCell::new(Vec::new());
+}
+
+/// The user and domain of an email address, with the domain in lower case
+fn split_email(address: &str) -> Option<(&str, String)> {
+    let (user, domain) = address.split_once('@')?;
+    let domain = domain.to_ascii_lowercase();
+    let valid_domain = domain.contains('.')
+        && domain.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-')
+        && !domain.starts_with(['.', '-']);
+    (!user.is_empty() && !user.contains(char::is_whitespace) && valid_domain).then_some((user, domain))
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters
+fn url_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The public keys of a recipients file: one per line, with blank and `#` lines ignored
+fn recipient_lines(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+struct HttpsResolver {
+    template: String,
+}
+
+impl Resolver for HttpsResolver {
+    fn describe(&self) -> String {
+        self.template.clone()
+    }
+
+    fn resolve(&self, name: &str) -> Result<Option<Vec<String>>> {
+        let (user, domain) = match split_email(name) {
+            Some(parts) => parts,
+            None => return Ok(None),
+        };
+        let url = self.template
+            .replace("{email}", &url_encode(name))
+            .replace("{user}", &url_encode(user))
+            .replace("{domain}", &domain);
+        let response = match http::agent().get(&url).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => return Err(http::http_error(&format!("look up the public key of '{}'", name), e).into()),
+        };
+        let mut body = String::new();
+        response.into_reader().take(MAX_RESPONSE).read_to_string(&mut body)
+            .map_err(|e| LockboxError::Network(format!("Failed to look up the public key of '{}': {}", name, e)))?;
+        let keys = recipient_lines(&body);
+        Ok((!keys.is_empty()).then_some(keys))
+    }
+}
+
+struct FunctionResolver {
+    function: Function,
+}
+
+impl Resolver for FunctionResolver {
+    fn describe(&self) -> String {
+        "R function".to_string()
+    }
+
+    fn resolve(&self, name: &str) -> Result<Option<Vec<String>>> {
+        let keys = self.function.call(pairlist!(name))?;
+        if keys.is_null() {
+            return Ok(None);
+        }
+        let keys = keys.as_string_vector().ok_or_else(|| {
+            LockboxError::InvalidRecipient(format!(
+                "The recipient resolver returned something other than public keys for '{}'",
+                name
+            ))
+        })?;
+        Ok((!keys.is_empty()).then_some(keys))
+    }
+}
+
+/// The public keys the session's resolvers give for `name`, or None if none knows it
+///
+/// Only public keys are accepted from a resolver, so names never resolve to names.
+pub(crate) fn resolve(name: &str) -> Result<Option<Vec<String>>> {
+    // A copy of the chain, so that an R function may change it while being called
+    let resolvers: Vec<Rc<dyn Resolver>> = RESOLVERS.with(|resolvers| resolvers.borrow().clone());
+    for resolver in &resolvers {
+        if let Some(keys) = resolver.resolve(name)? {
+            if let Some(key) = keys.iter().find(|key| !key.starts_with("age1")) {
+                return Err(LockboxError::InvalidRecipient(format!(
+                    "The recipient resolver {} returned '{}' for '{}', which is not an age public key",
+                    resolver.describe(),
+                    key,
+                    name
+                )).into());
+            }
+            return Ok(Some(keys));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether the session has any resolver
+pub(crate) fn any() -> bool {
+    RESOLVERS.with(|resolvers| !resolvers.borrow().is_empty())
+}
+
+/// Replace the session's resolvers
+///
+/// `resolvers` holds, in order, URL templates for HTTPS resolvers and R functions.
+/// An empty list removes them all.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_recipient_resolvers_use(resolvers: List) -> Result<()> {
+    let mut chain: Vec<Rc<dyn Resolver>> = Vec::new();
+    for resolver in resolvers.values() {
+        if let Some(template) = resolver.as_str() {
+            if !template.starts_with("https://") {
+                return Err(LockboxError::InvalidArgument(format!(
+                    "The resolver URL '{}' must start with https://",
+                    template
+                )).into());
+            }
+            if !template.contains("{email}") && !template.contains("{user}") {
+                return Err(LockboxError::InvalidArgument(format!(
+                    "The resolver URL '{}' must contain {{email}} or {{user}}",
+                    template
+                )).into());
+            }
+            chain.push(Rc::new(HttpsResolver { template: template.to_string() }));
+        } else if let Ok(function) = Function::try_from(resolver) {
+            chain.push(Rc::new(FunctionResolver { function }));
+        } else {
+            return Err(LockboxError::InvalidArgument(
+                "A recipient resolver is a URL template or a function".to_string(),
+            ).into());
+        }
+    }
+    RESOLVERS.with(|resolvers| *resolvers.borrow_mut() = chain);
+    Ok(())
+}
+
+/// Describe the session's resolvers, in order
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_recipient_resolvers() -> Vec<String> {
+    RESOLVERS.with(|resolvers| resolvers.borrow().iter().map(|resolver| resolver.describe()).collect())
+}
+
+extendr_module! {
+    mod resolver;
+    fn age_recipient_resolvers_use;
+    fn age_recipient_resolvers;
+}
@@ -0,0 +1,202 @@
+// Resumable decryption of large files
+//
+// On network file systems a multi-gigabyte decryption can die halfway, with the
+// mount or the session. age encrypts the payload in independently authenticated
+// 64 KiB chunks and the decrypting reader can seek, so a decryption can pick up
+// where it stopped: every CHECKPOINT_INTERVAL bytes the output is synced to disk and
+// the position recorded in a small sidecar next to it, `<output>.lockbox-resume`.
+// A later run with the same input and output truncates the output to that position
+// and continues from there; the sidecar is removed once the output is complete.
+//
+// The sidecar holds no secret, only the position and a fingerprint of the encrypted
+// file (its size and the hash of its start, which covers the age header), so that a
+// checkpoint is never applied to another file. Encryption cannot be resumed this way:
+// continuing an age stream needs its file key, which must not be written down.
+use extendr_api::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::access::Access;
+use crate::audit;
+use crate::buffers::{read_buffer_size, write_buffer_size};
+use crate::error::LockboxError;
+use crate::metadata::metadata_length;
+use crate::paths::fs_path;
+use crate::stats::{self, Direction};
+use crate::stream::{age_input, check_age_file, copy_interruptible, read_head, Progress};
+use crate::{create_output_file, parse_identities_from_key_file, policy, read_key_file};
+
+/// Plaintext bytes written between two checkpoints
+const CHECKPOINT_INTERVAL: u64 = 64 * 1024 * 1024;
+
+/// Bytes at the start of the encrypted file that identify it
+const FINGERPRINT_LENGTH: u64 = 64 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    version: u32,
+    input_size: u64,
+    input_sha256: String,
+    /// Position in the decrypted stream, metadata block included, up to which the
+    /// output is complete
+    offset: u64,
+}
+
+fn sidecar_path(output: &str) -> String {
+    format!("{}.lockbox-resume", output)
+}
+
+/// SHA-256 of the start of the encrypted file, in hex
+fn fingerprint(input: &mut File) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut (&*input).take(FINGERPRINT_LENGTH), &mut hasher)
+        .and_then(|_| input.seek(SeekFrom::Start(0)))
+        .map_err(|e| LockboxError::io("Failed to read encrypted file", &e))?;
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn read_checkpoint(path: &str) -> Result<Option<Checkpoint>> {
+    let content = match std::fs::read_to_string(fs_path(path)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(LockboxError::io(format!("Failed to read checkpoint '{}'", path), &e).into()),
+    };
+    serde_json::from_str(&content)
+        .map(Some)
+        .map_err(|e| LockboxError::InvalidArgument(format!("The checkpoint '{}' is damaged ({}); remove it to start over", path, e)).into())
+}
+
+/// Replace the sidecar in one step, so that a crash leaves the old or the new one
+fn write_checkpoint(path: &str, checkpoint: &Checkpoint) -> Result<()> {
+    let temp_path = format!("{}.tmp", path);
+    let json = serde_json::to_string(checkpoint)
+        .map_err(|e| LockboxError::io(format!("Failed to write checkpoint '{}'", path), &std::io::Error::from(e)))?;
+    std::fs::write(fs_path(&temp_path), json)
+        .and_then(|_| std::fs::rename(fs_path(&temp_path), fs_path(path)))
+        .map_err(|e| LockboxError::io(format!("Failed to write checkpoint '{}'", path), &e).into())
+}
+
+/// Flush the output to the disk, then record that it is complete up to `offset`
+fn checkpoint(writer: &mut BufWriter<File>, sidecar: &str, state: &mut Checkpoint, offset: u64) -> Result<()> {
+    writer.flush()
+        .and_then(|_| writer.get_ref().sync_data())
+        .map_err(|e| LockboxError::io("Failed to write output", &e))?;
+    state.offset = offset;
+    write_checkpoint(sidecar, state)
+}
+
+/// Decrypt a file with a private key file into `output`, resuming an interrupted run
+///
+/// An existing `output` is only accepted together with its checkpoint, which must
+/// belong to the same encrypted file. On failure the output and checkpoint are kept
+/// for the next attempt. Returns the metadata block, empty without one. `mode`,
+/// `owner` and `group`, if not NULL, are given to a new output.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_decrypt_resumable(
+    encrypted_file_path: &str,
+    output_file_path: &str,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+    private_key_path: &str,
+    strict_permissions: bool,
+    progress: Nullable<Function>,
+) -> Result<Raw> {
+    let result = (|| {
+        let access = Access::from_r(mode, owner, group)?;
+        let input_path = fs_path(encrypted_file_path);
+        policy::check_decrypt_from(&input_path)?;
+        check_age_file(&input_path)?;
+        policy::check_plaintext_to(&fs_path(output_file_path))?;
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+
+        let mut input = File::open(&input_path)
+            .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", encrypted_file_path), &e))?;
+        let mut state = Checkpoint {
+            version: 1,
+            input_size: input.metadata().map(|m| m.len()).unwrap_or(0),
+            input_sha256: fingerprint(&mut input)?,
+            offset: 0,
+        };
+
+        let sidecar = sidecar_path(output_file_path);
+        let output_exists = fs_path(output_file_path).exists();
+        let resume_from = match read_checkpoint(&sidecar)? {
+            Some(recorded) if output_exists => {
+                if recorded.version != state.version || recorded.input_size != state.input_size || recorded.input_sha256 != state.input_sha256 {
+                    return Err(LockboxError::InvalidArgument(format!(
+                        "The checkpoint '{}' was made for another encrypted file; remove it and '{}' to start over",
+                        sidecar, output_file_path
+                    )).into());
+                }
+                Some(recorded.offset)
+            }
+            _ => None,
+        };
+
+        let armored = age_input(BufReader::with_capacity(read_buffer_size(&input_path), input))?;
+        let decryptor = age::Decryptor::new(armored)
+            .map_err(|e| LockboxError::from_decrypt(e, false))?;
+        let scrypt = decryptor.is_scrypt();
+        let mut reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))
+            .map_err(|e| LockboxError::from_decrypt(e, scrypt))?;
+
+        let head = read_head(&mut reader, LockboxError::from_payload)?;
+        let skip = metadata_length(&head)? as u64;
+        let total = reader.seek(SeekFrom::End(0)).map_err(LockboxError::from_payload)?;
+
+        let (output, offset) = match resume_from {
+            Some(offset) if offset >= skip && offset <= total => {
+                let output = OpenOptions::new().write(true).open(fs_path(output_file_path))
+                    .map_err(|e| LockboxError::io(format!("Failed to open '{}'", output_file_path), &e))?;
+                output.set_len(offset - skip)
+                    .and_then(|_| (&output).seek(SeekFrom::End(0)))
+                    .map_err(|e| LockboxError::io(format!("Failed to truncate '{}'", output_file_path), &e))?;
+                (output, offset)
+            }
+            Some(_) => {
+                return Err(LockboxError::InvalidArgument(format!(
+                    "The checkpoint '{}' does not fit the encrypted file; remove it and '{}' to start over",
+                    sidecar, output_file_path
+                )).into());
+            }
+            None => (create_output_file(output_file_path, false, access)?, skip),
+        };
+        let mut writer = BufWriter::with_capacity(write_buffer_size(Path::new(output_file_path)), output);
+        checkpoint(&mut writer, &sidecar, &mut state, offset)?;
+        reader.seek(SeekFrom::Start(offset)).map_err(LockboxError::from_payload)?;
+
+        let mut progress = Progress::new(progress, total);
+        progress.set_processed(offset);
+        let mut reader = progress.track(reader);
+        let mut position = offset;
+        loop {
+            let copied = copy_interruptible(&mut (&mut reader).take(CHECKPOINT_INTERVAL), &mut writer, &mut progress, LockboxError::from_payload)?;
+            position += copied;
+            checkpoint(&mut writer, &sidecar, &mut state, position)?;
+            if copied < CHECKPOINT_INTERVAL {
+                break;
+            }
+        }
+        stats::add_bytes(Direction::Decrypt, position - offset);
+
+        std::fs::remove_file(fs_path(&sidecar))
+            .map_err(|e| LockboxError::io(format!("Failed to remove checkpoint '{}'", sidecar), &e))?;
+        Ok(Raw::from_bytes(&head[..skip as usize]))
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::file(encrypted_file_path, Some(output_file_path), audit::key_file_public_keys(private_key_path))
+    })
+}
+
+extendr_module! {
+    mod resume;
+    fn age_decrypt_resumable;
+}
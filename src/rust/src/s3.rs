@@ -0,0 +1,356 @@
+// Encrypted objects in S3-compatible storage
+//
+// `s3://bucket/key` objects are read and written with the streaming HTTP transfers
+// of http.rs, signed with AWS Signature Version 4. Credentials, region and endpoint
+// come from the standard AWS environment variables:
+//
+// - AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and, for temporary credentials,
+//   AWS_SESSION_TOKEN
+// - AWS_REGION or AWS_DEFAULT_REGION (default us-east-1)
+// - AWS_ENDPOINT_URL_S3 or AWS_ENDPOINT_URL for S3-compatible services such as
+//   MinIO, which are addressed path-style (`endpoint/bucket/key`)
+//
+// Payloads are sent as UNSIGNED-PAYLOAD: the body is streamed, so its hash is not
+// known when the request is signed, and age authenticates the content anyway.
+//
+// Support is only compiled with the `s3` feature. Without it, every function here
+// fails with an explanation, so the R wrappers exist in every build.
+use extendr_api::prelude::*;
+
+#[cfg(feature = "s3")]
+use crate::access::Access;
+use crate::error::LockboxError;
+#[cfg(feature = "s3")]
+use crate::audit;
+#[cfg(feature = "s3")]
+use crate::http::{agent, download_decrypt, encrypt_upload, output_path};
+#[cfg(feature = "s3")]
+use crate::policy;
+#[cfg(feature = "s3")]
+use crate::source::passphrase_from;
+#[cfg(feature = "s3")]
+use crate::{parse_identities_from_key_file, parse_recipients, read_key_file, scrypt_identity, scrypt_recipient};
+
+/// An object named by an `s3://bucket/key` URI
+#[cfg(feature = "s3")]
+struct S3Object {
+    bucket: String,
+    key: String,
+}
+
+#[cfg(feature = "s3")]
+impl S3Object {
+    fn parse(uri: &str) -> Result<Self> {
+        let invalid = || LockboxError::InvalidArgument(format!("'{}' is not an s3://bucket/key URI", uri));
+        let path = uri.strip_prefix("s3://").ok_or_else(invalid)?;
+        let (bucket, key) = path.split_once('/').ok_or_else(invalid)?;
+        if bucket.is_empty() || key.is_empty() {
+            return Err(invalid().into());
+        }
+        Ok(S3Object { bucket: bucket.to_string(), key: key.to_string() })
+    }
+}
+
+#[cfg(feature = "s3")]
+mod sigv4 {
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
+
+    pub const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+    pub const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+    pub struct Credentials {
+        pub access_key_id: String,
+        pub secret_access_key: zeroize::Zeroizing<String>,
+        pub session_token: Option<String>,
+    }
+
+    fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(data.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Percent-encode everything but RFC 3986 unreserved characters, keeping `/`
+    pub fn encode_path(path: &str) -> String {
+        let mut encoded = String::with_capacity(path.len());
+        for byte in path.bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+                _ => encoded.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+        encoded
+    }
+
+    /// Build the Authorization header for a request without a query string
+    ///
+    /// `headers` are the signed headers as lowercase names and trimmed values, sorted
+    /// by name; they must include `host` and `x-amz-date`. `timestamp` is the value
+    /// of `x-amz-date`.
+    pub fn authorization(
+        credentials: &Credentials,
+        region: &str,
+        method: &str,
+        canonical_uri: &str,
+        headers: &[(&str, &str)],
+        payload_hash: &str,
+        timestamp: &str,
+    ) -> String {
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_request = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method, canonical_uri, canonical_headers, signed_headers, payload_hash
+        );
+
+        let date = &timestamp[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, region);
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}",
+            ALGORITHM,
+            timestamp,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let secret = zeroize::Zeroizing::new(format!("AWS4{}", credentials.secret_access_key.as_str()));
+        let key = [date, region, "s3", "aws4_request"]
+            .iter()
+            .fold(secret.as_bytes().to_vec(), |key, part| hmac(&key, part));
+        let signature = hex(&hmac(&key, &string_to_sign));
+
+        format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, credentials.access_key_id, scope, signed_headers, signature
+        )
+    }
+}
+
+#[cfg(feature = "s3")]
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Build a signed request for an object, using the AWS environment variables
+#[cfg(feature = "s3")]
+fn signed_request(method: &str, uri: &str) -> Result<ureq::Request> {
+    let object = S3Object::parse(uri)?;
+    let credentials = sigv4::Credentials {
+        access_key_id: env("AWS_ACCESS_KEY_ID").ok_or_else(|| {
+            LockboxError::InvalidArgument("AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY must be set to use S3".to_string())
+        })?,
+        secret_access_key: zeroize::Zeroizing::new(env("AWS_SECRET_ACCESS_KEY").ok_or_else(|| {
+            LockboxError::InvalidArgument("AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY must be set to use S3".to_string())
+        })?),
+        session_token: env("AWS_SESSION_TOKEN"),
+    };
+    let region = env("AWS_REGION").or_else(|| env("AWS_DEFAULT_REGION")).unwrap_or_else(|| "us-east-1".to_string());
+
+    let key = sigv4::encode_path(&object.key);
+    let (base, host, canonical_uri) = match env("AWS_ENDPOINT_URL_S3").or_else(|| env("AWS_ENDPOINT_URL")) {
+        Some(endpoint) => {
+            let endpoint = endpoint.trim_end_matches('/');
+            let (scheme, rest) = endpoint.split_once("://").ok_or_else(|| {
+                LockboxError::InvalidArgument(format!("The S3 endpoint '{}' must start with http:// or https://", endpoint))
+            })?;
+            let (authority, prefix) = rest.find('/').map_or((rest, ""), |i| rest.split_at(i));
+            // The Host header leaves out the port when it is the scheme's default
+            let host = match (scheme, authority.rsplit_once(':')) {
+                ("http", Some((host, "80"))) | ("https", Some((host, "443"))) => host,
+                _ => authority,
+            };
+            let path = format!("{}/{}/{}", prefix, sigv4::encode_path(&object.bucket), key);
+            (format!("{}://{}", scheme, authority), host.to_string(), path)
+        }
+        None => {
+            let host = format!("{}.s3.{}.amazonaws.com", object.bucket, region);
+            (format!("https://{}", host), host, format!("/{}", key))
+        }
+    };
+
+    let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut headers = vec![
+        ("host", host.as_str()),
+        ("x-amz-content-sha256", sigv4::UNSIGNED_PAYLOAD),
+        ("x-amz-date", timestamp.as_str()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.as_str()));
+    }
+    let authorization = sigv4::authorization(
+        &credentials, &region, method, &canonical_uri, &headers, sigv4::UNSIGNED_PAYLOAD, &timestamp,
+    );
+
+    // ureq sets Host from the URL itself
+    let mut request = agent()
+        .request(method, &format!("{}{}", base, canonical_uri))
+        .set("Authorization", &authorization);
+    for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+        request = request.set(name, value);
+    }
+    Ok(request)
+}
+
+#[cfg(not(feature = "s3"))]
+fn signed_request(_method: &str, _uri: &str) -> Result<()> {
+    Err(LockboxError::InvalidArgument(
+        "lockbox was built without S3 support; reinstall it with the environment variable LOCKBOX_S3=true".to_string(),
+    )
+    .into())
+}
+
+/// Build a signed PUT; unless `overwrite`, the store is asked to refuse replacing an object
+#[cfg(feature = "s3")]
+fn put_request(uri: &str, overwrite: bool) -> Result<ureq::Request> {
+    let request = signed_request("PUT", uri)?;
+    Ok(if overwrite { request } else { request.set("If-None-Match", "*") })
+}
+
+/// Check the upload's status; object stores answer a successful PUT with 200
+#[cfg(feature = "s3")]
+fn uploaded(status: i32) -> Result<()> {
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(LockboxError::Network(format!("Failed to upload: the server responded with HTTP {}", status)).into())
+    }
+}
+
+/// Whether this build includes S3 support
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_s3_enabled() -> bool {
+    cfg!(feature = "s3")
+}
+
+/// Encrypt a file to public keys and upload it to S3
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_s3_encrypt_key(input_file_path: &str, uri: &str, recipients: Vec<String>, overwrite: bool, progress: Nullable<Function>) -> Result<()> {
+    #[cfg(feature = "s3")]
+    {
+        let keys = recipients.clone();
+        let result = (|| {
+            let parsed_recipients = parse_recipients(recipients)?;
+            let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+            uploaded(encrypt_upload(encryptor, input_file_path, put_request(uri, overwrite)?, progress)?)
+        })();
+        audit::record("encrypt", "key", result, |_| audit::Details::upload(input_file_path, uri, audit::recipient_keys(keys)))
+    }
+    #[cfg(not(feature = "s3"))]
+    {
+        let _ = (input_file_path, recipients, overwrite, progress);
+        signed_request("PUT", uri)
+    }
+}
+
+/// Encrypt a file with a passphrase and upload it to S3
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_s3_encrypt_passphrase(input_file_path: &str, uri: &str, passphrase: &str, passphrase_source: Nullable<String>, work_factor: Nullable<i32>, overwrite: bool, progress: Nullable<Function>) -> Result<()> {
+    #[cfg(feature = "s3")]
+    {
+        let result = (|| {
+            let passphrase = passphrase_from(passphrase, passphrase_source)?;
+            let recipient = scrypt_recipient(&passphrase, work_factor)?;
+            let encryptor = policy::encryptor(std::iter::once(&recipient as _))?;
+            uploaded(encrypt_upload(encryptor, input_file_path, put_request(uri, overwrite)?, progress)?)
+        })();
+        audit::record("encrypt", "passphrase", result, |_| audit::Details::upload(input_file_path, uri, Vec::new()))
+    }
+    #[cfg(not(feature = "s3"))]
+    {
+        let _ = (input_file_path, passphrase, passphrase_source, work_factor, overwrite, progress);
+        signed_request("PUT", uri)
+    }
+}
+
+/// Download an object from S3 and decrypt it with a private key
+///
+/// With `output_file_path`, the plaintext is written there, to a file given `mode`,
+/// `owner` and `group` if not NULL, and an empty raw vector is returned; otherwise the
+/// plaintext is returned.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_s3_decrypt_with_key(
+    uri: &str,
+    private_key_path: &str,
+    strict_permissions: bool,
+    output_file_path: Nullable<String>,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+    progress: Nullable<Function>,
+) -> Result<Raw> {
+    #[cfg(feature = "s3")]
+    {
+        let output = output_path(&output_file_path);
+        let result = (|| {
+            let key_content = read_key_file(private_key_path, strict_permissions)?;
+            let identities = parse_identities_from_key_file(&key_content)?;
+            let request = signed_request("GET", uri)?;
+            download_decrypt(request, identities.iter().map(|i| i.as_ref()), output_file_path, false, Access::from_r(mode, owner, group)?, progress)
+        })();
+        audit::record("decrypt", "key", result, |_| {
+            audit::Details::download(uri, output.as_deref(), audit::key_file_public_keys(private_key_path))
+        })
+    }
+    #[cfg(not(feature = "s3"))]
+    {
+        let _ = (private_key_path, strict_permissions, output_file_path, mode, owner, group, progress);
+        signed_request("GET", uri).map(|_| Raw::from_bytes(&[]))
+    }
+}
+
+/// Download an object from S3 and decrypt it with a passphrase
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_s3_decrypt_with_passphrase(
+    uri: &str,
+    passphrase: &str,
+    passphrase_source: Nullable<String>,
+    max_work_factor: Nullable<i32>,
+    output_file_path: Nullable<String>,
+    mode: Nullable<i32>,
+    owner: Nullable<String>,
+    group: Nullable<String>,
+    progress: Nullable<Function>,
+) -> Result<Raw> {
+    #[cfg(feature = "s3")]
+    {
+        let output = output_path(&output_file_path);
+        let result = (|| {
+            let passphrase = passphrase_from(passphrase, passphrase_source)?;
+            let identity = scrypt_identity(&passphrase, max_work_factor)?;
+            let request = signed_request("GET", uri)?;
+            download_decrypt(request, std::iter::once(&identity as _), output_file_path, false, Access::from_r(mode, owner, group)?, progress)
+        })();
+        audit::record("decrypt", "passphrase", result, |_| audit::Details::download(uri, output.as_deref(), Vec::new()))
+    }
+    #[cfg(not(feature = "s3"))]
+    {
+        let _ = (passphrase, passphrase_source, max_work_factor, output_file_path, mode, owner, group, progress);
+        signed_request("GET", uri).map(|_| Raw::from_bytes(&[]))
+    }
+}
+
+extendr_module! {
+    mod s3;
+    fn age_s3_enabled;
+    fn age_s3_encrypt_key;
+    fn age_s3_encrypt_passphrase;
+    fn age_s3_decrypt_with_key;
+    fn age_s3_decrypt_with_passphrase;
+}
@@ -0,0 +1,225 @@
+// Self-test against the age test vectors
+//
+// lockbox is compiled from source on whatever platform installs it, with whatever
+// compiler it finds. The test vectors of the age specification (C2SP's CCTV, as
+// shipped with the age crate in assets/testkit) are built into the library, so that
+// the cryptography can be checked where it actually runs: each vector is an age file,
+// armored or not, with the keys or passphrase to decrypt it and the expected outcome,
+// either the SHA-256 of the plaintext or the kind of failure.
+//
+// The age crate deviates from the vectors in a few documented ways, which count as
+// passing (see `tolerated()`).
+use extendr_api::prelude::*;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::str::FromStr;
+
+use crate::error::LockboxError;
+
+macro_rules! vectors {
+    ($($name:literal),* $(,)?) => {
+        &[$(($name, include_bytes!(concat!("../assets/testkit/", $name)))),*]
+    };
+}
+
+const VECTORS: &[(&str, &[u8])] = vectors![
+    "armor", "armor_crlf", "armor_empty_line_begin", "armor_empty_line_end",
+    "armor_eol_between_padding", "armor_full_last_line", "armor_garbage_encoded",
+    "armor_garbage_leading", "armor_garbage_trailing", "armor_header_crlf",
+    "armor_headers", "armor_invalid_character_header", "armor_invalid_character_payload",
+    "armor_long_line", "armor_lowercase", "armor_no_end_line", "armor_no_eol",
+    "armor_no_match", "armor_no_padding", "armor_not_canonical", "armor_pgp_checksum",
+    "armor_short_line", "armor_whitespace_begin", "armor_whitespace_end",
+    "armor_whitespace_eol", "armor_whitespace_last_line", "armor_whitespace_line_start",
+    "armor_whitespace_outside", "armor_wrong_type", "header_crlf", "hmac_bad",
+    "hmac_extra_space", "hmac_garbage", "hmac_missing", "hmac_no_space",
+    "hmac_not_canonical", "hmac_trailing_space", "hmac_truncated", "scrypt",
+    "scrypt_and_x25519", "scrypt_bad_tag", "scrypt_double", "scrypt_extra_argument",
+    "scrypt_long_file_key", "scrypt_no_match", "scrypt_not_canonical_body",
+    "scrypt_not_canonical_salt", "scrypt_salt_long", "scrypt_salt_missing",
+    "scrypt_salt_short", "scrypt_uppercase", "scrypt_work_factor_23",
+    "scrypt_work_factor_hex", "scrypt_work_factor_leading_garbage",
+    "scrypt_work_factor_leading_plus", "scrypt_work_factor_leading_zero_decimal",
+    "scrypt_work_factor_leading_zero_octal", "scrypt_work_factor_missing",
+    "scrypt_work_factor_negative", "scrypt_work_factor_overflow",
+    "scrypt_work_factor_trailing_garbage", "scrypt_work_factor_wrong",
+    "scrypt_work_factor_zero", "stanza_bad_start", "stanza_base64_padding",
+    "stanza_empty_argument", "stanza_empty_body", "stanza_empty_last_line",
+    "stanza_invalid_character", "stanza_long_line", "stanza_missing_body",
+    "stanza_missing_final_line", "stanza_multiple_short_lines", "stanza_no_arguments",
+    "stanza_not_canonical", "stanza_spurious_cr", "stanza_valid_characters",
+    "stream_bad_tag", "stream_bad_tag_second_chunk", "stream_bad_tag_second_chunk_full",
+    "stream_empty_payload", "stream_last_chunk_empty", "stream_last_chunk_full",
+    "stream_last_chunk_full_second", "stream_missing_tag", "stream_no_chunks",
+    "stream_no_final", "stream_no_final_full", "stream_no_final_two_chunks",
+    "stream_no_final_two_chunks_full", "stream_no_nonce", "stream_short_chunk",
+    "stream_short_nonce", "stream_short_second_chunk", "stream_three_chunks",
+    "stream_trailing_garbage_long", "stream_trailing_garbage_short", "stream_two_chunks",
+    "stream_two_final_chunks", "version_unsupported", "x25519", "x25519_bad_tag",
+    "x25519_extra_argument", "x25519_grease", "x25519_identity", "x25519_long_file_key",
+    "x25519_long_share", "x25519_low_order", "x25519_lowercase",
+    "x25519_multiple_recipients", "x25519_no_match", "x25519_not_canonical_body",
+    "x25519_not_canonical_share", "x25519_short_share",
+];
+
+/// Scrypt work factor allowed for the vectors, which go up to 2^23 on purpose
+const MAX_WORK_FACTOR: u8 = 16;
+
+#[derive(PartialEq)]
+enum Outcome {
+    /// With the SHA-256 of the plaintext
+    Success(Vec<u8>),
+    ArmorFailure,
+    HeaderFailure,
+    HmacFailure,
+    NoMatch,
+    /// With the SHA-256 of the plaintext released before the failure
+    PayloadFailure(Vec<u8>),
+}
+
+impl Outcome {
+    fn describe(&self) -> &'static str {
+        match self {
+            Outcome::Success(_) => "success",
+            Outcome::ArmorFailure => "armor failure",
+            Outcome::HeaderFailure => "header failure",
+            Outcome::HmacFailure => "HMAC failure",
+            Outcome::NoMatch => "no match",
+            Outcome::PayloadFailure(_) => "payload failure",
+        }
+    }
+}
+
+struct Vector {
+    expect: Outcome,
+    identities: Vec<String>,
+    passphrase: Option<String>,
+    age_file: Vec<u8>,
+}
+
+fn hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+/// Parse a vector: `key: value` lines up to an empty line, then the age file
+fn parse(name: &str, content: &[u8]) -> std::result::Result<Vector, LockboxError> {
+    let invalid = || LockboxError::Encoding(format!("The test vector '{}' is malformed", name));
+    let mut fields = Vec::new();
+    let mut rest = content;
+    loop {
+        let end = rest.iter().position(|&b| b == b'\n').ok_or_else(invalid)?;
+        let line = std::str::from_utf8(&rest[..end]).map_err(|_| invalid())?.trim();
+        rest = &rest[end + 1..];
+        if line.is_empty() {
+            break;
+        }
+        let (key, value) = line.split_once(": ").ok_or_else(invalid)?;
+        fields.push((key, value.trim()));
+    }
+    let field = |key: &str| fields.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+    let payload = || field("payload").and_then(hex_bytes).ok_or_else(invalid);
+    let expect = match field("expect").ok_or_else(invalid)? {
+        "success" => Outcome::Success(payload()?),
+        "armor failure" => Outcome::ArmorFailure,
+        "header failure" => Outcome::HeaderFailure,
+        "HMAC failure" => Outcome::HmacFailure,
+        "no match" => Outcome::NoMatch,
+        "payload failure" => Outcome::PayloadFailure(payload()?),
+        _ => return Err(invalid()),
+    };
+    Ok(Vector {
+        expect,
+        identities: fields.iter().filter(|(k, _)| *k == "identity").map(|(_, v)| v.to_string()).collect(),
+        passphrase: field("passphrase").map(String::from),
+        age_file: rest.to_vec(),
+    })
+}
+
+fn is_armor_error(e: &std::io::Error) -> bool {
+    e.get_ref().map_or(false, |inner| inner.is::<age::armor::ArmoredReadError>())
+}
+
+fn decrypt_error_outcome(e: age::DecryptError) -> Outcome {
+    match e {
+        age::DecryptError::InvalidMac => Outcome::HmacFailure,
+        age::DecryptError::DecryptionFailed | age::DecryptError::NoMatchingKeys => Outcome::NoMatch,
+        age::DecryptError::Io(e) if is_armor_error(&e) => Outcome::ArmorFailure,
+        _ => Outcome::HeaderFailure,
+    }
+}
+
+fn run(vector: &Vector) -> Outcome {
+    let decryptor = match age::Decryptor::new(age::armor::ArmoredReader::new(&vector.age_file[..])) {
+        Ok(decryptor) => decryptor,
+        Err(e) => return decrypt_error_outcome(e),
+    };
+    let reader = if decryptor.is_scrypt() {
+        let passphrase = vector.passphrase.clone().unwrap_or_default();
+        let mut identity = age::scrypt::Identity::new(age::secrecy::SecretString::from(passphrase));
+        identity.set_max_work_factor(MAX_WORK_FACTOR);
+        decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity))
+    } else {
+        let identities: Vec<age::x25519::Identity> = vector.identities.iter()
+            .filter_map(|identity| age::x25519::Identity::from_str(identity).ok())
+            .collect();
+        decryptor.decrypt(identities.iter().map(|i| i as &dyn age::Identity))
+    };
+    let mut reader = match reader {
+        Ok(reader) => reader,
+        Err(e) => return decrypt_error_outcome(e),
+    };
+    // What was read before a failure is part of the outcome: the vectors check that
+    // no unauthenticated byte is released
+    let mut payload = Vec::new();
+    match reader.read_to_end(&mut payload) {
+        Ok(_) => Outcome::Success(Sha256::digest(&payload).to_vec()),
+        Err(e) if is_armor_error(&e) => Outcome::ArmorFailure,
+        Err(_) => Outcome::PayloadFailure(Sha256::digest(&payload).to_vec()),
+    }
+}
+
+/// Known, deliberate differences between the age crate and the vectors
+fn tolerated(name: &str, expected: &Outcome, actual: &Outcome) -> bool {
+    match (expected, actual) {
+        // Stanzas without a final short line are still read, for old age files
+        (Outcome::HeaderFailure, Outcome::Success(_)) => {
+            ["stanza_missing_body", "stanza_missing_final_line"].contains(&name)
+        }
+        // Without a valid begin line the input is not taken to be armored at all
+        (Outcome::ArmorFailure, Outcome::HeaderFailure) => {
+            ["armor_garbage_leading", "armor_lowercase", "armor_whitespace_begin", "armor_wrong_type"].contains(&name)
+        }
+        (Outcome::Success(_), Outcome::HeaderFailure) => name == "armor_whitespace_outside",
+        _ => false,
+    }
+}
+
+/// Run the age test vectors built into the library
+///
+/// Returns one element per vector: `vector`, the `expected` and `actual` outcome,
+/// and whether it `passed`.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_self_test() -> Result<List> {
+    let mut names = Vec::with_capacity(VECTORS.len());
+    let mut expected = Vec::with_capacity(VECTORS.len());
+    let mut actual = Vec::with_capacity(VECTORS.len());
+    let mut passed = Vec::with_capacity(VECTORS.len());
+    for (name, content) in VECTORS {
+        let vector = parse(name, content)?;
+        let outcome = run(&vector);
+        passed.push(outcome == vector.expect || tolerated(name, &vector.expect, &outcome));
+        names.push(name.to_string());
+        expected.push(vector.expect.describe());
+        actual.push(outcome.describe());
+    }
+    Ok(list!(vector = names, expected = expected, actual = actual, passed = passed))
+}
+
+extendr_module! {
+    mod selftest;
+    fn age_self_test;
+}
@@ -0,0 +1,197 @@
+// Shamir secret sharing of private keys
+//
+// A key split into n shares with threshold k can be rebuilt from any k of them,
+// while k - 1 shares tell nothing about it: each byte of the key is the constant
+// term of a random polynomial of degree k - 1 over GF(256), and a share holds the
+// polynomials' values at one point. A lab can hand shares of its master key to its
+// PIs so that no one person holds it, yet any k of them can recover it together.
+//
+// Shares are bech32 strings, upper case like age private keys:
+// `LOCKBOX-SHARE-1...`, holding a version byte, the threshold, the share's point,
+// four bytes of the SHA-256 of the key's public key, and the 32 bytes of values.
+// The checksum catches a mistyped share; the public key hash catches shares of
+// another key, and a wrong recovery, which a set of valid shares can otherwise give.
+use bech32::{FromBase32, ToBase32, Variant};
+use extendr_api::prelude::*;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::error::LockboxError;
+use crate::mnemonic::{identity_from_bytes, secret_bytes, write_recovered_key};
+use crate::{parse_x25519_identities, read_key_file};
+
+const SHARE_HRP: &str = "lockbox-share-";
+const SHARE_VERSION: u8 = 1;
+
+/// Bytes of a share before its 32 values: version, threshold, point, key id
+const HEADER_LENGTH: usize = 7;
+
+/// Multiply in GF(256) with the AES polynomial, without branching on secret data
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        a = (a << 1) ^ (0x1b & (a >> 7).wrapping_neg());
+        b >>= 1;
+    }
+    product
+}
+
+/// Inverse in GF(256), as a^254; `a` is a share's point, never zero
+fn inv(a: u8) -> u8 {
+    (0..254).fold(1, |power, _| mul(power, a))
+}
+
+/// Four bytes identifying the key a share belongs to
+fn key_id(identity: &age::x25519::Identity) -> [u8; 4] {
+    let digest = Sha256::digest(identity.to_public().to_string().as_bytes());
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+struct Share {
+    threshold: u8,
+    point: u8,
+    id: [u8; 4],
+    values: Zeroizing<[u8; 32]>,
+}
+
+impl Share {
+    fn encode(&self) -> Result<Zeroizing<String>> {
+        let mut bytes = Zeroizing::new(vec![SHARE_VERSION, self.threshold, self.point]);
+        bytes.extend_from_slice(&self.id);
+        bytes.extend_from_slice(&*self.values);
+        let encoded = bech32::encode(SHARE_HRP, bytes.to_base32(), Variant::Bech32)
+            .map_err(|e| LockboxError::InvalidArgument(format!("Failed to encode a share: {}", e)))?;
+        Ok(Zeroizing::new(encoded.to_uppercase()))
+    }
+
+    /// Parse the share numbered `position` in the input, for error messages
+    fn decode(share: &str, position: usize) -> std::result::Result<Share, LockboxError> {
+        let invalid = |why: &str| LockboxError::InvalidArgument(format!("Share {} is not a valid key share: {}", position, why));
+        let (hrp, data, _) = bech32::decode(share.trim()).map_err(|_| invalid("it is mistyped or incomplete"))?;
+        if hrp != SHARE_HRP {
+            return Err(invalid("it does not start with LOCKBOX-SHARE-"));
+        }
+        let bytes = Zeroizing::new(Vec::<u8>::from_base32(&data).map_err(|_| invalid("it is mistyped or incomplete"))?);
+        if bytes.len() != HEADER_LENGTH + 32 || bytes[0] != SHARE_VERSION {
+            return Err(invalid("it was made by an unsupported version of lockbox"));
+        }
+        if bytes[1] < 2 || bytes[2] == 0 {
+            return Err(invalid("it is damaged"));
+        }
+        let mut values = Zeroizing::new([0u8; 32]);
+        values.copy_from_slice(&bytes[HEADER_LENGTH..]);
+        Ok(Share {
+            threshold: bytes[1],
+            point: bytes[2],
+            id: [bytes[3], bytes[4], bytes[5], bytes[6]],
+            values,
+        })
+    }
+}
+
+/// Split the private key of a key file into `shares` shares, any `threshold` of which
+/// rebuild it
+///
+/// The key file must hold exactly one private key.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_key_split(key_file_path: &str, strict_permissions: bool, shares: i32, threshold: i32) -> Result<Strings> {
+    if !(2..=255).contains(&shares) || !(2..=shares).contains(&threshold) {
+        return Err(LockboxError::InvalidArgument(
+            "`shares` must be between 2 and 255, and `threshold` between 2 and `shares`".to_string(),
+        ).into());
+    }
+    let key_content = read_key_file(key_file_path, strict_permissions)?;
+    let identities = parse_x25519_identities(&key_content)?;
+    if identities.len() != 1 {
+        return Err(LockboxError::InvalidIdentity(format!(
+            "The key file '{}' holds {} private keys; only a file with a single key can be split",
+            key_file_path,
+            identities.len()
+        )).into());
+    }
+    let secret = secret_bytes(&identities[0])?;
+
+    // One polynomial per key byte: the byte, then threshold - 1 random coefficients
+    let mut coefficients = Zeroizing::new(vec![0u8; 32 * (threshold as usize - 1)]);
+    rand::rngs::OsRng.fill_bytes(&mut coefficients);
+    let id = key_id(&identities[0]);
+
+    let mut encoded = Vec::with_capacity(shares as usize);
+    for point in 1..=shares as u8 {
+        let mut values = Zeroizing::new([0u8; 32]);
+        for (i, value) in values.iter_mut().enumerate() {
+            // Horner's rule, from the highest coefficient down to the key byte
+            *value = coefficients.chunks(32).rev().fold(0, |acc, c| mul(acc, point) ^ c[i]);
+            *value = mul(*value, point) ^ secret[i];
+        }
+        let share = Share { threshold: threshold as u8, point, id, values };
+        encoded.push(share.encode()?);
+    }
+    Ok(Strings::from_values(encoded.iter().map(|share| share.as_str())))
+}
+
+/// Rebuild a private key from its shares and save it to a key file
+///
+/// Any `threshold` distinct shares of the same key will do; more are ignored. An
+/// existing file is only replaced when `overwrite` is true. Returns the public key.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_key_combine(shares: Vec<String>, key_file_path: &str, overwrite: bool) -> Result<String> {
+    let shares = Zeroizing::new(shares);
+    let mut parsed: Vec<Share> = Vec::new();
+    for (position, share) in shares.iter().enumerate() {
+        let share = Share::decode(share, position + 1)?;
+        if parsed.iter().any(|other| other.point == share.point) {
+            continue;
+        }
+        parsed.push(share);
+    }
+    let first = parsed.first()
+        .ok_or_else(|| LockboxError::InvalidArgument("No shares given".to_string()))?;
+    if parsed.iter().any(|share| share.id != first.id) {
+        return Err(LockboxError::InvalidArgument("The shares do not all belong to the same key".to_string()).into());
+    }
+    if parsed.iter().any(|share| share.threshold != first.threshold) {
+        return Err(LockboxError::InvalidArgument("The shares come from different splits of the key".to_string()).into());
+    }
+    let threshold = first.threshold as usize;
+    if parsed.len() < threshold {
+        return Err(LockboxError::InvalidArgument(format!(
+            "{} distinct share{} given, but rebuilding this key takes {}",
+            parsed.len(),
+            if parsed.len() == 1 { " was" } else { "s were" },
+            threshold
+        )).into());
+    }
+    let parsed = &parsed[..threshold];
+
+    // Lagrange interpolation at zero, where subtraction is addition in GF(256)
+    let mut secret = Zeroizing::new([0u8; 32]);
+    for (j, share) in parsed.iter().enumerate() {
+        let basis = parsed.iter().enumerate()
+            .filter(|(m, _)| *m != j)
+            .fold(1, |acc, (_, other)| mul(acc, mul(other.point, inv(other.point ^ share.point))));
+        for (byte, value) in secret.iter_mut().zip(share.values.iter()) {
+            *byte ^= mul(basis, *value);
+        }
+    }
+
+    let identity = identity_from_bytes(&secret)?;
+    if key_id(&identity) != first.id {
+        return Err(LockboxError::InvalidArgument(
+            "The shares do not rebuild their key: one of them is damaged, or they come from different splits of it".to_string(),
+        ).into());
+    }
+    write_recovered_key(&identity, key_file_path, overwrite)
+}
+
+extendr_module! {
+    mod shamir;
+    fn age_key_split;
+    fn age_key_combine;
+}
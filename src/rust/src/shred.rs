@@ -0,0 +1,112 @@
+// Overwriting files before deleting them
+//
+// Deleting a file only removes its directory entry; the data stays on disk until
+// the blocks are reused. Shredding overwrites the contents first, with random data
+// and finally zeros, flushing each pass to the device, then renames the file to a
+// random name so that the old name does not linger in the directory either.
+//
+// This only reaches the original blocks on storage that writes in place. SSDs remap
+// writes to fresh flash cells, and copy-on-write or journaling file systems (btrfs,
+// ZFS, APFS, ext4 with data journaling), snapshots and backups can all keep earlier
+// copies. On such storage the only reliable protection is never to write plaintext
+// unencrypted in the first place.
+//
+// Random bytes come straight from the operating system rather than a generator seeded
+// once per process, so workers forked from one R session never pick the same names.
+use extendr_api::prelude::*;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::error::LockboxError;
+use crate::is_symlink_error;
+use crate::paths::fs_path;
+use crate::stream::{check_interrupt, CHUNK_SIZE};
+
+/// Overwrite a file `passes` times, the last time with zeros, then remove it
+pub(crate) fn shred_file(path: &str, passes: u32) -> std::io::Result<()> {
+    let path = fs_path(path);
+    let mut options = OpenOptions::new();
+    options.write(true);
+    // A symbolic link or a special file swapped in after the checks is refused by the
+    // open or by the metadata of the handle, and never written to; `O_NONBLOCK` keeps
+    // a FIFO without a reader from blocking the open
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK);
+    }
+    let mut file = options.open(&path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a regular file"));
+    }
+    let length = metadata.len();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    for pass in 1..=passes.max(1) {
+        let zeros = pass == passes.max(1);
+        file.seek(SeekFrom::Start(0))?;
+        let mut remaining = length;
+        while remaining > 0 {
+            let n = remaining.min(CHUNK_SIZE as u64) as usize;
+            if zeros {
+                buffer[..n].iter_mut().for_each(|b| *b = 0);
+            } else {
+                OsRng.fill_bytes(&mut buffer[..n]);
+            }
+            file.write_all(&buffer[..n])?;
+            remaining -= n as u64;
+        }
+        file.sync_all()?;
+    }
+    drop(file);
+
+    let mut random = [0u8; 8];
+    OsRng.fill_bytes(&mut random);
+    let name: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+    let renamed = path.with_file_name(name);
+    match std::fs::rename(&path, &renamed) {
+        Ok(()) => std::fs::remove_file(renamed),
+        Err(_) => std::fs::remove_file(&path),
+    }
+}
+
+/// Overwrite files and delete them
+///
+/// Each file is overwritten `passes` times (random data, then zeros on the last
+/// pass) before it is removed. Returns the number of files removed.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_shred(paths: Vec<String>, passes: i32) -> Result<i32> {
+    if passes < 1 {
+        return Err(LockboxError::InvalidArgument(format!("`passes` must be at least 1, got {}", passes)).into());
+    }
+    let mut removed = 0;
+    for path in &paths {
+        check_interrupt()?;
+        let file_type = std::fs::symlink_metadata(fs_path(path)).map(|m| m.file_type())
+            .map_err(|e| LockboxError::io(format!("Failed to shred '{}'", path), &e))?;
+        if file_type.is_symlink() {
+            return Err(LockboxError::Symlink(format!("'{}' is a symbolic link; refusing to shred the file it points to", path)).into());
+        }
+        if !file_type.is_file() {
+            return Err(LockboxError::InvalidArgument(format!("'{}' is not a regular file", path)).into());
+        }
+        shred_file(path, passes as u32).map_err(|e| {
+            if is_symlink_error(&e) {
+                LockboxError::Symlink(format!("'{}' is a symbolic link; refusing to shred the file it points to", path))
+            } else {
+                LockboxError::io(format!("Failed to shred '{}'", path), &e)
+            }
+        })?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+extendr_module! {
+    mod shred;
+    fn age_shred;
+}
@@ -0,0 +1,542 @@
+// Ed25519 signatures in the minisign format
+//
+// Encryption keeps data confidential but says nothing about who produced it: anyone
+// with the public key can encrypt. Signatures authenticate the origin of a file.
+// Keys and signatures use minisign's formats, so files signed here can be checked
+// with `minisign -V` and vice versa.
+//
+// A minisign signature covers the BLAKE2b-512 hash of the file ("prehashed", the
+// default since minisign 0.8), plus a trusted comment that is signed along with it.
+// Legacy signatures over the raw file are verified but never produced.
+//
+// Signatures made here always cover the file as written, so encrypt-then-sign signs
+// the ciphertext: recipients can check the origin before decrypting anything.
+use extendr_api::prelude::*;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use blake2::{Blake2b, Blake2b512, Digest};
+use blake2::digest::consts::U32;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::RngCore;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use zeroize::Zeroizing;
+
+use crate::access::Access;
+use crate::audit;
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stats::{self, Direction};
+use crate::stream::{age_input, check_age_file, copy_interruptible, PartialOutput, Progress};
+use crate::{create_output_file, parse_identities_from_key_file, parse_recipients, read_key_file};
+
+/// Signature algorithm of keys and legacy signatures
+const SIG_ALG: &[u8; 2] = b"Ed";
+/// Signature algorithm of signatures over the BLAKE2b-512 hash of the file
+const SIG_ALG_HASHED: &[u8; 2] = b"ED";
+/// Key derivation of unencrypted secret keys
+const KDF_NONE: &[u8; 2] = &[0, 0];
+/// Key derivation of password-protected secret keys
+const KDF_SCRYPT: &[u8; 2] = b"Sc";
+/// Checksum algorithm of secret keys
+const CHK_ALG: &[u8; 2] = b"B2";
+
+const UNTRUSTED_PREFIX: &str = "untrusted comment: ";
+const TRUSTED_PREFIX: &str = "trusted comment: ";
+
+/// Length of a decoded secret key: algorithms, KDF parameters, key ID, key pair, checksum
+const SECRET_KEY_LEN: usize = 2 + 2 + 2 + 32 + 8 + 8 + 8 + 64 + 32;
+
+/// Key ID as minisign displays it: the 8 bytes read as a little-endian integer
+fn key_id_hex(key_id: &[u8; 8]) -> String {
+    format!("{:016X}", u64::from_le_bytes(*key_id))
+}
+
+/// The base64 payload of a minisign file: the first line that is not a comment
+fn payload_line(content: &str) -> Option<&str> {
+    content.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with(UNTRUSTED_PREFIX))
+}
+
+/// Checksum stored in secret keys: BLAKE2b-256 of the algorithm, key ID and key pair
+fn secret_key_checksum(key_id: &[u8; 8], keypair: &[u8; 64]) -> [u8; 32] {
+    let mut hasher = Blake2b::<U32>::new();
+    hasher.update(SIG_ALG);
+    hasher.update(key_id);
+    hasher.update(keypair);
+    hasher.finalize().into()
+}
+
+/// A minisign public key
+struct PublicKey {
+    key_id: [u8; 8],
+    key: VerifyingKey,
+}
+
+impl PublicKey {
+    /// Parse a public key, either as the bare base64 string or as a `.pub` file's content
+    fn parse(content: &str) -> Result<Self> {
+        let invalid = |detail: &str| LockboxError::InvalidRecipient(format!("Invalid minisign public key: {}", detail));
+        let line = payload_line(content).ok_or_else(|| invalid("the key is empty"))?;
+        let bytes = STANDARD.decode(line).map_err(|_| invalid("the key is not valid base64"))?;
+        if bytes.len() != 42 || &bytes[..2] != SIG_ALG {
+            return Err(invalid("the key is not an Ed25519 minisign key").into());
+        }
+
+        let key_id: [u8; 8] = bytes[2..10].try_into().expect("slice of 8 bytes");
+        let key_bytes: [u8; 32] = bytes[10..].try_into().expect("slice of 32 bytes");
+        let key = VerifyingKey::from_bytes(&key_bytes)
+            .map_err(|_| invalid("the key is not a valid Ed25519 point"))?;
+        Ok(PublicKey { key_id, key })
+    }
+
+    /// The base64 string that identifies the key
+    fn encode(&self) -> String {
+        let mut bytes = Vec::with_capacity(42);
+        bytes.extend_from_slice(SIG_ALG);
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(self.key.as_bytes());
+        STANDARD.encode(bytes)
+    }
+}
+
+/// A minisign secret key
+struct SecretKey {
+    key_id: [u8; 8],
+    key: SigningKey,
+}
+
+impl SecretKey {
+    fn generate() -> Self {
+        let mut seed = Zeroizing::new([0u8; 32]);
+        let mut key_id = [0u8; 8];
+        rand::rngs::OsRng.fill_bytes(seed.as_mut());
+        rand::rngs::OsRng.fill_bytes(&mut key_id);
+        SecretKey { key_id, key: SigningKey::from_bytes(&seed) }
+    }
+
+    fn public(&self) -> PublicKey {
+        PublicKey { key_id: self.key_id, key: self.key.verifying_key() }
+    }
+
+    /// Parse an unencrypted minisign secret key file
+    fn parse(content: &str) -> Result<Self> {
+        let invalid = |detail: &str| LockboxError::InvalidIdentity(format!("Invalid minisign secret key: {}", detail));
+        let line = payload_line(content).ok_or_else(|| invalid("the key file is empty"))?;
+        let bytes = Zeroizing::new(STANDARD.decode(line).map_err(|_| invalid("the key is not valid base64"))?);
+        if bytes.len() != SECRET_KEY_LEN || &bytes[..2] != SIG_ALG || &bytes[4..6] != CHK_ALG {
+            return Err(invalid("the file is not an Ed25519 minisign secret key").into());
+        }
+        match &bytes[2..4] {
+            kdf if kdf == KDF_NONE => {}
+            kdf if kdf == KDF_SCRYPT => {
+                return Err(LockboxError::InvalidIdentity(
+                    "Password-protected minisign secret keys are not supported; create an unencrypted key with `minisign -G -W` or signing_key_generate()".to_string(),
+                ).into());
+            }
+            _ => return Err(invalid("unknown key derivation algorithm").into()),
+        }
+
+        // Key ID, key pair and checksum follow the KDF salt and limits
+        let key_id: [u8; 8] = bytes[54..62].try_into().expect("slice of 8 bytes");
+        let keypair: Zeroizing<[u8; 64]> = Zeroizing::new(bytes[62..126].try_into().expect("slice of 64 bytes"));
+        if secret_key_checksum(&key_id, &keypair) != bytes[126..] {
+            return Err(invalid("the checksum does not match, so the key file is damaged").into());
+        }
+        let key = SigningKey::from_keypair_bytes(&keypair)
+            .map_err(|_| invalid("the public half does not match the secret key"))?;
+        Ok(SecretKey { key_id, key })
+    }
+
+    /// Content of an unencrypted minisign secret key file
+    fn encode(&self) -> Zeroizing<String> {
+        let keypair = Zeroizing::new(self.key.to_keypair_bytes());
+        let mut bytes = Zeroizing::new(Vec::with_capacity(SECRET_KEY_LEN));
+        bytes.extend_from_slice(SIG_ALG);
+        bytes.extend_from_slice(KDF_NONE);
+        bytes.extend_from_slice(CHK_ALG);
+        // Salt, opslimit and memlimit are unused without a KDF
+        bytes.extend_from_slice(&[0u8; 48]);
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(keypair.as_ref());
+        bytes.extend_from_slice(&secret_key_checksum(&self.key_id, &keypair));
+
+        Zeroizing::new(format!(
+            "{}minisign secret key {}\n{}\n",
+            UNTRUSTED_PREFIX,
+            key_id_hex(&self.key_id),
+            STANDARD.encode(bytes.as_slice())
+        ))
+    }
+}
+
+/// A parsed `.minisig` file
+struct SignatureFile {
+    algorithm: [u8; 2],
+    key_id: [u8; 8],
+    signature: Signature,
+    trusted_comment: String,
+    global_signature: Signature,
+}
+
+impl SignatureFile {
+    fn parse(content: &str) -> Result<Self> {
+        let invalid = |detail: &str| LockboxError::Encoding(format!("Invalid minisign signature: {}", detail));
+        let mut lines = content.lines().map(|line| line.trim_end_matches('\r'));
+
+        match lines.next() {
+            Some(line) if line.starts_with(UNTRUSTED_PREFIX) => {}
+            _ => return Err(invalid("the first line must be an untrusted comment").into()),
+        }
+        let signature = STANDARD.decode(lines.next().unwrap_or_default())
+            .map_err(|_| invalid("the signature is not valid base64"))?;
+        if signature.len() != 74 {
+            return Err(invalid("the signature has the wrong length").into());
+        }
+        let trusted_comment = lines.next()
+            .and_then(|line| line.strip_prefix(TRUSTED_PREFIX))
+            .ok_or_else(|| invalid("the third line must be a trusted comment"))?;
+        let global_signature: [u8; 64] = STANDARD.decode(lines.next().unwrap_or_default())
+            .map_err(|_| invalid("the global signature is not valid base64"))?
+            .try_into()
+            .map_err(|_| invalid("the global signature has the wrong length"))?;
+
+        let algorithm: [u8; 2] = signature[..2].try_into().expect("slice of 2 bytes");
+        if &algorithm != SIG_ALG && &algorithm != SIG_ALG_HASHED {
+            return Err(invalid("unknown signature algorithm").into());
+        }
+        Ok(SignatureFile {
+            algorithm,
+            key_id: signature[2..10].try_into().expect("slice of 8 bytes"),
+            signature: Signature::from_bytes(&signature[10..].try_into().expect("slice of 64 bytes")),
+            trusted_comment: trusted_comment.to_string(),
+            global_signature: Signature::from_bytes(&global_signature),
+        })
+    }
+
+    fn encode(&self, untrusted_comment: &str) -> String {
+        let mut signature = Vec::with_capacity(74);
+        signature.extend_from_slice(&self.algorithm);
+        signature.extend_from_slice(&self.key_id);
+        signature.extend_from_slice(&self.signature.to_bytes());
+
+        format!(
+            "{}{}\n{}\n{}{}\n{}\n",
+            UNTRUSTED_PREFIX,
+            untrusted_comment,
+            STANDARD.encode(signature),
+            TRUSTED_PREFIX,
+            self.trusted_comment,
+            STANDARD.encode(self.global_signature.to_bytes())
+        )
+    }
+
+    /// The message covered by the global signature
+    fn global_message(&self) -> Vec<u8> {
+        let mut message = self.signature.to_bytes().to_vec();
+        message.extend_from_slice(self.trusted_comment.as_bytes());
+        message
+    }
+}
+
+fn open_file(file_path: &str) -> Result<File> {
+    File::open(fs_path(file_path))
+        .map_err(|e| LockboxError::io(format!("Failed to read '{}'", file_path), &e).into())
+}
+
+/// BLAKE2b-512 hash of an open file, read in chunks
+fn hash_file(file: &mut File, file_path: &str) -> Result<Vec<u8>> {
+    let mut hasher = Blake2b512::new();
+    copy_interruptible(file, &mut hasher, &mut Progress::none(), |e| {
+        LockboxError::io(format!("Failed to read '{}'", file_path), &e)
+    })?;
+    Ok(hasher.finalize().to_vec())
+}
+
+fn write_text_file(path: &str, content: &str, overwrite: bool, owner_only: bool) -> Result<()> {
+    let mut file = create_output_file(path, overwrite, if owner_only { Access::OWNER_ONLY } else { Access::default() })?;
+    file.write_all(content.as_bytes())
+        .map_err(|e| LockboxError::io(format!("Failed to write '{}'", path), &e).into())
+}
+
+/// Generate a minisign signing key pair
+///
+/// Writes the unencrypted secret key to `secret_key_path` (readable by the owner
+/// only) and, if given, the public key to `public_key_path`. Returns the public key.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_sign_keygen(secret_key_path: &str, public_key_path: Nullable<String>, overwrite: bool) -> Result<String> {
+    let secret_key = SecretKey::generate();
+    let public_key = secret_key.public();
+
+    write_text_file(secret_key_path, &secret_key.encode(), overwrite, true)?;
+    if let Nullable::NotNull(public_key_path) = public_key_path {
+        let content = format!(
+            "{}minisign public key {}\n{}\n",
+            UNTRUSTED_PREFIX,
+            key_id_hex(&public_key.key_id),
+            public_key.encode()
+        );
+        write_text_file(&public_key_path, &content, overwrite, false)?;
+    }
+    Ok(public_key.encode())
+}
+
+/// Public key of a minisign secret key file
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_sign_public_key(secret_key_path: &str, strict_permissions: bool) -> Result<String> {
+    let content = read_key_file(secret_key_path, strict_permissions)?;
+    Ok(SecretKey::parse(&content)?.public().encode())
+}
+
+/// Trusted comment of a new signature, checked or defaulted as minisign does
+fn trusted_comment(comment: Nullable<String>, file_path: &str) -> Result<String> {
+    match comment {
+        Nullable::NotNull(comment) if comment.contains(['\n', '\r']) => {
+            Err(LockboxError::InvalidArgument("The trusted comment must be a single line".to_string()).into())
+        }
+        Nullable::NotNull(comment) => Ok(comment),
+        Nullable::Null => {
+            let file_name = std::path::Path::new(file_path)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Ok(format!("timestamp:{}\tfile:{}\thashed", chrono::Utc::now().timestamp(), file_name))
+        }
+    }
+}
+
+/// Sign the BLAKE2b-512 hash of a file and write the signature
+fn write_signature(secret_key: &SecretKey, hash: &[u8], trusted_comment: String, signature_path: &str, overwrite: bool) -> Result<()> {
+    let signature = secret_key.key.sign(hash);
+    let mut signature_file = SignatureFile {
+        algorithm: *SIG_ALG_HASHED,
+        key_id: secret_key.key_id,
+        signature,
+        trusted_comment,
+        global_signature: signature,
+    };
+    signature_file.global_signature = secret_key.key.sign(&signature_file.global_message());
+
+    let untrusted_comment = format!("signature from minisign secret key {}", key_id_hex(&secret_key.key_id));
+    write_text_file(signature_path, &signature_file.encode(&untrusted_comment), overwrite, false)
+}
+
+/// Sign a file with a minisign secret key
+///
+/// Writes a prehashed signature to `signature_path`. `trusted_comment` defaults to
+/// the timestamp and file name, as minisign does.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_sign(
+    file_path: &str,
+    secret_key_path: &str,
+    signature_path: &str,
+    trusted_comment: Nullable<String>,
+    strict_permissions: bool,
+    overwrite: bool,
+) -> Result<()> {
+    let content = read_key_file(secret_key_path, strict_permissions)?;
+    let secret_key = SecretKey::parse(&content)?;
+    let trusted_comment = self::trusted_comment(trusted_comment, file_path)?;
+
+    let mut file = open_file(file_path)?;
+    write_signature(&secret_key, &hash_file(&mut file, file_path)?, trusted_comment, signature_path, overwrite)
+}
+
+/// Check a signature against a public key and an open file
+///
+/// Returns the trusted comment. The file is left at an unspecified position.
+fn verify_signature(file: &mut File, file_path: &str, signature_path: &str, public_key: &str) -> Result<String> {
+    let public_key = PublicKey::parse(public_key)?;
+    let content = std::fs::read_to_string(fs_path(signature_path))
+        .map_err(|e| LockboxError::io(format!("Failed to read signature '{}'", signature_path), &e))?;
+    let signature_file = SignatureFile::parse(&content)?;
+
+    if signature_file.key_id != public_key.key_id {
+        return Err(LockboxError::BadSignature(format!(
+            "The file was signed with key {}, not with the public key {}",
+            key_id_hex(&signature_file.key_id),
+            key_id_hex(&public_key.key_id)
+        )).into());
+    }
+
+    let message = if signature_file.algorithm == *SIG_ALG_HASHED {
+        hash_file(file, file_path)?
+    } else {
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)
+            .map_err(|e| LockboxError::io(format!("Failed to read '{}'", file_path), &e))?;
+        content
+    };
+    public_key.key.verify_strict(&message, &signature_file.signature)
+        .map_err(|_| LockboxError::BadSignature(
+            "Signature verification failed: the file was modified or the signature does not belong to it".to_string(),
+        ))?;
+    public_key.key.verify_strict(&signature_file.global_message(), &signature_file.global_signature)
+        .map_err(|_| LockboxError::BadSignature(
+            "Signature verification failed: the trusted comment was modified".to_string(),
+        ))?;
+
+    Ok(signature_file.trusted_comment)
+}
+
+/// Verify a minisign signature of a file
+///
+/// `public_key` is the base64 public key or the content of a `.pub` file. Returns
+/// the trusted comment, which is only meaningful once the signature is valid.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_verify(file_path: &str, signature_path: &str, public_key: &str) -> Result<String> {
+    let mut file = open_file(file_path)?;
+    verify_signature(&mut file, file_path, signature_path, public_key)
+}
+
+/// Writer that hashes everything written through it
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Blake2b512,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encrypt a file to public keys and sign the ciphertext
+///
+/// The ciphertext is hashed as it is written, so the file is encrypted and signed
+/// in a single pass. The signing key is read before anything is written, and if
+/// the signature cannot be written the ciphertext is removed too.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_encrypt_and_sign(
+    input_file_path: &str,
+    output_file_path: &str,
+    recipients: Vec<String>,
+    secret_key_path: &str,
+    signature_path: &str,
+    trusted_comment: Nullable<String>,
+    strict_permissions: bool,
+    armor: bool,
+    overwrite: bool,
+) -> Result<()> {
+    use age::armor::{ArmoredWriter, Format};
+
+    let keys = recipients.clone();
+    let result = (|| {
+        policy::check_armor(armor)?;
+        let content = read_key_file(secret_key_path, strict_permissions)?;
+        let secret_key = SecretKey::parse(&content)?;
+        let trusted_comment = self::trusted_comment(trusted_comment, output_file_path)?;
+
+        let parsed_recipients = parse_recipients(recipients)?;
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+
+        let mut input = open_file(input_file_path)?;
+        let output_file = create_output_file(output_file_path, overwrite, Access::default())?;
+        let partial = PartialOutput::new(output_file_path);
+
+        let hashing_writer = HashingWriter { inner: BufWriter::new(output_file), hasher: Blake2b512::new() };
+        let format = if armor { Format::AsciiArmor } else { Format::Binary };
+        let armored_writer = ArmoredWriter::wrap_output(hashing_writer, format)
+            .map_err(|e| LockboxError::Armor(format!("Failed to create armored writer: {}", e)))?;
+        let mut encrypted_writer = encryptor.wrap_output(armored_writer)
+            .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+
+        let bytes = copy_interruptible(&mut input, &mut encrypted_writer, &mut Progress::none(), |e| {
+            LockboxError::io("Failed to read input file", &e)
+        })?;
+        stats::add_bytes(Direction::Encrypt, bytes);
+        let mut hashing_writer = encrypted_writer.finish()
+            .and_then(|armored_writer| armored_writer.finish())
+            .map_err(|e| LockboxError::io("Failed to finalize encryption", &e))?;
+        hashing_writer.flush()
+            .map_err(|e| LockboxError::io("Failed to flush output", &e))?;
+
+        let hash = hashing_writer.hasher.finalize();
+        write_signature(&secret_key, &hash, trusted_comment, signature_path, overwrite)?;
+        partial.complete();
+        Ok(())
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(Some(input_file_path), output_file_path, done.is_some(), audit::recipient_keys(keys))
+    })
+}
+
+/// Verify the signature of an encrypted file, then decrypt it to a file
+///
+/// Nothing is decrypted unless the signature is valid. The file is opened once and
+/// read twice through the same handle, so replacing it between the check and the
+/// decryption has no effect. Returns the trusted comment.
+/// @keywords internal
+/// @noRd
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn age_verify_and_decrypt(
+    encrypted_file_path: &str,
+    signature_path: &str,
+    public_key: &str,
+    output_file_path: &str,
+    private_key_path: &str,
+    strict_permissions: bool,
+    overwrite: bool,
+) -> Result<String> {
+    let result = (|| {
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+
+        policy::check_decrypt_from(&fs_path(encrypted_file_path))?;
+        check_age_file(&fs_path(encrypted_file_path))?;
+        policy::check_plaintext_to(&fs_path(output_file_path))?;
+        let mut file = open_file(encrypted_file_path)?;
+        let trusted_comment = verify_signature(&mut file, encrypted_file_path, signature_path, public_key)?;
+        file.seek(SeekFrom::Start(0))
+            .map_err(|e| LockboxError::io(format!("Failed to read '{}'", encrypted_file_path), &e))?;
+
+        let decryptor = age::Decryptor::new(age_input(BufReader::new(file))?)
+            .map_err(|e| LockboxError::from_decrypt(e, false))?;
+        let scrypt = decryptor.is_scrypt();
+        let mut decrypted_reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))
+            .map_err(|e| LockboxError::from_decrypt(e, scrypt))?;
+
+        let output_file = create_output_file(output_file_path, overwrite, Access::OWNER_ONLY)?;
+        let partial = PartialOutput::new(output_file_path);
+        let mut output = BufWriter::new(output_file);
+        let bytes = copy_interruptible(&mut decrypted_reader, &mut output, &mut Progress::none(), LockboxError::from_payload)?;
+        stats::add_bytes(Direction::Decrypt, bytes);
+        output.flush()
+            .map_err(|e| LockboxError::io("Failed to flush output", &e))?;
+
+        partial.complete();
+        Ok(trusted_comment)
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::file(encrypted_file_path, Some(output_file_path), audit::key_file_public_keys(private_key_path))
+    })
+}
+
+extendr_module! {
+    mod signing;
+    fn age_sign_keygen;
+    fn age_sign_public_key;
+    fn age_sign;
+    fn age_verify;
+    fn age_encrypt_and_sign;
+    fn age_verify_and_decrypt;
+}
@@ -0,0 +1,111 @@
+// Passphrase sources
+//
+// Non-interactive jobs cannot answer a prompt, and a passphrase written into R code
+// or passed as an `Rscript` argument ends up in shell history, process listings and
+// logs. A passphrase source names where the passphrase is instead, and is read here
+// so that the passphrase itself never becomes an R string:
+//
+// - `env:NAME` reads the environment variable `NAME`
+// - `file:PATH` reads the first line of a file
+// - `fd:N` reads the first line of an open file descriptor (Unix only), such as a
+//   pipe set up by the calling shell with `3<<<"$SECRET"`
+//
+// A descriptor can only be read once, so the passphrase read from it is kept for the
+// rest of the session and reused whenever the same descriptor is named again.
+use extendr_api::prelude::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+
+static FD_PASSPHRASES: Mutex<Option<HashMap<i32, Zeroizing<String>>>> = Mutex::new(None);
+
+/// The first line of `content`, without its line ending
+fn first_line(content: &str) -> &str {
+    let line = content.split('\n').next().unwrap_or("");
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+fn from_env(name: &str) -> Result<Zeroizing<String>> {
+    std::env::var(name)
+        .map(Zeroizing::new)
+        .map_err(|_| LockboxError::NotFound(format!("The environment variable '{}' is not set", name)).into())
+}
+
+fn from_file(path: &str) -> Result<Zeroizing<String>> {
+    let content = Zeroizing::new(std::fs::read_to_string(fs_path(path))
+        .map_err(|e| LockboxError::io(format!("Failed to read the passphrase file '{}'", path), &e))?);
+    Ok(Zeroizing::new(first_line(&content).to_string()))
+}
+
+#[cfg(unix)]
+fn read_fd(fd: i32) -> Result<Zeroizing<String>> {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    // The descriptor belongs to whoever opened it, so it is read but never closed
+    let file = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd) });
+    let mut content = Zeroizing::new(Vec::new());
+    let mut byte = [0u8; 1];
+    loop {
+        match (&*file).read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => content.push(byte[0]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                return Err(LockboxError::io(format!("Failed to read a passphrase from file descriptor {}", fd), &e).into());
+            }
+        }
+    }
+    let content = std::str::from_utf8(&content)
+        .map_err(|_| LockboxError::Encoding(format!("The passphrase from file descriptor {} is not valid UTF-8", fd)))?;
+    Ok(Zeroizing::new(first_line(content).to_string()))
+}
+
+#[cfg(not(unix))]
+fn read_fd(_fd: i32) -> Result<Zeroizing<String>> {
+    Err(LockboxError::InvalidArgument("`fd:` passphrase sources are only supported on Unix".to_string()).into())
+}
+
+fn from_fd(fd: &str) -> Result<Zeroizing<String>> {
+    let fd: i32 = fd.parse().ok().filter(|fd| *fd >= 0)
+        .ok_or_else(|| LockboxError::InvalidArgument(format!("'{}' is not a file descriptor number", fd)))?;
+    let mut cache = FD_PASSPHRASES.lock().unwrap_or_else(|e| e.into_inner());
+    let cache = cache.get_or_insert_with(HashMap::new);
+    if let Some(passphrase) = cache.get(&fd) {
+        return Ok(passphrase.clone());
+    }
+    let passphrase = read_fd(fd)?;
+    cache.insert(fd, passphrase.clone());
+    Ok(passphrase)
+}
+
+/// Read the passphrase a source points to
+pub(crate) fn read_passphrase_source(source: &str) -> Result<Zeroizing<String>> {
+    let passphrase = match source.split_once(':') {
+        Some(("env", name)) if !name.is_empty() => from_env(name)?,
+        Some(("file", path)) if !path.is_empty() => from_file(path)?,
+        Some(("fd", fd)) => from_fd(fd)?,
+        _ => {
+            return Err(LockboxError::InvalidArgument(format!(
+                "Invalid passphrase source '{}'; use \"env:NAME\", \"file:PATH\" or \"fd:N\"",
+                source
+            )).into());
+        }
+    };
+    if passphrase.is_empty() {
+        return Err(LockboxError::InvalidArgument(format!("The passphrase from '{}' is empty", source)).into());
+    }
+    Ok(passphrase)
+}
+
+/// The passphrase to use: read from `source` if there is one, `passphrase` otherwise
+pub(crate) fn passphrase_from(passphrase: &str, source: Nullable<String>) -> Result<Zeroizing<String>> {
+    match source {
+        Nullable::NotNull(source) => read_passphrase_source(&source),
+        Nullable::Null => Ok(Zeroizing::new(passphrase.to_string())),
+    }
+}
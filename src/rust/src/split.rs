@@ -0,0 +1,246 @@
+// Encrypted files split into numbered parts
+//
+// Email and many upload forms cap the size of a single file. Rather than encrypting
+// each piece separately, the file is encrypted once and the age output is cut into
+// parts `<prefix>.001`, `<prefix>.002`, ... of at most `chunk_size` bytes. Joined
+// back in order, the parts are the original age file, so `cat prefix.* | age -d`
+// works too. Nothing records the number of parts: age authenticates every chunk of
+// the payload and marks the last one, so a missing, truncated, reordered or swapped
+// part fails decryption instead of yielding partial plaintext.
+use extendr_api::prelude::*;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::access::Access;
+use crate::audit;
+use crate::buffers::{read_buffer_size, write_buffer_size};
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::stream::{check_age_file, decrypt_reader_to_file, PartialOutput, Progress};
+use crate::{create_output_file, encrypt_stream, open_encrypt_input, parse_identities_from_key_file, parse_recipients, policy, read_key_file};
+
+/// The path of part `number` (from 1)
+fn part_path(prefix: &str, number: usize) -> String {
+    format!("{}.{:03}", prefix, number)
+}
+
+/// The existing parts of `prefix`, as (number, path), in order
+fn existing_parts(prefix: &str) -> Result<Vec<(usize, PathBuf)>> {
+    let prefix = fs_path(prefix);
+    let dir = match prefix.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let stem = prefix.file_name().and_then(|n| n.to_str()).unwrap_or("").to_string();
+    let listing = std::fs::read_dir(&dir)
+        .map_err(|e| LockboxError::io(format!("Failed to list '{}'", dir.display()), &e))?;
+
+    let mut parts = Vec::new();
+    for entry in listing {
+        let entry = entry.map_err(|e| LockboxError::io(format!("Failed to list '{}'", dir.display()), &e))?;
+        let name = entry.file_name();
+        let number = name.to_str()
+            .and_then(|n| n.strip_prefix(stem.as_str()))
+            .and_then(|n| n.strip_prefix('.'))
+            .filter(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|n| n.parse::<usize>().ok());
+        if let Some(number) = number {
+            parts.push((number, entry.path()));
+        }
+    }
+    parts.sort();
+    Ok(parts)
+}
+
+/// Writes its input across part files of at most `chunk_size` bytes
+///
+/// Each part is created on its first byte, so there is never an empty last part.
+/// Parts are removed again unless `finish()` is called.
+struct SplitWriter {
+    prefix: String,
+    chunk_size: u64,
+    overwrite: bool,
+    current: Option<BufWriter<File>>,
+    written: u64,
+    paths: Vec<String>,
+    partial: Vec<PartialOutput>,
+}
+
+impl SplitWriter {
+    fn new(prefix: &str, chunk_size: u64, overwrite: bool) -> Self {
+        SplitWriter {
+            prefix: prefix.to_string(),
+            chunk_size,
+            overwrite,
+            current: None,
+            written: 0,
+            paths: Vec::new(),
+            partial: Vec::new(),
+        }
+    }
+
+    fn next_part(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        let path = part_path(&self.prefix, self.paths.len() + 1);
+        let file = create_output_file(&path, self.overwrite, Access::default())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        self.current = Some(BufWriter::with_capacity(write_buffer_size(Path::new(&path)), file));
+        self.partial.push(PartialOutput::new(&path));
+        self.paths.push(path);
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Flush the last part and keep every part, returning their paths
+    fn finish(mut self) -> Result<Vec<String>> {
+        self.flush()
+            .map_err(|e| LockboxError::io("Failed to write output", &e))?;
+        for partial in self.partial.drain(..) {
+            partial.complete();
+        }
+        Ok(std::mem::take(&mut self.paths))
+    }
+}
+
+impl Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.current.is_none() || self.written == self.chunk_size {
+            self.next_part()?;
+        }
+        let room = (self.chunk_size - self.written).min(buf.len() as u64) as usize;
+        let n = self.current.as_mut().map_or(Ok(0), |part| part.write(&buf[..room]))?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.current.as_mut() {
+            Some(part) => part.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads the parts one after the other, opening each only when it is reached
+struct JoinedReader {
+    parts: VecDeque<PathBuf>,
+    current: Option<BufReader<File>>,
+}
+
+impl Read for JoinedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(part) = self.current.as_mut() {
+                let n = part.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+            }
+            match self.parts.pop_front() {
+                Some(path) => {
+                    let file = File::open(&path)?;
+                    self.current = Some(BufReader::with_capacity(read_buffer_size(&path), file));
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Encrypt a file to public keys as numbered parts of at most `chunk_size` bytes
+///
+/// Parts are named `<output_prefix>.001` and so on. Unless `overwrite` is true, no
+/// part may exist yet; with it, parts left over from a longer earlier split are
+/// removed, so that they cannot be joined by mistake. Returns the part paths.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_split(input_file_path: &str, output_prefix: &str, recipients: Vec<String>, chunk_size: f64, armor: bool, overwrite: bool) -> Result<Strings> {
+    let keys = recipients.clone();
+    let result = (|| {
+        if !(chunk_size >= 1.0 && chunk_size < u64::MAX as f64) {
+            return Err(LockboxError::InvalidArgument("`chunk_size` must be a positive number of bytes".to_string()).into());
+        }
+        policy::check_armor(armor)?;
+        let existing = existing_parts(output_prefix)?;
+        if !overwrite {
+            if let Some((_, path)) = existing.first() {
+                return Err(LockboxError::FileExists(format!(
+                    "Part '{}' already exists; use `overwrite = TRUE` to replace the parts",
+                    path.display()
+                )).into());
+            }
+        }
+
+        let parsed_recipients = parse_recipients(recipients)?;
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+
+        let (input, mut progress) = open_encrypt_input(input_file_path, false, Nullable::Null)?;
+        let mut input = progress.track(input);
+        let output = SplitWriter::new(output_prefix, chunk_size as u64, overwrite);
+        let paths = encrypt_stream(encryptor, &mut input, output, armor, None, &mut progress)?.finish()?;
+
+        for (number, path) in existing {
+            if number > paths.len() {
+                std::fs::remove_file(&path)
+                    .map_err(|e| LockboxError::io(format!("Failed to remove old part '{}'", path.display()), &e))?;
+            }
+        }
+        Ok(Strings::from_values(paths))
+    })();
+    // The parts are many files, so none is hashed
+    audit::record("encrypt", "key", result, |_| {
+        audit::Details::encrypted_file(Some(input_file_path), output_prefix, false, audit::recipient_keys(keys))
+    })
+}
+
+/// Join the parts of a split file and decrypt them with a private key file into `output`
+///
+/// Parts must be numbered from 1 without gaps. Returns the number of parts.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_join(input_prefix: &str, output_file_path: &str, private_key_path: &str, strict_permissions: bool) -> Result<i32> {
+    let result = (|| {
+        let parts = existing_parts(input_prefix)?;
+        if parts.is_empty() {
+            return Err(LockboxError::NotFound(format!("No parts named '{}' were found", part_path(input_prefix, 1))).into());
+        }
+        for (expected, (number, _)) in parts.iter().enumerate() {
+            if *number != expected + 1 {
+                return Err(LockboxError::NotFound(format!("Part '{}' is missing", part_path(input_prefix, expected + 1))).into());
+            }
+        }
+        for (_, path) in &parts {
+            policy::check_decrypt_from(path)?;
+        }
+        check_age_file(&parts[0].1)?;
+
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+
+        let count = parts.len() as i32;
+        let mut progress = Progress::none();
+        let reader = JoinedReader { parts: parts.into_iter().map(|(_, path)| path).collect(), current: None };
+        let reader = progress.track(reader);
+        decrypt_reader_to_file(reader, identities.iter().map(|i| i.as_ref()), output_file_path, false, Access::OWNER_ONLY, &mut progress)?;
+        Ok(count)
+    })();
+    audit::record("decrypt", "key", result, |_| audit::Details {
+        input: Some(input_prefix.to_string()),
+        output: Some(output_file_path.to_string()),
+        keys: audit::key_file_public_keys(private_key_path),
+        ..audit::Details::default()
+    })
+}
+
+extendr_module! {
+    mod split;
+    fn age_encrypt_split;
+    fn age_decrypt_join;
+}
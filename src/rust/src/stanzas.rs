@@ -0,0 +1,129 @@
+// The recipient stanza types of an age header, for explaining a failed decryption
+//
+// When no identity opens a file, age only reports that no key matched. That says
+// nothing about what the file does need: it may be encrypted to an SSH key, which
+// lockbox cannot decrypt at all, or to a YubiKey through a plugin, which needs the
+// plugin's identity file. `Stanzas` is handed
+// to age as one more identity, tried after the real ones; it never unwraps anything,
+// but sees the stanzas of the header and counts the identities tried before it.
+use age_core::format::{FileKey, Stanza};
+use std::cell::{Cell, RefCell};
+
+use crate::error::LockboxError;
+
+/// Stanza types that lockbox decrypts itself
+const SUPPORTED: &[&str] = &["X25519", "scrypt"];
+
+#[derive(Default)]
+pub(crate) struct Stanzas {
+    /// Stanza tags in header order, each with its number of stanzas, without grease
+    tags: RefCell<Vec<(String, usize)>>,
+    tried: Cell<usize>,
+    /// Whether a passphrase was among the identities tried
+    passphrase: Cell<bool>,
+}
+
+/// Whether `identity` is a passphrase
+///
+/// age's passphrase identity takes up every scrypt stanza and rejects this one for
+/// its missing arguments before any key derivation; private keys ignore it.
+fn is_passphrase(identity: &dyn age::Identity) -> bool {
+    let probe = Stanza { tag: "scrypt".to_string(), args: Vec::new(), body: Vec::new() };
+    identity.unwrap_stanza(&probe).is_some()
+}
+
+impl Stanzas {
+    /// `identities`, counted as age tries them, followed by the observer itself
+    pub(crate) fn observe<'s, 'a: 's, I>(&'s self, identities: I) -> Box<dyn Iterator<Item = &'s dyn age::Identity> + 's>
+    where
+        I: Iterator<Item = &'a dyn age::Identity> + 's,
+    {
+        Box::new(identities
+            .map(move |identity| {
+                self.tried.set(self.tried.get() + 1);
+                if is_passphrase(identity) {
+                    self.passphrase.set(true);
+                }
+                identity as &'s dyn age::Identity
+            })
+            .chain(std::iter::once(self as &dyn age::Identity)))
+    }
+
+    /// `err`, with what the header asks for when no identity matched it
+    ///
+    /// A passphrase given for a file encrypted to public keys is reported as such.
+    pub(crate) fn explain(&self, err: LockboxError) -> LockboxError {
+        let tags = self.tags.borrow();
+        if !matches!(err, LockboxError::WrongKey(_)) || tags.is_empty() {
+            return err;
+        }
+        let unsupported: Vec<String> = tags.iter()
+            .filter(|(tag, _)| !SUPPORTED.contains(&tag.as_str()))
+            .map(|(tag, _)| describe(tag))
+            .collect();
+        let x25519 = tags.iter().find(|(tag, _)| tag == "X25519").map_or(0, |(_, count)| *count);
+
+        if self.passphrase.get() {
+            let types = tags.iter().map(|(tag, _)| tag.as_str()).collect::<Vec<_>>().join(" and ");
+            let message = if x25519 > 0 {
+                format!(
+                    "Failed to decrypt: the file is encrypted to {} recipients, not with a passphrase; \
+                     decrypt it with a private key instead",
+                    types
+                )
+            } else {
+                format!(
+                    "Failed to decrypt: the file is encrypted to {} recipients, not with a passphrase, \
+                     and lockbox cannot decrypt it: {}",
+                    types,
+                    unsupported.join(", ")
+                )
+            };
+            return LockboxError::WrongMethod(message);
+        }
+        let recipients = match x25519 {
+            1 => "the file's X25519 recipient".to_string(),
+            n => format!("the file's {} X25519 recipients", n),
+        };
+        let mut message = match (x25519, self.tried.get()) {
+            (0, _) => "Failed to decrypt: the file has no X25519 recipient, so no private key file can open it".to_string(),
+            (1, 1) => format!("Failed to decrypt: the private key does not match {}", recipients),
+            (_, 1) => format!("Failed to decrypt: the private key matches none of {}", recipients),
+            (_, tried) => format!("Failed to decrypt: none of the {} private keys tried match {}", tried, recipients),
+        };
+        if !unsupported.is_empty() {
+            message.push_str(&format!(
+                ". The file is also encrypted to recipient types these keys cannot decrypt: {}",
+                unsupported.join(", ")
+            ));
+        }
+        LockboxError::WrongKey(message)
+    }
+}
+
+/// A stanza type lockbox does not decrypt, with what can decrypt it
+fn describe(tag: &str) -> String {
+    match tag {
+        "ssh-ed25519" | "ssh-rsa" => format!("{} (an SSH key, usable with the age or rage command line tools)", tag),
+        "piv-p256" => format!("{} (a YubiKey, through age-plugin-yubikey)", tag),
+        _ => format!("{} (a key held by an age plugin, used through its identity file)", tag),
+    }
+}
+
+impl age::Identity for Stanzas {
+    fn unwrap_stanza(&self, _stanza: &Stanza) -> Option<Result<FileKey, age::DecryptError>> {
+        None
+    }
+
+    fn unwrap_stanzas(&self, stanzas: &[Stanza]) -> Option<Result<FileKey, age::DecryptError>> {
+        let mut tags = self.tags.borrow_mut();
+        // age adds a stanza of random type to every header, to keep parsers tolerant
+        for stanza in stanzas.iter().filter(|s| !s.tag.ends_with("-grease")) {
+            match tags.iter_mut().find(|(tag, _)| *tag == stanza.tag) {
+                Some((_, count)) => *count += 1,
+                None => tags.push((stanza.tag.clone(), 1)),
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,119 @@
+// Session counters
+//
+// A plumber API or a Shiny app that decrypts on every request is a service, and its
+// operators want the usual gauges: how much it does, and how often it fails, and
+// with what. Wrapping every lockbox call to count them is tedious and misses the
+// calls made by other packages. Instead, the same place that writes the audit log
+// (see `audit::record()`) counts each encryption and decryption and each failure by
+// error code, and the stages that stream content count its bytes.
+//
+// The counters live in the R process and nothing is sent anywhere: exporting them,
+// to Prometheus or a log, is up to the caller. They are atomics, as content is also
+// streamed by worker threads.
+use extendr_api::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::error::error_parts;
+
+/// Which way content went through age
+#[derive(Clone, Copy)]
+pub(crate) enum Direction {
+    Encrypt,
+    Decrypt,
+}
+
+static ENCRYPTED: AtomicU64 = AtomicU64::new(0);
+static DECRYPTED: AtomicU64 = AtomicU64::new(0);
+static BYTES_ENCRYPTED: AtomicU64 = AtomicU64::new(0);
+static BYTES_DECRYPTED: AtomicU64 = AtomicU64::new(0);
+
+struct Failures {
+    /// When counting started, as an ISO 8601 UTC time
+    since: Option<String>,
+    /// Failures by error code, in the order the codes first occurred
+    by_code: Vec<(String, u64)>,
+}
+
+static FAILURES: Mutex<Failures> = Mutex::new(Failures { since: None, by_code: Vec::new() });
+
+fn failures() -> MutexGuard<'static, Failures> {
+    FAILURES.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn now() -> String {
+    chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+/// Count the plaintext bytes of content encrypted or decrypted
+pub(crate) fn add_bytes(direction: Direction, bytes: u64) {
+    let counter = match direction {
+        Direction::Encrypt => &BYTES_ENCRYPTED,
+        Direction::Decrypt => &BYTES_DECRYPTED,
+    };
+    counter.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Count the outcome of an encryption or decryption; `operation` is "encrypt" or "decrypt"
+pub(crate) fn count<T>(operation: &str, result: &Result<T>) {
+    match result {
+        Ok(_) if operation == "encrypt" => {
+            ENCRYPTED.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(_) => {
+            DECRYPTED.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(e) => {
+            let (code, _) = error_parts(e);
+            let mut failures = failures();
+            match failures.by_code.iter_mut().find(|(seen, _)| *seen == code) {
+                Some((_, n)) => *n += 1,
+                None => failures.by_code.push((code, 1)),
+            }
+        }
+    }
+}
+
+/// The session's counters
+///
+/// Returns `encrypted` and `decrypted`, the operations that succeeded, the plaintext
+/// `bytes_encrypted` and `bytes_decrypted`, as doubles since they outgrow an R
+/// integer, failures as parallel `failure_codes` and `failure_counts`, and `since`,
+/// when counting started.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_stats() -> List {
+    let mut failures = failures();
+    let since = failures.since.get_or_insert_with(now).clone();
+    let codes: Vec<String> = failures.by_code.iter().map(|(code, _)| code.clone()).collect();
+    let counts: Vec<f64> = failures.by_code.iter().map(|&(_, n)| n as f64).collect();
+    list!(
+        encrypted = ENCRYPTED.load(Ordering::Relaxed) as f64,
+        decrypted = DECRYPTED.load(Ordering::Relaxed) as f64,
+        bytes_encrypted = BYTES_ENCRYPTED.load(Ordering::Relaxed) as f64,
+        bytes_decrypted = BYTES_DECRYPTED.load(Ordering::Relaxed) as f64,
+        failure_codes = codes,
+        failure_counts = counts,
+        since = since
+    )
+}
+
+/// Set every counter back to zero, starting a new period now
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_stats_reset() {
+    let mut failures = failures();
+    for counter in [&ENCRYPTED, &DECRYPTED, &BYTES_ENCRYPTED, &BYTES_DECRYPTED] {
+        counter.store(0, Ordering::Relaxed);
+    }
+    failures.by_code.clear();
+    failures.since = Some(now());
+}
+
+extendr_module! {
+    mod stats;
+    fn age_stats;
+    fn age_stats_reset;
+}
@@ -0,0 +1,519 @@
+// Streaming helpers: interruptible chunked copies, progress reporting and safe output files
+//
+// Files are decrypted to disk through a fixed-size chain of buffers (file, armor
+// decoder, decryptor, output), so memory use does not grow with the file, armored or
+// not.
+use extendr_api::prelude::*;
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use std::cell::{Cell, RefCell};
+use std::fs::{File, Permissions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::os::raw::{c_int, c_void};
+use std::rc::Rc;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+use crate::access::Access;
+use crate::buffers::{read_buffer_size, write_buffer_size};
+use crate::create_output_file;
+use crate::error::LockboxError;
+use crate::logging::{self, Level, Timer, Value};
+use crate::metadata::{metadata_length, recorded_output, METADATA_BLOCK_MAX};
+use crate::paths::fs_path;
+use crate::stats::{self, Direction};
+use crate::stanzas::Stanzas;
+
+/// Number of bytes processed between two interrupt checks
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+extern "C" {
+    fn R_ToplevelExec(fun: Option<unsafe extern "C" fn(*mut c_void)>, data: *mut c_void) -> c_int;
+    fn R_CheckUserInterrupt();
+}
+
+unsafe extern "C" fn check_interrupt_callback(_data: *mut c_void) {
+    R_CheckUserInterrupt();
+}
+
+/// Fail if the user has pressed Ctrl-C (or Esc) since the last check
+///
+/// `R_CheckUserInterrupt()` longjmps when an interrupt is pending, which must never
+/// unwind through Rust frames. Running it inside `R_ToplevelExec()` catches the jump
+/// and reports it through the return value instead.
+pub(crate) fn check_interrupt() -> Result<()> {
+    let completed = unsafe { R_ToplevelExec(Some(check_interrupt_callback), std::ptr::null_mut()) };
+    if completed == 0 {
+        return Err(LockboxError::Interrupted("Operation interrupted by the user".to_string()).into());
+    }
+    Ok(())
+}
+
+/// Reports how many input bytes have been processed to an optional R callback
+///
+/// Progress is measured on the input side (plaintext when encrypting, ciphertext when
+/// decrypting), so `total` is always known up front. The callback receives
+/// `(processed, total)` as doubles, at most about once per percent and once at the end.
+pub(crate) struct Progress {
+    callback: Option<Function>,
+    total: u64,
+    processed: Rc<Cell<u64>>,
+    reported: u64,
+}
+
+impl Progress {
+    pub(crate) fn new(callback: Nullable<Function>, total: u64) -> Self {
+        let callback = match callback {
+            Nullable::NotNull(f) => Some(f),
+            Nullable::Null => None,
+        };
+        Progress { callback, total, processed: Rc::new(Cell::new(0)), reported: 0 }
+    }
+
+    /// Progress that is not reported anywhere
+    pub(crate) fn none() -> Self {
+        Progress::new(Nullable::Null, 0)
+    }
+
+    /// Wrap the input reader, so that every byte read from it counts as processed
+    pub(crate) fn track<R: Read>(&self, inner: R) -> TrackedReader<R> {
+        TrackedReader { inner, processed: Rc::clone(&self.processed) }
+    }
+
+    /// Record progress made outside of a tracked reader, such as by worker threads
+    pub(crate) fn set_processed(&self, processed: u64) {
+        self.processed.set(processed);
+    }
+
+    /// Call the callback if enough progress was made since the last call
+    pub(crate) fn update(&mut self) -> Result<()> {
+        let processed = self.processed.get();
+        if processed - self.reported >= (self.total / 100).max(1) {
+            self.report(processed)?;
+        }
+        Ok(())
+    }
+
+    /// Call the callback a final time, with all input processed
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        let processed = self.processed.get();
+        if self.reported < processed || processed == 0 {
+            self.report(processed)?;
+        }
+        Ok(())
+    }
+
+    fn report(&mut self, processed: u64) -> Result<()> {
+        if let Some(callback) = &self.callback {
+            callback.call(pairlist!(processed as f64, self.total as f64))?;
+        }
+        self.reported = processed;
+        Ok(())
+    }
+}
+
+/// Reader that adds the number of bytes read to a shared counter
+pub(crate) struct TrackedReader<R> {
+    inner: R,
+    processed: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for TrackedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.processed.set(self.processed.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Copy `reader` into `writer` in chunks, checking for interrupts before each chunk
+///
+/// Read errors are classified by `read_error`, since their meaning depends on whether
+/// the reader is a plain file or a decryptor. Write errors give the offset within
+/// what this call wrote. The chunk buffer is wiped when done.
+pub(crate) fn copy_interruptible<R, W, F>(
+    reader: &mut R,
+    writer: &mut W,
+    progress: &mut Progress,
+    read_error: F,
+) -> Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+    F: Fn(std::io::Error) -> LockboxError,
+{
+    let mut buffer = Zeroizing::new(vec![0u8; CHUNK_SIZE]);
+    let mut total = 0u64;
+
+    loop {
+        check_interrupt()?;
+        progress.update()?;
+
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => {
+                progress.finish()?;
+                return Ok(total);
+            }
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(read_error(e).into()),
+        };
+
+        writer.write_all(&buffer[..n])
+            .map_err(|e| LockboxError::io(format!("Failed to write output at byte {}", total), &e))?;
+        total += n as u64;
+    }
+}
+
+/// Removes a partially written output file unless the operation completes
+///
+/// Create the guard right after creating the file, so that an existing file that
+/// was refused (or never opened) is not removed.
+pub(crate) struct PartialOutput {
+    path: Option<PathBuf>,
+}
+
+impl PartialOutput {
+    pub(crate) fn new(path: &str) -> Self {
+        PartialOutput { path: Some(fs_path(path)) }
+    }
+
+    /// Keep the output file
+    pub(crate) fn complete(mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for PartialOutput {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A new temporary path next to `path`, for content that will be renamed over it
+///
+/// The name has a random part, so a file left behind by an interrupted run never
+/// blocks the next one.
+pub(crate) fn temp_path_for(path: &str) -> String {
+    use rand::RngCore;
+
+    let mut random = [0u8; 8];
+    rand::rngs::OsRng.fill_bytes(&mut random);
+    let tag: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}.{}.lockbox-tmp", path, tag)
+}
+
+/// Replace a file with content written by `write`, in the same format as before
+///
+/// The content goes to a temporary file in the same directory, which is renamed over
+/// the original only once `write` succeeds and removed otherwise, so the file is never
+/// left half-written. The original permissions are kept.
+pub(crate) fn replace_file<F>(path: &str, armored: bool, permissions: Permissions, write: F) -> Result<()>
+where
+    F: FnOnce(&mut ArmoredWriter<BufWriter<File>>) -> Result<()>,
+{
+    let temp_path = temp_path_for(path);
+    let temp_file = create_output_file(&temp_path, false, Access::default())?;
+    let partial = PartialOutput::new(&temp_path);
+
+    let format = if armored { Format::AsciiArmor } else { Format::Binary };
+    let mut output = ArmoredWriter::wrap_output(BufWriter::new(temp_file), format)
+        .map_err(|e| LockboxError::Armor(format!("Failed to create armored writer: {}", e)))?;
+    write(&mut output)?;
+    output.finish()
+        .and_then(|mut writer| writer.flush())
+        .map_err(|e| LockboxError::io("Failed to finalize output", &e))?;
+
+    std::fs::set_permissions(fs_path(&temp_path), permissions)
+        .map_err(|e| LockboxError::io(format!("Failed to set the permissions of the temporary file '{}'", temp_path), &e))?;
+    std::fs::rename(fs_path(&temp_path), fs_path(path))
+        .map_err(|e| LockboxError::io(format!("Failed to replace '{}' with the temporary file '{}'", path, temp_path), &e))?;
+    partial.complete();
+
+    Ok(())
+}
+
+/// The start of the plaintext, long enough to hold any metadata block
+///
+/// Read errors are classified by `read_error`, as in `copy_interruptible()`.
+pub(crate) fn read_head<R, F>(reader: &mut R, read_error: F) -> Result<Zeroizing<Vec<u8>>>
+where
+    R: Read,
+    F: Fn(std::io::Error) -> LockboxError,
+{
+    let mut head = Zeroizing::new(Vec::with_capacity(METADATA_BLOCK_MAX));
+    reader.take(METADATA_BLOCK_MAX as u64).read_to_end(&mut head)
+        .map_err(read_error)?;
+    Ok(head)
+}
+
+/// How an age file starts, binary (any version) or armored
+const AGE_PREFIX: &[u8] = b"age-encryption.org/";
+const ARMOR_BEGIN: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+const BYTE_ORDER_MARK: &[u8] = b"\xEF\xBB\xBF";
+
+/// Bytes read to identify an age file, leaving room for blank lines before the marker
+pub(crate) const HEAD_LENGTH: u64 = 1024;
+
+/// `bytes` past a UTF-8 byte order mark and ASCII whitespace at the start
+///
+/// Editors and mail clients put both in front of armored text, where age itself
+/// accepts neither.
+pub(crate) fn skip_preamble(bytes: &[u8]) -> &[u8] {
+    let bytes = bytes.strip_prefix(BYTE_ORDER_MARK).unwrap_or(bytes);
+    let blank = bytes.iter().take_while(|b| b.is_ascii_whitespace()).count();
+    &bytes[blank..]
+}
+
+/// Whether an age file starting with `head` is ASCII-armored
+pub(crate) fn is_armored(head: &[u8]) -> bool {
+    skip_preamble(head).starts_with(ARMOR_BEGIN)
+}
+
+/// The reader age decrypts from, for an age file in either format
+///
+/// A byte order mark and blank lines before the file are skipped, as with
+/// `skip_preamble()`. Armor lines may end in CRLF, which age accepts.
+pub(crate) fn age_input<R: BufRead>(mut input: R) -> std::result::Result<ArmoredReader<BufReader<R>>, LockboxError> {
+    let read_error = |e: std::io::Error| LockboxError::io("Failed to read encrypted input", &e);
+    if input.fill_buf().map_err(read_error)?.starts_with(BYTE_ORDER_MARK) {
+        input.consume(BYTE_ORDER_MARK.len());
+    }
+    loop {
+        let buf = input.fill_buf().map_err(read_error)?;
+        let blank = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+        if blank == 0 {
+            break;
+        }
+        input.consume(blank);
+    }
+    Ok(ArmoredReader::new(input))
+}
+
+/// Why input starting with `head` cannot be age-encrypted, or None if it may be
+///
+/// Input too short to hold a whole marker is left to the age parser, which reports
+/// it as truncated.
+pub(crate) fn not_age_reason(head: &[u8]) -> Option<&'static str> {
+    if head.is_empty() {
+        return Some("it is empty");
+    }
+    let head = skip_preamble(head);
+    let head = &head[..head.len().min(ARMOR_BEGIN.len())];
+    if head.is_empty() {
+        Some("it only holds blank lines")
+    } else if [AGE_PREFIX, ARMOR_BEGIN].iter().any(|marker| head.starts_with(marker) || marker.starts_with(head)) {
+        None
+    } else if head.starts_with(b"-----BEGIN ") {
+        Some("it is ASCII-armored, but not as an age file")
+    } else {
+        Some("it does not start with an age header")
+    }
+}
+
+/// Refuse a file that is not age-encrypted before parsing it, naming it in the error
+///
+/// A file that cannot be read passes, so that opening it reports why. When a file
+/// `<path>.age` exists next to it, the error suggests that instead.
+pub(crate) fn check_age_file(path: &Path) -> Result<()> {
+    let mut head = Vec::with_capacity(HEAD_LENGTH as usize);
+    let read = File::open(path).and_then(|file| file.take(HEAD_LENGTH).read_to_end(&mut head));
+    let reason = match read {
+        Ok(_) => not_age_reason(&head),
+        Err(_) => None,
+    };
+    let Some(reason) = reason else {
+        return Ok(());
+    };
+    let mut encrypted = path.as_os_str().to_owned();
+    encrypted.push(".age");
+    let encrypted = PathBuf::from(encrypted);
+    let hint = if encrypted.is_file() {
+        format!(" (did you mean '{}'?)", encrypted.display())
+    } else {
+        String::new()
+    };
+    Err(LockboxError::NotAgeFile(format!("'{}' is not an age-encrypted file: {}{}", path.display(), reason, hint)).into())
+}
+
+const ARMOR_END: &[u8] = b"-----END AGE ENCRYPTED FILE-----";
+
+/// Bytes kept from the end of an age file, enough to find the armor end marker
+const END_BYTES: usize = 64;
+
+/// What has been read of an age file: its length, both ends, and whether it ended
+#[derive(Default)]
+struct Seen {
+    len: u64,
+    ended: bool,
+    head: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+/// Tells an age file that was cut short from a damaged one when decrypting it fails
+///
+/// Armored text copied from an email or a chat window often loses its last lines,
+/// and age then only reports invalid armor or a failed decryption. The input is
+/// wrapped with `watch()`, and failures are classified by `header_error()` and
+/// `payload_error()` instead of `LockboxError`'s own functions. Only input that was
+/// read to its end can be found truncated.
+pub(crate) struct Truncation(Rc<RefCell<Seen>>);
+
+/// Reader that records what `Truncation` needs to know about its input
+pub(crate) struct WatchedReader<R> {
+    inner: R,
+    seen: Rc<RefCell<Seen>>,
+}
+
+impl<R: Read> Read for WatchedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut seen = self.seen.borrow_mut();
+        if n == 0 {
+            seen.ended |= !buf.is_empty();
+            return Ok(0);
+        }
+        let room = (HEAD_LENGTH as usize).saturating_sub(seen.head.len()).min(n);
+        seen.head.extend_from_slice(&buf[..room]);
+        seen.tail.extend_from_slice(&buf[n.saturating_sub(END_BYTES)..n]);
+        let excess = seen.tail.len().saturating_sub(END_BYTES);
+        seen.tail.drain(..excess);
+        seen.len += n as u64;
+        Ok(n)
+    }
+}
+
+impl Truncation {
+    pub(crate) fn watch<R: Read>(inner: R) -> (WatchedReader<R>, Truncation) {
+        let seen = Rc::new(RefCell::new(Seen::default()));
+        (WatchedReader { inner, seen: Rc::clone(&seen) }, Truncation(seen))
+    }
+
+    /// The error for armored text that ends before its end marker, if that is the case
+    fn missing_armor_end(&self) -> Option<LockboxError> {
+        let seen = self.0.borrow();
+        let armored = is_armored(&seen.head);
+        if !seen.ended || !armored || trim_end(&seen.tail).ends_with(ARMOR_END) {
+            return None;
+        }
+        Some(LockboxError::Armor(format!(
+            "Failed to decrypt: armor end marker missing, the armored text stops after {} bytes. \
+             It was probably cut off when copied; copy it again, up to and including the line '{}'",
+            seen.len,
+            String::from_utf8_lossy(ARMOR_END)
+        )))
+    }
+
+    /// Classify an error returned by age while reading the header or unwrapping the file key
+    pub(crate) fn header_error(&self, err: age::DecryptError, scrypt: bool) -> LockboxError {
+        if let Some(armor_error) = self.missing_armor_end() {
+            return armor_error;
+        }
+        let seen = self.0.borrow();
+        match &err {
+            age::DecryptError::Io(e) if seen.ended && e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                LockboxError::CorruptHeader(format!(
+                    "Failed to decrypt: the file is truncated at byte {}, inside the age header",
+                    seen.len
+                ))
+            }
+            _ => LockboxError::from_decrypt(err, scrypt),
+        }
+    }
+
+    /// Classify an I/O error raised while reading decrypted content
+    pub(crate) fn payload_error(&self, err: std::io::Error) -> LockboxError {
+        if let Some(armor_error) = self.missing_armor_end() {
+            return armor_error;
+        }
+        let seen = self.0.borrow();
+        if seen.ended && err.kind() == std::io::ErrorKind::UnexpectedEof {
+            return LockboxError::CorruptPayload(format!(
+                "Failed to decrypt: ciphertext truncated at byte {}, before the last chunk of the payload",
+                seen.len
+            ));
+        }
+        LockboxError::from_payload(err)
+    }
+}
+
+/// `bytes` without ASCII whitespace at the end
+fn trim_end(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(0, |i| i + 1);
+    &bytes[..end]
+}
+
+/// Decrypt a file in either format into a new output file, on the calling thread
+///
+/// With `into_directory`, `output` is a directory that receives the file under the
+/// name recorded at encryption. The metadata block is not written out but returned
+/// (empty without one). The output is only created once the key has been accepted,
+/// so a wrong passphrase can be retried, and is removed if decryption fails later.
+/// It is created with `access`, its mode, owner and group.
+pub(crate) fn decrypt_to_file<'a, I>(
+    input: &Path,
+    identities: I,
+    output: &str,
+    into_directory: bool,
+    access: Access,
+    progress: &mut Progress,
+) -> Result<Zeroizing<Vec<u8>>>
+where
+    I: Iterator<Item = &'a dyn age::Identity>,
+{
+    crate::policy::check_decrypt_from(input)?;
+    check_age_file(input)?;
+    let file = File::open(input)
+        .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", input.display()), &e))?;
+    let file = progress.track(BufReader::with_capacity(read_buffer_size(input), file));
+    decrypt_reader_to_file(file, identities, output, into_directory, access, progress)
+}
+
+/// Decrypt an age file read from `input`, such as bytes already in memory, into a new
+/// output file, as `decrypt_to_file()` does
+pub(crate) fn decrypt_reader_to_file<'a, R, I>(
+    input: R,
+    identities: I,
+    output: &str,
+    into_directory: bool,
+    access: Access,
+    progress: &mut Progress,
+) -> Result<Zeroizing<Vec<u8>>>
+where
+    R: Read,
+    I: Iterator<Item = &'a dyn age::Identity>,
+{
+    let timer = Timer::start();
+    let (input, truncation) = Truncation::watch(input);
+    let decryptor = age::Decryptor::new(age_input(BufReader::new(input))?)
+        .map_err(|e| truncation.header_error(e, false))?;
+    let scrypt = decryptor.is_scrypt();
+    let stanzas = Stanzas::default();
+    let mut reader = decryptor.decrypt(stanzas.observe(identities))
+        .map_err(|e| stanzas.explain(truncation.header_error(e, scrypt)))?;
+    logging::event(Level::Debug, "header", &[("scrypt", Value::Flag(scrypt)), ("ms", Value::Elapsed(timer))]);
+    let timer = Timer::start();
+    let head = read_head(&mut reader, |e| truncation.payload_error(e))?;
+    let skip = metadata_length(&head)?;
+
+    let output_file_path = &if into_directory { recorded_output(output, &head)? } else { output.to_string() };
+    crate::policy::check_plaintext_to(&fs_path(output_file_path))?;
+    let output_file = create_output_file(output_file_path, false, access)?;
+    let mut writer = BufWriter::with_capacity(write_buffer_size(Path::new(output_file_path)), output_file);
+    let partial = PartialOutput::new(output_file_path);
+    writer.write_all(&head[skip..])
+        .map_err(|e| LockboxError::io("Failed to write output", &e))?;
+    let bytes = copy_interruptible(&mut reader, &mut writer, progress, |e| truncation.payload_error(e))?;
+    stats::add_bytes(Direction::Decrypt, bytes + (head.len() - skip) as u64);
+    writer.flush()
+        .map_err(|e| LockboxError::io("Failed to write output", &e))?;
+    partial.complete();
+    logging::event(Level::Debug, "payload", &[
+        ("direction", Value::Label("decrypt")),
+        ("output", Value::Path(Path::new(output_file_path))),
+        ("bytes", Value::Number(bytes + (head.len() - skip) as u64)),
+        ("ms", Value::Elapsed(timer)),
+    ]);
+    Ok(Zeroizing::new(head[..skip].to_vec()))
+}
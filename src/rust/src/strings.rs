@@ -0,0 +1,127 @@
+// Vectorized encryption of character vectors
+//
+// Each element is encrypted independently, so that a data frame column can be
+// encrypted once and single values decrypted later. The elements are spread over a
+// rayon thread pool. Worker threads must never call into R, so they work on concrete
+// x25519 keys and report `LockboxError`s; interrupts are checked on the main thread
+// between batches.
+use extendr_api::prelude::*;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use rayon::prelude::*;
+use std::io::{Read, Write};
+use zeroize::Zeroizing;
+
+use crate::audit;
+use crate::encoding::decode_encrypted;
+use crate::error::LockboxError;
+use crate::policy;
+use crate::stats::{self, Direction};
+use crate::stream::{age_input, check_interrupt};
+use crate::{parse_x25519_identities, parse_x25519_recipients, read_key_file};
+
+/// Number of elements processed between two interrupt checks
+const BATCH_SIZE: usize = 4096;
+
+/// Encrypt one value to base64-encoded binary age
+fn encrypt_value(recipients: &[age::x25519::Recipient], value: &str) -> std::result::Result<String, LockboxError> {
+    let encryptor = policy::encryptor(recipients.iter().map(|r| r as &dyn age::Recipient))?;
+
+    let mut output = Vec::new();
+    let mut encrypted_writer = encryptor.wrap_output(&mut output)
+        .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+    encrypted_writer.write_all(value.as_bytes())
+        .and_then(|_| encrypted_writer.finish())
+        .map_err(|e| LockboxError::io("Failed to write encrypted data", &e))?;
+    stats::add_bytes(Direction::Encrypt, value.len() as u64);
+
+    Ok(STANDARD.encode(&output))
+}
+
+/// Decrypt one value, in any of the text encodings
+fn decrypt_value(identities: &[age::x25519::Identity], value: &str) -> std::result::Result<String, LockboxError> {
+    let encrypted_bytes = decode_encrypted(value)?;
+
+    let decryptor = age::Decryptor::new(age_input(encrypted_bytes.as_slice())?)
+        .map_err(|e| LockboxError::from_decrypt(e, false))?;
+    let scrypt = decryptor.is_scrypt();
+    let mut decrypted_reader = decryptor.decrypt(identities.iter().map(|i| i as &dyn age::Identity))
+        .map_err(|e| LockboxError::from_decrypt(e, scrypt))?;
+
+    let mut decrypted = Zeroizing::new(Vec::with_capacity(encrypted_bytes.len()));
+    decrypted_reader.read_to_end(&mut decrypted)
+        .map_err(LockboxError::from_payload)?;
+    stats::add_bytes(Direction::Decrypt, decrypted.len() as u64);
+
+    std::str::from_utf8(&decrypted)
+        .map(str::to_string)
+        .map_err(|e| LockboxError::Encoding(format!("Failed to convert decrypted content to UTF-8: {}", e)))
+}
+
+/// Apply `f` to every value on a thread pool, in batches, keeping the order
+pub(crate) fn map_parallel<T, U, F>(values: &[T], threads: Nullable<i32>, f: F) -> Result<Vec<U>>
+where
+    T: Sync,
+    U: Send,
+    F: Fn(&T) -> std::result::Result<U, LockboxError> + Sync,
+{
+    let threads = match threads {
+        Nullable::NotNull(n) if n < 1 => {
+            return Err(LockboxError::InvalidArgument(format!("`threads` must be at least 1, not {}", n)).into());
+        }
+        Nullable::NotNull(n) => n as usize,
+        // rayon picks one thread per core
+        Nullable::Null => 0,
+    };
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|e| LockboxError::Io(format!("Failed to start worker threads: {}", e)))?;
+
+    let mut results = Vec::with_capacity(values.len());
+    for batch in values.chunks(BATCH_SIZE) {
+        check_interrupt()?;
+        let batch_results = pool.install(|| {
+            batch.par_iter()
+                .map(&f)
+                .collect::<std::result::Result<Vec<U>, LockboxError>>()
+        })?;
+        results.extend(batch_results);
+    }
+    Ok(results)
+}
+
+/// Encrypt each element of a character vector to public keys
+///
+/// Returns base64-encoded binary age, one element per input element. The vector is
+/// one operation in the audit log, without a hash.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_strings(values: Vec<String>, recipients: Vec<String>, threads: Nullable<i32>) -> Result<Vec<String>> {
+    let result = parse_x25519_recipients(&recipients)
+        .and_then(|parsed| map_parallel(&values, threads, |value| encrypt_value(&parsed, value)));
+    audit::record("encrypt", "key", result, |_| audit::Details { keys: audit::recipient_keys(recipients), ..audit::Details::default() })
+}
+
+/// Decrypt each element of a character vector with a private key
+///
+/// The vector is one operation in the audit log, without a hash.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_strings(values: Vec<String>, private_key_path: &str, strict_permissions: bool, threads: Nullable<i32>) -> Result<Vec<String>> {
+    let result = (|| {
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_x25519_identities(&key_content)?;
+        map_parallel(&values, threads, |value| decrypt_value(&identities, value))
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details { keys: audit::key_file_public_keys(private_key_path), ..audit::Details::default() }
+    })
+}
+
+extendr_module! {
+    mod strings;
+    fn age_encrypt_strings;
+    fn age_decrypt_strings;
+}
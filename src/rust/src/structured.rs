@@ -0,0 +1,262 @@
+// Partial encryption of YAML and JSON documents
+//
+// Selected leaf values are replaced by armored age blobs, while keys, structure and
+// the other values stay in the clear, so that configuration files remain readable and
+// diffable under version control. Each blob holds the JSON encoding of the original
+// value, so numbers, booleans and nulls come back with their type when decrypted.
+//
+// Both formats are read into a `serde_yaml::Value`, whose mappings keep key order.
+// Comments and YAML formatting are not preserved.
+use extendr_api::prelude::*;
+use serde_yaml::Value;
+use std::collections::HashSet;
+use std::io::Write;
+use zeroize::Zeroizing;
+
+use crate::audit;
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stats::{self, Direction};
+use crate::stream::{replace_file, Progress};
+use crate::{decrypt_content, parse_identities_from_key_file, parse_recipients, read_key_file};
+
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// Document formats that can be partially encrypted
+#[derive(Clone, Copy)]
+enum DocumentFormat {
+    Yaml,
+    Json,
+}
+
+impl DocumentFormat {
+    fn parse(format: &str) -> Result<Self> {
+        match format {
+            "yaml" => Ok(DocumentFormat::Yaml),
+            "json" => Ok(DocumentFormat::Json),
+            _ => Err(LockboxError::InvalidArgument(format!(
+                "Unsupported document format '{}': use \"yaml\" or \"json\"",
+                format
+            )).into()),
+        }
+    }
+
+    fn read(self, content: &str) -> Result<Value> {
+        match self {
+            DocumentFormat::Yaml => serde_yaml::from_str(content)
+                .map_err(|e| LockboxError::Encoding(format!("Failed to parse YAML: {}", e)).into()),
+            DocumentFormat::Json => serde_json::from_str(content)
+                .map_err(|e| LockboxError::Encoding(format!("Failed to parse JSON: {}", e)).into()),
+        }
+    }
+
+    fn write(self, document: &Value) -> Result<String> {
+        match self {
+            DocumentFormat::Yaml => serde_yaml::to_string(document)
+                .map_err(|e| LockboxError::Encoding(format!("Failed to write YAML: {}", e)).into()),
+            DocumentFormat::Json => serde_json::to_string_pretty(document)
+                .map(|json| json + "\n")
+                .map_err(|e| LockboxError::Encoding(format!("Failed to write JSON: {}", e)).into()),
+        }
+    }
+}
+
+/// A mapping key as it appears in selectors and paths
+fn key_name(key: &Value) -> String {
+    match key {
+        Value::String(s) => s.clone(),
+        other => serde_json::to_string(other).unwrap_or_default(),
+    }
+}
+
+fn is_encrypted(value: &Value) -> bool {
+    matches!(value, Value::String(s) if s.starts_with(ARMOR_BEGIN))
+}
+
+/// Encrypt `plaintext` to an armored age blob
+fn encrypt_armored(recipients: &[Box<dyn age::Recipient>], plaintext: &[u8]) -> Result<String> {
+    use age::armor::{ArmoredWriter, Format};
+
+    let encryptor = policy::encryptor(recipients.iter().map(|r| r.as_ref()))?;
+
+    let mut output = Vec::new();
+    let armored_writer = ArmoredWriter::wrap_output(&mut output, Format::AsciiArmor)
+        .map_err(|e| LockboxError::Armor(format!("Failed to create armored writer: {}", e)))?;
+    let mut encrypted_writer = encryptor.wrap_output(armored_writer)
+        .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+    encrypted_writer.write_all(plaintext)
+        .map_err(|e| LockboxError::io("Failed to write encrypted data", &e))?;
+    encrypted_writer.finish()
+        .and_then(|armored_writer| armored_writer.finish())
+        .map_err(|e| LockboxError::io("Failed to finalize encryption", &e))?;
+    stats::add_bytes(Direction::Encrypt, plaintext.len() as u64);
+
+    // Armor is always ASCII
+    Ok(String::from_utf8(output).expect("armored output is ASCII"))
+}
+
+/// Walks a document, encrypting the leaves under selected keys
+struct ValueEncryptor<'a> {
+    selectors: &'a [String],
+    matched: HashSet<&'a str>,
+    recipients: Vec<Box<dyn age::Recipient>>,
+}
+
+impl<'a> ValueEncryptor<'a> {
+    /// Whether the key at `path` is selected, by name or by dotted path
+    fn selects(&mut self, path: &[String]) -> bool {
+        let name = path.last().map(String::as_str).unwrap_or_default();
+        let dotted = path.join(".");
+        let mut selected = false;
+        for selector in self.selectors {
+            if selector == name || *selector == dotted {
+                self.matched.insert(selector);
+                selected = true;
+            }
+        }
+        selected
+    }
+
+    fn visit(&mut self, value: &mut Value, path: &mut Vec<String>, selected: bool) -> Result<()> {
+        match value {
+            Value::Mapping(mapping) => {
+                for (key, child) in mapping.iter_mut() {
+                    path.push(key_name(key));
+                    let child_selected = self.selects(path) || selected;
+                    self.visit(child, path, child_selected)?;
+                    path.pop();
+                }
+            }
+            Value::Sequence(sequence) => {
+                for (i, child) in sequence.iter_mut().enumerate() {
+                    path.push(i.to_string());
+                    let child_selected = self.selects(path) || selected;
+                    self.visit(child, path, child_selected)?;
+                    path.pop();
+                }
+            }
+            Value::Tagged(tagged) => self.visit(&mut tagged.value, path, selected)?,
+            // Already encrypted values are left alone, so encrypting twice is harmless
+            leaf if selected && !is_encrypted(leaf) => {
+                let plaintext = Zeroizing::new(serde_json::to_vec(leaf)
+                    .map_err(|e| LockboxError::Encoding(format!("Failed to encode value: {}", e)))?);
+                *leaf = Value::String(encrypt_armored(&self.recipients, &plaintext)?);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Encrypt selected values of a YAML or JSON file in place
+///
+/// `keys` are matched against mapping keys, either by name at any depth or by
+/// dotted path from the root (sequence elements are numbered from 0). Every leaf
+/// under a matched key is encrypted. Fails without changing the file if a key
+/// matches nothing.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_structured(file_path: &str, recipients: Vec<String>, keys: Vec<String>, format: &str) -> Result<()> {
+    let public_keys = recipients.clone();
+    let result = (|| {
+        let format = DocumentFormat::parse(format)?;
+        let recipients = parse_recipients(recipients)?;
+
+        let content = Zeroizing::new(std::fs::read_to_string(fs_path(file_path))
+            .map_err(|e| LockboxError::io("Failed to read input file", &e))?);
+        let mut document = format.read(&content)?;
+
+        let mut encryptor = ValueEncryptor { selectors: &keys, matched: HashSet::new(), recipients };
+        encryptor.visit(&mut document, &mut Vec::new(), false)?;
+
+        let unmatched: Vec<&str> = keys.iter()
+            .map(String::as_str)
+            .filter(|key| !encryptor.matched.contains(key))
+            .collect();
+        if !unmatched.is_empty() {
+            return Err(LockboxError::NotFound(format!(
+                "Key(s) not found in document: {}",
+                unmatched.join(", ")
+            )).into());
+        }
+
+        let output = format.write(&document)?;
+        let permissions = std::fs::metadata(fs_path(file_path))
+            .map_err(|e| LockboxError::io("Failed to read input file", &e))?
+            .permissions();
+        replace_file(file_path, false, permissions, |writer| {
+            writer.write_all(output.as_bytes())
+                .map_err(|e| LockboxError::io("Failed to write output", &e).into())
+        })
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(Some(file_path), file_path, done.is_some(), audit::recipient_keys(public_keys))
+    })
+}
+
+/// Decrypt every armored age blob in a document, in place
+fn decrypt_values(value: &mut Value, identities: &[Box<dyn age::Identity>]) -> Result<()> {
+    match value {
+        Value::Mapping(mapping) => {
+            for (_, child) in mapping.iter_mut() {
+                decrypt_values(child, identities)?;
+            }
+        }
+        Value::Sequence(sequence) => {
+            for child in sequence.iter_mut() {
+                decrypt_values(child, identities)?;
+            }
+        }
+        Value::Tagged(tagged) => decrypt_values(&mut tagged.value, identities)?,
+        Value::String(blob) if blob.starts_with(ARMOR_BEGIN) => {
+            let plaintext = decrypt_content(blob.as_bytes(), identities.iter().map(|i| i.as_ref()), &mut Progress::none())?;
+            // Blobs written by other tools may hold a bare string rather than JSON
+            *value = match serde_json::from_slice(&plaintext) {
+                Ok(decoded) => decoded,
+                Err(_) => Value::String(
+                    std::str::from_utf8(&plaintext)
+                        .map_err(|e| LockboxError::Encoding(format!("Failed to convert decrypted value to UTF-8: {}", e)))?
+                        .to_string(),
+                ),
+            };
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Decrypt the encrypted values of a YAML or JSON file
+///
+/// Returns the whole document, with every armored age blob replaced by its
+/// decrypted value, as a string in the same format. The file is not modified.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_structured(file_path: &str, private_key_path: &str, strict_permissions: bool, format: &str) -> Result<String> {
+    let result = (|| {
+        let format = DocumentFormat::parse(format)?;
+
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+
+        let path = fs_path(file_path);
+        policy::check_decrypt_from(&path)?;
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| LockboxError::io("Failed to read input file", &e))?;
+        let mut document = format.read(&content)?;
+        decrypt_values(&mut document, &identities)?;
+
+        format.write(&document)
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::file(file_path, None, audit::key_file_public_keys(private_key_path))
+    })
+}
+
+extendr_module! {
+    mod structured;
+    fn age_encrypt_structured;
+    fn age_decrypt_structured;
+}
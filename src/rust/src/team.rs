@@ -0,0 +1,229 @@
+// Team recipient registry
+//
+// Public keys are long and change when someone replaces a laptop, so scripts that
+// spell them out must all be edited. A registry maps member names to their keys, and
+// once a session has one, every function that takes recipients also accepts member
+// and group names. The registry is read again at each call, so a rotated key takes
+// effect as soon as its line is edited.
+//
+// A registry is either a TOML file:
+//
+//     [members]
+//     alice = "age1..."
+//     bob = ["age1...", "age1..."]    # one key per device
+//
+//     [groups]
+//     analysts = ["alice", "bob"]
+//
+// or a directory of `<name>.pub` files, each holding one or more public keys and
+// optional `#` comments, as teams often collect them in a repository. Names the
+// registry does not know, such as the email addresses of people outside the team,
+// go to the session's recipient resolvers (see resolver.rs).
+use extendr_api::prelude::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::resolver;
+
+static TEAM: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+fn team() -> MutexGuard<'static, Option<PathBuf>> {
+    TEAM.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistryFile {
+    #[serde(default)]
+    members: BTreeMap<String, OneOrMore>,
+    #[serde(default)]
+    groups: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMore {
+    One(String),
+    More(Vec<String>),
+}
+
+struct Registry {
+    members: BTreeMap<String, Vec<String>>,
+    groups: BTreeMap<String, Vec<String>>,
+}
+
+fn is_public_key(recipient: &str) -> bool {
+    recipient.starts_with("age1")
+}
+
+impl Registry {
+    fn read(path: &Path) -> Result<Self> {
+        let invalid = |why: String| LockboxError::InvalidArgument(format!("Invalid team registry '{}': {}", path.display(), why));
+        let read_failed = |e: std::io::Error| LockboxError::io(format!("Failed to read the team registry '{}'", path.display()), &e);
+
+        let registry = if std::fs::metadata(path).map_err(read_failed)?.is_dir() {
+            let mut members = BTreeMap::new();
+            for entry in std::fs::read_dir(path).map_err(read_failed)? {
+                let file = entry.map_err(read_failed)?.path();
+                if file.extension().map_or(true, |ext| ext != "pub") {
+                    continue;
+                }
+                let name = file.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+                let keys = std::fs::read_to_string(&file).map_err(read_failed)?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(String::from)
+                    .collect();
+                members.insert(name, keys);
+            }
+            Registry { members, groups: BTreeMap::new() }
+        } else {
+            let content = std::fs::read_to_string(path).map_err(read_failed)?;
+            let file: RegistryFile = toml::from_str(&content).map_err(|e| invalid(e.to_string().trim_end().to_string()))?;
+            let members = file.members.into_iter()
+                .map(|(name, keys)| match keys {
+                    OneOrMore::One(key) => (name, vec![key]),
+                    OneOrMore::More(keys) => (name, keys),
+                })
+                .collect();
+            Registry { members, groups: file.groups }
+        };
+
+        for (name, keys) in &registry.members {
+            if is_public_key(name) {
+                return Err(invalid(format!("the member name '{}' looks like a public key", name)).into());
+            }
+            if keys.is_empty() {
+                return Err(invalid(format!("'{}' has no public key", name)).into());
+            }
+            if let Some(key) = keys.iter().find(|key| key.parse::<age::x25519::Recipient>().is_err()) {
+                return Err(invalid(format!("'{}' is not a valid public key for '{}'", key, name)).into());
+            }
+        }
+        for (group, names) in &registry.groups {
+            if registry.members.contains_key(group) {
+                return Err(invalid(format!("'{}' is both a member and a group", group)).into());
+            }
+            if let Some(name) = names.iter().find(|name| !registry.members.contains_key(*name)) {
+                return Err(invalid(format!("the group '{}' lists '{}', who is not a member", group, name)).into());
+            }
+        }
+        Ok(registry)
+    }
+
+    /// The public keys of a member, or of every member of a group
+    fn keys(&self, name: &str) -> Option<Vec<&String>> {
+        if let Some(keys) = self.members.get(name) {
+            return Some(keys.iter().collect());
+        }
+        self.groups.get(name).map(|names| names.iter().flat_map(|name| &self.members[name]).collect())
+    }
+}
+
+/// Replace member and group names among `recipients` by their public keys
+///
+/// Names are looked up in `registry`, or else in the session's registry, and then
+/// asked of the session's recipient resolvers. Public keys are kept as they are, and
+/// each key appears once in the result.
+pub(crate) fn resolve_recipients(recipients: &[String], registry: Option<&Path>) -> Result<Vec<String>> {
+    let name = match recipients.iter().find(|r| !is_public_key(r)) {
+        Some(name) => name,
+        None => return Ok(recipients.to_vec()),
+    };
+    let path = registry.map(Path::to_path_buf).or_else(|| team().clone());
+    if path.is_none() && !resolver::any() {
+        return Err(LockboxError::InvalidRecipient(format!(
+            "Invalid recipient '{}': not an age public key, and no team registry or recipient resolver is set (see `team_use()` and `recipient_resolvers_use()`)",
+            name
+        )).into());
+    }
+    let registry = path.as_deref().map(Registry::read).transpose()?;
+
+    let mut resolved: Vec<String> = Vec::new();
+    for recipient in recipients {
+        let keys = if is_public_key(recipient) {
+            vec![recipient.clone()]
+        } else if let Some(keys) = registry.as_ref().and_then(|registry| registry.keys(recipient)) {
+            keys.into_iter().cloned().collect()
+        } else if let Some(keys) = resolver::resolve(recipient)? {
+            keys
+        } else {
+            return Err(LockboxError::InvalidRecipient(match &path {
+                Some(path) => format!(
+                    "Unknown recipient '{}': not an age public key, or a member or group in '{}'",
+                    recipient,
+                    path.display()
+                ),
+                None => format!(
+                    "Unknown recipient '{}': not an age public key, and no recipient resolver knows it",
+                    recipient
+                ),
+            }).into());
+        };
+        for key in keys {
+            if !resolved.contains(&key) {
+                resolved.push(key);
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+/// Set or remove the session's team registry
+///
+/// The registry is read once to report problems now; with `path` NULL, it is removed.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_team_use(path: Nullable<String>) -> Result<()> {
+    *team() = match path {
+        Nullable::NotNull(path) => {
+            let path = fs_path(&path);
+            Registry::read(&path)?;
+            Some(path)
+        }
+        Nullable::Null => None,
+    };
+    Ok(())
+}
+
+/// The members of a team registry and their public keys, one row per key
+///
+/// With `path` NULL, the session's registry is listed. Returns `name`, `public` and
+/// `groups`, the groups the member belongs to separated by commas.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_team_members(path: Nullable<String>) -> Result<List> {
+    let path = match path {
+        Nullable::NotNull(path) => fs_path(&path),
+        Nullable::Null => team().clone().ok_or_else(|| {
+            LockboxError::NotFound("No team registry is set; see `team_use()`".to_string())
+        })?,
+    };
+    let registry = Registry::read(&path)?;
+    let (mut names, mut keys, mut groups) = (Vec::new(), Vec::new(), Vec::new());
+    for (name, member_keys) in &registry.members {
+        let member_groups: Vec<&str> = registry.groups.iter()
+            .filter(|(_, names)| names.contains(name))
+            .map(|(group, _)| group.as_str())
+            .collect();
+        for key in member_keys {
+            names.push(name.clone());
+            keys.push(key.clone());
+            groups.push(member_groups.join(","));
+        }
+    }
+    Ok(list!(name = names, public = keys, groups = groups))
+}
+
+extendr_module! {
+    mod team;
+    fn age_team_use;
+    fn age_team_members;
+}
@@ -0,0 +1,283 @@
+// Temporary plaintext files
+//
+// Some tools only read from a path, so the plaintext has to exist on disk for a
+// while. Decrypted temporary files are created readable by their owner only, under
+// a random name that is never reused, and recorded in a registry for the session.
+// Cleaning up shreds each recorded file with a single pass of zeros; R cleans
+// up when the package is unloaded and when the session ends, so a forgotten file
+// does not outlive the session that created it.
+//
+// Other tools write plaintext too, such as a key decrypted for SOPS or a file
+// exported for a command-line program, and they create files with whatever
+// permissions they like. `age_secure_tempdir()` gives them a directory that only its
+// owner can enter, mode 0700 whatever the umask, so what they write there is private
+// however they create it. By default it is made in R's temporary directory; on Linux,
+// /dev/shm keeps the plaintext in memory instead. These directories are recorded in
+// the same registry and wiped, with everything in them, by the same cleanup.
+//
+// A forked R worker inherits the registry of its parent. Its files are its own: the
+// registry remembers which process it belongs to and starts empty in any other, so a
+// worker that cleans up (or unloads the package) never wipes files its parent still
+// uses.
+//
+// A small SQLite database kept encrypted in a project is used the same way: it is
+// decrypted to a temporary file, opened there, and sealed again with
+// `age_encrypt_db()`, which replaces the encrypted copy only once the new one is
+// complete. SQLite writes journals next to the database (`-journal`, `-wal`,
+// `-shm`), which hold plaintext too and are wiped along with it.
+use extendr_api::prelude::*;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::access::Access;
+use crate::audit;
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::shred::shred_file;
+use crate::stream::{age_input, check_age_file, copy_interruptible, replace_file, PartialOutput, Progress};
+use crate::{create_output_file, encrypt_file, parse_identities_from_key_file, parse_recipients, read_key_file};
+
+/// Suffixes of the files SQLite keeps next to a database while it is open
+const SQLITE_SIDECARS: [&str; 3] = ["-journal", "-wal", "-shm"];
+
+struct Registry {
+    /// The process that created the files; 0 before the first one
+    pid: u32,
+    paths: Vec<String>,
+    /// Directories made by `age_secure_tempdir()`
+    dirs: Vec<String>,
+}
+
+static TEMP_FILES: Mutex<Registry> = Mutex::new(Registry { pid: 0, paths: Vec::new(), dirs: Vec::new() });
+
+/// The registry, emptied first if it was inherited from another process
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    let mut registry = TEMP_FILES.lock().unwrap_or_else(|e| e.into_inner());
+    let pid = std::process::id();
+    if registry.pid != pid {
+        registry.pid = pid;
+        registry.paths.clear();
+        registry.dirs.clear();
+    }
+    registry
+}
+
+/// A new random path in `dir`, ending in `extension`
+fn temp_path(dir: &str, extension: &str) -> String {
+    use rand::RngCore;
+
+    let mut random = [0u8; 12];
+    rand::rngs::OsRng.fill_bytes(&mut random);
+    let name: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+    Path::new(dir).join(format!("lockbox-{}{}", name, extension)).to_string_lossy().into_owned()
+}
+
+/// Wipe every file under `dir`, then remove it, counting the files in `removed`
+///
+/// Symbolic links are removed without following them.
+fn shred_dir(dir: &Path, removed: &mut i32) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            shred_dir(&path, removed)?;
+        } else if file_type.is_file() {
+            shred_file(&path.to_string_lossy(), 1)?;
+            *removed += 1;
+        } else {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    std::fs::remove_dir(dir)
+}
+
+/// Create a new directory in `root` that only its owner can use
+///
+/// The directory gets a random name and mode 0700, whatever the umask, and is
+/// recorded for `age_cleanup_temp()`. Returns its path.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_secure_tempdir(root: &str) -> Result<String> {
+    let path = temp_path(root, "");
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    // Creating the directory fails if the name is taken, so it cannot be someone else's
+    builder.create(fs_path(&path))
+        .map_err(|e| LockboxError::io(format!("Failed to create the temporary directory '{}'", path), &e))?;
+    // A umask that clears the owner's bits would leave it unusable
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if let Err(e) = std::fs::set_permissions(fs_path(&path), std::fs::Permissions::from_mode(0o700)) {
+            let _ = std::fs::remove_dir(fs_path(&path));
+            return Err(LockboxError::io(format!("Failed to set the permissions of '{}'", path), &e).into());
+        }
+    }
+    registry().dirs.push(path.clone());
+    Ok(path)
+}
+
+/// Decrypt a file into a new temporary file readable only by its owner
+///
+/// The file is created in `dir` with a random name ending in `extension` and
+/// recorded for `age_cleanup_temp()`. Returns its path.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_decrypt_to_tempfile(encrypted_file_path: &str, private_key_path: &str, strict_permissions: bool, dir: &str, extension: &str) -> Result<String> {
+    let key_content = read_key_file(private_key_path, strict_permissions)?;
+    let identities = parse_identities_from_key_file(&key_content)?;
+
+    policy::check_decrypt_from(&fs_path(encrypted_file_path))?;
+    check_age_file(&fs_path(encrypted_file_path))?;
+    let input = File::open(fs_path(encrypted_file_path))
+        .map_err(|e| LockboxError::io(format!("Failed to read encrypted file '{}'", encrypted_file_path), &e))?;
+    let decryptor = age::Decryptor::new(age_input(BufReader::new(input))?)
+        .map_err(|e| LockboxError::from_decrypt(e, false))?;
+    let scrypt = decryptor.is_scrypt();
+    let mut decrypted_reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))
+        .map_err(|e| LockboxError::from_decrypt(e, scrypt))?;
+
+    let path = temp_path(dir, extension);
+    policy::check_plaintext_to(&fs_path(&path))?;
+    let output = create_output_file(&path, false, Access::OWNER_ONLY)?;
+    let partial = PartialOutput::new(&path);
+    let mut writer = BufWriter::new(output);
+    copy_interruptible(&mut decrypted_reader, &mut writer, &mut Progress::none(), LockboxError::from_payload)?;
+    writer.flush()
+        .map_err(|e| LockboxError::io(format!("Failed to write '{}'", path), &e))?;
+    partial.complete();
+
+    registry().paths.push(path.clone());
+    Ok(path)
+}
+
+/// Temporary files created this session that still exist
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_temp_files() -> Vec<String> {
+    registry().paths.iter().filter(|path| fs_path(path).exists()).cloned().collect()
+}
+
+/// Wipe and remove recorded temporary files: those in `paths`, or all of them
+///
+/// Directories made by `age_secure_tempdir()` are removed with everything in them.
+/// Paths that were not created by `age_decrypt_to_tempfile()` or
+/// `age_secure_tempdir()` are left alone. Returns the number of files removed.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_cleanup_temp(paths: Nullable<Vec<String>>) -> Result<i32> {
+    let mut registry = registry();
+    let (selected, kept): (Vec<String>, Vec<String>) = registry.paths.drain(..).partition(|path| match &paths {
+        Nullable::NotNull(paths) => paths.contains(path),
+        Nullable::Null => true,
+    });
+    registry.paths = kept;
+    let (selected_dirs, kept_dirs): (Vec<String>, Vec<String>) = registry.dirs.drain(..).partition(|dir| match &paths {
+        Nullable::NotNull(paths) => paths.contains(dir),
+        Nullable::Null => true,
+    });
+    registry.dirs = kept_dirs;
+
+    // Files that could not be removed stay recorded, so a later cleanup retries them
+    let mut removed = 0;
+    let mut failed = Vec::new();
+    for path in selected {
+        match shred_file(&path, 1) {
+            Ok(()) => removed += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                failed.push(format!("'{}' ({})", path, e));
+                registry.paths.push(path);
+                continue;
+            }
+        }
+        for sidecar in SQLITE_SIDECARS.iter().map(|suffix| format!("{}{}", path, suffix)) {
+            match shred_file(&sidecar, 1) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => failed.push(format!("'{}' ({})", sidecar, e)),
+            }
+        }
+    }
+    // After the files, some of which may be inside
+    for dir in selected_dirs {
+        match shred_dir(&fs_path(&dir), &mut removed) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                failed.push(format!("'{}' ({})", dir, e));
+                registry.dirs.push(dir);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(LockboxError::Io(format!("Failed to remove temporary files: {}", failed.join(", "))).into());
+    }
+    Ok(removed)
+}
+
+/// Encrypt a decrypted temporary database back to `output`
+///
+/// Refused while SQLite still has a journal next to the database, since changes in
+/// it would be lost: the connection must be closed first. An existing `output` is
+/// replaced only once the new file is complete, keeping its permissions.
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_encrypt_db(database_path: &str, output_file_path: &str, recipients: Vec<String>, armor: bool) -> Result<()> {
+    let keys = recipients.clone();
+    let result = (|| {
+        if let Some(journal) = ["-journal", "-wal"].iter()
+            .map(|suffix| format!("{}{}", database_path, suffix))
+            .find(|journal| std::fs::metadata(fs_path(journal)).map(|m| m.len() > 0).unwrap_or(false))
+        {
+            return Err(LockboxError::InvalidArgument(format!(
+                "The database has unsaved changes in '{}'; close its connection before encrypting it",
+                journal
+            )).into());
+        }
+
+        let parsed_recipients = parse_recipients(recipients)?;
+        let encryptor = policy::encryptor(parsed_recipients.iter().map(|r| r.as_ref()))?;
+
+        let permissions = match std::fs::metadata(fs_path(output_file_path)) {
+            Ok(metadata) => metadata.permissions(),
+            Err(_) => return encrypt_file(encryptor, database_path, output_file_path, armor, false, Access::default(), false, false, Nullable::Null),
+        };
+        policy::check_armor(armor)?;
+        let mut input = File::open(fs_path(database_path))
+            .map_err(|e| LockboxError::io("Failed to read input file", &e))?;
+        replace_file(output_file_path, armor, permissions, |output| {
+            let mut encrypted_writer = encryptor.wrap_output(output)
+                .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+            copy_interruptible(&mut input, &mut encrypted_writer, &mut Progress::none(), |e| {
+                LockboxError::io("Failed to read input file", &e)
+            })?;
+            encrypted_writer.finish()
+                .map_err(|e| LockboxError::io("Failed to finalize encryption", &e))?;
+            Ok(())
+        })
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(Some(database_path), output_file_path, done.is_some(), audit::recipient_keys(keys))
+    })
+}
+
+extendr_module! {
+    mod tempfile;
+    fn age_secure_tempdir;
+    fn age_decrypt_to_tempfile;
+    fn age_temp_files;
+    fn age_cleanup_temp;
+    fn age_encrypt_db;
+}
@@ -0,0 +1,202 @@
+// Encrypted key-value vault
+//
+// A vault is a single armored age file whose plaintext is a JSON document holding the
+// recipients it is encrypted to and a map of named secrets. The recipients are kept
+// inside the encrypted document because age headers do not record them, and every
+// update must re-encrypt the vault for the same people.
+use extendr_api::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Write};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::access::Access;
+use crate::audit;
+use crate::error::LockboxError;
+use crate::paths::fs_path;
+use crate::policy;
+use crate::stats::{self, Direction};
+use crate::stream::{replace_file, PartialOutput, Progress};
+use crate::{create_output_file, decrypt_content, parse_identities_from_key_file, parse_recipients, read_key_file};
+
+/// Version of the vault document format
+const VAULT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Vault {
+    version: u32,
+    recipients: Vec<String>,
+    secrets: BTreeMap<String, String>,
+}
+
+impl Drop for Vault {
+    fn drop(&mut self) {
+        for value in self.secrets.values_mut() {
+            value.zeroize();
+        }
+    }
+}
+
+/// Decrypt and parse a vault
+fn read_vault(vault_path: &str, private_key_path: &str, strict_permissions: bool) -> Result<Vault> {
+    let result = (|| {
+        let key_content = read_key_file(private_key_path, strict_permissions)?;
+        let identities = parse_identities_from_key_file(&key_content)?;
+
+        let path = fs_path(vault_path);
+        policy::check_decrypt_from(&path)?;
+        let file_content = std::fs::read(&path)
+            .map_err(|e| LockboxError::io("Failed to read vault", &e))?;
+        let plaintext = decrypt_content(&file_content, identities.iter().map(|i| i.as_ref()), &mut Progress::none())?;
+
+        let vault: Vault = serde_json::from_slice(&plaintext)
+            .map_err(|e| LockboxError::Encoding(format!("The file is not a lockbox vault: {}", e)))?;
+        if vault.version > VAULT_VERSION {
+            return Err(LockboxError::Encoding(format!(
+                "The vault uses format version {}, which this version of lockbox cannot read",
+                vault.version
+            )).into());
+        }
+        Ok(vault)
+    })();
+    audit::record("decrypt", "key", result, |_| {
+        audit::Details::file(vault_path, None, audit::key_file_public_keys(private_key_path))
+    })
+}
+
+/// Encrypt a vault for its recipients into `output`
+fn write_vault<W: Write>(vault: &Vault, output: W) -> Result<W> {
+    let recipients = parse_recipients(vault.recipients.clone())?;
+    let encryptor = policy::encryptor(recipients.iter().map(|r| r.as_ref()))?;
+
+    let plaintext = Zeroizing::new(serde_json::to_vec(vault)
+        .map_err(|e| LockboxError::Encoding(format!("Failed to serialize vault: {}", e)))?);
+
+    let mut encrypted_writer = encryptor.wrap_output(output)
+        .map_err(|e| LockboxError::io("Failed to wrap output for encryption", &e))?;
+    encrypted_writer.write_all(&plaintext)
+        .map_err(|e| LockboxError::io("Failed to write encrypted data", &e))?;
+    let output = encrypted_writer.finish()
+        .map_err(|e| LockboxError::io("Failed to finalize encryption", &e))?;
+    stats::add_bytes(Direction::Encrypt, plaintext.len() as u64);
+    Ok(output)
+}
+
+/// Replace an existing vault with an updated one, keeping its permissions
+fn update_vault(vault_path: &str, vault: &Vault) -> Result<()> {
+    let result = (|| {
+        let permissions = std::fs::metadata(fs_path(vault_path))
+            .map_err(|e| LockboxError::io("Failed to read vault", &e))?
+            .permissions();
+        replace_file(vault_path, true, permissions, |output| {
+            write_vault(vault, output)?;
+            Ok(())
+        })
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(None, vault_path, done.is_some(), audit::recipient_keys(vault.recipients.clone()))
+    })
+}
+
+fn missing_keys(vault: &Vault, keys: &[String]) -> Result<()> {
+    let missing: Vec<&str> = keys.iter()
+        .filter(|key| !vault.secrets.contains_key(*key))
+        .map(|key| key.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(LockboxError::NotFound(format!(
+            "Secret(s) not found in vault: {}",
+            missing.join(", ")
+        )).into());
+    }
+    Ok(())
+}
+
+/// Create an empty vault encrypted for the given recipients
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_vault_create(vault_path: &str, recipients: Vec<String>, overwrite: bool) -> Result<()> {
+    use age::armor::{ArmoredWriter, Format};
+
+    let keys = recipients.clone();
+    let result = (|| {
+        let vault = Vault { version: VAULT_VERSION, recipients, secrets: BTreeMap::new() };
+
+        let file = create_output_file(vault_path, overwrite, Access::default())?;
+        let partial = PartialOutput::new(vault_path);
+        let armored_writer = ArmoredWriter::wrap_output(BufWriter::new(file), Format::AsciiArmor)
+            .map_err(|e| LockboxError::Armor(format!("Failed to create armored writer: {}", e)))?;
+        write_vault(&vault, armored_writer)?
+            .finish()
+            .and_then(|mut writer| writer.flush())
+            .map_err(|e| LockboxError::io("Failed to finalize vault", &e))?;
+        partial.complete();
+
+        Ok(())
+    })();
+    audit::record("encrypt", "key", result, |done| {
+        audit::Details::encrypted_file(None, vault_path, done.is_some(), audit::recipient_keys(keys))
+    })
+}
+
+/// Add or replace secrets in a vault
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_vault_set(vault_path: &str, private_key_path: &str, keys: Vec<String>, values: Vec<String>, strict_permissions: bool) -> Result<()> {
+    if keys.len() != values.len() {
+        return Err(LockboxError::InvalidArgument("`key` and `value` must have the same length".to_string()).into());
+    }
+
+    let mut vault = read_vault(vault_path, private_key_path, strict_permissions)?;
+    for (key, value) in keys.into_iter().zip(values) {
+        if let Some(mut old) = vault.secrets.insert(key, value) {
+            old.zeroize();
+        }
+    }
+    update_vault(vault_path, &vault)
+}
+
+/// Get secrets from a vault, in the order of `keys`
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_vault_get(vault_path: &str, private_key_path: &str, keys: Vec<String>, strict_permissions: bool) -> Result<Vec<String>> {
+    let vault = read_vault(vault_path, private_key_path, strict_permissions)?;
+    missing_keys(&vault, &keys)?;
+    Ok(keys.iter().map(|key| vault.secrets[key].clone()).collect())
+}
+
+/// List the names of the secrets in a vault
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_vault_list(vault_path: &str, private_key_path: &str, strict_permissions: bool) -> Result<Vec<String>> {
+    let vault = read_vault(vault_path, private_key_path, strict_permissions)?;
+    Ok(vault.secrets.keys().cloned().collect())
+}
+
+/// Delete secrets from a vault
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_vault_delete(vault_path: &str, private_key_path: &str, keys: Vec<String>, strict_permissions: bool) -> Result<()> {
+    let mut vault = read_vault(vault_path, private_key_path, strict_permissions)?;
+    missing_keys(&vault, &keys)?;
+    for key in &keys {
+        if let Some(mut old) = vault.secrets.remove(key) {
+            old.zeroize();
+        }
+    }
+    update_vault(vault_path, &vault)
+}
+
+extendr_module! {
+    mod vault;
+    fn age_vault_create;
+    fn age_vault_set;
+    fn age_vault_get;
+    fn age_vault_list;
+    fn age_vault_delete;
+}
@@ -0,0 +1,38 @@
+// What this build of lockbox supports
+//
+// Bug reports and compatibility checks need to know which age library a binary was
+// built with and what it can read and write. The versions come from build.rs.
+use extendr_api::prelude::*;
+
+/// The header line of every age file this library writes and reads
+const AGE_FORMAT: &str = "age-encryption.org/v1";
+
+/// Kinds of recipients and identities lockbox handles itself; others go through age plugins
+const KEY_TYPES: &[&str] = &["X25519", "scrypt"];
+
+/// Cargo features compiled in, by name
+const FEATURES: &[(&str, bool)] = &[("s3", cfg!(feature = "s3")), ("clipboard", cfg!(feature = "clipboard"))];
+
+/// Describe the build: versions, supported key types and features
+/// @keywords internal
+/// @noRd
+#[extendr]
+fn age_version_info() -> List {
+    let features: Vec<&str> = FEATURES.iter().filter(|(_, enabled)| *enabled).map(|(name, _)| *name).collect();
+    list!(
+        age = env!("LOCKBOX_AGE_VERSION"),
+        format = AGE_FORMAT,
+        recipients = KEY_TYPES.to_vec(),
+        identities = KEY_TYPES.to_vec(),
+        plugins = true,
+        armor = true,
+        features = features,
+        rustc = env!("LOCKBOX_RUSTC_VERSION"),
+        target = env!("LOCKBOX_TARGET")
+    )
+}
+
+extendr_module! {
+    mod version;
+    fn age_version_info;
+}